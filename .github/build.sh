@@ -141,6 +141,10 @@ compress_and_move() {
   chmod +x "${target_dir}/${bin_name}"
   cd "${target_dir}"
   tar czvf $name-$tag-${build_target}.tar.gz $bin_name
+  # Sign the archive in place, `vproxy self update` refuses unsigned archives
+  if [ -n "$UPDATE_SIGNING_KEY" ]; then
+    zipsign sign tar $name-$tag-${build_target}.tar.gz "$UPDATE_SIGNING_KEY"
+  fi
   shasum -a 256 $name-$tag-${build_target}.tar.gz >$name-$tag-${build_target}.tar.gz.sha256
   mv $name-$tag-${build_target}.tar.gz $root/bin/
   mv $name-$tag-${build_target}.tar.gz.sha256 $root/bin/
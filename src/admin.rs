@@ -0,0 +1,450 @@
+//! Admin API.
+//!
+//! A small plain-text HTTP API for operating a running instance:
+//!
+//! - `GET /users` lists the users of the credential file, one per line;
+//! - `PUT /users/<name>` adds a user or replaces its password and settings,
+//!   with a body in the credential file syntax, e.g. `secret cidr=2001:db8::/48`;
+//! - `POST /users/<name>/disable` and `POST /users/<name>/enable` toggle
+//!   whether a user may log in;
+//! - `GET /bans` lists the banned client IPs, IPv6 ones as /64, with the
+//!   seconds left of their ban, one per line, rate limit bans included;
+//! - `DELETE /bans/<ip>` lifts the ban of `ip`, or of its /64 for IPv6;
+//! - `GET /cache` reports the number and size of cached HTTP responses;
+//! - `DELETE /cache` purges the response cached for the URL in the body, or
+//!   every response when the body is empty;
+//! - `GET /sessions/<user>/<id>` reports the egress address the session `id`
+//!   of `user` is pinned to, i.e. the one of the login `<user>-session-<id>`;
+//! - `DELETE /sessions/<user>/<id>` moves the session to a new egress address
+//!   and reports it;
+//! - `GET /egress-bans` lists the banned egress prefixes, one per line;
+//! - `POST /egress-bans` bans the egress address or prefix in the body, e.g.
+//!   `2001:db8:1::/64`, and `DELETE /egress-bans` lifts its ban;
+//! - `GET /destinations` lists the destination hosts with the most recent
+//!   requests, or bytes with `?by=bytes`, 20 of them unless `?top=N` says
+//!   otherwise;
+//! - `GET /egress-health` lists the subnets probed by the egress health
+//!   check, each followed by `alive` or `dead`;
+//! - `GET /update` reports the running version and the newest release found
+//!   by `--update-check`;
+//! - `GET /metrics` serves the DNS, connect and TLS handshake latency
+//!   histograms in the Prometheus text format, with the egress subnet health;
+//! - `GET /healthz` answers as long as the process is alive;
+//! - `GET /readyz` reports the listener, route setup and resource pressure,
+//!   as well as a drain before an update is applied, answering 503 Service Unavailable when the instance is not ready.
+//!
+//! User changes are written back to the credential file and apply to new
+//! logins, established tunnels are kept. Requests need the `Authorization: Bearer`
+//! token when one is configured, and must come from a loopback address
+//! otherwise. The health endpoints are open to all clients so that load
+//! balancers can probe them.
+
+use crate::{
+    ban::Bans,
+    connect::Connector,
+    extension::{parser, Extension},
+    http::cache::HttpCache,
+    users::Users,
+};
+use bytes::Bytes;
+use cidr::IpCidr;
+use http::{header, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::net::TcpListener;
+use tracing::Instrument;
+
+/// Largest request body accepted.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Destination hosts listed by default.
+const DEFAULT_TOP_DESTINATIONS: usize = 20;
+
+/// State shared by the admin API connections.
+pub(crate) struct Admin {
+    token: Option<String>,
+    users: Option<Arc<Users>>,
+    bans: Option<Arc<Bans>>,
+    cache: Option<Arc<HttpCache>>,
+    connector: Connector,
+}
+
+impl Admin {
+    /// Create the admin API state.
+    pub(crate) fn new(
+        token: Option<String>,
+        users: Option<Arc<Users>>,
+        bans: Option<Arc<Bans>>,
+        cache: Option<Arc<HttpCache>>,
+        connector: Connector,
+    ) -> Self {
+        Self {
+            token,
+            users,
+            bans,
+            cache,
+            connector,
+        }
+    }
+
+    /// Serves the admin API on `bind`.
+    pub(crate) async fn serve(self, bind: SocketAddr) -> std::io::Result<()> {
+        let listener = TcpListener::bind(bind).await?;
+        tracing::info!("Admin API listening on {}", listener.local_addr()?);
+
+        let admin = Arc::new(self);
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    tracing::debug!("[admin] accept error: {}", err);
+                    continue;
+                }
+            };
+
+            let admin = admin.clone();
+            tokio::spawn(
+                async move {
+                    let service = service_fn(|req| {
+                        let admin = admin.clone();
+                        async move { Ok::<_, hyper::Error>(admin.handle(peer, req).await) }
+                    });
+                    if let Err(err) = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await
+                    {
+                        tracing::debug!("[admin] connection error: {}", err);
+                    }
+                }
+                .in_current_span(),
+            );
+        }
+    }
+
+    /// Whether the request may use the API.
+    fn authorized(&self, peer: SocketAddr, req: &Request<Incoming>) -> bool {
+        match &self.token {
+            Some(token) => req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .is_some_and(|value| constant_time_eq(value.as_bytes(), token.as_bytes())),
+            None => peer.ip().is_loopback(),
+        }
+    }
+
+    async fn handle(&self, peer: SocketAddr, req: Request<Incoming>) -> Response<Full<Bytes>> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/healthz") => return text(StatusCode::OK, "ok\n"),
+            (&Method::GET, "/readyz") => {
+                let (ready, report) = crate::status::readiness();
+                let status = if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                return text(status, report);
+            }
+            _ => {}
+        }
+
+        if !self.authorized(peer, &req) {
+            return text(StatusCode::UNAUTHORIZED, "unauthorized\n");
+        }
+
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+        match segments.first() {
+            Some(&"users") => {
+                let Some(users) = &self.users else {
+                    return text(StatusCode::NOT_FOUND, "no credential file configured\n");
+                };
+                Self::handle_users(users, peer, method, &segments, req).await
+            }
+            Some(&"bans") => {
+                let Some(bans) = &self.bans else {
+                    return text(StatusCode::NOT_FOUND, "banning is not enabled\n");
+                };
+                Self::handle_bans(bans, peer, method, &segments)
+            }
+            Some(&"cache") => {
+                let Some(cache) = &self.cache else {
+                    return text(StatusCode::NOT_FOUND, "caching is not enabled\n");
+                };
+                Self::handle_cache(cache, peer, method, &segments, req).await
+            }
+            Some(&"sessions") => self.handle_sessions(peer, method, &segments),
+            Some(&"egress-bans") if segments.len() == 1 => {
+                self.handle_egress_bans(peer, method, req).await
+            }
+            Some(&"destinations") if method == Method::GET && segments.len() == 1 => {
+                Self::handle_destinations(req.uri().query())
+            }
+            Some(&"egress-health") if method == Method::GET && segments.len() == 1 => {
+                let health = self
+                    .connector
+                    .health()
+                    .subnets()
+                    .into_iter()
+                    .map(|(subnet, alive)| {
+                        format!("{subnet} {}\n", if alive { "alive" } else { "dead" })
+                    })
+                    .collect::<String>();
+                text(StatusCode::OK, health)
+            }
+            Some(&"update") if method == Method::GET && segments.len() == 1 => {
+                text(StatusCode::OK, crate::update::report())
+            }
+            #[cfg(feature = "metrics")]
+            Some(&"metrics") if method == Method::GET && segments.len() == 1 => text(
+                StatusCode::OK,
+                crate::metrics::render(self.connector.health()),
+            ),
+            _ => text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+    }
+
+    async fn handle_users(
+        users: &Users,
+        peer: SocketAddr,
+        method: Method,
+        segments: &[&str],
+        req: Request<Incoming>,
+    ) -> Response<Full<Bytes>> {
+        match (method, segments) {
+            (Method::GET, ["users"]) => text(StatusCode::OK, users.list()),
+            (Method::PUT, ["users", name]) => {
+                let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(err) => return text(StatusCode::BAD_REQUEST, format!("{err}\n")),
+                };
+                let Ok(settings) = std::str::from_utf8(&body) else {
+                    return text(StatusCode::BAD_REQUEST, "body is not UTF-8\n");
+                };
+
+                match users.put(name, settings).await {
+                    Ok(()) => {
+                        tracing::info!("[admin] user {} updated by {}", name, peer);
+                        text(StatusCode::OK, "ok\n")
+                    }
+                    Err(err) => text(StatusCode::BAD_REQUEST, format!("{err}\n")),
+                }
+            }
+            (Method::POST, ["users", name, action @ ("disable" | "enable")]) => {
+                match users.set_disabled(name, *action == "disable").await {
+                    Ok(true) => {
+                        tracing::info!("[admin] user {} {}d by {}", name, action, peer);
+                        text(StatusCode::OK, "ok\n")
+                    }
+                    Ok(false) => text(StatusCode::NOT_FOUND, "no such user\n"),
+                    Err(err) => text(StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")),
+                }
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+    }
+
+    fn handle_bans(
+        bans: &Bans,
+        peer: SocketAddr,
+        method: Method,
+        segments: &[&str],
+    ) -> Response<Full<Bytes>> {
+        match (method, segments) {
+            (Method::GET, ["bans"]) => text(StatusCode::OK, bans.list()),
+            (Method::DELETE, ["bans", ip]) => {
+                let Ok(ip) = ip.parse::<IpAddr>() else {
+                    return text(StatusCode::BAD_REQUEST, "invalid IP address\n");
+                };
+                if bans.unban(ip) {
+                    tracing::info!("[admin] {} unbanned by {}", ip, peer);
+                    text(StatusCode::OK, "ok\n")
+                } else {
+                    text(StatusCode::NOT_FOUND, "not banned\n")
+                }
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+    }
+
+    fn handle_sessions(
+        &self,
+        peer: SocketAddr,
+        method: Method,
+        segments: &[&str],
+    ) -> Response<Full<Bytes>> {
+        let (user, id) = match segments {
+            ["sessions", user, id] if !user.is_empty() => (*user, *id),
+            _ => return text(StatusCode::NOT_FOUND, "not found\n"),
+        };
+
+        // The same hash a login of the session gets, see `extension::parser`.
+        let extension = parser(user.to_owned(), format!("{user}-session-{id}"));
+        let Some(session) = extension.session else {
+            return text(StatusCode::BAD_REQUEST, "invalid session\n");
+        };
+
+        match method {
+            Method::GET => {}
+            Method::DELETE => {
+                let generation = self.connector.invalidations().invalidate(session);
+                tracing::info!(
+                    "[admin] session {} of {} invalidated by {} ({} times)",
+                    id,
+                    user,
+                    peer,
+                    generation
+                );
+            }
+            _ => return text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+
+        match self.egress_addr(user, extension) {
+            Ok(Some(ip)) => text(StatusCode::OK, format!("{ip}\n")),
+            Ok(None) => text(StatusCode::OK, "default\n"),
+            Err(err) => text(StatusCode::SERVICE_UNAVAILABLE, format!("{err}\n")),
+        }
+    }
+
+    /// Returns the egress address of `extension` for the user `user`, from
+    /// the user's own pool if it has one.
+    fn egress_addr(&self, user: &str, extension: Extension) -> std::io::Result<Option<IpAddr>> {
+        let pool = self.users.as_ref().and_then(|users| users.pool(user));
+        let connector = match pool {
+            Some(pool) if !pool.is_empty() => self.connector.with_pool(&pool),
+            _ => self.connector.clone(),
+        };
+        connector.tcp_connector().egress_addr(extension)
+    }
+
+    async fn handle_egress_bans(
+        &self,
+        peer: SocketAddr,
+        method: Method,
+        req: Request<Incoming>,
+    ) -> Response<Full<Bytes>> {
+        let bans = self.connector.egress_bans();
+        if method == Method::GET {
+            return text(StatusCode::OK, bans.list());
+        }
+        if method != Method::POST && method != Method::DELETE {
+            return text(StatusCode::NOT_FOUND, "not found\n");
+        }
+
+        let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(err) => return text(StatusCode::BAD_REQUEST, format!("{err}\n")),
+        };
+        let Some(cidr) = std::str::from_utf8(&body)
+            .ok()
+            .and_then(|body| body.trim().parse::<IpCidr>().ok())
+        else {
+            return text(StatusCode::BAD_REQUEST, "invalid IP-CIDR\n");
+        };
+
+        let res = if method == Method::POST {
+            bans.ban(cidr).await
+        } else {
+            bans.unban(cidr).await
+        };
+        match res {
+            Ok(true) => {
+                let action = if method == Method::POST {
+                    "banned"
+                } else {
+                    "unbanned"
+                };
+                tracing::info!("[admin] egress {} {} by {}", cidr, action, peer);
+                text(StatusCode::OK, "ok\n")
+            }
+            Ok(false) if method == Method::POST => text(StatusCode::OK, "already banned\n"),
+            Ok(false) => text(StatusCode::NOT_FOUND, "not banned\n"),
+            Err(err) => text(StatusCode::INTERNAL_SERVER_ERROR, format!("{err}\n")),
+        }
+    }
+
+    fn handle_destinations(query: Option<&str>) -> Response<Full<Bytes>> {
+        let mut top = DEFAULT_TOP_DESTINATIONS;
+        let mut by_bytes = false;
+        for (key, value) in query
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+        {
+            match (key, value) {
+                ("top", n) => match n.parse() {
+                    Ok(n) => top = n,
+                    Err(_) => return text(StatusCode::BAD_REQUEST, "invalid top\n"),
+                },
+                ("by", "requests") => by_bytes = false,
+                ("by", "bytes") => by_bytes = true,
+                ("by", _) => {
+                    return text(StatusCode::BAD_REQUEST, "by must be requests or bytes\n")
+                }
+                _ => {}
+            }
+        }
+        text(
+            StatusCode::OK,
+            crate::stats::top_destinations(top, by_bytes),
+        )
+    }
+
+    async fn handle_cache(
+        cache: &HttpCache,
+        peer: SocketAddr,
+        method: Method,
+        segments: &[&str],
+        req: Request<Incoming>,
+    ) -> Response<Full<Bytes>> {
+        match (method, segments) {
+            (Method::GET, ["cache"]) => {
+                let (responses, size) = cache.usage();
+                text(
+                    StatusCode::OK,
+                    format!("{responses} responses, {size} bytes\n"),
+                )
+            }
+            (Method::DELETE, ["cache"]) => {
+                let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+                    Ok(body) => body.to_bytes(),
+                    Err(err) => return text(StatusCode::BAD_REQUEST, format!("{err}\n")),
+                };
+                let Ok(url) = std::str::from_utf8(&body) else {
+                    return text(StatusCode::BAD_REQUEST, "body is not UTF-8\n");
+                };
+
+                let url = Some(url.trim()).filter(|url| !url.is_empty());
+                let purged = cache.purge(url);
+                tracing::info!("[admin] {} cached responses purged by {}", purged, peer);
+                text(StatusCode::OK, format!("{purged} purged\n"))
+            }
+            _ => text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+    }
+}
+
+/// Builds a plain-text response.
+fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(body.into()));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("text/plain"),
+    );
+    resp
+}
+
+/// Compares `a` and `b` in a time that does not depend on where they differ,
+/// so the token cannot be guessed byte by byte from response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
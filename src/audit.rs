@@ -0,0 +1,99 @@
+//! Authentication audit log.
+//!
+//! Every authentication attempt is appended to a file as a JSON line, e.g.
+//!
+//! ```json
+//! {"time":1700000000.123,"event":"auth","protocol":"socks5","client_ip":"192.0.2.1","username":"alice","success":false,"connection":"3f2a9c01"}
+//! ```
+//!
+//! independently of the log level. `connection` is the id of the client
+//! connection its log lines are tagged with. Records are written by a thread of their
+//! own so that a slow disk does not hold up connections; when it falls too far
+//! behind, records are dropped and counted in a warning.
+
+use crate::{
+    hooks::{AuthAttempt, Hooks, Protocol},
+    stats::ConnectionId,
+};
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Records waiting to be written, beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 65536;
+
+/// Appends authentication attempts to a file.
+pub(crate) struct AuditLog {
+    records: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    /// Opens the audit log at `path`, creating it if needed.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (records, rx) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+
+        std::thread::Builder::new()
+            .name("vproxy-audit".to_owned())
+            .spawn(move || {
+                let mut writer = BufWriter::new(file);
+                while let Ok(record) = rx.recv() {
+                    let mut res = writer.write_all(record.as_bytes());
+                    // Flush once the backlog is written
+                    for record in rx.try_iter() {
+                        res = res.and_then(|_| writer.write_all(record.as_bytes()));
+                    }
+                    if let Err(err) = res.and_then(|_| writer.flush()) {
+                        tracing::error!("Failed to write the audit log: {}", err);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            records,
+            dropped: AtomicU64::new(0),
+        })
+    }
+}
+
+impl Hooks for AuditLog {
+    fn on_auth_attempt(&self, attempt: &AuthAttempt<'_>) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let protocol = match attempt.protocol {
+            Protocol::Http => "http",
+            Protocol::Socks5 => "socks5",
+            Protocol::Sni => "sni",
+            Protocol::Forward => "forward",
+        };
+
+        let mut record = serde_json::json!({
+            "time": (time * 1000.0).round() / 1000.0,
+            "event": "auth",
+            "protocol": protocol,
+            "client_ip": attempt.peer.ip().to_string(),
+            "username": attempt.username,
+            "success": attempt.success,
+            "connection": ConnectionId::current().map(|id| id.to_string()),
+        })
+        .to_string();
+        record.push('\n');
+
+        if self.records.try_send(record).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % 10_000 == 1 {
+                tracing::warn!("Audit log is behind, {} records dropped", dropped);
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+//! External authentication webhook.
+//!
+//! Logins are checked by POSTing them to an operator's endpoint as JSON:
+//!
+//! ```json
+//! {"username": "alice-session-1", "password": "secret", "client_ip": "192.0.2.1"}
+//! ```
+//!
+//! which answers with a JSON object such as:
+//!
+//! ```json
+//! {"allow": true, "user": "alice", "cidr": "2001:db8::/48", "cidr_range": 64}
+//! ```
+//!
+//! Only `allow` is required. `user` is the name the login starts with, so
+//! the rest of it is parsed as an extension like for the other authentication
+//! modes, unless `extension` (e.g. `session-123`) overrides it. `cidr`,
+//! `cidr_range` and `fallback` override the egress pool of the command line.
+//!
+//! Answers are cached for a while so that the endpoint is not asked on every
+//! request, keyed by a digest of the password rather than the password itself.
+//! Logins the endpoint cannot be asked about are denied and not cached.
+
+use crate::{
+    extension::{self, Extension},
+    users::{self, Login, Pool},
+};
+use cidr::IpCidr;
+use reqwest::{Client, Url};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, net::IpAddr, sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+/// Time allowed for the endpoint to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most answers kept in the cache.
+const MAX_CACHED: usize = 65536;
+
+#[derive(Serialize)]
+struct Query<'a> {
+    username: &'a str,
+    password: &'a str,
+    client_ip: IpAddr,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    allow: bool,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    extension: Option<String>,
+    #[serde(default)]
+    cidr: Option<String>,
+    #[serde(default)]
+    cidr_range: Option<u8>,
+    #[serde(default)]
+    fallback: Option<String>,
+}
+
+/// What an allowed login is granted.
+#[derive(Clone)]
+struct Grant {
+    user: Option<String>,
+    extension: Option<String>,
+    pool: Pool,
+}
+
+/// Username, password digest and client address of a login.
+type Key = (String, [u8; 32], IpAddr);
+
+/// Authenticates logins against an HTTP endpoint.
+pub(crate) struct AuthWebhook {
+    url: Url,
+    client: Client,
+    ttl: Duration,
+    cache: Mutex<HashMap<Key, (Instant, Option<Grant>)>>,
+}
+
+impl AuthWebhook {
+    /// Create a webhook POSTing to `url`, caching its answers for `ttl`.
+    pub(crate) fn new(url: Url, ttl: Duration) -> io::Result<Self> {
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(Self {
+            url,
+            client,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Authenticates a login from `client_ip`, returning its extension and
+    /// egress pool if the endpoint allows it.
+    pub(crate) async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        client_ip: IpAddr,
+    ) -> Option<(Extension, Login)> {
+        let key = (username.to_owned(), users::digest(password), client_ip);
+        let grant = match self.cached(&key) {
+            Some(grant) => grant,
+            None => {
+                let grant = match self.query(username, password, client_ip).await {
+                    Ok(grant) => grant,
+                    Err(err) => {
+                        tracing::warn!("Authentication webhook failed: {}", err);
+                        return None;
+                    }
+                };
+                self.store(key, grant.clone());
+                grant
+            }
+        }?;
+
+        let extension = match (&grant.extension, &grant.user) {
+            (Some(extension), _) => extension::parser(String::new(), format!("-{extension}")),
+            (None, Some(user)) => Extension::try_from(user, username).await.ok()?,
+            (None, None) => Extension::default(),
+        };
+        let login = Login {
+            username: username.to_owned(),
+            pool: grant.pool,
+        };
+        Some((extension, login))
+    }
+
+    /// Returns the cached answer for `key`, if it has not expired.
+    fn cached(&self, key: &Key) -> Option<Option<Grant>> {
+        let cache = self.cache.lock().ok()?;
+        cache
+            .get(key)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, grant)| grant.clone())
+    }
+
+    fn store(&self, key: Key, grant: Option<Grant>) {
+        let Ok(mut cache) = self.cache.lock() else {
+            return;
+        };
+
+        let now = Instant::now();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, (expires, _)| *expires > now);
+        }
+        if cache.len() >= MAX_CACHED {
+            cache.clear();
+        }
+        cache.insert(key, (now + self.ttl, grant));
+    }
+
+    /// Asks the endpoint about a login.
+    async fn query(
+        &self,
+        username: &str,
+        password: &str,
+        client_ip: IpAddr,
+    ) -> Result<Option<Grant>, String> {
+        let answer = self
+            .client
+            .post(self.url.clone())
+            .json(&Query {
+                username,
+                password,
+                client_ip,
+            })
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| err.to_string())?
+            .json::<Answer>()
+            .await
+            .map_err(|err| format!("invalid answer: {err}"))?;
+
+        if !answer.allow {
+            return Ok(None);
+        }
+
+        let pool = Pool {
+            cidr: answer
+                .cidr
+                .map(|cidr| cidr.parse::<IpCidr>())
+                .transpose()
+                .map_err(|err| format!("invalid cidr: {err}"))?,
+            cidr_range: answer.cidr_range,
+            fallback: answer
+                .fallback
+                .map(|fallback| fallback.parse::<IpAddr>())
+                .transpose()
+                .map_err(|err| format!("invalid fallback: {err}"))?,
+        };
+
+        Ok(Some(Grant {
+            user: answer.user,
+            extension: answer.extension,
+            pool,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::Full;
+    use hyper::{server::conn::http1, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+    use tokio::net::TcpListener;
+
+    /// Serves `answer` to every request, returning the URL of the endpoint
+    /// and the number of requests it got.
+    async fn endpoint(answer: &'static str) -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let counter = counter.clone();
+                let service = service_fn(move |_| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    async move { Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(answer)))) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (url.parse().unwrap(), requests)
+    }
+
+    #[tokio::test]
+    async fn test_cached_grant() {
+        let (url, requests) =
+            endpoint(r#"{"allow": true, "user": "alice", "cidr_range": 64}"#).await;
+        let webhook = AuthWebhook::new(url, Duration::from_secs(60)).unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..2 {
+            let (extension, login) = webhook
+                .authenticate("alice-session-1", "secret", ip)
+                .await
+                .unwrap();
+            assert!(extension.session.is_some());
+            assert_eq!(login.pool.cidr_range, Some(64));
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+
+        // Another password is another login
+        webhook.authenticate("alice-session-1", "other", ip).await;
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_denial() {
+        let (url, requests) = endpoint(r#"{"allow": false}"#).await;
+        let webhook = AuthWebhook::new(url, Duration::from_secs(60)).unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..2 {
+            assert!(webhook.authenticate("bob", "secret", ip).await.is_none());
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let (url, requests) = endpoint(r#"{"allow": true}"#).await;
+        let webhook = AuthWebhook::new(url, Duration::ZERO).unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..2 {
+            assert!(webhook.authenticate("carol", "secret", ip).await.is_some());
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+    }
+}
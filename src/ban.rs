@@ -0,0 +1,222 @@
+//! Fail2ban-style banning of clients failing to authenticate.
+//!
+//! Failed logins are counted per client IP within a time window, HTTP requests
+//! answered with an authentication challenge not being logins. A client
+//! reaching the threshold is banned for a while: its connections are closed
+//! as soon as they are accepted, before any handshake. Clients exceeding the
+//! rate limit are banned here as well, see [`crate::limit`].
+//!
+//! IPv6 clients are keyed by their /64, which a single host usually gets
+//! whole, so that a client cannot escape its ban by changing addresses.
+
+use crate::hooks::{AuthAttempt, Hooks, Protocol};
+use cidr::{IpCidr, IpInet};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Failures and ban of a client.
+struct Client {
+    failures: u32,
+    window_start: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Returns the key clients of `ip` are counted and banned by: the address
+/// itself for IPv4, its /64 for IPv6.
+pub(crate) fn client_key(ip: IpAddr) -> IpCidr {
+    let ip = ip.to_canonical();
+    let prefix = if ip.is_ipv4() { 32 } else { 64 };
+    IpInet::new(ip, prefix).map_or(IpCidr::new_host(ip), |inet| inet.network())
+}
+
+/// Bans keyed by client IP, or /64 for IPv6.
+pub(crate) struct Bans {
+    /// Failed logins after which a client is banned, if they are counted.
+    max_failures: Option<u32>,
+    window: Duration,
+    ban: Duration,
+    clients: Mutex<HashMap<IpCidr, Client>>,
+}
+
+impl Bans {
+    /// Create bans of `ban` for clients failing `max_failures` logins within
+    /// `window`. Without `max_failures`, clients are only banned through
+    /// [`Bans::ban`].
+    pub(crate) fn new(max_failures: Option<u32>, window: Duration, ban: Duration) -> Self {
+        Self {
+            max_failures: max_failures.map(|max| max.max(1)),
+            window,
+            ban,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `ip` is currently banned.
+    pub(crate) fn banned(&self, ip: IpAddr, now: Instant) -> bool {
+        self.clients.lock().is_ok_and(|clients| {
+            clients
+                .get(&client_key(ip))
+                .and_then(|client| client.banned_until)
+                .is_some_and(|until| until > now)
+        })
+    }
+
+    /// Bans `ip` for `ban`, unless it already is for longer.
+    pub(crate) fn ban(&self, ip: IpAddr, ban: Duration, now: Instant) {
+        let Ok(mut clients) = self.clients.lock() else {
+            return;
+        };
+        let client = clients.entry(client_key(ip)).or_insert(Client {
+            failures: 0,
+            window_start: now,
+            banned_until: None,
+        });
+        client.banned_until = client.banned_until.max(Some(now + ban));
+    }
+
+    /// Records a failed login of `ip`, banning it once it reaches the
+    /// threshold.
+    fn fail(&self, ip: IpAddr, now: Instant) {
+        let Some(max_failures) = self.max_failures else {
+            return;
+        };
+        let Ok(mut clients) = self.clients.lock() else {
+            return;
+        };
+        let client = clients.entry(client_key(ip)).or_insert(Client {
+            failures: 0,
+            window_start: now,
+            banned_until: None,
+        });
+
+        if now.saturating_duration_since(client.window_start) > self.window {
+            client.failures = 0;
+            client.window_start = now;
+        }
+
+        client.failures += 1;
+        if client.failures >= max_failures {
+            tracing::warn!(
+                "{} failed to authenticate {} times, banned for {:?}",
+                client_key(ip),
+                client.failures,
+                self.ban
+            );
+            client.failures = 0;
+            client.window_start = now;
+            client.banned_until = Some(now + self.ban);
+        }
+    }
+
+    /// Lists the banned clients, IPv6 ones as /64, with the seconds left of
+    /// their ban, one per line.
+    pub(crate) fn list(&self) -> String {
+        let now = Instant::now();
+        let Ok(clients) = self.clients.lock() else {
+            return String::new();
+        };
+
+        let mut bans = clients
+            .iter()
+            .filter_map(|(ip, client)| {
+                let until = client.banned_until.filter(|until| *until > now)?;
+                Some((*ip, until.duration_since(now).as_secs()))
+            })
+            .collect::<Vec<_>>();
+        bans.sort();
+
+        bans.into_iter()
+            .fold(String::new(), |mut list, (ip, left)| {
+                let _ = writeln!(list, "{ip} {left}");
+                list
+            })
+    }
+
+    /// Lifts the ban of `ip`, or of its /64 for IPv6, returning whether it
+    /// was banned.
+    pub(crate) fn unban(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.clients.lock().is_ok_and(|mut clients| {
+            clients
+                .remove(&client_key(ip))
+                .and_then(|client| client.banned_until)
+                .is_some_and(|until| until > now)
+        })
+    }
+
+    /// Forgets clients that are neither banned nor failed recently, every
+    /// `interval`.
+    pub(crate) async fn prune(&self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let now = Instant::now();
+            if let Ok(mut clients) = self.clients.lock() {
+                clients.retain(|_, client| {
+                    client.banned_until.is_some_and(|until| until > now)
+                        || now.saturating_duration_since(client.window_start) <= self.window
+                });
+            }
+        }
+    }
+}
+
+impl Hooks for Bans {
+    fn on_connect(&self, _: Protocol, peer: SocketAddr) -> bool {
+        !self.banned(peer.ip(), Instant::now())
+    }
+
+    fn on_auth_attempt(&self, attempt: &AuthAttempt<'_>) {
+        // A challenged HTTP request is not a failed login
+        if !attempt.success && attempt.presented {
+            self.fail(attempt.peer.ip(), Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ban_after_failures() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        let bans = Bans::new(Some(3), Duration::from_secs(60), Duration::from_secs(600));
+
+        bans.fail(ip, now);
+        bans.fail(ip, now);
+        // The window restarts after a minute
+        bans.fail(ip, now + Duration::from_secs(61));
+        assert!(!bans.banned(ip, now + Duration::from_secs(61)));
+
+        bans.fail(ip, now + Duration::from_secs(62));
+        bans.fail(ip, now + Duration::from_secs(63));
+        assert!(bans.banned(ip, now + Duration::from_secs(63)));
+        assert!(!bans.banned(ip, now + Duration::from_secs(664)));
+    }
+
+    #[test]
+    fn test_challenge_is_not_a_failure() {
+        let peer = "192.0.2.1:40000".parse::<SocketAddr>().unwrap();
+        let bans = Bans::new(Some(1), Duration::from_secs(60), Duration::from_secs(600));
+        let attempt = |presented| AuthAttempt {
+            protocol: Protocol::Http,
+            peer,
+            username: None,
+            success: false,
+            presented,
+        };
+
+        bans.on_auth_attempt(&attempt(false));
+        assert!(!bans.banned(peer.ip(), Instant::now()));
+        bans.on_auth_attempt(&attempt(true));
+        assert!(bans.banned(peer.ip(), Instant::now()));
+    }
+}
@@ -0,0 +1,316 @@
+#[cfg(feature = "https")]
+use crate::CertOptions;
+#[cfg(feature = "socks")]
+use crate::Socks5Options;
+use crate::{
+    connect::Connector, serve, AssignMode, AuthMode, BootArgs, ForwardOptions, Hooks, HttpOptions,
+    Overflow, Proxy, Result, TcpOptions, BIN_NAME,
+};
+use cidr::IpCidr;
+use clap::{Args, Command, FromArgMatches, Subcommand};
+#[cfg(feature = "https")]
+use std::path::PathBuf;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::task::JoinHandle;
+
+/// Builds and spawns a proxy server inside an existing tokio runtime.
+///
+/// Every option starts out with the same default as the command line, and the
+/// server type defaults to HTTP.
+///
+/// ```no_run
+/// # async fn example() -> vproxy::Result<()> {
+/// let _handle = vproxy::ProxyBuilder::new()
+///     .bind("127.0.0.1:1080".parse()?)
+///     .cidr("2001:db8::/32".parse()?)
+///     .auth("user", "pass")
+///     .socks5()
+///     .spawn();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ProxyBuilder {
+    args: BootArgs,
+}
+
+impl Default for ProxyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyBuilder {
+    /// Create a builder with the command line defaults.
+    pub fn new() -> Self {
+        let mut args: BootArgs = parse_defaults(BootArgs::augment_args, &["http"]);
+        // An embedded server must not be stopped through the control socket
+        #[cfg(unix)]
+        let _ = args.control_socket.take();
+        Self { args }
+    }
+
+    /// Create a builder from parsed command line arguments.
+    pub fn from_args(args: BootArgs) -> Self {
+        Self { args }
+    }
+
+    /// Sets the listener address.
+    pub fn bind(mut self, bind: SocketAddr) -> Self {
+        self.args.bind = bind;
+        self
+    }
+
+    /// Sets the listener name used to label logs.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.args.name = Some(name.into());
+        self
+    }
+
+    /// Sets the connect timeout in seconds.
+    pub fn connect_timeout(mut self, secs: u64) -> Self {
+        self.args.connect_timeout = secs;
+        self
+    }
+
+    /// Sets the time clients have to finish their handshake, in seconds.
+    pub fn handshake_timeout(mut self, secs: u64) -> Self {
+        self.args.handshake_timeout = secs;
+        self
+    }
+
+    /// Expects a PROXY protocol header from the load balancer in front of the
+    /// server on every connection.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.args.proxy_protocol = enabled;
+        self
+    }
+
+    /// Only accepts PROXY protocol headers from load balancers in `cidr`.
+    pub fn proxy_protocol_from(mut self, cidr: IpCidr) -> Self {
+        self.args.proxy_protocol_from.push(cidr);
+        self
+    }
+
+    /// Sets the maximum number of concurrent connections.
+    pub fn concurrent(mut self, concurrent: usize) -> Self {
+        self.args.concurrent = concurrent;
+        self
+    }
+
+    /// Sets what happens to connections beyond the concurrent connection limit.
+    pub fn concurrent_overflow(mut self, overflow: Overflow) -> Self {
+        self.args.concurrent_overflow = overflow;
+        self
+    }
+
+    /// Sets the CIDR egress addresses are assigned from.
+    pub fn cidr(mut self, cidr: IpCidr) -> Self {
+        self.args.cidr = Some(cidr);
+        self
+    }
+
+    /// Sets the prefix length of the range extension.
+    pub fn cidr_range(mut self, range: u8) -> Self {
+        self.args.cidr_range = Some(range);
+        self
+    }
+
+    /// Sets the egress address used when the CIDR cannot be used.
+    pub fn fallback(mut self, fallback: IpAddr) -> Self {
+        self.args.fallback = Some(fallback);
+        self
+    }
+
+    /// Sets how egress addresses are picked for connections without an
+    /// extension.
+    pub fn assign_mode(mut self, assign_mode: AssignMode) -> Self {
+        self.args.assign_mode = assign_mode;
+        self
+    }
+
+    /// Sets the TCP socket options.
+    pub fn tcp(mut self, tcp: TcpOptions) -> Self {
+        self.args.tcp = tcp;
+        self
+    }
+
+    /// Requires clients to authenticate with `username` and `password`.
+    ///
+    /// The credentials are kept when the server type is changed afterwards.
+    pub fn auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        if let Some(auth) = auth_mut(&mut self.args.proxy) {
+            auth.username = Some(username.into());
+            auth.password = Some(password.into());
+        }
+        self
+    }
+
+    /// Serves plain HTTP.
+    pub fn http(self) -> Self {
+        self.proxy("http")
+    }
+
+    /// Serves HTTP over TLS, with a self-signed certificate unless
+    /// [`tls`](Self::tls) is called.
+    #[cfg(feature = "https")]
+    pub fn https(self) -> Self {
+        self.proxy("https")
+    }
+
+    /// Sets the certificate and private key of the HTTPS server.
+    #[cfg(feature = "https")]
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        if let Proxy::Https {
+            tls_cert, tls_key, ..
+        } = &mut self.args.proxy
+        {
+            *tls_cert = Some(cert.into());
+            *tls_key = Some(key.into());
+        }
+        self
+    }
+
+    /// Sets the parameters of the self-signed certificate the HTTPS server
+    /// uses without [`tls`](Self::tls).
+    #[cfg(feature = "https")]
+    pub fn self_signed(mut self, opts: CertOptions) -> Self {
+        if let Proxy::Https { cert, .. } = &mut self.args.proxy {
+            *cert = opts;
+        }
+        self
+    }
+
+    /// Serves SOCKS5.
+    #[cfg(feature = "socks")]
+    pub fn socks5(self) -> Self {
+        self.proxy("socks5")
+    }
+
+    /// Forwards raw TCP connections and UDP datagrams according to `opts`
+    /// instead of serving a proxy protocol; the listen addresses of the rules
+    /// replace [`bind`](Self::bind).
+    pub fn forward(mut self, opts: ForwardOptions) -> Self {
+        self.args.proxy = Proxy::Forward { forward: opts };
+        self
+    }
+
+    /// Sets the options of the HTTP or HTTPS server.
+    pub fn http_options(mut self, opts: HttpOptions) -> Self {
+        match &mut self.args.proxy {
+            Proxy::Http { http, .. } => *http = opts,
+            #[cfg(feature = "https")]
+            Proxy::Https { http, .. } => *http = opts,
+            #[cfg(feature = "socks")]
+            Proxy::Socks5 { .. } => {}
+            Proxy::Sni { .. } | Proxy::Forward { .. } => {}
+        }
+        self
+    }
+
+    /// Sets the options of the SOCKS5 server.
+    #[cfg(feature = "socks")]
+    pub fn socks5_options(mut self, opts: Socks5Options) -> Self {
+        if let Proxy::Socks5 { socks5, .. } = &mut self.args.proxy {
+            *socks5 = opts;
+        }
+        self
+    }
+
+    /// Registers lifecycle hooks called for every client connection.
+    pub fn hooks(mut self, hooks: impl Hooks + 'static) -> Self {
+        self.args.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Serves the control socket `vproxy ps` and `vproxy stop` talk to at
+    /// `path`. Builders created with [`new`](Self::new) serve none.
+    #[cfg(unix)]
+    pub fn control_socket(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.args.control_socket = Some(path.into());
+        self
+    }
+
+    /// Builds a standalone egress connector from the configured CIDR, fallback
+    /// and socket options, for use without a server.
+    pub fn connector(&self) -> Connector {
+        serve::connector(&self.args)
+    }
+
+    /// Returns the arguments the server will be started with.
+    pub fn build(self) -> BootArgs {
+        self.args
+    }
+
+    /// Spawns the server onto the current tokio runtime.
+    ///
+    /// The returned handle completes when the server stops. Unlike the
+    /// command line, no global logger is installed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub fn spawn(self) -> JoinHandle<Result<()>> {
+        tokio::spawn(serve::start(self.args))
+    }
+
+    /// Switches to the server type `name`, keeping the configured
+    /// authentication.
+    fn proxy(mut self, name: &str) -> Self {
+        let auth = auth_mut(&mut self.args.proxy).cloned();
+        let mut proxy: Proxy = parse_defaults(Proxy::augment_subcommands, &[name]);
+        if let (Some(auth), Some(slot)) = (auth, auth_mut(&mut proxy)) {
+            *slot = auth;
+        }
+        self.args.proxy = proxy;
+        self
+    }
+}
+
+/// Returns the authentication of any server type that has one.
+fn auth_mut(proxy: &mut Proxy) -> Option<&mut AuthMode> {
+    match proxy {
+        Proxy::Http { auth, .. } => Some(auth),
+        #[cfg(feature = "https")]
+        Proxy::Https { auth, .. } => Some(auth),
+        #[cfg(feature = "socks")]
+        Proxy::Socks5 { auth, .. } => Some(auth),
+        Proxy::Sni { .. } | Proxy::Forward { .. } => None,
+    }
+}
+
+/// Parses `args` with a command built by `augment`, so the result carries the
+/// same defaults as the command line.
+fn parse_defaults<T: FromArgMatches>(augment: fn(Command) -> Command, args: &[&str]) -> T {
+    augment(Command::new(BIN_NAME).no_binary_name(true))
+        .try_get_matches_from(args.iter().copied())
+        .and_then(|matches| T::from_arg_matches(&matches))
+        .expect("command line defaults are valid")
+}
+
+#[cfg(all(test, feature = "socks"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_defaults() {
+        let args = ProxyBuilder::new()
+            .auth("user", "pass")
+            .socks5()
+            .cidr("2001:db8::/32".parse().unwrap())
+            .build();
+
+        assert_eq!(args.connect_timeout, 10);
+        assert_eq!(args.cidr, Some("2001:db8::/32".parse().unwrap()));
+        match args.proxy {
+            Proxy::Socks5 { auth, socks5 } => {
+                assert_eq!(auth.username.as_deref(), Some("user"));
+                assert_eq!(socks5.udp_send_queue, 64);
+            }
+            _ => panic!("expected a socks5 server"),
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Management of the self-signed CA behind the `ca` subcommand.
+//!
+//! The HTTPS server uses the CA stored in the certificate directory when no
+//! certificate is configured, generating one on first start. These commands
+//! create and replace it deliberately and export its certificate, so clients
+//! can be set up to trust it.
+
+use crate::{
+    http::genca::{cert_dir, cert_paths, generate_self_signed, store},
+    CertOptions,
+};
+use clap::Subcommand;
+use std::{io, path::PathBuf};
+
+#[derive(Subcommand, Clone)]
+pub enum CaCommand {
+    /// Generate the CA certificate and key
+    Generate {
+        /// Certificate parameters
+        #[clap(flatten)]
+        cert: CertOptions,
+
+        /// Replace an existing CA without keeping it
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Print the CA certificate, for clients to install and trust
+    Export {
+        /// Directory the certificate is stored in, defaults to the `vproxy`
+        /// state directory of the user
+        #[clap(long, value_name = "DIR")]
+        cert_dir: Option<PathBuf>,
+
+        /// File the certificate is written to instead of standard output
+        #[clap(short, long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+
+    /// Replace the CA with a new one, keeping the previous one as `*.pem.old`;
+    /// running servers use the new one once restarted
+    Rotate {
+        /// Certificate parameters
+        #[clap(flatten)]
+        cert: CertOptions,
+    },
+}
+
+/// Runs a `ca` subcommand.
+pub fn run(command: CaCommand) -> crate::Result<()> {
+    match command {
+        CaCommand::Generate { cert, force } => {
+            let dir = cert_dir(&cert);
+            let (cert_path, key_path) = cert_paths(&dir);
+            if !force && (cert_path.exists() || key_path.exists()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "a CA already exists in {}, use `ca rotate` to replace it",
+                        dir.display()
+                    ),
+                )
+                .into());
+            }
+            generate(&cert, dir)
+        }
+        CaCommand::Export {
+            cert_dir: dir,
+            output,
+        } => {
+            let dir = cert_dir(&CertOptions {
+                cert_dir: dir,
+                ..Default::default()
+            });
+            let (cert_path, _) = cert_paths(&dir);
+            let cert = std::fs::read(&cert_path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("cannot read {}: {}", cert_path.display(), err),
+                )
+            })?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, cert)?;
+                    println!("Exported CA certificate to {}", path.display());
+                }
+                None => io::Write::write_all(&mut io::stdout(), &cert)?,
+            }
+            Ok(())
+        }
+        CaCommand::Rotate { cert } => {
+            let dir = cert_dir(&cert);
+            let (cert_path, key_path) = cert_paths(&dir);
+            for path in [cert_path, key_path] {
+                if path.exists() {
+                    let mut old = path.clone().into_os_string();
+                    old.push(".old");
+                    std::fs::rename(&path, old)?;
+                }
+            }
+            generate(&cert, dir)
+        }
+    }
+}
+
+/// Generates a CA described by `cert` and stores it in `dir`.
+fn generate(cert: &CertOptions, dir: PathBuf) -> crate::Result<()> {
+    let (pem, key) = generate_self_signed(cert)?;
+    store(&dir, &pem, &key)?;
+    println!("Generated CA certificate {}", cert_paths(&dir).0.display());
+    Ok(())
+}
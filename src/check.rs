@@ -0,0 +1,236 @@
+//! Configuration check behind the `check` subcommand.
+//!
+//! Validates everything the server would load or bind on startup and prints
+//! one line per check, without serving and without changing the host's
+//! routes or sysctls.
+
+use crate::{rules::EgressRules, users::Users, BootArgs, Result};
+use cidr::IpCidr;
+use std::{fmt::Display, io, net::SocketAddr};
+
+/// Outcome of the checks, printed as they run.
+#[derive(Default)]
+struct Report {
+    errors: usize,
+}
+
+impl Report {
+    fn ok(&mut self, what: impl Display) {
+        println!("ok     {what}");
+    }
+
+    fn warn(&mut self, what: impl Display) {
+        println!("warn   {what}");
+    }
+
+    fn error(&mut self, what: impl Display) {
+        self.errors += 1;
+        println!("error  {what}");
+    }
+}
+
+/// Checks the configuration described by `args`, failing if the server would
+/// not start or could not serve with it.
+pub fn check(args: BootArgs) -> Result<()> {
+    let mut report = Report::default();
+
+    match (pool_error(args.cidr, args.cidr_range), args.cidr) {
+        (Some(err), _) => report.error(err),
+        (None, Some(cidr)) => report.ok(format!("CIDR {cidr}")),
+        (None, None) => {}
+    }
+    check_cidr_bind(&mut report, &args);
+
+    if let Some(path) = &args.cidr_file {
+        match crate::cidr_file::CidrFile::load(path) {
+            Ok(cidr_file) => {
+                let cidrs = cidr_file.cidrs();
+                report.ok(format!(
+                    "CIDR file {}: {} CIDRs",
+                    path.display(),
+                    cidrs.len()
+                ));
+                for cidr in cidrs.iter() {
+                    if let Some(err) = pool_error(Some(*cidr), args.cidr_range) {
+                        report.error(format!("CIDR file: {err}"));
+                    }
+                }
+            }
+            Err(err) => report.error(format!("CIDR file: {err}")),
+        }
+    }
+
+    if let Some(path) = args.proxy.auth().and_then(|auth| auth.auth_file.as_ref()) {
+        match Users::load(path) {
+            Ok(users) => {
+                report.ok(format!(
+                    "auth file {}: {} users",
+                    path.display(),
+                    users.len()
+                ));
+                for (name, pool) in users.pools() {
+                    let cidr = pool.cidr.or(args.cidr);
+                    let range = pool.cidr_range.or(args.cidr_range);
+                    if let Some(err) = pool_error(cidr, range) {
+                        report.error(format!("user {name}: {err}"));
+                    }
+                }
+            }
+            Err(err) => report.error(format!("auth file: {err}")),
+        }
+    }
+
+    if args.destination.allow_private_destinations {
+        report.warn("private destinations are allowed, clients can reach internal networks");
+    }
+
+    if let Some(path) = &args.hosts {
+        match crate::dns::load_hosts(path) {
+            Ok(hosts) => report.ok(format!(
+                "hosts file {}: {} hosts",
+                path.display(),
+                hosts.len()
+            )),
+            Err(err) => report.error(format!("hosts file: {err}")),
+        }
+    }
+
+    if let Some(path) = &args.egress_rules {
+        match EgressRules::load(path) {
+            Ok(rules) => {
+                report.ok(format!(
+                    "egress rules {}: {} rules",
+                    path.display(),
+                    rules.len()
+                ));
+                for (pattern, pool) in rules.rules() {
+                    if let Some(err) = pool_error(pool.cidr, pool.cidr_range) {
+                        report.error(format!("egress rule {pattern}: {err}"));
+                    }
+                }
+            }
+            Err(err) => report.error(format!("egress rules: {err}")),
+        }
+    }
+
+    #[cfg(feature = "https")]
+    if let crate::Proxy::Https {
+        tls_cert, tls_key, ..
+    } = &args.proxy
+    {
+        match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => match crate::http::check_tls(cert, key) {
+                Ok(()) => report.ok(format!("TLS certificate {}", cert.display())),
+                Err(err) => report.error(format!(
+                    "TLS certificate {} or key {}: {}",
+                    cert.display(),
+                    key.display(),
+                    err
+                )),
+            },
+            _ => report.warn("no TLS certificate, a self-signed one is used"),
+        }
+    }
+
+    if let Some(url) = &args.health.health_check {
+        match crate::health::check_target(url) {
+            Ok(target) => report.ok(format!("health check target {target}")),
+            Err(err) => report.error(format!("health check target: {err}")),
+        }
+    }
+
+    match crate::upstream::UpstreamPool::load(&args.upstream) {
+        Ok(Some(upstreams)) => report.ok(format!("{} upstream proxies", upstreams.len())),
+        Ok(None) => {}
+        Err(err) => report.error(format!("upstream proxies: {err}")),
+    }
+
+    #[cfg(feature = "wasm")]
+    if let Some(path) = &args.policy_wasm {
+        match crate::policy::WasmPolicy::load(path) {
+            Ok(_) => report.ok(format!("request policy {}", path.display())),
+            Err(err) => report.error(format!("request policy: {err}")),
+        }
+    }
+
+    match &args.proxy {
+        crate::Proxy::Forward { forward } => {
+            for rule in &forward.tcp {
+                check_bind(&mut report, "forward", rule.listen);
+            }
+            for rule in &forward.udp {
+                match std::net::UdpSocket::bind(rule.listen) {
+                    Ok(_) => report.ok(format!("UDP forward address {} is available", rule.listen)),
+                    Err(err) => {
+                        report.error(format!("UDP forward address {}: {}", rule.listen, err))
+                    }
+                }
+            }
+        }
+        _ => check_bind(&mut report, "listener", args.bind),
+    }
+    #[cfg(feature = "admin")]
+    if let Some(bind) = args.admin.admin_bind {
+        check_bind(&mut report, "admin API", bind);
+    }
+    #[cfg(feature = "socks")]
+    if let crate::Proxy::Socks5 { socks5, .. } = &args.proxy {
+        if let Some(bind) = socks5.stun_bind {
+            match std::net::UdpSocket::bind(bind) {
+                Ok(_) => report.ok(format!("STUN endpoint {bind} is available")),
+                Err(err) => report.error(format!("STUN endpoint {bind}: {err}")),
+            }
+        }
+    }
+
+    if report.errors > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} check(s) failed", report.errors),
+        )
+        .into());
+    }
+    println!("configuration is valid");
+    Ok(())
+}
+
+/// Returns why the range extension prefix does not fit the CIDR it splits.
+fn pool_error(cidr: Option<IpCidr>, range: Option<u8>) -> Option<String> {
+    match (cidr, range) {
+        (Some(cidr), Some(range)) => {
+            let max = if cidr.is_ipv4() { 32 } else { 128 };
+            (range < cidr.network_length() || range > max).then(|| {
+                format!("cidr-range {range} must be between the prefix length of {cidr} and {max}")
+            })
+        }
+        (None, Some(_)) => Some("cidr-range is set without a CIDR".to_owned()),
+        _ => None,
+    }
+}
+
+/// Checks that addresses of the CIDR can be bound.
+#[cfg(all(target_os = "linux", feature = "route"))]
+fn check_cidr_bind(report: &mut Report, args: &BootArgs) {
+    let Some(cidr) = &args.cidr else {
+        return;
+    };
+    match crate::route::check_cidr_bind(cidr, &args.route) {
+        Ok(()) => report.ok(format!("addresses of {cidr} can be bound")),
+        // The route is added on startup when running as root
+        Err(err) if args.fallback.is_some() || nix::unistd::Uid::effective().is_root() => {
+            report.warn(err)
+        }
+        Err(err) => report.error(err),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "route")))]
+fn check_cidr_bind(_: &mut Report, _: &BootArgs) {}
+
+/// Checks that the server could listen on `addr`.
+fn check_bind(report: &mut Report, what: &str, addr: SocketAddr) {
+    match std::net::TcpListener::bind(addr) {
+        Ok(_) => report.ok(format!("{what} address {addr} is available")),
+        Err(err) => report.error(format!("{what} address {addr}: {err}")),
+    }
+}
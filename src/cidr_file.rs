@@ -0,0 +1,229 @@
+//! Egress CIDRs loaded from `--cidr-file`.
+//!
+//! Each non-empty line that is not a `#` comment is an IP-CIDR:
+//!
+//! ```text
+//! # egress prefixes
+//! 2001:db8:1::/48
+//! 2001:db8:2::/48
+//! 203.0.113.0/24
+//! ```
+//!
+//! The file is watched while the proxy runs. Prefixes added to it get their
+//! local route and serve new connections, prefixes removed from it lose their
+//! route and are no longer assigned, without restarting the proxy.
+
+use crate::RouteOptions;
+use cidr::IpCidr;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// How often the file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// CIDRs of a CIDR file, shared by the connectors.
+#[derive(Debug)]
+pub(crate) struct CidrFile {
+    path: PathBuf,
+    cidrs: RwLock<Arc<[IpCidr]>>,
+    /// Modification time of the file when it was last read.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl CidrFile {
+    /// Loads the CIDR file at `path`.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let modified = modified(path);
+        let cidrs = read(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            cidrs: RwLock::new(cidrs.into()),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Returns the current CIDRs, in file order.
+    pub(crate) fn cidrs(&self) -> Arc<[IpCidr]> {
+        self.cidrs
+            .read()
+            .map_or_else(|err| err.into_inner().clone(), |cidrs| cidrs.clone())
+    }
+
+    /// Picks the CIDR an egress address is assigned from, among those of the
+    /// IPv4 or IPv6 family if `ipv4` is given and the file has any.
+    ///
+    /// The same `key`, e.g. the hash of a session, picks the same CIDR as
+    /// long as the file is unchanged. Without one the CIDR is random.
+    pub(crate) fn pick(&self, ipv4: Option<bool>, key: Option<u64>) -> Option<IpCidr> {
+        let cidrs = self.cidrs();
+        let family = cidrs
+            .iter()
+            .filter(|cidr| ipv4.unwrap_or(cidr.is_ipv4()) == cidr.is_ipv4())
+            .copied()
+            .collect::<Vec<_>>();
+        let candidates = if family.is_empty() {
+            &cidrs[..]
+        } else {
+            &family[..]
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let key = key.unwrap_or_else(rand::random);
+        Some(candidates[(key % candidates.len() as u64) as usize])
+    }
+
+    /// Rereads the file if it was modified, returning the CIDRs added and
+    /// removed by the change.
+    fn reload(&self) -> io::Result<Option<(Vec<IpCidr>, Vec<IpCidr>)>> {
+        let modified = modified(&self.path);
+        {
+            let mut last = self.modified.lock().unwrap_or_else(|err| err.into_inner());
+            if modified == *last {
+                return Ok(None);
+            }
+            *last = modified;
+        }
+
+        let cidrs = read(&self.path)?;
+        let old = self.cidrs();
+        let added = cidrs
+            .iter()
+            .filter(|cidr| !old.contains(cidr))
+            .copied()
+            .collect::<Vec<_>>();
+        let removed = old
+            .iter()
+            .filter(|cidr| !cidrs.contains(cidr))
+            .copied()
+            .collect::<Vec<_>>();
+
+        *self.cidrs.write().unwrap_or_else(|err| err.into_inner()) = cidrs.into();
+        Ok(Some((added, removed)))
+    }
+
+    /// Checks the file for changes every few seconds, applying them until
+    /// the process exits. Routes are added and removed as described by
+    /// `route`.
+    pub(crate) async fn watch(self: Arc<Self>, route: RouteOptions) {
+        let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let (added, removed) = match self.reload() {
+                Ok(Some(change)) => change,
+                Ok(None) => continue,
+                Err(err) => {
+                    tracing::warn!("Keeping the CIDRs of {}: {}", self.path.display(), err);
+                    continue;
+                }
+            };
+
+            for cidr in &added {
+                tracing::info!("CIDR {} added by {}", cidr, self.path.display());
+                setup(cidr, &route).await;
+            }
+            for cidr in &removed {
+                tracing::info!("CIDR {} removed by {}", cidr, self.path.display());
+                #[cfg(all(target_os = "linux", feature = "route"))]
+                if !route.no_route {
+                    crate::route::sysctl_route_del_cidr(cidr, &route).await;
+                }
+            }
+        }
+    }
+}
+
+/// Prepares the host for egress addresses of `cidr`, as for the command
+/// line CIDR.
+pub(crate) async fn setup(cidr: &IpCidr, route: &RouteOptions) {
+    #[cfg(all(target_os = "linux", feature = "route"))]
+    {
+        crate::route::prepare_cidr(cidr, route).await;
+
+        if let Err(err) = crate::route::check_cidr_bind(cidr, route) {
+            tracing::warn!("{}", err);
+            crate::status::add_route_failure();
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "route")))]
+    let _ = (cidr, route);
+}
+
+/// Returns the modification time of `path`, if it can be read.
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Reads the CIDR file at `path`.
+fn read(path: &Path) -> io::Result<Vec<IpCidr>> {
+    let content = std::fs::read_to_string(path)?;
+    parse(&content).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })
+}
+
+/// Parses the content of a CIDR file, or of another list of IP-CIDRs such
+/// as the egress ban file, dropping duplicates.
+pub(crate) fn parse(content: &str) -> Result<Vec<IpCidr>, String> {
+    let mut cidrs = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let cidr = line
+            .parse::<IpCidr>()
+            .map_err(|err| format!("line {}: {}: {}", number + 1, line, err))?;
+        if !cidrs.contains(&cidr) {
+            cidrs.push(cidr);
+        }
+    }
+    Ok(cidrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_pick() {
+        let cidrs = parse(
+            "# egress prefixes\n\
+             2001:db8:1::/48\n\
+             \n\
+             203.0.113.0/24\n\
+             2001:db8:1::/48\n",
+        )
+        .unwrap();
+        assert_eq!(cidrs.len(), 2);
+        assert!(parse("2001:db8::/129").is_err());
+
+        let file = CidrFile {
+            path: PathBuf::new(),
+            cidrs: RwLock::new(cidrs.into()),
+            modified: Mutex::new(None),
+        };
+        assert_eq!(
+            file.pick(Some(true), None),
+            Some("203.0.113.0/24".parse().unwrap())
+        );
+        assert_eq!(
+            file.pick(Some(false), Some(7)),
+            Some("2001:db8:1::/48".parse().unwrap())
+        );
+        assert_eq!(file.pick(None, Some(42)), file.pick(None, Some(42)));
+    }
+}
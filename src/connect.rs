@@ -1,21 +1,67 @@
-use super::{extension::Extension, http::error::Error};
+use super::{
+    cidr_file::CidrFile,
+    destination::DestinationGuard,
+    dns::{self, apply_policy, CachingResolver, DnsCache},
+    egress_ban::EgressBans,
+    extension::{Extension, Invalidations},
+    health::{self, EgressHealth},
+    http::{deadline::DeadlineBody, error::Error},
+    stats::{self, EgressSlot},
+    upstream::UpstreamPool,
+    users::Pool,
+    AssignMode, ResolvePolicy, TcpOptions,
+};
 use cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr};
-use http::{uri::Authority, Request, Response};
+use http::{header::HOST, uri::Authority, HeaderValue, Request, Response, Uri, Version};
 use hyper::body::Incoming;
 use hyper_util::{
     client::legacy::{connect, Client},
-    rt::{TokioExecutor, TokioTimer},
+    rt::{TokioExecutor, TokioIo, TokioTimer},
 };
 use rand::random;
+use socket2::{SockRef, TcpKeepalive};
 use std::{
+    collections::HashMap,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 use tokio::{
-    net::{lookup_host, TcpSocket, TcpStream, UdpSocket},
-    time::timeout,
+    net::{TcpSocket, TcpStream, UdpSocket},
+    time::{timeout, timeout_at, Instant},
 };
 
+/// Maximum number of pooled HTTP clients kept by a `Connector`.
+const MAX_POOLED_HTTP_CLIENTS: usize = 1024;
+
+/// Maximum number of times an address assignment is re-rolled when it lands
+/// on an excluded address.
+const MAX_EXCLUDE_REROLLS: usize = 64;
+
+/// Head start of the preferred address family before the other one is tried
+/// (RFC 8305 connection attempt delay).
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+
+/// How long an idle upstream keep-alive connection is kept in the pool.
+const HTTP_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// A hyper client used to forward plain HTTP requests.
+type HttpClient = Client<connect::HttpConnector<CachingResolver>, Incoming>;
+
+/// The local addresses an HTTP client binds its outbound connections to.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum LocalAddrs {
+    Single(Option<IpAddr>),
+    Dual(Ipv4Addr, Ipv6Addr),
+}
+
+/// Key of a pooled HTTP client: its local addresses and the range extension
+/// of the logins using it.
+type PoolKey = (LocalAddrs, Option<u64>);
+
 /// `Connector` struct is used to create HTTP connectors, optionally configured
 /// with an IPv6 CIDR and a fallback IP address.
 #[derive(Clone)]
@@ -27,14 +73,71 @@ pub struct Connector {
     /// Optional CIDR range for IP addresses.
     cidr_range: Option<u8>,
 
+    /// CIDRs of the `--cidr-file`, used when no CIDR is set.
+    cidr_file: Option<Arc<CidrFile>>,
+
     /// Optional IP address as a fallback option in case of connection failure.
     fallback: Option<IpAddr>,
 
+    /// Addresses inside the CIDR that are never assigned.
+    exclude: Arc<Vec<IpCidr>>,
+
+    /// Prefixes banned at runtime, avoided like the excluded ones.
+    egress_bans: Arc<EgressBans>,
+
+    /// Assignment strategy for connections without an extension.
+    assign_mode: AssignMode,
+
+    /// Whether random addresses used by tunnels of other owners are re-rolled.
+    avoid_collisions: bool,
+
+    /// Most connections per egress address of the CIDR.
+    max_conns_per_ip: Option<usize>,
+
+    /// Owner of the connections made through this connector, see
+    /// [`stats::owner`].
+    owner: Option<u64>,
+
+    /// Next index handed out in round-robin mode.
+    round_robin: Arc<AtomicU64>,
+
+    /// Subnets found unreachable by the egress health check.
+    health: Arc<EgressHealth>,
+
+    /// Sessions moved to a new address by the admin API.
+    invalidations: Arc<Invalidations>,
+
+    /// Addresses of prefetched domains.
+    dns: Arc<DnsCache>,
+
     /// Connect timeout in milliseconds.
     connect_timeout: Duration,
 
+    /// TCP socket options for outbound connections.
+    tcp: TcpOptions,
+
+    /// Network device outbound sockets are bound to.
+    interface: Option<Arc<str>>,
+
+    /// Refuses internal destinations, unless they are allowed.
+    guard: Option<Arc<DestinationGuard>>,
+
+    /// Address families of resolved destinations that are connected to.
+    resolve: Option<ResolvePolicy>,
+
+    /// Addresses the proxy listens on, refused as destinations.
+    listeners: Arc<Vec<SocketAddr>>,
+
+    /// Upstream proxies outbound TCP connections go through, if any.
+    upstreams: Option<Arc<UpstreamPool>>,
+
     /// Default http connector
-    http: connect::HttpConnector,
+    http: connect::HttpConnector<CachingResolver>,
+
+    /// HTTP clients keyed by their local addresses and the range extension
+    /// of the login, so that upstream keep-alive connections are reused
+    /// across forwarded requests but never across ranges.
+    http_clients: Arc<Mutex<HashMap<PoolKey, HttpClient>>>,
 }
 
 impl Connector {
@@ -44,18 +147,511 @@ impl Connector {
         cidr: Option<IpCidr>,
         cidr_range: Option<u8>,
         fallback: Option<IpAddr>,
+        exclude: Vec<IpCidr>,
+        assign_mode: AssignMode,
         connect_timeout: u64,
+        tcp: TcpOptions,
     ) -> Self {
         let connect_timeout = Duration::from_secs(connect_timeout);
-        let mut http_connector = connect::HttpConnector::new();
-        http_connector.set_connect_timeout(Some(connect_timeout));
+        let dns = Arc::new(DnsCache::default());
+        let http_connector = http_connector(dns.clone(), None, None, connect_timeout, tcp);
         Connector {
-            cidr,
+            cidr: cidr.map(normalize_cidr),
             cidr_range,
-            fallback,
+            cidr_file: None,
+            fallback: fallback.map(|ip| ip.to_canonical()),
+            exclude: Arc::new(exclude.into_iter().map(normalize_cidr).collect()),
+            egress_bans: Arc::new(EgressBans::default()),
+            assign_mode,
+            avoid_collisions: false,
+            max_conns_per_ip: None,
+            owner: None,
+            round_robin: Arc::new(AtomicU64::new(0)),
+            health: Arc::new(EgressHealth::default()),
+            invalidations: Arc::new(Invalidations::default()),
+            dns,
             connect_timeout,
+            tcp,
+            interface: None,
+            guard: None,
+            resolve: None,
+            listeners: Arc::new(Vec::new()),
+            upstreams: None,
             http: http_connector,
+            http_clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Avoids the egress prefixes banned in `egress_bans`, which may change
+    /// while the proxy runs.
+    pub(super) fn with_egress_bans(mut self, egress_bans: Arc<EgressBans>) -> Self {
+        self.egress_bans = egress_bans;
+        self
+    }
+
+    /// Re-rolls random egress addresses while tunnels of other users or
+    /// clients are using them, so that unrelated clients do not share an
+    /// address a target may rate limit.
+    pub(super) fn with_avoid_collisions(mut self, avoid_collisions: bool) -> Self {
+        self.avoid_collisions = avoid_collisions;
+        self
+    }
+
+    /// Re-rolls egress addresses of the CIDR that already have `max` open
+    /// tunnels, dials and plain HTTP requests in flight, sessions included,
+    /// so that no address carries too much traffic of its own.
+    pub(super) fn with_max_conns_per_ip(mut self, max: Option<usize>) -> Self {
+        self.max_conns_per_ip = max.filter(|max| *max > 0);
+        self
+    }
+
+    /// Marks subnets dead after `failures` failed health check rounds in a
+    /// row.
+    pub(super) fn with_health_check_failures(mut self, failures: u32) -> Self {
+        self.health = Arc::new(EgressHealth::new(failures));
+        self
+    }
+
+    /// Returns a connector making connections on behalf of `owner`, see
+    /// [`stats::owner`].
+    pub(crate) fn with_owner(&self, owner: u64) -> Connector {
+        let mut connector = self.clone();
+        connector.owner = Some(owner);
+        connector
+    }
+
+    /// Assigns egress addresses from the CIDRs of `cidr_file`, which may
+    /// change while the proxy runs, when no CIDR is set.
+    pub(super) fn with_cidr_file(mut self, cidr_file: Option<Arc<CidrFile>>) -> Self {
+        self.cidr_file = cidr_file;
+        self
+    }
+
+    /// Binds outbound sockets to the network device `interface`
+    /// (SO_BINDTODEVICE), which selects the uplink on multi-homed hosts where
+    /// binding by address alone is not enough.
+    pub(super) fn with_interface(mut self, interface: Option<String>) -> Self {
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &interface {
+            self.http.set_interface(interface.as_str());
+        }
+        self.interface = interface.map(Into::into);
+        self
+    }
+
+    /// Refuses destinations in private, loopback, link-local and other
+    /// internal ranges with `guard`, both before and after resolving them.
+    pub(super) fn with_guard(mut self, guard: Option<DestinationGuard>) -> Self {
+        self.guard = guard.map(Arc::new);
+        self.rebuild_http();
+        self
+    }
+
+    /// Connects only to the addresses of resolved destinations `policy`
+    /// allows, in the order it prefers.
+    pub(super) fn with_resolve(mut self, policy: Option<ResolvePolicy>) -> Self {
+        self.resolve = policy;
+        self.rebuild_http();
+        self
+    }
+
+    /// Rebuilds the HTTP connector after its resolver settings changed.
+    fn rebuild_http(&mut self) {
+        self.http = http_connector(
+            self.dns.clone(),
+            self.guard.clone(),
+            self.resolve,
+            self.connect_timeout,
+            self.tcp,
+        );
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &self.interface {
+            self.http.set_interface(interface.as_ref());
+        }
+    }
+
+    /// Refuses connections back to `listeners`, the addresses the proxy
+    /// listens on, which would otherwise loop until file descriptors run out.
+    pub(super) fn with_listeners(mut self, listeners: Vec<SocketAddr>) -> Self {
+        self.listeners = Arc::new(listeners.into_iter().map(normalize_socket_addr).collect());
+        self
+    }
+
+    /// Connects outbound TCP connections through the proxies of `upstreams`
+    /// instead of directly.
+    pub(super) fn with_upstreams(mut self, upstreams: Option<Arc<UpstreamPool>>) -> Self {
+        self.upstreams = upstreams;
+        self
+    }
+
+    /// Returns the timeout of outbound connections.
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Connects to `addr` from the default address of the host, without
+    /// checking it, for upstreams and other targets set by the operator.
+    pub(crate) async fn connect_direct(&self, addr: SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        self.tcp.apply(SockRef::from(&socket))?;
+        self.bind_device(SockRef::from(&socket))?;
+        socket.connect(addr).await
+    }
+
+    /// Fails if `target` is one of the addresses the proxy listens on.
+    fn check_loop(&self, target: SocketAddr) -> std::io::Result<()> {
+        let looped = self.listeners.iter().any(|listener| {
+            listener.port() == target.port()
+                && (listener.ip() == target.ip()
+                    || listener.ip().is_unspecified() && is_local(target.ip()))
+        });
+        if looped {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("proxy loop: {target} is a listener of this proxy"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns a connector reaching any destination, for targets set by the
+    /// operator rather than by clients.
+    pub(crate) fn unguarded(&self) -> Connector {
+        self.clone().with_guard(None)
+    }
+
+    /// Checks a destination host before it is resolved.
+    pub(crate) fn check_host(&self, host: &str) -> std::io::Result<()> {
+        match &self.guard {
+            Some(guard) => guard.check_host(host),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails connections of logins asking for named upstream proxies when
+    /// there is no pool, rather than connecting without them.
+    fn check_upstream(&self, extension: Extension) -> std::io::Result<()> {
+        if extension.upstream.is_some() && self.upstreams.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no upstream proxy of the requested name",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks a resolved destination address.
+    fn check_addr(&self, addr: SocketAddr) -> std::io::Result<()> {
+        match &self.guard {
+            Some(guard) => guard.check_addr(addr),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a connector drawing egress addresses from `pool` where it
+    /// overrides the configured CIDR, range or fallback, for the requests of
+    /// a user with a pool of their own.
+    ///
+    /// Health state, DNS cache and pooled HTTP clients stay shared.
+    pub(crate) fn with_pool(&self, pool: &Pool) -> Connector {
+        let mut connector = self.clone();
+        if let Some(cidr) = pool.cidr {
+            connector.cidr = Some(normalize_cidr(cidr));
+        }
+        if let Some(range) = pool.cidr_range {
+            connector.cidr_range = Some(range);
+        }
+        if let Some(fallback) = pool.fallback {
+            connector.fallback = Some(fallback.to_canonical());
         }
+        connector
+    }
+
+    /// Returns a connector drawing egress addresses from `pool` only,
+    /// ignoring the configured CIDR, range and fallback, for destinations
+    /// matched by an egress rule. An empty pool connects directly.
+    pub(crate) fn with_egress(&self, pool: &Pool) -> Connector {
+        let mut connector = self.clone();
+        connector.cidr = pool.cidr.map(normalize_cidr);
+        connector.cidr_file = None;
+        connector.cidr_range = pool.cidr_range;
+        connector.fallback = pool.fallback.map(|ip| ip.to_canonical());
+        connector
+    }
+
+    /// Returns the CIDR the egress address for `extension` is assigned from:
+    /// the configured one, or else one of the CIDR file, of the family of
+    /// `ipv4` if it has one.
+    fn cidr(&self, extension: Extension, ipv4: Option<bool>) -> Option<IpCidr> {
+        if self.cidr.is_some() {
+            return self.cidr;
+        }
+        let key = extension.range.or(extension.sticky());
+        self.cidr_file.as_ref()?.pick(ipv4, key).map(normalize_cidr)
+    }
+
+    /// Binds `socket` to the configured network device, if any.
+    fn bind_device(&self, socket: SockRef<'_>) -> std::io::Result<()> {
+        match self.interface.as_deref() {
+            #[cfg(target_os = "linux")]
+            Some(interface) => socket.bind_device(Some(interface.as_bytes())),
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "binding to a network device is only supported on Linux",
+            )),
+            None => {
+                let _ = socket;
+                Ok(())
+            }
+        }
+    }
+
+    /// Assigns an address from `cidr` for the given extension, re-rolling
+    /// while the assignment lands on an excluded address.
+    ///
+    /// Addresses in subnets marked dead by the health check are re-rolled as
+    /// well. Session and TTL extensions are re-rolled deterministically, so a
+    /// session keeps getting the same replacement address. In round-robin
+    /// mode, connections without an extension take the next address instead.
+    /// With collision avoidance, random addresses in use by another owner
+    /// are re-rolled too, and with a per-address limit, addresses at it.
+    /// When every address tried is at the limit, the fallback address of the
+    /// same family takes the connection. Fails with `AddrNotAvailable` if no
+    /// usable address is found.
+    fn assign_ip(&self, cidr: IpCidr, extension: Extension) -> std::io::Result<IpAddr> {
+        self.reserve_ip(cidr, extension).map(|(ip, _)| ip)
+    }
+
+    /// Assigns an address from `cidr` like [`Self::assign_ip`], reserving a
+    /// slot of it with a per-address limit. The slot is held while the
+    /// connection is dialed or the request is in flight.
+    fn reserve_ip(
+        &self,
+        cidr: IpCidr,
+        extension: Extension,
+    ) -> std::io::Result<(IpAddr, Option<EgressSlot>)> {
+        let round_robin = extension.is_none() && self.assign_mode == AssignMode::RoundRobin;
+        let avoid_owner = self
+            .owner
+            .filter(|_| self.avoid_collisions && extension.is_none() && !round_robin);
+
+        let mut extension = self.invalidations.apply(extension);
+        let mut dead_candidate = None;
+        let mut taken_candidate = None;
+        let mut saturated = false;
+        for _ in 0..=MAX_EXCLUDE_REROLLS {
+            let ip = match cidr {
+                _ if round_robin => {
+                    let index = self.round_robin.fetch_add(1, Ordering::Relaxed);
+                    assign_ip_sequential(cidr, index)
+                }
+                IpCidr::V4(cidr) => {
+                    IpAddr::V4(assign_ipv4_from_extension(cidr, self.cidr_range, extension))
+                }
+                IpCidr::V6(cidr) => {
+                    IpAddr::V6(assign_ipv6_from_extension(cidr, self.cidr_range, extension))
+                }
+            };
+
+            extension = reroll_extension(extension);
+
+            if self.exclude.iter().any(|excluded| excluded.contains(&ip)) {
+                tracing::debug!("assigned address {} is excluded, re-rolling", ip);
+                continue;
+            }
+
+            if self.egress_bans.is_banned(ip) {
+                tracing::debug!("assigned address {} is banned, re-rolling", ip);
+                continue;
+            }
+
+            let slot = match self.max_conns_per_ip {
+                Some(max) => match stats::reserve_egress(ip, max) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        tracing::debug!(
+                            "assigned address {} is at its connection limit, re-rolling",
+                            ip
+                        );
+                        saturated = true;
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            if self.health.is_dead(ip) {
+                tracing::debug!("assigned address {} is in a dead subnet, re-rolling", ip);
+                dead_candidate.get_or_insert((ip, slot));
+                continue;
+            }
+
+            if avoid_owner.is_some_and(|owner| stats::egress_taken(ip, owner)) {
+                tracing::trace!("assigned address {} is in use, re-rolling", ip);
+                taken_candidate.get_or_insert((ip, slot));
+                continue;
+            }
+
+            return Ok((ip, slot));
+        }
+
+        // Unlike exclusions, dead subnets and addresses in use are only
+        // avoided while there are alternatives, a shared or possibly dead
+        // address beats failing the connection.
+        if let Some(candidate) = taken_candidate.or(dead_candidate) {
+            return Ok(candidate);
+        }
+
+        if let Some(fallback) = self.fallback.filter(|ip| {
+            saturated && ip.is_ipv4() == cidr.is_ipv4() && !self.egress_bans.is_banned(*ip)
+        }) {
+            tracing::debug!(
+                "no address of {} below the connection limit, using the fallback",
+                cidr
+            );
+            return Ok((fallback, None));
+        }
+
+        let reason = if saturated {
+            "below the connection limit"
+        } else {
+            "outside the exclusion and ban lists"
+        };
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("no address {reason} found in {cidr}"),
+        ))
+    }
+
+    /// Returns the extension of a connection from `client`. With the
+    /// client-ip assign mode, connections without an extension get a session
+    /// derived from the client address, so the client keeps its egress address.
+    pub fn client_extension(&self, client: IpAddr, extension: Extension) -> Extension {
+        if extension.is_none() && self.assign_mode == AssignMode::ClientIp {
+            return Extension {
+                upstream: extension.upstream,
+                ..Extension::session(fxhash::hash64(&client.to_canonical()))
+            };
+        }
+        extension
+    }
+
+    /// Whether egress addresses are configured for both IPv4 and IPv6, by the
+    /// CIDR and the fallback address together.
+    fn has_dual_egress(&self) -> bool {
+        let has_family = |ipv4: bool| {
+            self.cidr(Extension::default(), Some(ipv4))
+                .is_some_and(|cidr| cidr.is_ipv4() == ipv4)
+                || self.fallback.is_some_and(|ip| ip.is_ipv4() == ipv4)
+        };
+        has_family(true) && has_family(false)
+    }
+
+    /// Returns `fallback` unless it is banned like the addresses of the CIDR.
+    fn check_fallback(&self, fallback: IpAddr) -> std::io::Result<IpAddr> {
+        if self.egress_bans.is_banned(fallback) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!("fallback address {fallback} is banned"),
+            ));
+        }
+        Ok(fallback)
+    }
+
+    /// Returns the egress bans shared by clones of this connector.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) fn egress_bans(&self) -> &EgressBans {
+        &self.egress_bans
+    }
+
+    /// Returns the session invalidations shared by clones of this connector.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) fn invalidations(&self) -> &Invalidations {
+        &self.invalidations
+    }
+
+    /// Returns the egress health state shared by clones of this connector.
+    pub fn health(&self) -> &EgressHealth {
+        &self.health
+    }
+
+    /// Returns the cache of prefetched domains shared by clones of this
+    /// connector.
+    pub fn dns(&self) -> &Arc<DnsCache> {
+        &self.dns
+    }
+
+    /// Probes `samples` random addresses of the CIDR by binding to them and
+    /// connecting to `target`, then marks the subnet of each address alive if
+    /// any of its probes succeeded and dead otherwise.
+    ///
+    /// Subnets are `--cidr-range` long if set, or 8 bits longer than the CIDR.
+    pub async fn check_egress_health(&self, target: &str, samples: usize) {
+        let Some(cidr) = self.cidr else {
+            return;
+        };
+
+        let target_addr = match dns::resolve_authority(target).await {
+            Ok(addrs) => addrs
+                .into_iter()
+                .map(normalize_socket_addr)
+                .find(|addr| addr.is_ipv4() == cidr.is_ipv4()),
+            Err(err) => {
+                tracing::warn!("Health check target {} lookup failed: {}", target, err);
+                return;
+            }
+        };
+        let Some(target_addr) = target_addr else {
+            tracing::warn!(
+                "Health check target {} has no address of the CIDR family",
+                target
+            );
+            return;
+        };
+
+        let max_len = if cidr.is_ipv4() { 32 } else { 128 };
+        let prefix = self
+            .cidr_range
+            .unwrap_or(cidr.network_length().saturating_add(8))
+            .clamp(cidr.network_length(), max_len);
+
+        let mut probes = tokio::task::JoinSet::new();
+        for _ in 0..samples {
+            let connector = self.clone();
+            probes.spawn(async move {
+                let ip = assign_rand_ip(cidr);
+                let alive = connector.probe(ip, target_addr).await.is_ok();
+                (ip, alive)
+            });
+        }
+
+        let mut subnets = HashMap::new();
+        while let Some(Ok((ip, alive))) = probes.join_next().await {
+            if let Some(subnet) = health::subnet_of(ip, prefix) {
+                *subnets.entry(subnet).or_insert(false) |= alive;
+            }
+        }
+
+        for (subnet, alive) in subnets {
+            self.health.update(subnet, alive);
+        }
+    }
+
+    /// Binds to `ip` and connects to `target` within the connect timeout.
+    async fn probe(&self, ip: IpAddr, target: SocketAddr) -> std::io::Result<()> {
+        let socket = match ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.bind_device(SockRef::from(&socket))?;
+        socket.bind(SocketAddr::new(ip, 0))?;
+        timeout(self.connect_timeout, socket.connect(target)).await??;
+        Ok(())
     }
 
     /// Returns a new instance of `HttpConnector` configured with the same settings
@@ -76,7 +672,11 @@ impl Connector {
     /// ```
     #[inline(always)]
     pub fn http_connector(&self) -> HttpConnector {
-        HttpConnector { inner: self }
+        HttpConnector {
+            inner: self,
+            response_header_timeout: None,
+            request_deadline: None,
+        }
     }
 
     /// Returns a new instance of `TcpConnector` configured with the same settings
@@ -175,27 +775,22 @@ impl TcpConnector<'_> {
     where
         F: FnOnce() -> std::io::Result<IpAddr>,
     {
-        match (self.inner.cidr, self.inner.fallback) {
-            (Some(cidr), _) => match cidr {
-                IpCidr::V4(cidr) => {
-                    let ip = IpAddr::V4(assign_ipv4_from_extension(
-                        cidr,
-                        self.inner.cidr_range,
-                        extension,
-                    ));
-                    Ok(SocketAddr::new(ip, 0))
-                }
-                IpCidr::V6(cidr) => {
-                    let ip = IpAddr::V6(assign_ipv6_from_extension(
-                        cidr,
-                        self.inner.cidr_range,
-                        extension,
-                    ));
-                    Ok(SocketAddr::new(ip, 0))
-                }
-            },
-            (None, Some(fallback)) => Ok(SocketAddr::new(fallback, 0)),
-            _ => default().map(|ip| SocketAddr::new(ip, 0)),
+        match self.egress_addr(extension)? {
+            Some(ip) => Ok(SocketAddr::new(ip, 0)),
+            None => default().map(|ip| SocketAddr::new(ip, 0)),
+        }
+    }
+
+    /// Returns the egress IP address that would be selected for the given
+    /// extension, or `None` if the system default address would be used.
+    ///
+    /// An address from the CIDR takes precedence over the fallback address.
+    /// Without an extension the CIDR address is random, so the result is only
+    /// a sample of what a real connection would use.
+    pub fn egress_addr(&self, extension: Extension) -> std::io::Result<Option<IpAddr>> {
+        match (self.inner.cidr(extension, None), self.inner.fallback) {
+            (Some(cidr), _) => self.inner.assign_ip(cidr, extension).map(Some),
+            (None, fallback) => fallback.map(|ip| self.inner.check_fallback(ip)).transpose(),
         }
     }
 
@@ -212,6 +807,10 @@ impl TcpConnector<'_> {
     /// made because the iterator is empty, it returns a `ConnectionAborted`
     /// error.
     ///
+    /// When egress addresses are configured for both IPv4 and IPv6 and the
+    /// target has addresses of both families, the families are raced Happy
+    /// Eyeballs style, each bound to its own egress pool.
+    ///
     /// # Arguments
     ///
     /// * `addrs` - An `IntoIterator` of the target addresses to connect to.
@@ -227,6 +826,31 @@ impl TcpConnector<'_> {
         &self,
         addrs: impl IntoIterator<Item = SocketAddr>,
         extension: Extension,
+    ) -> std::io::Result<TcpStream> {
+        self.inner.check_upstream(extension)?;
+        let addrs = addrs.into_iter().map(normalize_socket_addr);
+        if !self.inner.has_dual_egress() {
+            return self.connect_each(addrs, extension).await;
+        }
+
+        // The family of the first address is preferred
+        let mut addrs = addrs.peekable();
+        let prefer_ipv4 = addrs.peek().is_some_and(SocketAddr::is_ipv4);
+        let (preferred, other): (Vec<_>, Vec<_>) =
+            addrs.partition(|addr| addr.is_ipv4() == prefer_ipv4);
+
+        if other.is_empty() {
+            self.connect_each(preferred, extension).await
+        } else {
+            self.happy_eyeballs(preferred, other, extension).await
+        }
+    }
+
+    /// Tries the target addresses one after another.
+    async fn connect_each(
+        &self,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        extension: Extension,
     ) -> std::io::Result<TcpStream> {
         let mut last_err = None;
 
@@ -240,6 +864,59 @@ impl TcpConnector<'_> {
         Err(error(last_err))
     }
 
+    /// Races the addresses of both families, each bound to the egress pool of
+    /// its family, giving the preferred family a head start (RFC 8305).
+    ///
+    /// The first established connection wins and the other attempt is
+    /// dropped. If one family fails, the other is tried right away.
+    async fn happy_eyeballs(
+        &self,
+        preferred: Vec<SocketAddr>,
+        other: Vec<SocketAddr>,
+        extension: Extension,
+    ) -> std::io::Result<TcpStream> {
+        let preferred = self.connect_each(preferred, extension);
+        let delay = tokio::time::sleep(HAPPY_EYEBALLS_DELAY);
+        tokio::pin!(preferred, delay);
+
+        tokio::select! {
+            res = &mut preferred => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    tracing::debug!("[happy eyeballs] preferred family failed: {}", err);
+                    return self.connect_each(other, extension).await;
+                }
+            },
+            _ = &mut delay => {}
+        }
+
+        let other = self.connect_each(other, extension);
+        tokio::pin!(other);
+
+        tokio::select! {
+            res = &mut preferred => match res {
+                Ok(stream) => {
+                    tracing::debug!("[happy eyeballs] preferred family won, other dropped");
+                    Ok(stream)
+                }
+                Err(err) => {
+                    tracing::debug!("[happy eyeballs] preferred family failed: {}", err);
+                    other.await
+                }
+            },
+            res = &mut other => match res {
+                Ok(stream) => {
+                    tracing::debug!("[happy eyeballs] other family won, preferred dropped");
+                    Ok(stream)
+                }
+                Err(err) => {
+                    tracing::debug!("[happy eyeballs] other family failed: {}", err);
+                    preferred.await
+                }
+            },
+        }
+    }
+
     /// Attempts to establish a TCP connection to each of the target addresses
     /// resolved from the provided authority.
     ///
@@ -275,16 +952,53 @@ impl TcpConnector<'_> {
         authority: Authority,
         extension: Extension,
     ) -> std::io::Result<TcpStream> {
-        let addrs = lookup_host(authority.as_str()).await?;
+        self.inner.check_host(authority.host())?;
+        self.inner.check_upstream(extension)?;
+        if let Some(upstreams) = &self.inner.upstreams {
+            let port = authority.port_u16().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{authority}: no port"),
+                )
+            })?;
+            let host = authority
+                .host()
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            return upstreams
+                .connect(self.inner, host, port, extension.upstream)
+                .await;
+        }
+        let cached = authority
+            .port_u16()
+            .and_then(|port| self.inner.dns.cached(authority.host(), port));
+        let addrs = match cached {
+            Some(addrs) => addrs,
+            None => {
+                let port = authority.port_u16().ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{authority}: no port"),
+                    )
+                })?;
+                dns::resolve(authority.host(), port).await?
+            }
+        };
+        let addrs = apply_policy(self.inner.resolve, authority.host(), addrs)?;
         self.connect_with_addrs(addrs, extension).await
     }
 
+    /// Resolves `host` and `port` like the connections to domains are.
+    pub async fn lookup(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        self.inner.dns.lookup(host, port).await
+    }
+
     /// Attempts to establish a TCP connection to the target domain using the
     /// provided extensions.
     ///
     /// This function takes a tuple of a `String` and a `u16` for the host and
     /// port of the target domain and an `Extensions` reference. It resolves
-    /// the host to a list of IP addresses using the `lookup_host` function and
+    /// the host to a list of IP addresses using the resolver of the process and
     /// then attempts to connect to each IP address in turn using the
     /// `try_connect_with_iter` function.
     ///
@@ -311,7 +1025,16 @@ impl TcpConnector<'_> {
         host: (String, u16),
         extension: Extension,
     ) -> std::io::Result<TcpStream> {
-        let addrs = lookup_host(host).await?;
+        self.inner.check_host(&host.0)?;
+        self.inner.check_upstream(extension)?;
+        // The upstream resolves the domain
+        if let Some(upstreams) = &self.inner.upstreams {
+            return upstreams
+                .connect(self.inner, &host.0, host.1, extension.upstream)
+                .await;
+        }
+        let addrs = self.inner.dns.lookup(&host.0, host.1).await?;
+        let addrs = apply_policy(self.inner.resolve, &host.0, addrs)?;
         self.connect_with_addrs(addrs, extension).await
     }
 
@@ -358,7 +1081,20 @@ impl TcpConnector<'_> {
         target_addr: SocketAddr,
         extension: Extension,
     ) -> std::io::Result<TcpStream> {
-        match (self.inner.cidr, self.inner.fallback) {
+        let target_addr = normalize_socket_addr(target_addr);
+        self.inner.check_addr(target_addr)?;
+        self.inner.check_loop(target_addr)?;
+        self.inner.check_upstream(extension)?;
+        if let Some(upstreams) = &self.inner.upstreams {
+            let host = target_addr.ip().to_string();
+            return upstreams
+                .connect(self.inner, &host, target_addr.port(), extension.upstream)
+                .await;
+        }
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let cidr = self.inner.cidr(extension, Some(target_addr.is_ipv4()));
+        match egress_for_target(cidr, self.inner.fallback, target_addr)? {
             (None, Some(fallback)) => {
                 timeout(
                     self.inner.connect_timeout,
@@ -381,15 +1117,45 @@ impl TcpConnector<'_> {
                 .await?
             }
             (None, None) => {
-                timeout(self.inner.connect_timeout, TcpStream::connect(target_addr)).await?
+                timeout(
+                    self.inner.connect_timeout,
+                    self.inner.connect_direct(target_addr),
+                )
+                .await?
             }
         }
         .and_then(|stream| {
-            tracing::info!("connect {} via {}", target_addr, stream.local_addr()?);
+            let local = stream.local_addr()?;
+            tracing::info!("connect {} via {}", target_addr, local);
+            #[cfg(feature = "metrics")]
+            crate::metrics::observe_connect(
+                target_addr.ip(),
+                self.egress_source(local.ip()),
+                started.elapsed(),
+            );
             Ok(stream)
         })
     }
 
+    /// Returns where the egress address `local` came from.
+    #[cfg(feature = "metrics")]
+    fn egress_source(&self, local: IpAddr) -> crate::metrics::Egress {
+        let local = local.to_canonical();
+        let in_cidr = match &self.inner.cidr_file {
+            Some(file) if self.inner.cidr.is_none() => {
+                file.cidrs().iter().any(|cidr| cidr.contains(&local))
+            }
+            _ => self.inner.cidr.is_some_and(|cidr| cidr.contains(&local)),
+        };
+        if in_cidr {
+            crate::metrics::Egress::Cidr
+        } else if self.inner.fallback == Some(local) {
+            crate::metrics::Egress::Fallback
+        } else {
+            crate::metrics::Egress::Default
+        }
+    }
+
     /// Attempts to establish a TCP connection to the target address using an IP
     /// address from the provided CIDR range.
     ///
@@ -422,7 +1188,7 @@ impl TcpConnector<'_> {
         cidr: IpCidr,
         extension: Extension,
     ) -> std::io::Result<TcpStream> {
-        let socket = self.create_socket_with_cidr(cidr, extension).await?;
+        let (socket, _slot) = self.create_socket_with_cidr(cidr, extension).await?;
         socket.connect(target_addr).await
     }
 
@@ -520,17 +1286,22 @@ impl TcpConnector<'_> {
     ///
     /// This function returns a `std::io::Result<TcpSocket>`. If the socket is
     /// successfully created and bound, it returns `Ok(socket)`. If there is an
-    /// error creating or binding the socket, it returns the error in the `Result`.
+    /// error creating or binding the socket, or the address is banned, it
+    /// returns the error in the `Result`.
     fn create_socket_with_addr(&self, ip: IpAddr) -> std::io::Result<TcpSocket> {
-        match ip {
+        match self.inner.check_fallback(ip)? {
             IpAddr::V4(_) => {
                 let socket = TcpSocket::new_v4()?;
+                self.inner.tcp.apply(SockRef::from(&socket))?;
+                self.inner.bind_device(SockRef::from(&socket))?;
                 let bind_addr = SocketAddr::new(ip, 0);
                 socket.bind(bind_addr)?;
                 Ok(socket)
             }
             IpAddr::V6(_) => {
                 let socket = TcpSocket::new_v6()?;
+                self.inner.tcp.apply(SockRef::from(&socket))?;
+                self.inner.bind_device(SockRef::from(&socket))?;
                 let bind_addr = SocketAddr::new(ip, 0);
                 socket.bind(bind_addr)?;
                 Ok(socket)
@@ -560,35 +1331,23 @@ impl TcpConnector<'_> {
     ///
     /// This function returns a `std::io::Result<TcpSocket>`. If the socket is
     /// successfully created, assigned an IP address, and bound, it returns
-    /// `Ok(socket)`. If there is an error at any step, it returns the error in the
-    /// `Result`.
+    /// `Ok(socket)` along with the slot of the address, to be held until the
+    /// connection is established. If there is an error at any step, it returns
+    /// the error in the `Result`.
     async fn create_socket_with_cidr(
         &self,
         cidr: IpCidr,
         extension: Extension,
-    ) -> std::io::Result<TcpSocket> {
-        match cidr {
-            IpCidr::V4(cidr) => {
-                let socket = TcpSocket::new_v4()?;
-                let bind = IpAddr::V4(assign_ipv4_from_extension(
-                    cidr,
-                    self.inner.cidr_range,
-                    extension,
-                ));
-                socket.bind(SocketAddr::new(bind, 0))?;
-                Ok(socket)
-            }
-            IpCidr::V6(cidr) => {
-                let socket = TcpSocket::new_v6()?;
-                let bind = IpAddr::V6(assign_ipv6_from_extension(
-                    cidr,
-                    self.inner.cidr_range,
-                    extension,
-                ));
-                socket.bind(SocketAddr::new(bind, 0))?;
-                Ok(socket)
-            }
-        }
+    ) -> std::io::Result<(TcpSocket, Option<EgressSlot>)> {
+        let socket = match cidr {
+            IpCidr::V4(_) => TcpSocket::new_v4()?,
+            IpCidr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.inner.tcp.apply(SockRef::from(&socket))?;
+        self.inner.bind_device(SockRef::from(&socket))?;
+        let (bind, slot) = self.inner.reserve_ip(cidr, extension)?;
+        socket.bind(SocketAddr::new(bind, 0))?;
+        Ok((socket, slot))
     }
 }
 
@@ -630,17 +1389,24 @@ impl UdpConnector<'_> {
     /// ```
     #[inline(always)]
     pub async fn bind_socket(&self, extension: Extension) -> std::io::Result<UdpSocket> {
-        match (self.inner.cidr, self.inner.fallback) {
+        match (self.inner.cidr(extension, None), self.inner.fallback) {
             (None, Some(fallback)) => self.create_socket_with_addr(fallback).await,
             (Some(cidr), None) => self.create_socket_with_cidr(cidr, extension).await,
             (Some(cidr), Some(fallback)) => {
                 self.create_socket_with_cidr_and_fallback(cidr, fallback, extension)
                     .await
             }
-            (None, None) => UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await,
+            (None, None) => self.bind(SocketAddr::from(([0, 0, 0, 0], 0))).await,
         }
     }
 
+    /// Binds a UDP socket to `addr` and the configured network device.
+    async fn bind(&self, addr: SocketAddr) -> std::io::Result<UdpSocket> {
+        let socket = UdpSocket::bind(addr).await?;
+        self.inner.bind_device(SockRef::from(&socket))?;
+        Ok(socket)
+    }
+
     /// Sends a UDP packet to the specified address using the provided UDP socket.
     ///
     /// This method sends a UDP packet to the specified destination address using the provided
@@ -674,13 +1440,16 @@ impl UdpConnector<'_> {
         pkt: &[u8],
         dst_addr: SocketAddr,
     ) -> std::io::Result<usize> {
+        let dst_addr = normalize_socket_addr(dst_addr);
+        self.inner.check_addr(dst_addr)?;
         dispatch_socket.send_to(pkt, dst_addr).await
     }
 
     /// Sends a UDP packet to the specified domain and port using the provided UDP socket.
     ///
-    /// This method resolves the domain to an IP address and sends a UDP packet to the specified
-    /// destination domain and port using the provided UDP socket.
+    /// This method resolves the domain, as allowed by `--resolve`, to an IP address and
+    /// sends a UDP packet to the specified destination domain and port using the provided
+    /// UDP socket.
     ///
     /// # Arguments
     ///
@@ -709,8 +1478,14 @@ impl UdpConnector<'_> {
         pkt: &[u8],
         dst_domain: (String, u16),
     ) -> std::io::Result<usize> {
+        self.inner.check_host(&dst_domain.0)?;
         let mut last_err = None;
-        let addrs = lookup_host(dst_domain).await?;
+        let is_ipv4 = dispatch_socket.local_addr()?.is_ipv4();
+        let addrs = self.inner.dns.lookup(&dst_domain.0, dst_domain.1).await?;
+        let addrs = apply_policy(self.inner.resolve, &dst_domain.0, addrs)?
+            .into_iter()
+            .map(normalize_socket_addr)
+            .filter(|addr| addr.is_ipv4() == is_ipv4);
         for addr in addrs {
             match self.send_packet_with_addr(dispatch_socket, pkt, addr).await {
                 Ok(s) => return Ok(s),
@@ -723,6 +1498,44 @@ impl UdpConnector<'_> {
         Err(error(last_err))
     }
 
+    /// Connects `socket` to `host` and `port`, so that it only exchanges
+    /// datagrams with that address. A domain is resolved once, as allowed by
+    /// `--resolve`, to an address of the family of the socket.
+    pub async fn connect_socket(
+        &self,
+        socket: &UdpSocket,
+        host: &str,
+        port: u16,
+    ) -> std::io::Result<SocketAddr> {
+        let addrs = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => {
+                self.inner.check_host(host)?;
+                let is_ipv4 = socket.local_addr()?.is_ipv4();
+                let addrs = self.inner.dns.lookup(host, port).await?;
+                apply_policy(self.inner.resolve, host, addrs)?
+                    .into_iter()
+                    .map(normalize_socket_addr)
+                    .filter(|addr| addr.is_ipv4() == is_ipv4)
+                    .collect()
+            }
+        };
+
+        let mut last_err = None;
+        for addr in addrs {
+            let addr = normalize_socket_addr(addr);
+            match self.inner.check_addr(addr) {
+                Ok(()) => match socket.connect(addr).await {
+                    Ok(()) => return Ok(addr),
+                    Err(err) => last_err = Some(err),
+                },
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(error(last_err))
+    }
+
     /// Creates a UDP socket and binds it to the provided IP address.
     ///
     /// This function takes an `IpAddr` reference as an argument and creates a new
@@ -739,10 +1552,12 @@ impl UdpConnector<'_> {
     ///
     /// This function returns a `std::io::Result<UdpSocket>`. If the socket is
     /// successfully created and bound, it returns `Ok(socket)`. If there is an
-    /// error creating or binding the socket, it returns the error in the `Result`.
+    /// error creating or binding the socket, or the address is banned, it
+    /// returns the error in the `Result`.
     #[inline]
     async fn create_socket_with_addr(&self, ip: IpAddr) -> std::io::Result<UdpSocket> {
-        UdpSocket::bind(SocketAddr::new(ip, 0)).await
+        let ip = self.inner.check_fallback(ip)?;
+        self.bind(SocketAddr::new(ip, 0)).await
     }
 
     /// Creates a UDP socket and binds it to an IP address within the provided CIDR
@@ -774,24 +1589,8 @@ impl UdpConnector<'_> {
         cidr: IpCidr,
         extension: Extension,
     ) -> std::io::Result<UdpSocket> {
-        match cidr {
-            IpCidr::V4(cidr) => {
-                let bind = IpAddr::V4(assign_ipv4_from_extension(
-                    cidr,
-                    self.inner.cidr_range,
-                    extension,
-                ));
-                UdpSocket::bind(SocketAddr::new(bind, 0)).await
-            }
-            IpCidr::V6(cidr) => {
-                let bind = IpAddr::V6(assign_ipv6_from_extension(
-                    cidr,
-                    self.inner.cidr_range,
-                    extension,
-                ));
-                UdpSocket::bind(SocketAddr::new(bind, 0)).await
-            }
-        }
+        let bind = self.inner.assign_ip(cidr, extension)?;
+        self.bind(SocketAddr::new(bind, 0)).await
     }
 
     /// Creates a UDP socket and binds it to an IP address within the provided CIDR
@@ -851,14 +1650,42 @@ impl UdpConnector<'_> {
 /// ```
 pub struct HttpConnector<'a> {
     inner: &'a Connector,
+
+    /// How long to wait for the origin's response headers.
+    response_header_timeout: Option<Duration>,
+
+    /// How long the whole request may take, including the response body.
+    request_deadline: Option<Duration>,
 }
 
 impl HttpConnector<'_> {
+    /// Sets how long to wait for the response headers, in seconds.
+    pub fn response_header_timeout(mut self, secs: Option<u64>) -> Self {
+        self.response_header_timeout = secs.map(Duration::from_secs);
+        self
+    }
+
+    /// Sets how long the whole request may take, in seconds. Once the deadline
+    /// has passed, the response body fails and the client connection is aborted.
+    pub fn request_deadline(mut self, secs: Option<u64>) -> Self {
+        self.request_deadline = secs.map(Duration::from_secs);
+        self
+    }
+
     /// Sends an HTTP request using the configured `HttpConnector`.
     ///
     /// This method sets the local addresses based on the provided CIDR and fallback IP address,
     /// and then sends the HTTP request.
     ///
+    /// Whenever the local addresses are stable across requests (no CIDR, or a
+    /// `Session`/`TTL` extension), the request goes through a pooled client so
+    /// that upstream keep-alive connections are reused. Randomly rotated
+    /// addresses always use a fresh client.
+    ///
+    /// If the response headers do not arrive before the response header
+    /// timeout or the request deadline, whichever comes first, a
+    /// `GatewayTimeout` error is returned.
+    ///
     /// # Arguments
     ///
     /// * `req` - The HTTP request to be sent.
@@ -878,40 +1705,206 @@ impl HttpConnector<'_> {
         self,
         req: Request<Incoming>,
         extension: Extension,
-    ) -> Result<Response<Incoming>, Error> {
-        let mut connector = self.inner.http.clone();
-        match (self.inner.cidr, self.inner.fallback) {
-            (Some(IpCidr::V4(cidr)), Some(IpAddr::V6(v6))) => {
-                let v4 = assign_ipv4_from_extension(cidr, self.inner.cidr_range, extension);
-                connector.set_local_addresses(v4, v6);
+    ) -> Result<Response<DeadlineBody<Incoming>>, Error> {
+        let started = Instant::now();
+        let deadline = self.request_deadline.map(|deadline| started + deadline);
+        let header_deadline = self
+            .response_header_timeout
+            .map(|timeout| started + timeout)
+            .into_iter()
+            .chain(deadline)
+            .min();
+        self.inner.check_upstream(extension)?;
+        let uri = req.uri().clone();
+        if let Some(host) = uri.host() {
+            self.inner.check_host(host)?;
+            if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse() {
+                let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                    Some("https") => 443,
+                    _ => 80,
+                });
+                self.inner
+                    .check_loop(normalize_socket_addr(SocketAddr::new(ip, port)))?;
             }
-            (Some(IpCidr::V4(cidr)), None) => {
-                let v4 = assign_ipv4_from_extension(cidr, self.inner.cidr_range, extension);
-                connector.set_local_address(Some(v4.into()));
+        }
+
+        if self.inner.upstreams.is_some() {
+            let request = self.upstream_request(req, extension);
+            let res = match header_deadline {
+                Some(at) => timeout_at(at, request).await.map_err(|_| {
+                    Error::GatewayTimeout(format!(
+                        "no response headers from {} after {:?}",
+                        uri,
+                        started.elapsed()
+                    ))
+                })?,
+                None => request.await,
+            }?;
+            return Ok(res.map(|body| DeadlineBody::new(body, deadline)));
+        }
+
+        let cidr = self.inner.cidr(extension, None);
+        let (local_addrs, slot) = match (cidr, self.inner.fallback) {
+            (Some(cidr), fallback) => {
+                let (ip, slot) = self.inner.reserve_ip(cidr, extension)?;
+                let fallback = fallback.filter(|ip| !self.inner.egress_bans.is_banned(*ip));
+                let local_addrs = match (ip, fallback) {
+                    (IpAddr::V4(v4), Some(IpAddr::V6(v6)))
+                    | (IpAddr::V6(v6), Some(IpAddr::V4(v4))) => LocalAddrs::Dual(v4, v6),
+                    (IpAddr::V4(v4), None) => LocalAddrs::Single(Some(v4.into())),
+                    (IpAddr::V6(v6), None) => LocalAddrs::Single(Some(v6.into())),
+                    _ => LocalAddrs::Single(None),
+                };
+                (local_addrs, slot)
+            }
+            (None, addr) => {
+                let addr = addr.map(|ip| self.inner.check_fallback(ip)).transpose()?;
+                (LocalAddrs::Single(addr), None)
+            }
+        };
+
+        let poolable = cidr.is_none() || extension.sticky().is_some();
+
+        let client = if poolable {
+            self.pooled_client(local_addrs, extension.range)
+        } else {
+            self.build_client(local_addrs)
+        };
+
+        let res = match header_deadline {
+            Some(at) => timeout_at(at, client.request(req)).await.map_err(|_| {
+                Error::GatewayTimeout(format!(
+                    "no response headers from {} after {:?}",
+                    uri,
+                    started.elapsed()
+                ))
+            })?,
+            None => client.request(req).await,
+        }?;
+
+        Ok(res.map(|body| DeadlineBody::new(body, deadline).with_slot(slot)))
+    }
+
+    /// Sends `req` on a connection of its own, tunneled through the upstream
+    /// proxies.
+    async fn upstream_request(
+        &self,
+        mut req: Request<Incoming>,
+        extension: Extension,
+    ) -> Result<Response<Incoming>, Error> {
+        let uri = req.uri().clone();
+        let authority = uri.authority().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{uri}: no host"))
+        })?;
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        });
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let stream = self
+            .inner
+            .tcp_connector()
+            .connect_with_domain((host, port), extension)
+            .await?;
+
+        // The origin server is sent the path only
+        if !req.headers().contains_key(HOST) {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                req.headers_mut().insert(HOST, host);
             }
-            (Some(IpCidr::V6(cidr)), Some(IpAddr::V4(v4))) => {
-                let v6 = assign_ipv6_from_extension(cidr, self.inner.cidr_range, extension);
-                connector.set_local_addresses(v4, v6);
+        }
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        *req.uri_mut() = path.parse::<Uri>().map_err(http::Error::from)?;
+        *req.version_mut() = Version::HTTP_11;
+
+        let (mut sender, conn) = hyper::client::conn::http1::Builder::new()
+            .title_case_headers(true)
+            .preserve_header_case(true)
+            .handshake(TokioIo::new(stream))
+            .await?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::debug!("upstream connection error: {}", err);
             }
-            (Some(IpCidr::V6(cidr)), None) => {
-                let v6 = assign_ipv6_from_extension(cidr, self.inner.cidr_range, extension);
-                connector.set_local_address(Some(v6.into()));
+        });
+        sender.send_request(req).await.map_err(Into::into)
+    }
+
+    /// Returns the pooled client bound to `local_addrs` for logins of `range`,
+    /// creating it if needed.
+    fn pooled_client(&self, local_addrs: LocalAddrs, range: Option<u64>) -> HttpClient {
+        let mut clients = self
+            .inner
+            .http_clients
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+
+        let key = (local_addrs, range);
+        if let Some(client) = clients.get(&key) {
+            return client.clone();
+        }
+
+        // Keep the pool bounded; evicting a client only closes its idle connections.
+        if clients.len() >= MAX_POOLED_HTTP_CLIENTS {
+            if let Some(key) = clients.keys().next().copied() {
+                clients.remove(&key);
             }
-            (None, addr) => connector.set_local_address(addr),
-            _ => {}
+        }
+
+        let client = self.build_client(local_addrs);
+        clients.insert(key, client.clone());
+        client
+    }
+
+    /// Builds a new client whose connections are bound to `local_addrs`.
+    fn build_client(&self, local_addrs: LocalAddrs) -> HttpClient {
+        let mut connector = self.inner.http.clone();
+        match local_addrs {
+            LocalAddrs::Single(addr) => connector.set_local_address(addr),
+            LocalAddrs::Dual(v4, v6) => connector.set_local_addresses(v4, v6),
         }
 
         Client::builder(TokioExecutor::new())
             .timer(TokioTimer::new())
+            .pool_timer(TokioTimer::new())
+            .pool_idle_timeout(HTTP_POOL_IDLE_TIMEOUT)
             .http1_title_case_headers(true)
             .http1_preserve_header_case(true)
             .build(connector)
-            .request(req)
-            .await
-            .map_err(Into::into)
     }
 }
 
+/// Whether `ip` is an address of this host, which only local addresses can
+/// be bound to.
+fn is_local(ip: IpAddr) -> bool {
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+/// Builds the HTTP connector of plain HTTP requests, whose resolver refuses
+/// the addresses `guard` refuses and applies the resolve `policy`.
+fn http_connector(
+    dns: Arc<DnsCache>,
+    guard: Option<Arc<DestinationGuard>>,
+    policy: Option<ResolvePolicy>,
+    connect_timeout: Duration,
+    tcp: TcpOptions,
+) -> connect::HttpConnector<CachingResolver> {
+    let mut http_connector =
+        connect::HttpConnector::new_with_resolver(CachingResolver::new(dns, guard, policy));
+    http_connector.set_connect_timeout(Some(connect_timeout));
+    http_connector.set_nodelay(tcp.tcp_nodelay);
+    http_connector.set_keepalive(tcp.tcp_keepalive.map(Duration::from_secs));
+    http_connector.set_send_buffer_size(tcp.tcp_send_buffer);
+    http_connector.set_recv_buffer_size(tcp.tcp_recv_buffer);
+    http_connector
+}
+
 /// Returns the last error encountered during a series of connection attempts,
 /// or a `ConnectionAborted` error if no connection attempts were made.
 ///
@@ -942,72 +1935,185 @@ fn error(last_err: Option<std::io::Error>) -> std::io::Error {
     }
 }
 
+impl TcpOptions {
+    /// Applies the socket options to a TCP socket or stream.
+    ///
+    /// Buffer sizes should be applied before the socket connects, so that the
+    /// TCP window scale is negotiated accordingly.
+    pub fn apply(&self, socket: SockRef<'_>) -> std::io::Result<()> {
+        if self.tcp_nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(secs) = self.tcp_keepalive {
+            let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        if let Some(size) = self.tcp_send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.tcp_recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts an IPv4-mapped IPv6 socket address (`[::ffff:a.b.c.d]:port`) to
+/// a plain IPv4 socket address. Other addresses are returned unchanged.
+fn normalize_socket_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Converts a CIDR inside the IPv4-mapped range `::ffff:0:0/96` to the
+/// equivalent IPv4 CIDR, so sockets are created with the IPv4 family.
+/// Other CIDRs are returned unchanged.
+fn normalize_cidr(cidr: IpCidr) -> IpCidr {
+    if let IpCidr::V6(v6) = cidr {
+        if v6.network_length() >= 96 {
+            if let Some(v4) = v6.first_address().to_ipv4_mapped() {
+                if let Ok(v4) = Ipv4Cidr::new(v4, v6.network_length() - 96) {
+                    return IpCidr::V4(v4);
+                }
+            }
+        }
+    }
+    cidr
+}
+
+/// Selects the configured CIDR and fallback address usable to reach `target`.
+///
+/// Sockets can only be bound to an address of the same family as the target,
+/// so a CIDR or fallback of the other family is ignored. If egress addresses
+/// are configured but none of them matches the family of the target, an
+/// `AddrNotAvailable` error is returned instead of silently connecting from
+/// the host's default address.
+fn egress_for_target(
+    cidr: Option<IpCidr>,
+    fallback: Option<IpAddr>,
+    target: SocketAddr,
+) -> std::io::Result<(Option<IpCidr>, Option<IpAddr>)> {
+    let is_ipv4 = target.is_ipv4();
+    let matched_cidr = cidr.filter(|cidr| cidr.is_ipv4() == is_ipv4);
+    let matched_fallback = fallback.filter(|ip| ip.is_ipv4() == is_ipv4);
+
+    if (cidr.is_some() || fallback.is_some())
+        && matched_cidr.is_none()
+        && matched_fallback.is_none()
+    {
+        let family = if is_ipv4 { "IPv4" } else { "IPv6" };
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("no {family} egress address configured to reach {target}"),
+        ));
+    }
+
+    Ok((matched_cidr, matched_fallback))
+}
+
+/// Derives the extension used to re-roll an assignment that landed on an
+/// excluded address.
+///
+/// Session and TTL values are hashed again, so the replacement address stays
+/// stable for the same session. Random and range assignments already pick a
+/// fresh random host on every call and are kept as is.
+fn reroll_extension(mut extension: Extension) -> Extension {
+    let reroll = |value: u64| fxhash::hash64(&value.to_be_bytes());
+    extension.session = extension.session.map(reroll);
+    extension.ttl = extension.ttl.map(reroll);
+    extension
+}
+
+/// Returns a random address of `cidr`.
+pub(crate) fn assign_rand_ip(cidr: IpCidr) -> IpAddr {
+    match cidr {
+        IpCidr::V4(cidr) => IpAddr::V4(assign_rand_ipv4(cidr)),
+        IpCidr::V6(cidr) => IpAddr::V6(assign_rand_ipv6(cidr)),
+    }
+}
+
+/// Returns the address at position `index` of `cidr`, wrapping around once
+/// the end of the CIDR is reached.
+fn assign_ip_sequential(cidr: IpCidr, index: u64) -> IpAddr {
+    match cidr {
+        IpCidr::V4(cidr) => {
+            let size = 1u64 << (32 - cidr.network_length());
+            let offset = (index % size) as u32;
+            IpAddr::V4(Ipv4Addr::from(u32::from(cidr.first_address()) | offset))
+        }
+        IpCidr::V6(cidr) => {
+            let host_bits = 128 - cidr.network_length() as u32;
+            let offset = match 1u128.checked_shl(host_bits) {
+                Some(size) => index as u128 % size,
+                None => index as u128,
+            };
+            IpAddr::V6(Ipv6Addr::from(u128::from(cidr.first_address()) | offset))
+        }
+    }
+}
+
 /// Assigns an IPv4 address based on the provided CIDR and extension.
-/// If the extension is a Session with an ID, the function generates a
-/// deterministic IPv4 address within the CIDR range using a murmurhash of the
-/// ID. The network part of the address is preserved, and the host part is
-/// generated from the hash. If the extension is not a Session, the function
-/// generates a random IPv4 address within the CIDR range.
-fn assign_ipv4_from_extension(
+/// With a range extension and a `--cidr-range` or a width of its own, the
+/// range picks the subnet and a session or TTL picks the host inside it, which
+/// is random otherwise. Without a range, a session or TTL generates a
+/// deterministic IPv4 address within the CIDR from its hash: the network part
+/// of the address is preserved, and the host part is generated from the hash.
+/// Without any extension, the function generates a random IPv4 address
+/// within the CIDR.
+pub(crate) fn assign_ipv4_from_extension(
     cidr: Ipv4Cidr,
     cidr_range: Option<u8>,
     extension: Extension,
 ) -> Ipv4Addr {
-    if let Some(combined) = extract_value_from_extension(extension) {
-        match extension {
-            Extension::TTL(_) | Extension::Session(_) => {
-                // Calculate the subnet mask and apply it to ensure the base_ip is preserved in
-                // the non-variable part
-                let subnet_mask = !((1u32 << (32 - cidr.network_length())) - 1);
-                let base_ip_bits = u32::from(cidr.first_address()) & subnet_mask;
-                let capacity = 2u32.pow(32 - cidr.network_length() as u32) - 1;
-                let ip_num = base_ip_bits | ((combined as u32) % capacity);
-                return Ipv4Addr::from(ip_num);
-            }
-            Extension::Range(_) => {
-                // If a CIDR range is provided, use it to assign an IP address
-                if let Some(range) = cidr_range {
-                    return assign_ipv4_with_range(cidr, range, combined as u32);
-                }
-            }
-            _ => {}
-        }
+    let sticky = extension.sticky();
+    let range = extension
+        .range_width
+        .filter(|width| *width <= 32)
+        .or(cidr_range);
+    if let (Some(combined), Some(range)) = (extension.range, range) {
+        return assign_ipv4_with_range(cidr, range, combined as u32, sticky.map(|v| v as u32));
+    }
+
+    if let Some(sticky) = sticky {
+        // Calculate the subnet mask and apply it to ensure the base_ip is preserved in
+        // the non-variable part
+        let subnet_mask = !((1u32 << (32 - cidr.network_length())) - 1);
+        let base_ip_bits = u32::from(cidr.first_address()) & subnet_mask;
+        let capacity = 2u32.pow(32 - cidr.network_length() as u32) - 1;
+        let ip_num = base_ip_bits | ((sticky as u32) % capacity);
+        return Ipv4Addr::from(ip_num);
     }
 
     assign_rand_ipv4(cidr)
 }
 
 /// Assigns an IPv6 address based on the provided CIDR and extension.
-/// If the extension is a Session with an ID, the function generates a
-/// deterministic IPv6 address within the CIDR range using a murmurhash of the
-/// ID. The network part of the address is preserved, and the host part is
-/// generated from the hash. If the extension is not a Session, the function
-/// generates a random IPv6 address within the CIDR range.
-fn assign_ipv6_from_extension(
+/// With a range extension and a `--cidr-range` or a width of its own, the
+/// range picks the subnet and a session or TTL picks the host inside it, which
+/// is random otherwise. Without a range, a session or TTL generates a
+/// deterministic IPv6 address within the CIDR from its hash: the network part
+/// of the address is preserved, and the host part is generated from the hash.
+/// Without any extension, the function generates a random IPv6 address
+/// within the CIDR.
+pub(crate) fn assign_ipv6_from_extension(
     cidr: Ipv6Cidr,
     cidr_range: Option<u8>,
     extension: Extension,
 ) -> Ipv6Addr {
-    if let Some(combined) = extract_value_from_extension(extension) {
-        match extension {
-            Extension::TTL(_) | Extension::Session(_) => {
-                let network_length = cidr.network_length();
-                // Calculate the subnet mask and apply it to ensure the base_ip is preserved in
-                // the non-variable part
-                let subnet_mask = !((1u128 << (128 - network_length)) - 1);
-                let base_ip_bits = u128::from(cidr.first_address()) & subnet_mask;
-                let capacity = 2u128.pow(128 - network_length as u32) - 1;
-                let ip_num = base_ip_bits | (combined as u128 % capacity);
-                return Ipv6Addr::from(ip_num);
-            }
-            Extension::Range(_) => {
-                // If a range is provided, use it to assign an IP
-                if let Some(range) = cidr_range {
-                    return assign_ipv6_with_range(cidr, range, combined as u128);
-                }
-            }
-            _ => {}
-        }
+    let sticky = extension.sticky();
+    let range = extension.range_width.or(cidr_range);
+    if let (Some(combined), Some(range)) = (extension.range, range) {
+        return assign_ipv6_with_range(cidr, range, combined as u128, sticky.map(u128::from));
+    }
+
+    if let Some(sticky) = sticky {
+        let network_length = cidr.network_length();
+        // Calculate the subnet mask and apply it to ensure the base_ip is preserved in
+        // the non-variable part
+        let subnet_mask = !((1u128 << (128 - network_length)) - 1);
+        let base_ip_bits = u128::from(cidr.first_address()) & subnet_mask;
+        let capacity = 2u128.pow(128 - network_length as u32) - 1;
+        let ip_num = base_ip_bits | (sticky as u128 % capacity);
+        return Ipv6Addr::from(ip_num);
     }
 
     assign_rand_ipv6(cidr)
@@ -1048,6 +2154,7 @@ fn assign_rand_ipv6(cidr: Ipv6Cidr) -> Ipv6Addr {
 /// - `cidr`: The CIDR notation representing the network range, e.g., "192.168.0.0/24".
 /// - `range`: The length of the address range to be fixed by the combined value (e.g., 28 for a /28 subnet).
 /// - `combined`: A fixed value used to influence the specific address within the range.
+/// - `host`: A fixed value picking the host inside the range, random when `None`.
 ///
 /// # Returns
 /// An `Ipv4Addr` representing the generated IPv4 address.
@@ -1057,10 +2164,10 @@ fn assign_rand_ipv6(cidr: Ipv6Cidr) -> Ipv6Addr {
 /// let cidr = "192.168.0.0/24".parse::<Ipv4Cidr>().unwrap();
 /// let range = 28;
 /// let combined = 0x5;
-/// let ipv4_address = assign_ipv4_with_range(&cidr, range, combined);
+/// let ipv4_address = assign_ipv4_with_range(&cidr, range, combined, None);
 /// println!("Generated IPv4 Address: {}", ipv4_address);
 /// ```
-fn assign_ipv4_with_range(cidr: Ipv4Cidr, range: u8, combined: u32) -> Ipv4Addr {
+fn assign_ipv4_with_range(cidr: Ipv4Cidr, range: u8, combined: u32, host: Option<u32>) -> Ipv4Addr {
     let base_ip: u32 = u32::from(cidr.first_address());
     let prefix_len = cidr.network_length();
 
@@ -1076,9 +2183,9 @@ fn assign_ipv4_with_range(cidr: Ipv4Cidr, range: u8, combined: u32) -> Ipv4Addr
     let subnet_mask = !((1u32 << (32 - prefix_len)) - 1);
     let subnet_with_fixed = (base_ip & subnet_mask) | combined_shifted;
 
-    // Generate a mask for the host part and a random host part value.
+    // Generate a mask for the host part and a fixed or random host part value.
     let host_mask = (1u32 << (32 - range)) - 1;
-    let host_part: u32 = random::<u32>() & host_mask;
+    let host_part: u32 = host.unwrap_or_else(random) & host_mask;
 
     // Combine the fixed subnet part and the random host part to form the final IP address.
     Ipv4Addr::from(subnet_with_fixed | host_part)
@@ -1091,6 +2198,7 @@ fn assign_ipv4_with_range(cidr: Ipv4Cidr, range: u8, combined: u32) -> Ipv4Addr
 /// - `cidr`: The CIDR notation representing the network range, e.g., "2001:470:e953::/48".
 /// - `range`: The length of the address range to be fixed by the combined value (e.g., 64 for a /64 subnet).
 /// - `combined`: A fixed value used to influence the specific address within the range.
+/// - `host`: A fixed value picking the host inside the range, random when `None`.
 ///
 /// # Returns
 /// An `Ipv6Addr` representing the generated IPv6 address.
@@ -1100,10 +2208,15 @@ fn assign_ipv4_with_range(cidr: Ipv4Cidr, range: u8, combined: u32) -> Ipv4Addr
 /// let cidr = "2001:470:e953::/48".parse::<Ipv6Cidr>().unwrap();
 /// let range = 64;
 /// let combined = 0x12345;
-/// let ipv6_address = assign_ipv6_with_range(&cidr, range, combined);
+/// let ipv6_address = assign_ipv6_with_range(&cidr, range, combined, None);
 /// println!("Generated IPv6 Address: {}", ipv6_address);
 /// ```
-fn assign_ipv6_with_range(cidr: Ipv6Cidr, range: u8, combined: u128) -> Ipv6Addr {
+fn assign_ipv6_with_range(
+    cidr: Ipv6Cidr,
+    range: u8,
+    combined: u128,
+    host: Option<u128>,
+) -> Ipv6Addr {
     let base_ip: u128 = cidr.first_address().into();
     let prefix_len = cidr.network_length();
 
@@ -1119,46 +2232,14 @@ fn assign_ipv6_with_range(cidr: Ipv6Cidr, range: u8, combined: u128) -> Ipv6Addr
     let subnet_mask = !((1u128 << (128 - prefix_len)) - 1);
     let subnet_with_fixed = (base_ip & subnet_mask) | combined_shifted;
 
-    // Generate a mask for the host part and a random host part value.
+    // Generate a mask for the host part and a fixed or random host part value.
     let host_mask = (1u128 << (128 - range)) - 1;
-    let host_part: u128 = (random::<u64>() as u128) & host_mask;
+    let host_part: u128 = host.unwrap_or_else(|| random::<u64>() as u128) & host_mask;
 
     // Combine the fixed subnet part and the random host part to form the final IP address.
     Ipv6Addr::from(subnet_with_fixed | host_part)
 }
 
-/// Extracts a value from the given `Extension` enum variant.
-///
-/// This function takes an `Extension` enum and returns an `Option<u64>` containing the value
-/// associated with the `Range`, `Session`, or `TTL` variants. If the `Extension` variant does
-/// not contain a value (i.e., it is not one of the aforementioned variants), the function returns `None`.
-///
-/// # Arguments
-///
-/// * `extension` - An `Extension` enum variant from which to extract the value.
-///
-/// # Returns
-///
-/// * `Option<u64>` - The extracted value if the `extension` is a `Range`, `Session`, or `TTL` variant; otherwise, `None`.
-///
-/// # Examples
-///
-/// ```
-/// let ext = Extension::Range(42);
-/// assert_eq!(extract_value_from_extension(ext), Some(42));
-///
-/// let ext = Extension::Unknown;
-/// assert_eq!(extract_value_from_extension(ext), None);
-/// ```
-fn extract_value_from_extension(extension: Extension) -> Option<u64> {
-    match extension {
-        Extension::Range(value) => Some(value),
-        Extension::Session(value) => Some(value),
-        Extension::TTL(ttl) => Some(ttl),
-        _ => None,
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1173,8 +2254,8 @@ mod tests {
             combined += i;
 
             // Generate two IPv4 addresses with the same combined value
-            let ipv4_address1 = assign_ipv4_with_range(cidr, range, combined);
-            let ipv4_address2 = assign_ipv4_with_range(cidr, range, combined);
+            let ipv4_address1 = assign_ipv4_with_range(cidr, range, combined, None);
+            let ipv4_address2 = assign_ipv4_with_range(cidr, range, combined, None);
 
             println!("IPv4 Address 1: {}", ipv4_address1);
             println!("IPv4 Address 2: {}", ipv4_address2);
@@ -1190,22 +2271,277 @@ mod tests {
         for i in 0..5 {
             combined += i;
             // Generate two IPv6 addresses with the same combined value
-            let ipv6_address1 = assign_ipv6_with_range(cidr, range, combined);
-            let ipv6_address2 = assign_ipv6_with_range(cidr, range, combined);
+            let ipv6_address1 = assign_ipv6_with_range(cidr, range, combined, None);
+            let ipv6_address2 = assign_ipv6_with_range(cidr, range, combined, None);
 
             println!("{}", ipv6_address1);
             println!("{}", ipv6_address2)
         }
     }
 
+    #[test]
+    fn test_normalize_v4_mapped_addresses() {
+        let mapped: SocketAddr = "[::ffff:192.0.2.1]:443".parse().unwrap();
+        assert_eq!(
+            normalize_socket_addr(mapped),
+            "192.0.2.1:443".parse::<SocketAddr>().unwrap()
+        );
+
+        let v6: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        assert_eq!(normalize_socket_addr(v6), v6);
+
+        let mapped_cidr: IpCidr = "::ffff:192.0.2.0/120".parse().unwrap();
+        assert_eq!(
+            normalize_cidr(mapped_cidr),
+            "192.0.2.0/24".parse::<IpCidr>().unwrap()
+        );
+
+        let v6_cidr: IpCidr = "2001:db8::/32".parse().unwrap();
+        assert_eq!(normalize_cidr(v6_cidr), v6_cidr);
+    }
+
+    #[test]
+    fn test_egress_for_target_family() {
+        let v6_cidr: IpCidr = "2001:db8::/32".parse().unwrap();
+        let v4_fallback: IpAddr = "192.0.2.1".parse().unwrap();
+        let v4_target: SocketAddr = "198.51.100.1:80".parse().unwrap();
+        let v6_target: SocketAddr = "[2001:db8:1::1]:80".parse().unwrap();
+
+        let (cidr, fallback) =
+            egress_for_target(Some(v6_cidr), Some(v4_fallback), v4_target).unwrap();
+        assert_eq!((cidr, fallback), (None, Some(v4_fallback)));
+
+        let (cidr, fallback) =
+            egress_for_target(Some(v6_cidr), Some(v4_fallback), v6_target).unwrap();
+        assert_eq!((cidr, fallback), (Some(v6_cidr), None));
+
+        let err = egress_for_target(Some(v6_cidr), None, v4_target).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrNotAvailable);
+
+        assert_eq!(
+            egress_for_target(None, None, v4_target).unwrap(),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn test_assign_ip_skips_excluded() {
+        let cidr: IpCidr = "192.0.2.0/30".parse().unwrap();
+        let exclude = vec![
+            "192.0.2.0/31".parse().unwrap(),
+            IpCidr::new_host("192.0.2.2".parse().unwrap()),
+        ];
+        let connector = Connector::new(
+            Some(cidr),
+            None,
+            None,
+            exclude,
+            AssignMode::Random,
+            10,
+            TcpOptions::default(),
+        );
+
+        for _ in 0..100 {
+            let ip = connector.assign_ip(cidr, Extension::default()).unwrap();
+            assert_eq!(ip, "192.0.2.3".parse::<IpAddr>().unwrap());
+        }
+
+        // A session is re-rolled deterministically and keeps its replacement.
+        let cidr: IpCidr = "192.0.2.0/24".parse().unwrap();
+        let session = Extension::session(fxhash::hash64("session"));
+        let assigned = connector.assign_ip(cidr, session).unwrap();
+        let connector = Connector::new(
+            Some(cidr),
+            None,
+            None,
+            vec![IpCidr::new_host(assigned)],
+            AssignMode::Random,
+            10,
+            TcpOptions::default(),
+        );
+        let rerolled = connector.assign_ip(cidr, session).unwrap();
+        assert_ne!(rerolled, assigned);
+        assert_eq!(connector.assign_ip(cidr, session).unwrap(), rerolled);
+    }
+
+    #[test]
+    fn test_client_extension() {
+        let cidr: IpCidr = "2001:db8::/64".parse().unwrap();
+        let connector = Connector::new(
+            Some(cidr),
+            None,
+            None,
+            Vec::new(),
+            AssignMode::ClientIp,
+            10,
+            TcpOptions::default(),
+        );
+        let client = "192.0.2.1".parse().unwrap();
+
+        let sticky = connector.client_extension(client, Extension::default());
+        let assigned = connector.assign_ip(cidr, sticky).unwrap();
+        for _ in 0..10 {
+            let extension = connector.client_extension(client, Extension::default());
+            assert_eq!(connector.assign_ip(cidr, extension).unwrap(), assigned);
+        }
+        assert_eq!(
+            connector.client_extension(client, Extension::ttl(30)),
+            Extension::ttl(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_without_pool() {
+        let connector = Connector::new(
+            None,
+            None,
+            None,
+            Vec::new(),
+            AssignMode::Random,
+            10,
+            TcpOptions::default(),
+        );
+        let extension = Extension {
+            upstream: Some(fxhash::hash64(b"provider-a")),
+            ..Extension::default()
+        };
+        let err = connector
+            .tcp_connector()
+            .connect_with_domain(("example.com".to_owned(), 443), extension)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_saturated_fallback() {
+        let cidr: IpCidr = "198.51.100.6/31".parse().unwrap();
+        let fallback: IpAddr = "198.51.100.8".parse().unwrap();
+        let connector = Connector::new(
+            Some(cidr),
+            None,
+            Some(fallback),
+            Vec::new(),
+            AssignMode::Random,
+            10,
+            TcpOptions::default(),
+        )
+        .with_max_conns_per_ip(Some(1));
+        assert!(cidr.contains(&connector.assign_ip(cidr, Extension::default()).unwrap()));
+
+        let _tunnels = ["198.51.100.6", "198.51.100.7"].map(|egress| {
+            crate::stats::Tunnel::register(crate::stats::TunnelInfo {
+                protocol: crate::hooks::Protocol::Http,
+                client: "192.0.2.1:4000".parse().unwrap(),
+                username: None,
+                target: "example.com:443".to_owned(),
+                egress: Some(egress.parse().unwrap()),
+                server: None,
+                progress: Default::default(),
+                client_first: true,
+                started: std::time::Instant::now(),
+            })
+        });
+        let ip = connector.assign_ip(cidr, Extension::default()).unwrap();
+        assert_eq!(ip, fallback);
+    }
+
+    #[test]
+    fn test_reserved_slots() {
+        let cidr: IpCidr = "198.51.100.16/31".parse().unwrap();
+        let fallback: IpAddr = "198.51.100.8".parse().unwrap();
+        let connector = Connector::new(
+            Some(cidr),
+            None,
+            Some(fallback),
+            Vec::new(),
+            AssignMode::Random,
+            10,
+            TcpOptions::default(),
+        )
+        .with_max_conns_per_ip(Some(1));
+        let (first, first_slot) = connector.reserve_ip(cidr, Extension::default()).unwrap();
+        let (second, second_slot) = connector.reserve_ip(cidr, Extension::default()).unwrap();
+        assert_ne!(first, second);
+        assert!(first_slot.is_some() && second_slot.is_some());
+
+        let (ip, slot) = connector.reserve_ip(cidr, Extension::default()).unwrap();
+        assert_eq!(ip, fallback);
+        assert!(slot.is_none());
+
+        drop(first_slot);
+        let (ip, _slot) = connector.reserve_ip(cidr, Extension::default()).unwrap();
+        assert_eq!(ip, first);
+    }
+
+    #[tokio::test]
+    async fn test_banned_fallback() {
+        let fallback: IpAddr = "198.51.100.8".parse().unwrap();
+        let connector = Connector::new(
+            None,
+            None,
+            Some(fallback),
+            Vec::new(),
+            AssignMode::Random,
+            10,
+            TcpOptions::default(),
+        );
+        let egress = connector.tcp_connector().egress_addr(Extension::default());
+        assert_eq!(egress.unwrap(), Some(fallback));
+
+        let ban = "198.51.100.0/24".parse().unwrap();
+        assert!(connector.egress_bans().ban(ban).await.unwrap());
+        let egress = connector.tcp_connector().egress_addr(Extension::default());
+        assert_eq!(
+            egress.unwrap_err().kind(),
+            std::io::ErrorKind::AddrNotAvailable
+        );
+    }
+
+    #[test]
+    fn test_assign_ip_sequential() {
+        let v4: IpCidr = "192.0.2.0/30".parse().unwrap();
+        let assigned = (0..6)
+            .map(|index| assign_ip_sequential(v4, index).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            assigned,
+            [
+                "192.0.2.0",
+                "192.0.2.1",
+                "192.0.2.2",
+                "192.0.2.3",
+                "192.0.2.0",
+                "192.0.2.1"
+            ]
+        );
+
+        let v6: IpCidr = "2001:db8::/64".parse().unwrap();
+        assert_eq!(
+            assign_ip_sequential(v6, u64::MAX),
+            "2001:db8::ffff:ffff:ffff:ffff".parse::<IpAddr>().unwrap()
+        );
+    }
+
     #[test]
     fn test_assign_ipv4_from_extension() {
         let cidr = "2001:470:e953::/48".parse().unwrap();
-        let extension = Extension::Session(0x12345);
+        let extension = Extension::session(0x12345);
         let ipv6_address = assign_ipv6_from_extension(cidr, None, extension);
         assert_eq!(
             ipv6_address,
             std::net::Ipv6Addr::from([0x2001, 0x470, 0xe953, 0, 0, 0, 1, 0x2345])
         );
+
+        // A range picks the subnet, the session the host inside it
+        let extension = Extension {
+            range: Some(0x7),
+            ..extension
+        };
+        let ipv6_address = assign_ipv6_from_extension(cidr, Some(64), extension);
+        assert_eq!(
+            ipv6_address,
+            std::net::Ipv6Addr::from([0x2001, 0x470, 0xe953, 0x7, 0, 0, 1, 0x2345])
+        );
     }
 }
@@ -0,0 +1,274 @@
+//! Control socket of a running instance.
+//!
+//! The server listens on a unix socket for single-line commands, answering
+//! with plain text before closing the connection:
+//!
+//! - `stats` returns the live statistics, see [`crate::stats`];
+//! - `connections` lists the open tunnels as a table, `connections json` as
+//!   a JSON array;
+//! - `stop` makes the process exit once its connections are closed, or after
+//!   [`STOP_DRAIN_TIMEOUT`].
+//!
+//! The socket is only accessible to the user running the server: it is bound
+//! and restricted inside a private directory before being moved in place.
+
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::UnixStream as StdUnixStream,
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+/// Where the control socket is created unless configured otherwise.
+pub const DEFAULT_PATH: &str = concat!("/var/run/", env!("CARGO_PKG_NAME"), "/control.sock");
+
+/// Time allowed for the daemon to answer a command.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest command accepted.
+const MAX_COMMAND_LEN: u64 = 1024;
+
+/// Time open connections are given to close on `stop`, below the time the
+/// `stop` command waits for the process to exit.
+const STOP_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether the control socket is being served.
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+/// Serves the control socket at `path`. Only the first call serves it, later
+/// calls return immediately.
+pub(crate) async fn serve(path: PathBuf) {
+    if SERVING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    if path.exists() {
+        if StdUnixStream::connect(&path).is_ok() {
+            tracing::warn!(
+                "Control socket {} is used by another instance",
+                path.display()
+            );
+            return;
+        }
+        // Left behind by an instance that did not exit cleanly
+        let _ = std::fs::remove_file(&path);
+    } else if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+
+    let listener = match bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::PermissionDenied {
+                tracing::debug!("Cannot create control socket {}: {}", path.display(), err);
+            } else {
+                tracing::warn!("Cannot create control socket {}: {}", path.display(), err);
+            }
+            return;
+        }
+    };
+    tracing::info!("Control socket listening on {}", path.display());
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle(stream, &path).await {
+                        tracing::debug!("[control] connection error: {}", err);
+                    }
+                });
+            }
+            Err(err) => tracing::debug!("[control] accept error: {}", err),
+        }
+    }
+}
+
+/// Binds the socket at `path`, accessible to the current user only. It is
+/// bound in a directory only that user can enter, so it is never reachable
+/// with the default permissions, then moved to `path`.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(name);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let bound = dir.join("control.sock");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, PermissionsExt::from_mode(0o600))?;
+        std::fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&bound);
+    let _ = std::fs::remove_dir(&dir);
+    listener
+}
+
+async fn handle(stream: UnixStream, path: &Path) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut command = String::new();
+    BufReader::new(read.take(MAX_COMMAND_LEN))
+        .read_line(&mut command)
+        .await?;
+
+    match command.trim() {
+        "stats" => write.write_all(crate::stats::report().as_bytes()).await?,
+        "connections" => {
+            let connections = crate::stats::connections(false);
+            write.write_all(connections.as_bytes()).await?
+        }
+        "connections json" => {
+            let connections = crate::stats::connections(true);
+            write.write_all(connections.as_bytes()).await?
+        }
+        "stop" => {
+            tracing::info!("Stopping on request of the control socket");
+            write.write_all(b"stopping\n").await?;
+            write.shutdown().await?;
+            crate::status::drain(STOP_DRAIN_TIMEOUT, "stopping").await;
+            crate::notify::deliver(crate::notify::Event::Stopping {
+                reason: "control socket",
+            })
+            .await;
+            let _ = std::fs::remove_file(path);
+            std::process::exit(0);
+        }
+        command => {
+            write
+                .write_all(format!("unknown command: {command}\n").as_bytes())
+                .await?
+        }
+    }
+
+    write.shutdown().await
+}
+
+/// Sends `command` to the instance listening on the control socket at
+/// `path`, returning its answer.
+pub fn request(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = StdUnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+    Ok(answer)
+}
+
+/// Returns the command line a daemon runs its server with: the arguments
+/// `args` of the `start` or `restart` command, run in the foreground with
+/// `run` and without the daemon options. A `control` socket is passed on to
+/// the server, unless `args` set one already. Arguments of other commands
+/// are returned as they are.
+pub fn server_args<I>(args: I, control: Option<&Path>) -> Vec<OsString>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter().collect::<Vec<_>>();
+    if !args
+        .first()
+        .is_some_and(|command| command == "start" || command == "restart")
+    {
+        return args;
+    }
+    args.remove(0);
+    let has_control = args.iter().any(|arg| {
+        arg == "--control-socket"
+            || arg
+                .to_str()
+                .is_some_and(|arg| arg.starts_with("--control-socket="))
+    });
+
+    let mut server = vec![OsString::from("run")];
+    if let Some(control) = control.filter(|_| !has_control) {
+        server.push("--control-socket".into());
+        server.push(control.into());
+    }
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--instance" {
+            args.next();
+            continue;
+        }
+        if arg == "--supervise"
+            || arg
+                .to_str()
+                .is_some_and(|arg| arg.starts_with("--instance="))
+        {
+            continue;
+        }
+        server.push(arg);
+    }
+    server
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_args() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        let control = Path::new("/var/run/vproxy/eu.sock");
+
+        assert_eq!(
+            server_args(
+                args(&[
+                    "start",
+                    "--instance",
+                    "eu",
+                    "--supervise",
+                    "-i",
+                    "2001:db8::/32",
+                    "http"
+                ]),
+                Some(control)
+            ),
+            args(&[
+                "run",
+                "--control-socket",
+                "/var/run/vproxy/eu.sock",
+                "-i",
+                "2001:db8::/32",
+                "http"
+            ])
+        );
+        assert_eq!(
+            server_args(
+                args(&["restart", "--supervise", "--instance=eu", "http"]),
+                None
+            ),
+            args(&["run", "http"])
+        );
+        assert_eq!(
+            server_args(
+                args(&[
+                    "start",
+                    "--instance",
+                    "eu",
+                    "--control-socket=/run/eu.sock",
+                    "http"
+                ]),
+                Some(control)
+            ),
+            args(&["run", "--control-socket=/run/eu.sock", "http"])
+        );
+        assert_eq!(
+            server_args(args(&["run", "-i", "2001:db8::/32", "http"]), Some(control)),
+            args(&["run", "-i", "2001:db8::/32", "http"])
+        );
+    }
+}
@@ -1,20 +1,117 @@
-use crate::{serve, BootArgs, BIN_NAME};
+use crate::{BootArgs, BIN_NAME};
+use clap::Args;
 use daemonize::Daemonize;
 use nix::sys::signal;
 use nix::unistd::{Pid, Uid, User};
 use std::{
+    ffi::OsString,
     fs::{File, Permissions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
     os::unix::fs::PermissionsExt,
-    path::Path,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use tokio::signal::unix::{signal, SignalKind};
+use vproxy::{control, ProxyBuilder};
 
 const PID_PATH: &str = concat!("/var/run/", env!("CARGO_PKG_NAME"), ".pid");
 const DEFAULT_STDOUT_PATH: &str = concat!("/var/run/", env!("CARGO_PKG_NAME"), ".out");
 const DEFAULT_STDERR_PATH: &str = concat!("/var/run/", env!("CARGO_PKG_NAME"), ".err");
+const DEFAULT_CRASH_PATH: &str = concat!("/var/run/", env!("CARGO_PKG_NAME"), ".crash");
+
+/// Delay before a crashed server is restarted, doubled after every crash up
+/// to `MAX_RESTART_DELAY`.
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// Uptime after which a crash is no longer counted as part of a crash loop,
+/// resetting the restart delay.
+const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// How often followed logs are checked for new lines.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Daemon instance a command applies to.
+///
+/// Every named instance has its own PID file, logs and control socket, so
+/// several daemons can run side by side. Without a name the default
+/// instance is used.
+#[derive(Args, Clone, Default)]
+pub struct Instance {
+    /// Name of the daemon instance, e.g. `eu`, to run several daemons side by
+    /// side
+    #[clap(long = "instance", value_name = "NAME", value_parser = parse_instance)]
+    name: Option<String>,
+}
+
+impl Instance {
+    fn path(&self, default: &str, extension: &str) -> PathBuf {
+        match &self.name {
+            Some(name) => PathBuf::from(format!(
+                "/var/run/{}-{}.{}",
+                env!("CARGO_PKG_NAME"),
+                name,
+                extension
+            )),
+            None => PathBuf::from(default),
+        }
+    }
+
+    fn pid_path(&self) -> PathBuf {
+        self.path(PID_PATH, "pid")
+    }
+
+    fn stdout_path(&self) -> PathBuf {
+        self.path(DEFAULT_STDOUT_PATH, "out")
+    }
+
+    fn stderr_path(&self) -> PathBuf {
+        self.path(DEFAULT_STDERR_PATH, "err")
+    }
+
+    fn crash_path(&self) -> PathBuf {
+        self.path(DEFAULT_CRASH_PATH, "crash")
+    }
+
+    /// Control sockets of named instances live in a directory of their own,
+    /// so that no name maps to the socket of the default instance.
+    fn control_path(&self) -> PathBuf {
+        let default = Path::new(control::DEFAULT_PATH);
+        match &self.name {
+            Some(name) => default
+                .with_file_name("instances")
+                .join(format!("{name}.sock")),
+            None => default.to_owned(),
+        }
+    }
+
+    /// Describes the instance in messages.
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} instance {}", BIN_NAME, name),
+            None => BIN_NAME.to_owned(),
+        }
+    }
+}
+
+/// Instance names end up in file names, so only letters, digits, `-` and
+/// `_` are allowed.
+fn parse_instance(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(name.to_owned())
+    } else {
+        Err("use only letters, digits, '-' and '_'".to_owned())
+    }
+}
 
 #[inline(always)]
-fn pid() -> Option<String> {
-    if let Ok(data) = std::fs::read(PID_PATH) {
+fn pid(instance: &Instance) -> Option<String> {
+    if let Ok(data) = std::fs::read(instance.pid_path()) {
         let binding = String::from_utf8(data).expect("pid file is not utf8");
         return Some(binding.trim().to_string());
     }
@@ -29,37 +126,56 @@ pub fn check_root() {
     }
 }
 
-pub fn start(args: BootArgs) -> crate::Result<()> {
-    if let Some(pid) = pid() {
-        println!("{} is already running with pid: {}", BIN_NAME, pid);
+pub fn start(instance: Instance, supervise: bool, args: BootArgs) -> crate::Result<()> {
+    if let Some(pid) = pid(&instance) {
+        println!("{} is already running with pid: {}", instance.label(), pid);
         return Ok(());
     }
 
     check_root();
 
-    let pid_file = File::create(PID_PATH)?;
+    let pid_path = instance.pid_path();
+    let pid_file = File::create(&pid_path)?;
     pid_file.set_permissions(Permissions::from_mode(0o755))?;
 
-    let stdout = File::create(DEFAULT_STDOUT_PATH)?;
+    let stdout = File::create(instance.stdout_path())?;
     stdout.set_permissions(Permissions::from_mode(0o755))?;
 
-    let stderr = File::create(DEFAULT_STDERR_PATH)?;
+    let stderr = File::create(instance.stderr_path())?;
     stdout.set_permissions(Permissions::from_mode(0o755))?;
 
+    // Created before the umask below, which would leave it unreadable
+    let crashes = File::create(instance.crash_path())?;
+    crashes.set_permissions(Permissions::from_mode(0o644))?;
+
+    // With `--user` the server drops its privileges itself once the host is
+    // set up, otherwise the daemon runs as the user who started it
+    let user_name = match args.user() {
+        Some(user) => User::from_name(user).ok().flatten(),
+        None => std::env::var("SUDO_USER")
+            .ok()
+            .and_then(|user| User::from_name(&user).ok().flatten())
+            .or_else(|| User::from_uid(Uid::current()).ok().flatten()),
+    };
+
+    // The daemon creates its control socket after dropping privileges
+    let control = instance.control_path();
+    if let Some(dir) = control.parent() {
+        std::fs::create_dir_all(dir)?;
+        if let Some(real_user) = &user_name {
+            nix::unistd::chown(dir, Some(real_user.uid), Some(real_user.gid))?;
+        }
+    }
+
     let mut daemonize = Daemonize::new()
-        .pid_file(PID_PATH) // Every method except `new` and `start`
+        .pid_file(pid_path) // Every method except `new` and `start`
         .chown_pid_file(true) // is optional, see `Daemonize` documentation
         .umask(0o777) // Set umask, `0o027` by default.
         .stdout(stdout) // Redirect stdout to `/tmp/daemon.out`.
         .stderr(stderr) // Redirect stderr to `/tmp/daemon.err`.
         .privileged_action(|| "Executed before drop privileges");
 
-    let user_name = std::env::var("SUDO_USER")
-        .ok()
-        .and_then(|user| User::from_name(&user).ok().flatten())
-        .or_else(|| User::from_uid(Uid::current()).ok().flatten());
-
-    if let Some(real_user) = user_name {
+    if let Some(real_user) = user_name.filter(|_| args.user().is_none()) {
         daemonize = daemonize
             .user(real_user.name.as_str())
             .group(real_user.gid.as_raw());
@@ -70,33 +186,138 @@ pub fn start(args: BootArgs) -> crate::Result<()> {
         std::process::exit(-1)
     }
 
-    serve::run(args)
+    if supervise {
+        let control = instance.name.as_ref().map(|_| control.as_path());
+        let server = control::server_args(std::env::args_os().skip(1), control);
+        return tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+            .block_on(self::supervise(server, crashes));
+    }
+
+    // Named instances must not share the control socket of the default one
+    let args = match instance.name {
+        Some(_) => ProxyBuilder::from_args(args)
+            .control_socket(control)
+            .build(),
+        None => args,
+    };
+    vproxy::run(args)
 }
 
-pub fn stop() -> crate::Result<()> {
+pub fn stop(instance: &Instance) -> crate::Result<()> {
     check_root();
 
-    if let Some(pid) = pid() {
+    if let Some(pid) = pid(instance) {
         let pid = pid.parse::<i32>()?;
+
+        // Ask the daemon to exit, falling back to a signal for daemons
+        // without a control socket
+        if control::request(&instance.control_path(), "stop").is_ok() {
+            for _ in 0..360 {
+                if signal::kill(Pid::from_raw(pid), None).is_err() {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100))
+            }
+        }
+
         for _ in 0..360 {
             if signal::kill(Pid::from_raw(pid), signal::SIGINT).is_err() {
                 break;
             }
             std::thread::sleep(std::time::Duration::from_secs(1))
         }
-        let _ = std::fs::remove_file(PID_PATH);
+        let _ = std::fs::remove_file(instance.pid_path());
     }
 
     Ok(())
 }
 
-pub fn restart(args: BootArgs) -> crate::Result<()> {
-    stop()?;
-    start(args)
+pub fn restart(instance: Instance, supervise: bool, args: BootArgs) -> crate::Result<()> {
+    stop(&instance)?;
+    start(instance, supervise, args)
+}
+
+/// Runs the server as a child process with the arguments `server`,
+/// restarting it with exponential backoff whenever it exits with an error
+/// or is killed, until it exits cleanly or the supervisor is asked to stop.
+///
+/// Every crash is logged and appended to `crashes` as a JSON line.
+async fn supervise(server: Vec<OsString>, mut crashes: File) -> crate::Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut delay = MIN_RESTART_DELAY;
+
+    loop {
+        let started = Instant::now();
+        let mut child = tokio::process::Command::new(&exe).args(&server).spawn()?;
+        eprintln!("Supervisor started the server with pid {:?}", child.id());
+
+        let status = tokio::select! {
+            status = child.wait() => status?,
+            _ = interrupt.recv() => return stop_child(child).await,
+            _ = terminate.recv() => return stop_child(child).await,
+        };
+        if status.success() {
+            eprintln!("Server exited, supervisor stopping");
+            return Ok(());
+        }
+
+        let uptime = started.elapsed();
+        if uptime >= STABLE_UPTIME {
+            delay = MIN_RESTART_DELAY;
+        }
+        eprintln!(
+            "Server crashed ({}) after {:?}, restarting in {:?}",
+            status, uptime, delay
+        );
+        if let Err(err) = writeln!(crashes, "{}", crash_record(&status, uptime)) {
+            eprintln!("Failed to record the crash: {err}");
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = interrupt.recv() => return Ok(()),
+            _ = terminate.recv() => return Ok(()),
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Asks the supervised server to shut down and waits for it to exit.
+async fn stop_child(mut child: tokio::process::Child) -> crate::Result<()> {
+    if let Some(pid) = child.id() {
+        let _ = signal::kill(Pid::from_raw(pid as i32), signal::SIGINT);
+    }
+    child.wait().await?;
+    Ok(())
+}
+
+/// Describes a crash of the server as a JSON object.
+fn crash_record(status: &ExitStatus, uptime: Duration) -> serde_json::Value {
+    use std::os::unix::process::ExitStatusExt;
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    serde_json::json!({
+        "time": time,
+        "code": status.code(),
+        "signal": status.signal(),
+        "reason": status.to_string(),
+        "uptime": uptime.as_secs(),
+    })
 }
 
-pub fn status() -> crate::Result<()> {
-    match pid() {
+pub fn status(instance: &Instance, json: bool) -> crate::Result<()> {
+    if json {
+        return status_json(instance);
+    }
+
+    match pid(instance) {
         Some(pid) => {
             let mut sys = sysinfo::System::new();
 
@@ -115,48 +336,316 @@ pub fn status() -> crate::Result<()> {
                     );
                 }
             }
+
+            let control = instance.control_path();
+            match control::request(&control, "stats") {
+                Ok(stats) => print!("\n{stats}"),
+                Err(err) => println!("\nLive statistics unavailable: {err}"),
+            }
+            if let Ok(connections) = control::request(&control, "connections") {
+                print!("\n{connections}");
+            }
+            print_crashes(instance);
         }
-        None => println!("{} is not running", BIN_NAME),
+        None => println!("{} is not running", instance.label()),
     }
     Ok(())
 }
 
-pub fn log() -> crate::Result<()> {
-    fn read_and_print_file(file_path: &'static str, placeholder: &str) -> crate::Result<()> {
-        if !Path::new(file_path).exists() {
-            return Ok(());
-        }
+/// Prints how often the supervised server crashed and the last reason.
+fn print_crashes(instance: &Instance) {
+    let Ok(content) = std::fs::read_to_string(instance.crash_path()) else {
+        return;
+    };
+    let crashes = content.lines().collect::<Vec<_>>();
+    let Some(last) = crashes
+        .last()
+        .and_then(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+    else {
+        return;
+    };
+    println!(
+        "\nServer restarted {} times, last crash: {}",
+        crashes.len(),
+        last["reason"].as_str().unwrap_or("unknown")
+    );
+}
 
-        // Check if the file is empty before opening it
-        let metadata = std::fs::metadata(file_path)?;
-        if metadata.len() == 0 {
-            return Ok(());
+/// Prints the daemon process and its open connections as a JSON object.
+fn status_json(instance: &Instance) -> crate::Result<()> {
+    let Some(pid) = pid(instance) else {
+        println!("{}", serde_json::json!({ "running": false }));
+        return Ok(());
+    };
+    let pid = pid.parse::<u32>()?;
+
+    let mut sys = sysinfo::System::new();
+    sys.refresh_all();
+    let process = sys.process(sysinfo::Pid::from_u32(pid));
+
+    let connections = control::request(&instance.control_path(), "connections json")
+        .ok()
+        .and_then(|connections| serde_json::from_str::<serde_json::Value>(&connections).ok());
+
+    let status = serde_json::json!({
+        "running": true,
+        "pid": pid,
+        "cpu": process.map(|process| process.cpu_usage()),
+        "memory": process.map(|process| process.memory()),
+        "connections": connections,
+    });
+    println!("{status}");
+    Ok(())
+}
+
+/// Options of `vproxy log`.
+#[derive(Args, Clone)]
+pub struct LogArgs {
+    /// Keep printing lines as they are written, until interrupted
+    #[clap(short, long)]
+    follow: bool,
+
+    /// Print only the last N lines of each log
+    #[clap(short = 'n', long, value_name = "N")]
+    lines: Option<usize>,
+
+    /// Print only lines of this level or more severe, e.g. warn; lines
+    /// without a level, such as panic messages, are always printed
+    #[clap(long)]
+    level: Option<tracing::Level>,
+
+    /// Print only lines containing this text
+    #[clap(long, value_name = "TEXT")]
+    grep: Option<String>,
+}
+
+impl LogArgs {
+    /// Whether `line` passes the level and text filters.
+    fn matches(&self, line: &str) -> bool {
+        if let (Some(wanted), Some(level)) = (self.level, line_level(line)) {
+            // More verbose levels compare greater
+            if level > wanted {
+                return false;
+            }
         }
+        match &self.grep {
+            Some(text) => line.contains(text.as_str()),
+            None => true,
+        }
+    }
+}
 
-        let file = File::open(file_path)?;
-        let reader = std::io::BufReader::new(file);
-        let mut start = true;
+pub fn log(instance: &Instance, opts: &LogArgs) -> crate::Result<()> {
+    let mut logs = [
+        (instance.stdout_path(), "STDOUT>", 0),
+        (instance.stderr_path(), "STDERR>", 0),
+    ];
+    for (path, placeholder, offset) in &mut logs {
+        let Ok(mut file) = File::open(&*path) else {
+            continue;
+        };
+        *offset = file.metadata()?.len();
 
-        use std::io::BufRead;
+        // Only the end of the log is read for the last lines, which may be
+        // far smaller than the whole log
+        if let Some(wanted) = opts.lines {
+            let lines = last_lines(&mut file, *offset, wanted, |line| opts.matches(line))?;
+            if !lines.is_empty() {
+                println!("{placeholder}");
+            }
+            for line in &lines {
+                println!("{line}");
+            }
+            continue;
+        }
 
-        for line in reader.lines() {
-            if let Ok(content) = line {
-                if start {
-                    start = false;
+        let mut printed = false;
+        for line in BufReader::new(file.take(*offset)).split(b'\n') {
+            let line = line?;
+            let line = String::from_utf8_lossy(&line);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if opts.matches(line) {
+                if !std::mem::replace(&mut printed, true) {
                     println!("{placeholder}");
                 }
-                println!("{}", content);
-            } else if let Err(err) = line {
-                eprintln!("Error reading line: {}", err);
+                println!("{line}");
             }
         }
+    }
 
-        Ok(())
+    if !opts.follow {
+        return Ok(());
+    }
+    loop {
+        std::thread::sleep(FOLLOW_INTERVAL);
+        for (path, placeholder, offset) in &mut logs {
+            for line in read_new_lines(path, offset)? {
+                if opts.matches(&line) {
+                    println!("{placeholder} {line}");
+                }
+            }
+        }
     }
+}
 
-    read_and_print_file(DEFAULT_STDOUT_PATH, "STDOUT>")?;
+/// Returns the last `wanted` lines `matches` accepts among the first `len`
+/// bytes of `file`, reading it backwards in blocks.
+fn last_lines(
+    file: &mut File,
+    len: u64,
+    wanted: usize,
+    matches: impl Fn(&str) -> bool,
+) -> std::io::Result<Vec<String>> {
+    const BLOCK: u64 = 64 * 1024;
 
-    read_and_print_file(DEFAULT_STDERR_PATH, "STDERR>")?;
+    let mut lines = Vec::new();
+    let mut end = len;
+    // Start of the line the block read last began in, read with the next one
+    let mut partial = Vec::new();
+    while end > 0 && lines.len() < wanted {
+        let start = end.saturating_sub(BLOCK);
+        let mut block = vec![0; (end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block)?;
+        if end == len && block.last() == Some(&b'\n') {
+            block.pop();
+        }
+        block.extend_from_slice(&partial);
+        end = start;
 
-    Ok(())
+        let complete = if start > 0 {
+            let Some(newline) = block.iter().position(|byte| *byte == b'\n') else {
+                partial = block;
+                continue;
+            };
+            partial = block[..newline].to_vec();
+            &block[newline + 1..]
+        } else {
+            partial.clear();
+            &block[..]
+        };
+        for line in complete.rsplit(|byte| *byte == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if matches(line) {
+                lines.push(line.to_owned());
+                if lines.len() == wanted {
+                    break;
+                }
+            }
+        }
+    }
+    lines.reverse();
+    Ok(lines)
+}
+
+/// Returns the complete lines written to `path` since `offset`, advancing
+/// it. A log that got shorter was recreated by a restart and is read from
+/// its start.
+fn read_new_lines(path: &Path, offset: &mut u64) -> crate::Result<Vec<String>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    if file.metadata()?.len() < *offset {
+        *offset = 0;
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+
+    // A line still being written is left for the next read
+    let complete = content
+        .iter()
+        .rposition(|byte| *byte == b'\n')
+        .map_or(0, |end| end + 1);
+    *offset += complete as u64;
+    Ok(String::from_utf8_lossy(&content[..complete])
+        .lines()
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Returns the level of a log line written by the server, which follows
+/// the timestamp and may be wrapped in color codes.
+fn line_level(line: &str) -> Option<tracing::Level> {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the escape sequence up to its final letter
+            chars.by_ref().find(|c| c.is_ascii_alphabetic());
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+        .split_whitespace()
+        .take(2)
+        .filter(|word| word.chars().all(|c| c.is_ascii_uppercase()))
+        .find_map(|word| word.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_last_lines() {
+        let path = std::env::temp_dir().join(format!("vproxy-log-{}", std::process::id()));
+        let content = (0..20_000)
+            .map(|number| format!("line {number}\n"))
+            .collect::<String>();
+        std::fs::write(&path, &content).unwrap();
+        let mut file = File::open(&path).unwrap();
+        let len = content.len() as u64;
+
+        let lines = last_lines(&mut file, len, 3, |_| true).unwrap();
+        assert_eq!(lines, ["line 19997", "line 19998", "line 19999"]);
+        let lines = last_lines(&mut file, len, 2, |line| line.ends_with("000")).unwrap();
+        assert_eq!(lines, ["line 18000", "line 19000"]);
+        let lines = last_lines(&mut file, len, 5, |line| line.ends_with(" 1")).unwrap();
+        assert_eq!(lines, ["line 1"]);
+        assert_eq!(
+            last_lines(&mut file, len, 30_000, |_| true).unwrap().len(),
+            20_000
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_control_path() {
+        let instance = |name: &str| Instance {
+            name: Some(name.to_owned()),
+        };
+        assert_eq!(
+            Instance::default().control_path(),
+            Path::new(control::DEFAULT_PATH)
+        );
+        assert_ne!(
+            instance("control").control_path(),
+            Path::new(control::DEFAULT_PATH)
+        );
+        assert_eq!(
+            instance("eu").control_path(),
+            Path::new(control::DEFAULT_PATH)
+                .with_file_name("instances")
+                .join("eu.sock")
+        );
+    }
+
+    #[test]
+    fn test_line_level() {
+        let colored = "\x1b[2m2024-05-01T10:00:00.000000Z\x1b[0m \x1b[33m WARN\x1b[0m \x1b[2mvproxy\x1b[0m: slow";
+        assert_eq!(line_level(colored), Some(tracing::Level::WARN));
+        assert_eq!(
+            line_level("2024-05-01T10:00:00.000000Z  INFO vproxy::serve: listening"),
+            Some(tracing::Level::INFO)
+        );
+        assert_eq!(
+            line_level("thread 'main' panicked at src/main.rs:1:1"),
+            None
+        );
+    }
 }
@@ -0,0 +1,91 @@
+//! Debugging utilities behind the `debug` subcommand.
+
+use crate::{
+    connect::{assign_ipv4_from_extension, assign_ipv6_from_extension},
+    extension::{parser, Extension},
+};
+use cidr::{Ipv4Cidr, Ipv6Cidr};
+use std::{hint::black_box, time::Instant};
+
+/// Runs micro benchmarks of the per-request hot paths: extension parsing and
+/// IP assignment.
+///
+/// Each benchmark is warmed up once, then timed over `samples` batches of
+/// `iterations` calls. The mean, median, minimum and maximum time per call
+/// are printed, so results can be compared across releases on the same host.
+pub fn micro_bench(iterations: u32, samples: u32) -> crate::Result<()> {
+    let iterations = iterations.max(1);
+    let samples = samples.max(1);
+    let v4 = "10.0.0.0/8".parse::<Ipv4Cidr>()?;
+    let v6 = "2001:db8::/32".parse::<Ipv6Cidr>()?;
+    let session = Extension::session(fxhash::hash64("session-id"));
+    let range = Extension::range(fxhash::hash64("range-id"));
+
+    println!(
+        "{} iterations x {} samples (ns/iter)\n",
+        iterations, samples
+    );
+    println!(
+        "{:<32} {:>10} {:>10} {:>10} {:>10}",
+        "benchmark", "mean", "median", "min", "max"
+    );
+
+    bench("parse extension (none)", iterations, samples, || {
+        parser("user".to_owned(), "user".to_owned())
+    });
+    bench("parse extension (session)", iterations, samples, || {
+        parser("user".to_owned(), "user-session-123456".to_owned())
+    });
+    bench("parse extension (ttl)", iterations, samples, || {
+        parser("user".to_owned(), "user-ttl-60".to_owned())
+    });
+    bench("parse extension (range)", iterations, samples, || {
+        parser("user".to_owned(), "user-range-123456".to_owned())
+    });
+    bench("assign ipv4 (random)", iterations, samples, || {
+        assign_ipv4_from_extension(v4, None, Extension::default())
+    });
+    bench("assign ipv4 (session)", iterations, samples, || {
+        assign_ipv4_from_extension(v4, None, session)
+    });
+    bench("assign ipv6 (random)", iterations, samples, || {
+        assign_ipv6_from_extension(v6, None, Extension::default())
+    });
+    bench("assign ipv6 (session)", iterations, samples, || {
+        assign_ipv6_from_extension(v6, None, session)
+    });
+    bench("assign ipv6 (range /64)", iterations, samples, || {
+        assign_ipv6_from_extension(v6, Some(64), range)
+    });
+
+    Ok(())
+}
+
+/// Times `f` and prints one line of statistics.
+fn bench<T>(name: &str, iterations: u32, samples: u32, mut f: impl FnMut() -> T) {
+    // Warm up caches and the branch predictor before measuring.
+    for _ in 0..iterations {
+        black_box(f());
+    }
+
+    let mut per_iter = (0..samples)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..iterations {
+                black_box(f());
+            }
+            start.elapsed().as_nanos() as f64 / iterations as f64
+        })
+        .collect::<Vec<_>>();
+    per_iter.sort_by(f64::total_cmp);
+
+    let mean = per_iter.iter().sum::<f64>() / per_iter.len() as f64;
+    let median = per_iter[per_iter.len() / 2];
+    let min = per_iter[0];
+    let max = per_iter[per_iter.len() - 1];
+
+    println!(
+        "{:<32} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+        name, mean, median, min, max
+    );
+}
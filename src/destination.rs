@@ -0,0 +1,201 @@
+//! Protection against proxying into the operator's own network.
+//!
+//! Unless disabled, destinations in private, loopback, link-local and other
+//! special purpose ranges are refused, which covers the cloud metadata
+//! endpoints. Host names are checked before they are resolved, so literal
+//! addresses and well known local names never reach the resolver, and every
+//! resolved address is checked again before connecting, so public names
+//! pointing at private addresses are refused as well.
+
+use cidr::IpCidr;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::LazyLock,
+};
+
+/// Ranges refused unless allowed explicitly.
+static BLOCKED: LazyLock<Vec<IpCidr>> = LazyLock::new(|| {
+    [
+        // IPv4 "this network", private, shared, loopback, link-local
+        // (including metadata services), protocol assignments, benchmarking,
+        // multicast and reserved
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/4",
+        "240.0.0.0/4",
+        // IPv6 unspecified, loopback, NAT64 with local addresses, unique
+        // local (including metadata services), link-local and multicast
+        "::/128",
+        "::1/128",
+        "64:ff9b:1::/48",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|cidr| cidr.parse().expect("valid blocked range"))
+    .collect()
+});
+
+/// Names that resolve to the local host or a metadata service.
+const BLOCKED_NAMES: &[&str] = &["localhost", "metadata", "metadata.google.internal"];
+
+/// Refuses destinations in internal ranges.
+#[derive(Debug, Default)]
+pub struct DestinationGuard {
+    /// Ranges reachable despite being internal.
+    allow: Vec<IpCidr>,
+}
+
+impl DestinationGuard {
+    /// Create a guard letting destinations inside `allow` through.
+    pub fn new(allow: Vec<IpCidr>) -> Self {
+        Self { allow }
+    }
+
+    /// Checks a destination host before it is resolved.
+    pub fn check_host(&self, host: &str) -> io::Result<()> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.check_ip(ip);
+        }
+
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        if BLOCKED_NAMES.contains(&name.as_str()) || name.ends_with(".localhost") {
+            return Err(denied(host));
+        }
+        Ok(())
+    }
+
+    /// Checks a resolved destination address.
+    pub fn check_addr(&self, addr: SocketAddr) -> io::Result<()> {
+        self.check_ip(addr.ip())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> io::Result<()> {
+        let ip = ip.to_canonical();
+        if is_blocked(ip) && !self.allow.iter().any(|cidr| cidr.contains(&ip)) {
+            return Err(denied(ip));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `ip` lies in one of the internal ranges.
+fn is_blocked(ip: IpAddr) -> bool {
+    BLOCKED.iter().any(|cidr| cidr.contains(&ip))
+}
+
+/// Normalizes a destination host before it is matched against rules and
+/// resolved, so the same name cannot be spelled in ways that slip past them.
+///
+/// Percent-encoded bytes are decoded, internationalized names are converted to
+/// punycode, case and compatibility forms are folded and trailing dots are
+/// removed. Names with whitespace, control characters or other code points
+/// not allowed in a domain are refused. Address literals are left as they are.
+pub(crate) fn normalize_host(host: &str) -> io::Result<String> {
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return Ok(host.to_owned());
+    }
+
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid destination host {:?}", host),
+        )
+    };
+    let decoded = percent_decode(host).ok_or_else(invalid)?;
+    if decoded
+        .iter()
+        .any(|byte| byte.is_ascii_whitespace() || byte.is_ascii_control())
+    {
+        return Err(invalid());
+    }
+
+    let name =
+        idna::domain_to_ascii_cow(&decoded, idna::AsciiDenyList::URL).map_err(|_| invalid())?;
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return Err(invalid());
+    }
+    Ok(name.to_owned())
+}
+
+/// Decodes the `%XX` escapes of `value`, or returns `None` for a malformed
+/// escape.
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    Some(decoded)
+}
+
+fn denied(destination: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("destination {destination} is in a private range"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("Example.COM.").unwrap(), "example.com");
+        assert_eq!(
+            normalize_host("bücher.example").unwrap(),
+            "xn--bcher-kva.example"
+        );
+        assert_eq!(normalize_host("ｅｘａｍｐｌｅ.com").unwrap(), "example.com");
+        assert_eq!(normalize_host("ex%61mple.com").unwrap(), "example.com");
+        assert_eq!(normalize_host("[::1]").unwrap(), "[::1]");
+        assert_eq!(normalize_host("192.0.2.1").unwrap(), "192.0.2.1");
+
+        assert!(normalize_host("exa mple.com").is_err());
+        assert!(normalize_host("example.com%00").is_err());
+        assert!(normalize_host("example.com\t").is_err());
+        assert!(normalize_host("ex%6").is_err());
+        assert!(normalize_host("...").is_err());
+    }
+
+    #[test]
+    fn test_guard() {
+        let guard = DestinationGuard::new(vec!["10.1.0.0/16".parse().unwrap()]);
+
+        assert!(guard.check_host("example.com").is_ok());
+        assert!(guard.check_host("93.184.215.14").is_ok());
+        assert!(guard.check_host("[2606:2800:21f:cb07::1]").is_ok());
+        assert!(guard.check_host("10.1.2.3").is_ok());
+
+        assert!(guard.check_host("localhost").is_err());
+        assert!(guard.check_host("app.localhost.").is_err());
+        assert!(guard.check_host("Metadata.Google.Internal").is_err());
+        assert!(guard.check_host("169.254.169.254").is_err());
+        assert!(guard.check_host("[::1]").is_err());
+        assert!(guard.check_host("192.168.1.1").is_err());
+        assert!(guard
+            .check_addr("[::ffff:127.0.0.1]:80".parse().unwrap())
+            .is_err());
+        assert!(guard
+            .check_addr("[fd00:ec2::254]:80".parse().unwrap())
+            .is_err());
+    }
+}
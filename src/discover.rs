@@ -0,0 +1,158 @@
+//! Discovery of the egress CIDR behind `--cidr auto[:iface]`.
+//!
+//! The global IPv6 addresses of the host, or of the given interface, are read
+//! over netlink and the shortest prefix they are configured with becomes the
+//! CIDR, e.g. `2001:db8:1::/48` for an address `2001:db8:1::1/48` on the
+//! uplink. Link-local, unique local, temporary, deprecated and tentative
+//! addresses are skipped, as are single addresses (`/128`).
+
+use cidr::IpCidr;
+
+/// Parses the value of `--cidr`: a CIDR, or `auto` and `auto:IFACE` for the
+/// prefix discovered on the host or on the interface.
+pub fn parse_cidr(value: &str) -> Result<IpCidr, String> {
+    let interface = match value.split_once(':') {
+        Some(("auto", interface)) if !interface.is_empty() => Some(interface),
+        _ if value == "auto" => None,
+        _ => return value.parse().map_err(|err| format!("{err}")),
+    };
+    discover(interface)
+}
+
+/// Returns the shortest global IPv6 prefix of `interface`, or of any
+/// interface.
+#[cfg(all(target_os = "linux", feature = "route"))]
+fn discover(interface: Option<&str>) -> Result<IpCidr, String> {
+    // Arguments may be parsed within a runtime, which cannot be blocked on
+    let interface = interface.map(ToOwned::to_owned);
+    std::thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| err.to_string())?
+            .block_on(netlink::discover(interface.as_deref()))
+    })
+    .join()
+    .map_err(|_| "CIDR discovery panicked".to_owned())?
+}
+
+#[cfg(not(all(target_os = "linux", feature = "route")))]
+fn discover(_interface: Option<&str>) -> Result<IpCidr, String> {
+    Err("CIDR discovery is only supported on Linux".to_owned())
+}
+
+/// Whether `ip` is a global unicast address, i.e. in 2000::/3.
+#[cfg_attr(not(all(target_os = "linux", feature = "route")), allow(dead_code))]
+fn is_global(ip: std::net::Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+/// Returns the prefix of the shortest of `prefixes`, the first one on a tie.
+#[cfg_attr(not(all(target_os = "linux", feature = "route")), allow(dead_code))]
+fn shortest(prefixes: impl IntoIterator<Item = (std::net::Ipv6Addr, u8)>) -> Option<IpCidr> {
+    prefixes
+        .into_iter()
+        .filter(|(ip, len)| is_global(*ip) && *len < 128)
+        .min_by_key(|(_, len)| *len)
+        .and_then(|(ip, len)| cidr::Ipv6Inet::new(ip, len).ok())
+        .map(|inet| IpCidr::V6(inet.network()))
+}
+
+#[cfg(all(target_os = "linux", feature = "route"))]
+mod netlink {
+    use super::shortest;
+    use cidr::IpCidr;
+    use futures::TryStreamExt;
+    use netlink_packet_route::{
+        address::{AddressAttribute, AddressFlag, AddressScope},
+        AddressFamily,
+    };
+    use rtnetlink::new_connection;
+    use std::net::IpAddr;
+
+    pub(super) async fn discover(interface: Option<&str>) -> Result<IpCidr, String> {
+        let (connection, handle, _) =
+            new_connection().map_err(|err| format!("cannot open a netlink connection: {err}"))?;
+        tokio::spawn(connection);
+
+        let mut request = handle.address().get();
+        if let Some(interface) = interface {
+            let link = handle
+                .link()
+                .get()
+                .match_name(interface.to_owned())
+                .execute()
+                .try_next()
+                .await
+                .map_err(|err| format!("no interface named {interface}: {err}"))?
+                .ok_or_else(|| format!("no interface named {interface}"))?;
+            request = request.set_link_index_filter(link.header.index);
+        }
+
+        let mut prefixes = Vec::new();
+        let mut addresses = request.execute();
+        while let Some(address) = addresses
+            .try_next()
+            .await
+            .map_err(|err| format!("cannot list addresses: {err}"))?
+        {
+            if address.header.family != AddressFamily::Inet6
+                || address.header.scope != AddressScope::Universe
+            {
+                continue;
+            }
+            let mut ip = None;
+            let mut usable = true;
+            for attribute in &address.attributes {
+                match attribute {
+                    AddressAttribute::Address(IpAddr::V6(address)) => ip = Some(*address),
+                    AddressAttribute::Flags(flags) => {
+                        // Temporary IPv6 addresses carry IFA_F_SECONDARY
+                        usable = !flags.iter().any(|flag| {
+                            matches!(
+                                flag,
+                                AddressFlag::Secondary
+                                    | AddressFlag::Deprecated
+                                    | AddressFlag::Tentative
+                                    | AddressFlag::Dadfailed
+                            )
+                        })
+                    }
+                    _ => {}
+                }
+            }
+            if let (Some(ip), true) = (ip, usable) {
+                prefixes.push((ip, address.header.prefix_len));
+            }
+        }
+
+        shortest(prefixes).ok_or_else(|| match interface {
+            Some(interface) => format!("no global IPv6 prefix on {interface}"),
+            None => "no global IPv6 prefix on the host".to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shortest() {
+        let prefixes = [
+            ("fe80::1".parse().unwrap(), 64),
+            ("fd00::1".parse().unwrap(), 48),
+            ("2001:db8:1::1".parse().unwrap(), 64),
+            ("2001:db8:2::1".parse().unwrap(), 128),
+            ("2001:db8:3::1".parse().unwrap(), 56),
+        ];
+        assert_eq!(shortest(prefixes), Some("2001:db8:3::/56".parse().unwrap()));
+        assert_eq!(shortest([("fe80::1".parse().unwrap(), 64)]), None);
+
+        assert_eq!(
+            parse_cidr("2001:db8::/32"),
+            Ok("2001:db8::/32".parse().unwrap())
+        );
+        assert!(parse_cidr("2001:db8::1/32").is_err());
+    }
+}
@@ -0,0 +1,492 @@
+//! Name resolution and prefetching of frequently used domains.
+//!
+//! Names are resolved asynchronously by a process-wide hickory resolver, so
+//! lookups do not take a blocking thread each. It uses the nameservers,
+//! search domains and options of `/etc/resolv.conf` unless `--dns-server` and
+//! `--dns-ndots` say otherwise, and queries A and AAAA records concurrently.
+//!
+//! Domains configured for prefetching are resolved in the background and kept
+//! in a [`DnsCache`], so the first client request to them does not wait for
+//! the resolver. Every entry is refreshed at a random point before it expires,
+//! which spreads the lookups of many domains over time.
+//!
+//! Hosts of a `--hosts` file are answered from the file instead, without
+//! ever asking the resolver.
+
+use crate::{destination::DestinationGuard, ResolvePolicy, ResolverOptions};
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+use hyper_util::client::legacy::connect::dns::Name;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, OnceLock, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tower_service::Service;
+
+/// Resolver of the process, set once on startup.
+static RESOLVER: OnceLock<TokioAsyncResolver> = OnceLock::new();
+
+/// Port of nameservers given without one.
+const DNS_PORT: u16 = 53;
+
+/// Resolved addresses of prefetched domains and static hosts.
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    entries: RwLock<HashMap<String, Entry>>,
+    hosts: RwLock<HashMap<String, Arc<[IpAddr]>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    addrs: Arc<[IpAddr]>,
+    expires: Instant,
+}
+
+impl DnsCache {
+    /// Returns the static addresses of `host`, or its cached addresses
+    /// unless they have expired.
+    pub fn get(&self, host: &str) -> Option<Arc<[IpAddr]>> {
+        let host = host.to_ascii_lowercase();
+        if let Some(addrs) = self.hosts.read().ok()?.get(&host) {
+            return Some(addrs.clone());
+        }
+        let entries = self.entries.read().ok()?;
+        let entry = entries.get(&host)?;
+        (entry.expires > Instant::now()).then(|| entry.addrs.clone())
+    }
+
+    /// Replaces the static hosts, which never expire and take precedence
+    /// over resolved addresses.
+    pub fn set_hosts(&self, hosts: HashMap<String, Vec<IpAddr>>) {
+        if let Ok(mut entries) = self.hosts.write() {
+            *entries = hosts
+                .into_iter()
+                .map(|(host, addrs)| (host, addrs.into()))
+                .collect();
+        }
+    }
+
+    /// Caches `addrs` for `host` for the given time to live.
+    pub fn insert(&self, host: &str, addrs: Vec<IpAddr>, ttl: Duration) {
+        if let Ok(mut entries) = self.entries.write() {
+            entries.insert(
+                host.to_ascii_lowercase(),
+                Entry {
+                    addrs: addrs.into(),
+                    expires: Instant::now() + ttl,
+                },
+            );
+        }
+    }
+
+    /// Returns the cached socket addresses of `host` and `port`.
+    pub fn cached(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        self.get(host)
+            .map(|addrs| addrs.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+
+    /// Resolves `host` and `port`, answering from the cache when possible.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match self.cached(host, port) {
+            Some(addrs) => Ok(addrs),
+            None => resolve(host, port).await,
+        }
+    }
+}
+
+/// Sets up the resolver of the process from `options`, querying only the
+/// address families `policy` connects to. Later calls keep the first resolver.
+pub(crate) fn init(options: &ResolverOptions, policy: Option<ResolvePolicy>) -> io::Result<()> {
+    if RESOLVER.get().is_some() {
+        return Ok(());
+    }
+    let (config, mut opts) = system_config();
+    let config = if options.dns_servers.is_empty() {
+        config
+    } else {
+        tracing::info!("Resolving names with {:?}", options.dns_servers);
+        let name_servers = options
+            .dns_servers
+            .iter()
+            .flat_map(|addr| {
+                [Protocol::Udp, Protocol::Tcp]
+                    .map(|protocol| NameServerConfig::new(*addr, protocol))
+            })
+            .collect::<Vec<_>>();
+        ResolverConfig::from_parts(
+            config.domain().cloned(),
+            config.search().to_vec(),
+            name_servers,
+        )
+    };
+    if let Some(ndots) = options.dns_ndots {
+        opts.ndots = ndots;
+    }
+    if let Some(timeout) = options.dns_timeout {
+        opts.timeout = Duration::from_secs(timeout.max(1));
+    }
+    opts.ip_strategy = match policy {
+        Some(ResolvePolicy::Ipv4Only) => LookupIpStrategy::Ipv4Only,
+        Some(ResolvePolicy::Ipv6Only) => LookupIpStrategy::Ipv6Only,
+        _ => LookupIpStrategy::Ipv4AndIpv6,
+    };
+    let _ = RESOLVER.set(TokioAsyncResolver::tokio(config, opts));
+    Ok(())
+}
+
+/// Returns the configuration of `/etc/resolv.conf`, or the defaults when it
+/// cannot be read.
+fn system_config() -> (ResolverConfig, ResolverOpts) {
+    match hickory_resolver::system_conf::read_system_conf() {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::warn!("Cannot read the system resolver configuration: {}", err);
+            (ResolverConfig::default(), ResolverOpts::default())
+        }
+    }
+}
+
+/// Returns the resolver of the process, set up from the system configuration
+/// if [`init`] was not called.
+fn resolver() -> &'static TokioAsyncResolver {
+    RESOLVER.get_or_init(|| {
+        let (config, mut opts) = system_config();
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        TokioAsyncResolver::tokio(config, opts)
+    })
+}
+
+/// Resolves `host` and `port`, recording how long it took when metrics are
+/// enabled. IPv6 addresses may be enclosed in brackets.
+pub(crate) async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    #[cfg(feature = "metrics")]
+    let started = std::time::Instant::now();
+    let addrs = resolver()
+        .lookup_ip(host)
+        .await
+        .map_err(|err| lookup_error(host, err))?
+        .iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect::<Vec<_>>();
+    #[cfg(feature = "metrics")]
+    crate::metrics::observe_dns(&addrs, started.elapsed());
+    Ok(addrs)
+}
+
+/// Resolves a `host:port` target.
+pub(crate) async fn resolve_authority(target: &str) -> io::Result<Vec<SocketAddr>> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{target}: invalid socket address"),
+            )
+        })?;
+    resolve(host, port).await
+}
+
+/// Converts the failed lookup of `host` into an I/O error.
+fn lookup_error(host: &str, err: ResolveError) -> io::Error {
+    let kind = match err.kind() {
+        ResolveErrorKind::NoRecordsFound { .. } => io::ErrorKind::NotFound,
+        ResolveErrorKind::Timeout => io::ErrorKind::TimedOut,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{host}: {err}"))
+}
+
+/// Parses a nameserver address, port 53 being used when it has none.
+pub fn parse_nameserver(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse::<SocketAddr>()
+        .or_else(|_| {
+            value
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        })
+        .map_err(|_| format!("{value}: not an address or address:port"))
+}
+
+/// Keeps the resolved `addrs` of `host` that `policy` allows, in the order it
+/// prefers, failing when none is left.
+pub(crate) fn apply_policy(
+    policy: Option<ResolvePolicy>,
+    host: &str,
+    mut addrs: Vec<SocketAddr>,
+) -> io::Result<Vec<SocketAddr>> {
+    let Some(policy) = policy else {
+        return Ok(addrs);
+    };
+    match policy {
+        ResolvePolicy::Ipv4Only => addrs.retain(SocketAddr::is_ipv4),
+        ResolvePolicy::Ipv6Only => addrs.retain(SocketAddr::is_ipv6),
+        // Stable, so the resolver order is kept within a family
+        ResolvePolicy::PreferIpv4 => addrs.sort_by_key(SocketAddr::is_ipv6),
+        ResolvePolicy::PreferIpv6 => addrs.sort_by_key(SocketAddr::is_ipv4),
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{host} has no address allowed by the resolve policy"),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Keeps `domains` resolved in `cache`.
+///
+/// Every entry lives for `ttl`, whatever the TTL of its records, and is
+/// refreshed after a random 70 to 90 percent of it.
+pub async fn prefetch(cache: Arc<DnsCache>, domains: Vec<String>, ttl: Duration) {
+    tracing::info!("Prefetching {} domains every {:?}", domains.len(), ttl);
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for domain in domains {
+        tasks.spawn(refresh(cache.clone(), domain, ttl));
+    }
+    while tasks.join_next().await.is_some() {}
+}
+
+/// Resolves `domain` into `cache` before each expiry, forever.
+async fn refresh(cache: Arc<DnsCache>, domain: String, ttl: Duration) {
+    loop {
+        match resolve(&domain, 0).await {
+            Ok(addrs) => {
+                let addrs = addrs.iter().map(|addr| addr.ip()).collect::<Vec<_>>();
+                tracing::debug!("Prefetched {}: {:?}", domain, addrs);
+                cache.insert(&domain, addrs, ttl);
+            }
+            Err(err) => tracing::debug!("Prefetch of {} failed: {}", domain, err),
+        }
+
+        tokio::time::sleep(jittered(ttl)).await;
+    }
+}
+
+/// Loads a hosts file in the `/etc/hosts` format: an address followed by
+/// its names on each line, `#` starting a comment.
+///
+/// Names listed on several lines resolve to all of their addresses.
+pub fn load_hosts(path: &Path) -> io::Result<HashMap<String, Vec<IpAddr>>> {
+    let content = std::fs::read_to_string(path)?;
+    parse_hosts(&content).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), err),
+        )
+    })
+}
+
+/// Parses the content of a hosts file.
+fn parse_hosts(content: &str) -> Result<HashMap<String, Vec<IpAddr>>, String> {
+    let mut hosts = HashMap::<String, Vec<IpAddr>>::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(addr) = fields.next() else {
+            continue;
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("line {}: {}: {}", number + 1, addr, err))?;
+        let mut names = fields.peekable();
+        if names.peek().is_none() {
+            return Err(format!("line {}: no host name for {}", number + 1, addr));
+        }
+        for name in names {
+            let addrs = hosts.entry(name.to_ascii_lowercase()).or_default();
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Returns a random duration between 70 and 90 percent of `ttl`.
+fn jittered(ttl: Duration) -> Duration {
+    ttl.mul_f64(rand::rng().random_range(0.7..0.9))
+        .max(Duration::from_secs(1))
+}
+
+/// Resolver for the HTTP client, answering prefetched domains from the cache
+/// and everything else through the resolver of the process.
+///
+/// Addresses refused by the destination guard are dropped, so a name
+/// resolving to internal addresses only fails to resolve.
+#[derive(Clone)]
+pub struct CachingResolver {
+    cache: Arc<DnsCache>,
+    guard: Option<Arc<DestinationGuard>>,
+    policy: Option<ResolvePolicy>,
+}
+
+impl CachingResolver {
+    /// Create a resolver backed by `cache`, dropping the addresses `guard`
+    /// refuses and those of the families `policy` excludes.
+    pub fn new(
+        cache: Arc<DnsCache>,
+        guard: Option<Arc<DestinationGuard>>,
+        policy: Option<ResolvePolicy>,
+    ) -> Self {
+        Self {
+            cache,
+            guard,
+            policy,
+        }
+    }
+}
+
+impl Service<Name> for CachingResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let guard = self.guard.clone();
+        let policy = self.policy;
+        let host = name.as_str().to_owned();
+        let cached = self.cache.cached(name.as_str(), 0);
+        Box::pin(async move {
+            let addrs = match cached {
+                Some(addrs) => addrs,
+                None => resolve(&host, 0).await?,
+            };
+            let addrs = apply_policy(policy, &host, addrs)?;
+            let Some(guard) = guard else {
+                return Ok(addrs.into_iter());
+            };
+
+            let mut last_err = None;
+            let allowed = addrs
+                .into_iter()
+                .filter(|addr| match guard.check_addr(*addr) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        last_err = Some(err);
+                        false
+                    }
+                })
+                .collect::<Vec<_>>();
+            match (allowed.is_empty(), last_err) {
+                (true, Some(err)) => Err(err),
+                _ => Ok(allowed.into_iter()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_expiry() {
+        let cache = DnsCache::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        cache.insert("Example.com", vec![ip], Duration::from_secs(60));
+        assert_eq!(cache.get("example.com").as_deref(), Some(&[ip][..]));
+
+        cache.insert("expired.example", vec![ip], Duration::ZERO);
+        assert!(cache.get("expired.example").is_none());
+    }
+
+    #[test]
+    fn test_parse_nameserver() {
+        assert_eq!(
+            parse_nameserver("1.1.1.1"),
+            Ok("1.1.1.1:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_nameserver("[2606:4700::1111]:5353"),
+            Ok("[2606:4700::1111]:5353".parse().unwrap())
+        );
+        assert_eq!(
+            parse_nameserver("2606:4700::1111"),
+            Ok("[2606:4700::1111]:53".parse().unwrap())
+        );
+        assert!(parse_nameserver("dns.example").is_err());
+    }
+
+    #[test]
+    fn test_hosts() {
+        let hosts = parse_hosts(
+            "# split horizon\n\
+             10.0.0.5 git.internal.corp git\n\
+             fd00::5  Git.internal.corp # dual stack\n",
+        )
+        .unwrap();
+        let cache = DnsCache::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        cache.insert("git", vec![ip], Duration::from_secs(60));
+        cache.set_hosts(hosts);
+
+        assert_eq!(
+            cache.cached("git.internal.corp", 443),
+            Some(vec![
+                "10.0.0.5:443".parse().unwrap(),
+                "[fd00::5]:443".parse().unwrap()
+            ])
+        );
+        assert_eq!(
+            cache.get("git").as_deref(),
+            Some(&["10.0.0.5".parse().unwrap()][..])
+        );
+
+        assert!(parse_hosts("10.0.0.300 bad").is_err());
+        assert!(parse_hosts("10.0.0.5").is_err());
+    }
+
+    #[test]
+    fn test_apply_policy() {
+        let addrs: Vec<SocketAddr> = vec![
+            "192.0.2.1:443".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+            "192.0.2.2:443".parse().unwrap(),
+        ];
+        let policy = |policy| apply_policy(Some(policy), "example.com", addrs.clone());
+
+        assert_eq!(
+            apply_policy(None, "example.com", addrs.clone()).unwrap(),
+            addrs
+        );
+        assert_eq!(
+            policy(ResolvePolicy::PreferIpv6).unwrap(),
+            [addrs[1], addrs[0], addrs[2]]
+        );
+        assert_eq!(
+            policy(ResolvePolicy::PreferIpv4).unwrap(),
+            [addrs[0], addrs[2], addrs[1]]
+        );
+        assert_eq!(policy(ResolvePolicy::Ipv6Only).unwrap(), [addrs[1]]);
+        assert!(
+            apply_policy(Some(ResolvePolicy::Ipv6Only), "example.com", vec![addrs[0]]).is_err()
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Egress addresses banned at runtime.
+//!
+//! Addresses or prefixes a target has blocked can be banned through the
+//! admin API. Address assignment re-rolls addresses inside a banned prefix,
+//! as it does for `--exclude-cidr`, and a banned fallback address is not
+//! used. With `--egress-ban-file` the bans are saved, one IP-CIDR per line,
+//! and loaded again on the next start.
+
+use crate::cidr_file;
+use cidr::IpCidr;
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+/// Banned egress prefixes.
+#[derive(Debug, Default)]
+pub(crate) struct EgressBans {
+    banned: RwLock<Vec<IpCidr>>,
+    /// File the bans are saved to.
+    path: Option<PathBuf>,
+    /// Held while a change is saved, so changes are saved one at a time.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl EgressBans {
+    /// Loads the bans saved in the file at `path`, which need not exist yet.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let banned = match std::fs::read_to_string(path) {
+            Ok(content) => cidr_file::parse(&content).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), err),
+                )
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            banned: RwLock::new(banned),
+            path: Some(path.to_owned()),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Number of banned prefixes.
+    pub(crate) fn len(&self) -> usize {
+        self.banned.read().map_or(0, |banned| banned.len())
+    }
+
+    /// Whether `ip` is inside a banned prefix.
+    pub(crate) fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned
+            .read()
+            .is_ok_and(|banned| banned.iter().any(|cidr| cidr.contains(&ip)))
+    }
+
+    /// Returns the banned prefixes, one per line.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) fn list(&self) -> String {
+        self.banned.read().map_or_else(
+            |_| String::new(),
+            |banned| banned.iter().map(|cidr| format!("{cidr}\n")).collect(),
+        )
+    }
+
+    /// Bans `cidr`, then saves the bans. Returns whether it was not banned
+    /// yet.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) async fn ban(&self, cidr: IpCidr) -> io::Result<bool> {
+        self.update(|banned| {
+            if banned.contains(&cidr) {
+                return false;
+            }
+            banned.push(cidr);
+            true
+        })
+        .await
+    }
+
+    /// Lifts the ban of `cidr`, then saves the bans. Returns whether it was
+    /// banned.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) async fn unban(&self, cidr: IpCidr) -> io::Result<bool> {
+        self.update(|banned| {
+            let len = banned.len();
+            banned.retain(|banned| *banned != cidr);
+            banned.len() != len
+        })
+        .await
+    }
+
+    /// Applies `change` to a copy of the bans and, if they changed, writes
+    /// them to the file, if any, before they take effect. The file is written
+    /// off the runtime without holding the lock address assignment reads.
+    async fn update<F>(&self, change: F) -> io::Result<bool>
+    where
+        F: FnOnce(&mut Vec<IpCidr>) -> bool,
+    {
+        let _saving = self.saving.lock().await;
+        let mut banned = self
+            .banned
+            .read()
+            .map_err(|_| io::Error::other("egress bans lock poisoned"))?
+            .clone();
+        if !change(&mut banned) {
+            return Ok(false);
+        }
+
+        if let Some(path) = self.path.clone() {
+            let content = banned
+                .iter()
+                .map(|cidr| format!("{cidr}\n"))
+                .collect::<String>();
+            tokio::task::spawn_blocking(move || crate::file::replace(&path, content.as_bytes()))
+                .await
+                .map_err(io::Error::other)??;
+        }
+
+        *self
+            .banned
+            .write()
+            .map_err(|_| io::Error::other("egress bans lock poisoned"))? = banned;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ban_and_unban() {
+        let bans = EgressBans::default();
+        let prefix = "2001:db8:1::/64".parse().unwrap();
+        assert!(bans.ban(prefix).await.unwrap());
+        assert!(!bans.ban(prefix).await.unwrap());
+        assert!(bans.is_banned("2001:db8:1::42".parse().unwrap()));
+        assert!(!bans.is_banned("2001:db8:2::42".parse().unwrap()));
+        assert_eq!(bans.list(), "2001:db8:1::/64\n");
+
+        assert!(bans.unban(prefix).await.unwrap());
+        assert!(!bans.unban(prefix).await.unwrap());
+        assert!(!bans.is_banned("2001:db8:1::42".parse().unwrap()));
+
+        let path = std::env::temp_dir().join(format!("vproxy-egress-bans-{}", std::process::id()));
+        let bans = EgressBans::load(&path).unwrap();
+        assert!(bans.ban(prefix).await.unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "2001:db8:1::/64\n");
+        assert_eq!(EgressBans::load(&path).unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(cidr_file::parse("# bans\n192.0.2.7\n").unwrap().len(), 1);
+        assert!(cidr_file::parse("192.0.2.7/33").is_err());
+    }
+}
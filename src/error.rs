@@ -19,6 +19,7 @@ pub enum Error {
     #[error(transparent)]
     Nix(#[from] nix::Error),
 
+    #[cfg(feature = "https")]
     #[error(transparent)]
     Rcgen(#[from] rcgen::Error),
 
@@ -1,20 +1,96 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
-/// Enum representing different types of extensions.
-#[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, Debug, Default)]
-pub enum Extension {
-    #[default]
-    None,
-    TTL(u64),
-    Range(u64),
-    Session(u64),
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Time after which an invalidated session that is not used any more is
+/// forgotten, moving it back to its original address should it come back.
+const INVALIDATION_IDLE: Duration = Duration::from_secs(24 * 3600);
+
+/// Egress selection requested through the suffix of a username, e.g.
+/// `alice-session-abc-range-eu`.
+///
+/// Extensions combine. A session or a TTL pins the egress address, and both
+/// together pin it per session for one TTL window. A range picks the
+/// `--cidr-range` subnet the address is taken from; with a session or TTL the
+/// address inside that subnet is pinned as well, otherwise it is random.
+/// An upstream picks the upstream proxies of that name, e.g.
+/// `alice-upstream-provider-a`, independently of the address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Extension {
+    /// Hash of the session ID.
+    pub session: Option<u64>,
+    /// Hash of the current TTL window.
+    pub ttl: Option<u64>,
+    /// Hash of the range ID.
+    pub range: Option<u64>,
+    /// Prefix length of the range subnet, overriding `--cidr-range`.
+    pub range_width: Option<u8>,
+    /// Hash of the name of the upstream proxies connections go through.
+    pub upstream: Option<u64>,
 }
 
 impl Extension {
     const EXTENSION_TTL: &'static str = "-ttl-";
     const EXTENSION_SESSION: &'static str = "-session-";
     const EXTENSION_RANGE_SESSION: &'static str = "-range-";
+    const EXTENSION_UPSTREAM: &'static str = "-upstream-";
+
+    const MARKERS: [&'static str; 4] = [
+        Self::EXTENSION_SESSION,
+        Self::EXTENSION_TTL,
+        Self::EXTENSION_RANGE_SESSION,
+        Self::EXTENSION_UPSTREAM,
+    ];
+
+    /// An extension pinning the address to the session hashed to `value`.
+    pub const fn session(value: u64) -> Self {
+        Self {
+            session: Some(value),
+            ttl: None,
+            range: None,
+            range_width: None,
+            upstream: None,
+        }
+    }
+
+    /// An extension pinning the address for the TTL window hashed to `value`.
+    pub const fn ttl(value: u64) -> Self {
+        Self {
+            session: None,
+            ttl: Some(value),
+            range: None,
+            range_width: None,
+            upstream: None,
+        }
+    }
+
+    /// An extension picking the subnet of the range hashed to `value`.
+    pub const fn range(value: u64) -> Self {
+        Self {
+            session: None,
+            ttl: None,
+            range: Some(value),
+            range_width: None,
+            upstream: None,
+        }
+    }
+
+    /// Whether no extension choosing the egress address was requested, an
+    /// upstream aside.
+    pub fn is_none(&self) -> bool {
+        self.session.is_none() && self.ttl.is_none() && self.range.is_none()
+    }
+
+    /// Returns the value pinning the egress address: the session, the TTL
+    /// window, or both combined.
+    pub fn sticky(&self) -> Option<u64> {
+        match (self.session, self.ttl) {
+            (Some(session), Some(ttl)) => Some(fxhash::hash64(&(session, ttl))),
+            (session, ttl) => session.or(ttl),
+        }
+    }
 
     #[inline]
     pub async fn try_from<O>(prefix: &str, full: O) -> crate::Result<Extension>
@@ -29,157 +105,320 @@ impl Extension {
     }
 }
 
-/// This function takes a tuple of two strings as input: a prefix (the username)
-/// and a string `full` (the username-session-id).
-#[inline]
-fn parser(prefix: String, full: String) -> Extension {
-    // If it does, remove the prefix from `s`.
-    if let Some(extracted_tag) = full.strip_prefix(&prefix) {
-        if let Some(extension) = parse_extension(
-            false,
-            &full,
-            Extension::EXTENSION_SESSION,
-            parse_session_extension,
-        ) {
-            return extension;
-        }
+/// Sessions moved to a new egress address by the admin API.
+///
+/// Each invalidation of a session bumps its generation, which is mixed into
+/// the session hash, so the session gets pinned to another address while
+/// other sessions keep theirs.
+///
+/// A session with a TTL gets a new address with its next TTL window anyway,
+/// so its invalidation expires with the window it was first used in. Other
+/// invalidations are forgotten after [`INVALIDATION_IDLE`] without use.
+#[derive(Debug, Default)]
+pub(crate) struct Invalidations {
+    generations: RwLock<HashMap<u64, Generation>>,
+}
+
+/// Invalidations of a session.
+#[derive(Debug)]
+struct Generation {
+    generation: u64,
+    /// TTL window the session was used in since its invalidation.
+    ttl: Option<u64>,
+    used: Instant,
+}
 
-        if let Some(extension) = parse_extension(
-            true,
-            extracted_tag,
-            Extension::EXTENSION_TTL,
-            parse_ttl_extension,
-        ) {
+impl Invalidations {
+    /// Moves the session hashed to `session` to a new address, returning how
+    /// many times it has been invalidated.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub(crate) fn invalidate(&self, session: u64) -> u64 {
+        let now = Instant::now();
+        let mut generations = self
+            .generations
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        generations.retain(|_, generation| {
+            now.saturating_duration_since(generation.used) < INVALIDATION_IDLE
+        });
+        let generation = generations.entry(session).or_insert(Generation {
+            generation: 0,
+            ttl: None,
+            used: now,
+        });
+        generation.generation += 1;
+        generation.used = now;
+        generation.generation
+    }
+
+    /// Returns `extension` with the session of an invalidated session
+    /// replaced by the one of its current generation.
+    pub(crate) fn apply(&self, mut extension: Extension) -> Extension {
+        let Some(session) = extension.session else {
+            return extension;
+        };
+        let invalidated = self
+            .generations
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .contains_key(&session);
+        if !invalidated {
             return extension;
         }
 
-        if let Some(extension) = parse_extension(
-            true,
-            extracted_tag,
-            Extension::EXTENSION_RANGE_SESSION,
-            parse_range_extension,
-        ) {
+        let mut generations = self
+            .generations
+            .write()
+            .unwrap_or_else(|err| err.into_inner());
+        let Some(generation) = generations.get_mut(&session) else {
             return extension;
+        };
+        if let Some(ttl) = extension.ttl {
+            if *generation.ttl.get_or_insert(ttl) != ttl {
+                // The TTL window moved the session to a new address already
+                generations.remove(&session);
+                return extension;
+            }
         }
+        generation.used = Instant::now();
+        extension.session = Some(fxhash::hash64(&(session, generation.generation)));
+        extension
     }
+}
 
-    // If the string `s` does not start with the prefix, or if the remaining string
-    // after removing the prefix and "-" is empty, return the `None` variant
-    // of `Extensions`.
-    Extension::None
+/// Returns the username of a login without its extension, e.g. `alice` for
+/// `alice-session-123`.
+pub(crate) fn base_username(full: &str) -> &str {
+    next_marker(full).map_or(full, |(index, _)| &full[..index])
 }
 
-/// Handles an extension string.
-///
-/// This function takes a string `s`, a prefix, and a handler function.
-/// If the string `s` starts with the given prefix, the function removes the
-/// prefix and applies the handler function to the remaining string.
-///
-/// The handler function should take a string and return an `Extensions` enum.
-///
-/// If the string `s` does not start with the prefix, the function returns
-/// `None`.
-///
-/// # Arguments
-///
-/// * `trim` - Whether to trim the string before checking the prefix.
-/// * `s` - The string to handle.
-/// * `prefix` - The prefix to check and remove from the string.
-/// * `handler` - The function to apply to the string after removing the prefix.
-///
-/// # Returns
+/// This function takes a tuple of two strings as input: a prefix (the username)
+/// and a string `full` (the username followed by its extensions).
 ///
-/// This function returns an `Option<Extensions>`. If the string starts with the
-/// prefix, it returns `Some(Extensions)`. Otherwise, it returns `None`.
-#[tracing::instrument(level = "trace", skip(handler))]
+/// Every `-session-`, `-ttl-`, `-range-` or `-upstream-` marker starts an
+/// extension whose value runs up to the next marker, so
+/// `user-session-a-b-ttl-60` has the session `a-b`. An extension given twice
+/// takes its last value.
 #[inline]
-fn parse_extension(
-    trim: bool,
-    s: &str,
-    prefix: &str,
-    handler: fn(&str) -> Extension,
-) -> Option<Extension> {
-    if !s.contains(prefix) {
-        return None;
+pub(crate) fn parser(prefix: String, full: String) -> Extension {
+    let mut extension = Extension::default();
+
+    // If the string `full` does not start with the prefix, return no extension.
+    let Some(mut rest) = full.strip_prefix(&prefix) else {
+        return extension;
+    };
+
+    while let Some((start, marker)) = next_marker(rest) {
+        let tail = &rest[start + marker.len()..];
+        let end = next_marker(tail).map_or(tail.len(), |(index, _)| index);
+        let value = &tail[..end];
+        rest = &tail[end..];
+
+        match marker {
+            Extension::EXTENSION_SESSION => {
+                extension.session = Some(parse_session_extension(&prefix, value));
+            }
+            Extension::EXTENSION_TTL => {
+                if let Some(ttl) = parse_ttl_extension(value, &full) {
+                    extension.ttl = Some(ttl);
+                }
+            }
+            Extension::EXTENSION_UPSTREAM => {
+                extension.upstream = Some(fxhash::hash64(value.as_bytes()));
+            }
+            _ => {
+                let (range, width) = parse_range_extension(value);
+                extension.range = Some(range);
+                extension.range_width = width;
+            }
+        }
     }
-    let s = trim.then(|| s.trim_start_matches(prefix)).unwrap_or(s);
-    let extension = handler(s);
+
     tracing::trace!("Extension: {:?}", extension);
-    Some(extension)
+    extension
+}
+
+/// Returns the position and the marker of the first extension in `s`.
+fn next_marker(s: &str) -> Option<(usize, &'static str)> {
+    Extension::MARKERS
+        .iter()
+        .filter_map(|marker| s.find(marker).map(|index| (index, *marker)))
+        .min_by_key(|(index, _)| *index)
 }
 
 /// Parses a Range extension string.
-/// This function takes a string `s` and attempts to parse it into a Range
-/// extension. The function uses the `murmurhash3_x64_128` function to generate
-/// a 128-bit hash from the string. The hash is then returned as a tuple `(a, b)`
-/// wrapped in the `Extensions::Range` variant.
-/// # Arguments
-/// * `s` - The string to parse.
-/// # Returns
-/// This function returns an `Extensions` enum.
-/// If the string is empty, it returns `Extensions::None`.
-/// If the string is not empty, it returns `Extensions::Range(a, b)`.
+///
+/// The range ID is hashed, and the hash picks the `--cidr-range` subnet. An
+/// ID ending in a prefix length, e.g. `foo/64`, picks a subnet of that width
+/// instead.
 #[inline(always)]
-fn parse_range_extension(s: &str) -> Extension {
-    let hash = fxhash::hash64(s.as_bytes());
-    Extension::Range(hash)
+fn parse_range_extension(s: &str) -> (u64, Option<u8>) {
+    let width = s
+        .rsplit_once('/')
+        .and_then(|(id, width)| Some((id, width.parse::<u8>().ok()?)))
+        .filter(|(_, width)| *width <= 128);
+    match width {
+        Some((id, width)) => (fxhash::hash64(id.as_bytes()), Some(width)),
+        None => (fxhash::hash64(s.as_bytes()), None),
+    }
 }
 
 /// Parses a session extension string.
 ///
-/// This function takes a string `s` and attempts to parse it into a session
-/// extension. If the string is not empty, it is considered as the session ID.
-///
-/// The function uses the `murmurhash3_x64_128` function to generate a 128-bit
-/// hash from the session ID. The hash is then returned as a tuple `(a, b)`
-/// wrapped in the `Extensions::Session` variant.
-///
-/// If the string is empty, the function returns `Extensions::None`.
-///
-/// # Arguments
-///
-/// * `s` - The string to parse.
-///
-/// # Returns
-///
-/// This function returns an `Extensions` enum. If the string is not empty, it
-/// will return a `Extensions::Session` variant containing a tuple `(a, b)`.
-/// Otherwise, it will return `Extensions::None`.
+/// The session ID is hashed together with the username, so the same ID of
+/// two users pins different addresses.
 #[inline(always)]
-fn parse_session_extension(s: &str) -> Extension {
-    let hash = fxhash::hash64(s.as_bytes());
-    Extension::Session(hash)
+fn parse_session_extension(prefix: &str, s: &str) -> u64 {
+    let session = format!("{prefix}{}{s}", Extension::EXTENSION_SESSION);
+    fxhash::hash64(session.as_bytes())
 }
 
 /// Parses a TTL (Time To Live) extension string.
 ///
-/// This function attempts to parse a given string `s` into a `u64` representing
-/// the TTL value. If successful, it returns an `Extensions::Session` variant
-/// with the parsed TTL value and a fixed value of `1`. If the string cannot be
-/// parsed into a `u64`, it returns `Extensions::None`.
-///
-/// # Arguments
-///
-/// * `s` - The string to parse as a TTL value.
+/// This function attempts to parse a given string `s` of the form
+/// `<secs>[j<jitter>][e<epoch>]`, e.g. `300`, `300j30` or `300j30e60`, into a
+/// window length in seconds. If successful, it returns a hash of the current
+/// window, which changes when the window ends. Values that are not a positive
+/// number of seconds are ignored.
 ///
-/// # Returns
-///
-/// Returns an `Extensions` enum variant. If parsing is successful, returns
-/// `Extensions::Session` with the TTL value and `1`. Otherwise, returns
-/// `Extensions::None`.
+/// Windows start `epoch` seconds after the multiples of their length, plus up
+/// to `jitter` seconds derived from the `full` username, so logins with
+/// different sessions do not all rotate at the same moment.
 #[inline(always)]
-fn parse_ttl_extension(s: &str) -> Extension {
-    if let Ok(ttl) = s.parse::<u64>() {
-        let start = SystemTime::now();
-        let timestamp = start
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_secs())
-            .unwrap_or(rand::random());
+fn parse_ttl_extension(s: &str, full: &str) -> Option<u64> {
+    let (ttl, mut rest) = split_number(s)?;
+    let (mut jitter, mut epoch) = (0, 0);
+    while let Some(kind) = rest.chars().next() {
+        let (value, tail) = split_number(&rest[kind.len_utf8()..])?;
+        match kind {
+            'j' => jitter = value,
+            'e' => epoch = value,
+            _ => return None,
+        }
+        rest = tail;
+    }
+    if ttl == 0 {
+        return None;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(rand::random());
+    // A jitter of u64::MAX has no upper bound and is ignored
+    let offset = match jitter.checked_add(1) {
+        Some(bound) => epoch.wrapping_add(fxhash::hash64(full.as_bytes()) % bound),
+        None => epoch,
+    };
+    let time = ttl_window(timestamp, ttl, offset);
+    Some(fxhash::hash64(&time.to_be_bytes()))
+}
+
+/// Returns the start of the window of `ttl` seconds containing `timestamp`,
+/// with window boundaries shifted by `offset` seconds.
+fn ttl_window(timestamp: u64, ttl: u64, offset: u64) -> u64 {
+    let offset = offset % ttl;
+    let shifted = timestamp.wrapping_sub(offset);
+    (shifted - (shifted % ttl)).wrapping_add(offset)
+}
+
+/// Splits the leading decimal number off `s`.
+fn split_number(s: &str) -> Option<(u64, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(full: &str) -> Extension {
+        parser("user".to_owned(), full.to_owned())
+    }
+
+    #[test]
+    fn test_parser() {
+        assert!(parse("user").is_none());
+        assert!(parse("other-session-1").is_none());
+        assert!(parse("user-ttl-0").is_none());
+
+        let session = parse("user-session-abc");
+        assert_eq!(
+            session,
+            Extension::session(fxhash::hash64("user-session-abc".as_bytes()))
+        );
+        assert_eq!(
+            parse("user-range-eu"),
+            Extension::range(fxhash::hash64(b"eu"))
+        );
+
+        let wide = parse("user-range-eu/56");
+        assert_eq!(wide.range, Some(fxhash::hash64(b"eu")));
+        assert_eq!(wide.range_width, Some(56));
+        assert_eq!(parse("user-range-eu/200").range_width, None);
+
+        let combined = parse("user-session-abc-range-");
+        assert_eq!(combined.session, session.session);
+        assert_eq!(combined.range, Some(fxhash::hash64(b"")));
+        assert_eq!(combined.sticky(), session.session);
+
+        let rotating = parse("user-ttl-300-session-abc");
+        assert_eq!(rotating.session, session.session);
+        assert!(rotating.ttl.is_some());
+        assert_ne!(rotating.sticky(), session.session);
+
+        let upstream = parse("user-upstream-provider-a-session-abc");
+        assert_eq!(upstream.upstream, Some(fxhash::hash64(b"provider-a")));
+        assert_eq!(upstream.session, session.session);
+        assert!(parse("user-upstream-provider-a").is_none());
+
+        assert_eq!(base_username("user-ttl-300-session-abc"), "user");
+        assert_eq!(base_username("user-upstream-provider-a"), "user");
+    }
+
+    #[test]
+    fn test_invalidations() {
+        let invalidations = Invalidations::default();
+        let session = parse("user-session-abc");
+        assert_eq!(invalidations.apply(session), session);
+
+        invalidations.invalidate(session.session.unwrap());
+        let moved = invalidations.apply(session);
+        assert_ne!(moved, session);
+        assert_eq!(invalidations.apply(session), moved);
+        assert_eq!(invalidations.invalidate(session.session.unwrap()), 2);
+        assert_ne!(invalidations.apply(session), moved);
+
+        let range = parse("user-range-eu");
+        assert_eq!(invalidations.apply(range), range);
+
+        // Expires with the TTL window the session was used in
+        let window = Extension {
+            ttl: Some(1),
+            ..session
+        };
+        invalidations.invalidate(session.session.unwrap());
+        assert_ne!(invalidations.apply(window), window);
+        let next = Extension {
+            ttl: Some(2),
+            ..session
+        };
+        assert_eq!(invalidations.apply(next), next);
+        assert_eq!(invalidations.apply(window), window);
+    }
 
-        let time = timestamp - (timestamp % ttl);
+    #[test]
+    fn test_ttl_jitter() {
+        assert!(parse("user-ttl-300j30e60").ttl.is_some());
+        assert!(parse("user-ttl-300x30").ttl.is_none());
+        assert!(parse("user-ttl-300j").ttl.is_none());
+        assert!(parse("user-ttl-j30").ttl.is_none());
+        assert!(parse(&format!("user-ttl-300j{}", u64::MAX)).ttl.is_some());
 
-        let hash = fxhash::hash64(&time.to_be_bytes());
-        return Extension::TTL(hash);
+        assert_eq!(ttl_window(1000, 300, 0), 900);
+        // Boundaries move from 900 and 1200 to 960 and 1260
+        assert_eq!(ttl_window(1000, 300, 60), 960);
+        assert_eq!(ttl_window(1259, 300, 60), 960);
+        assert_eq!(ttl_window(1260, 300, 60), 1260);
     }
-    Extension::None
 }
@@ -0,0 +1,26 @@
+//! Files the proxy writes back at runtime.
+
+use std::{io, path::Path};
+
+/// Replaces the file at `path` with `content` atomically, so a crash cannot
+/// leave it truncated, keeping the permissions of the original.
+///
+/// The content is first written to a copy next to it, only readable by the
+/// owner until it takes the place of the original, as these files may hold
+/// credentials.
+pub(crate) fn replace(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(&tmp)?, content)?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        std::fs::set_permissions(&tmp, metadata.permissions())?;
+    }
+    std::fs::rename(&tmp, path)
+}
@@ -0,0 +1,599 @@
+//! Raw TCP and UDP port forwarding.
+//!
+//! Every connection accepted on the listen address of a rule is relayed to
+//! the rule's fixed target through the [`Connector`], so the egress address
+//! comes from the CIDR like for proxied connections. Clients do not need to
+//! speak any proxy protocol and are not authenticated.
+//!
+//! UDP datagrams are relayed through an egress socket per client address, a
+//! NAT entry that is dropped once it has been idle for a while. The socket is
+//! connected to the target, resolved when the entry is created, so only
+//! answers of the target are sent back to the client from the listen address.
+//!
+//! TCP targets that speak the PROXY protocol can be told the address of the
+//! client with a version 2 header ahead of the relayed stream.
+
+use crate::{
+    connect::Connector,
+    extension::Extension,
+    hooks::{Decision, Protocol, ProxyRequest, SharedHooks, TunnelClose},
+    relay::{self, Progress},
+    serve::Serve,
+    stats::{self, ActiveConnection, ConnectionId, Tunnel, TunnelInfo},
+    ForwardOptions, TcpOptions,
+};
+use http::uri::Authority;
+use socket2::SockRef;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinSet,
+};
+use tracing::Instrument;
+
+/// A forwarding rule, `LISTEN=TARGET`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForwardRule {
+    /// Address connections are accepted on.
+    pub listen: SocketAddr,
+    /// Host and port connections are relayed to.
+    pub target: Authority,
+}
+
+impl FromStr for ForwardRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (listen, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected LISTEN=TARGET: {s}"))?;
+        let listen = listen
+            .parse()
+            .map_err(|err| format!("invalid listen address {listen}: {err}"))?;
+        let target = target
+            .parse::<Authority>()
+            .ok()
+            .filter(|target| target.port_u16().is_some())
+            .ok_or_else(|| format!("expected host:port target: {target}"))?;
+        Ok(Self { listen, target })
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.listen, self.target)
+    }
+}
+
+/// Largest datagram relayed.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Datagrams of a new UDP client kept while its egress socket is set up,
+/// later ones are dropped.
+const MAX_OPENING_DATAGRAMS: usize = 16;
+
+/// Forwarding server, one listener or UDP socket per rule.
+pub struct ForwardServer {
+    listeners: Vec<(TcpListener, Authority)>,
+    udp_sockets: Vec<(UdpSocket, Authority)>,
+    udp_idle_timeout: Duration,
+    proxy_protocol: bool,
+    concurrent: usize,
+    connector: Connector,
+    tcp: TcpOptions,
+    hooks: SharedHooks,
+    stats: stats::Listener,
+}
+
+impl ForwardServer {
+    /// Create a forwarding server listening on the addresses of the rules,
+    /// with a backlog of `concurrent` connections each and at most as many
+    /// UDP clients per rule.
+    pub fn new(
+        opts: ForwardOptions,
+        concurrent: usize,
+        connector: Connector,
+        tcp: TcpOptions,
+        hooks: SharedHooks,
+        stats: stats::Listener,
+    ) -> std::io::Result<Self> {
+        let udp_sockets = opts
+            .udp
+            .into_iter()
+            .map(|rule| {
+                let socket = std::net::UdpSocket::bind(rule.listen)?;
+                socket.set_nonblocking(true)?;
+                Ok((UdpSocket::from_std(socket)?, rule.target))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let listeners = opts
+            .tcp
+            .into_iter()
+            .map(|rule| {
+                let socket = if rule.listen.is_ipv4() {
+                    tokio::net::TcpSocket::new_v4()?
+                } else {
+                    tokio::net::TcpSocket::new_v6()?
+                };
+                socket.set_reuseaddr(true)?;
+                #[cfg(all(target_os = "linux", feature = "io-uring"))]
+                socket.set_reuseport(crate::uring::enabled())?;
+                socket.bind(rule.listen)?;
+                Ok((socket.listen(concurrent as u32)?, rule.target))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            listeners,
+            udp_sockets,
+            udp_idle_timeout: Duration::from_secs(opts.forward_udp_idle_timeout.max(1)),
+            proxy_protocol: opts.forward_proxy_protocol,
+            concurrent,
+            connector,
+            tcp,
+            hooks,
+            stats,
+        })
+    }
+}
+
+impl Serve for ForwardServer {
+    async fn serve(self) -> std::io::Result<()> {
+        let mut tasks = JoinSet::new();
+        for (listener, target) in self.listeners {
+            tracing::info!(
+                "Forwarding {} to {}",
+                listener.local_addr()?,
+                target.as_str()
+            );
+            tasks.spawn(
+                accept(
+                    listener,
+                    target,
+                    self.connector.clone(),
+                    self.tcp,
+                    self.proxy_protocol,
+                    self.hooks.clone(),
+                    self.stats.clone(),
+                )
+                .in_current_span(),
+            );
+        }
+        for (socket, target) in self.udp_sockets {
+            tracing::info!(
+                "Forwarding UDP {} to {}",
+                socket.local_addr()?,
+                target.as_str()
+            );
+            let nat = Nat {
+                listener: Arc::new(socket),
+                target,
+                connector: self.connector.clone(),
+                hooks: self.hooks.clone(),
+                stats: self.stats.clone(),
+                idle_timeout: self.udp_idle_timeout,
+                max_clients: self.concurrent,
+                clients: Arc::default(),
+            };
+            tasks.spawn(Arc::new(nat).relay().in_current_span());
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            result.map_err(std::io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts the connections of one rule.
+async fn accept(
+    listener: TcpListener,
+    target: Authority,
+    connector: Connector,
+    tcp: TcpOptions,
+    proxy_protocol: bool,
+    hooks: SharedHooks,
+    stats: stats::Listener,
+) {
+    loop {
+        let (stream, peer) = crate::serve::accept(&listener).await;
+        if !hooks.on_connect(Protocol::Forward, peer) {
+            tracing::debug!("[FORWARD] connection from {} rejected by hook", peer);
+            continue;
+        }
+
+        if let Err(err) = tcp.apply(SockRef::from(&stream)) {
+            tracing::trace!("[FORWARD] failed to apply tcp options: {}", err);
+        }
+
+        let target = target.clone();
+        let connector = connector.clone();
+        let hooks = hooks.clone();
+        let Some(active) = ActiveConnection::admit(&stats).await else {
+            tracing::debug!(
+                "[FORWARD] connection from {} rejected, too many connections",
+                peer
+            );
+            continue;
+        };
+        tokio::spawn(ConnectionId::new().scope(async move {
+            let _active = active;
+            if let Err(err) = forward(stream, peer, target, connector, proxy_protocol, hooks).await
+            {
+                tracing::debug!("[FORWARD] {} error: {}", peer, err);
+            }
+        }));
+    }
+}
+
+/// Relays `stream` to `target`.
+async fn forward(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    target: Authority,
+    connector: Connector,
+    proxy_protocol: bool,
+    hooks: SharedHooks,
+) -> std::io::Result<()> {
+    let connector = connector.with_owner(stats::owner(None, peer.ip()));
+    let extension = connector.client_extension(peer.ip(), Extension::default());
+    let extension = match hooks.on_request(&ProxyRequest {
+        protocol: Protocol::Forward,
+        peer,
+        username: None,
+        target: target.as_str(),
+        extension,
+    }) {
+        Decision::Allow => extension,
+        Decision::Egress(extension) => extension,
+        Decision::Deny => {
+            tracing::info!("[FORWARD] connection from {} to {} denied", peer, target);
+            return Ok(());
+        }
+    };
+
+    let mut target_stream = connector
+        .tcp_connector()
+        .connect_with_authority(target.clone(), extension)
+        .await?;
+    if proxy_protocol {
+        let header = crate::proxy_protocol::header_v2(peer, stream.local_addr()?);
+        target_stream.write_all(&header).await?;
+    }
+
+    let progress = Arc::new(Progress::default());
+    let _tunnel = Tunnel::register(TunnelInfo {
+        protocol: Protocol::Forward,
+        client: peer,
+        username: None,
+        target: target.to_string(),
+        egress: target_stream.local_addr().ok().map(|addr| addr.ip()),
+        server: target_stream.peer_addr().ok(),
+        progress: progress.clone(),
+        client_first: true,
+        started: std::time::Instant::now(),
+    });
+
+    let result = relay::copy_bidirectional(&mut stream, &mut target_stream, &progress).await;
+    let (sent, received) = match &result {
+        Ok(copied) => *copied,
+        Err(_) => progress.copied(),
+    };
+    tracing::info!(
+        "[FORWARD] {} wrote {} bytes and received {} bytes",
+        peer,
+        sent,
+        received
+    );
+    hooks.on_tunnel_close(&TunnelClose {
+        protocol: Protocol::Forward,
+        peer,
+        username: None,
+        target: target.to_string(),
+        sent,
+        received,
+    });
+    result.map(drop)
+}
+
+/// Egress socket of a UDP client, connected to the target.
+struct NatEntry {
+    socket: UdpSocket,
+    /// Whether a datagram was relayed since the last idle check.
+    active: AtomicBool,
+}
+
+/// A UDP client of a rule.
+enum Client {
+    /// The egress socket is being set up, with the datagrams received
+    /// meanwhile.
+    Opening(Vec<Vec<u8>>),
+    Open(Arc<NatEntry>),
+}
+
+/// UDP relay of one rule.
+struct Nat {
+    listener: Arc<UdpSocket>,
+    target: Authority,
+    connector: Connector,
+    hooks: SharedHooks,
+    stats: stats::Listener,
+    idle_timeout: Duration,
+    max_clients: usize,
+    clients: Arc<Mutex<HashMap<SocketAddr, Client>>>,
+}
+
+impl Nat {
+    /// Relays the datagrams of clients to the target.
+    async fn relay(self: Arc<Self>) {
+        let mut buf = crate::pool::get(MAX_DATAGRAM_SIZE);
+        loop {
+            let (len, peer) = match self.listener.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::trace!("[FORWARD] UDP receive error: {}", err);
+                    continue;
+                }
+            };
+
+            // The egress socket of a new client is set up by a task of its
+            // own, so that other clients are not held up meanwhile
+            let entry = {
+                let Ok(mut clients) = self.clients.lock() else {
+                    continue;
+                };
+                let full = clients.len() >= self.max_clients;
+                match clients.get_mut(&peer) {
+                    Some(Client::Open(entry)) => entry.clone(),
+                    Some(Client::Opening(queued)) => {
+                        if queued.len() < MAX_OPENING_DATAGRAMS {
+                            queued.push(buf[..len].to_vec());
+                        }
+                        continue;
+                    }
+                    None if full => {
+                        tracing::debug!(
+                            "[FORWARD] too many UDP clients, dropping datagram of {}",
+                            peer
+                        );
+                        continue;
+                    }
+                    None => {
+                        clients.insert(peer, Client::Opening(vec![buf[..len].to_vec()]));
+                        tokio::spawn(self.clone().open(peer).in_current_span());
+                        continue;
+                    }
+                }
+            };
+            entry.active.store(true, Ordering::Relaxed);
+
+            if let Err(err) = entry.socket.send(&buf[..len]).await {
+                tracing::debug!("[FORWARD] UDP send error from {}: {}", peer, err);
+            }
+        }
+    }
+
+    /// Sets up the NAT entry of a new client, then relays its answers until
+    /// it is idle. The client is forgotten if the entry cannot be set up.
+    async fn open(self: Arc<Self>, peer: SocketAddr) {
+        let Some(entry) = self.setup(peer).await else {
+            if let Ok(mut clients) = self.clients.lock() {
+                clients.remove(&peer);
+            }
+            return;
+        };
+
+        let queued = match self.clients.lock() {
+            Ok(mut clients) => match clients.insert(peer, Client::Open(entry.clone())) {
+                Some(Client::Opening(queued)) => queued,
+                _ => Vec::new(),
+            },
+            Err(_) => return,
+        };
+        for datagram in queued {
+            if let Err(err) = entry.socket.send(&datagram).await {
+                tracing::debug!("[FORWARD] UDP send error from {}: {}", peer, err);
+            }
+        }
+
+        let _active = ActiveConnection::new(&self.stats);
+        self.answer(peer, &entry).await;
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.remove(&peer);
+        }
+    }
+
+    /// Creates the egress socket of a new client, connected to the target.
+    async fn setup(&self, peer: SocketAddr) -> Option<Arc<NatEntry>> {
+        if !self.hooks.on_connect(Protocol::Forward, peer) {
+            tracing::debug!("[FORWARD] UDP client {} rejected by hook", peer);
+            return None;
+        }
+        let extension = self
+            .connector
+            .client_extension(peer.ip(), Extension::default());
+        let extension = match self.hooks.on_request(&ProxyRequest {
+            protocol: Protocol::Forward,
+            peer,
+            username: None,
+            target: self.target.as_str(),
+            extension,
+        }) {
+            Decision::Allow => extension,
+            Decision::Egress(extension) => extension,
+            Decision::Deny => {
+                tracing::info!("[FORWARD] UDP from {} to {} denied", peer, self.target);
+                return None;
+            }
+        };
+
+        let udp = self.connector.udp_connector();
+        let socket = match udp.bind_socket(extension).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                tracing::debug!("[FORWARD] cannot bind UDP egress for {}: {}", peer, err);
+                return None;
+            }
+        };
+        // Answers are only taken from the target, resolved once per client
+        let port = self.target.port_u16().unwrap_or_default();
+        if let Err(err) = udp.connect_socket(&socket, self.target.host(), port).await {
+            tracing::debug!(
+                "[FORWARD] cannot reach UDP target {} for {}: {}",
+                self.target,
+                peer,
+                err
+            );
+            return None;
+        }
+        Some(Arc::new(NatEntry {
+            socket,
+            active: AtomicBool::new(true),
+        }))
+    }
+
+    /// Sends the answers received by `entry` to `peer`, until the entry has
+    /// been idle for the idle timeout.
+    async fn answer(&self, peer: SocketAddr, entry: &NatEntry) {
+        let mut buf = crate::pool::get(MAX_DATAGRAM_SIZE);
+        loop {
+            match tokio::time::timeout(self.idle_timeout, entry.socket.recv(&mut buf)).await {
+                Ok(Ok(len)) => {
+                    entry.active.store(true, Ordering::Relaxed);
+                    if let Err(err) = self.listener.send_to(&buf[..len], peer).await {
+                        tracing::debug!("[FORWARD] UDP send error to {}: {}", peer, err);
+                    }
+                }
+                Ok(Err(err)) => {
+                    tracing::trace!("[FORWARD] UDP receive error for {}: {}", peer, err);
+                }
+                Err(_) => {
+                    if !entry.active.swap(false, Ordering::Relaxed) {
+                        tracing::debug!("[FORWARD] UDP client {} idle, closing", peer);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rule() {
+        let rule = "0.0.0.0:2222=10.0.0.5:22".parse::<ForwardRule>().unwrap();
+        assert_eq!(rule.listen, "0.0.0.0:2222".parse().unwrap());
+        assert_eq!(rule.target.as_str(), "10.0.0.5:22");
+
+        let rule = "[::]:8443=example.com:443".parse::<ForwardRule>().unwrap();
+        assert_eq!(rule.target.host(), "example.com");
+
+        assert!("0.0.0.0:2222".parse::<ForwardRule>().is_err());
+        assert!("0.0.0.0:2222=example.com".parse::<ForwardRule>().is_err());
+        assert!("2222=example.com:22".parse::<ForwardRule>().is_err());
+    }
+
+    /// Records the tunnels that close.
+    #[derive(Default)]
+    struct Closed(Mutex<Vec<TunnelClose>>);
+
+    impl crate::hooks::Hooks for Closed {
+        fn on_tunnel_close(&self, event: &TunnelClose) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_answers_from_target_only() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let nat = Nat {
+            listener: Arc::new(listener),
+            target: target.local_addr().unwrap().to_string().parse().unwrap(),
+            connector: Connector::new(
+                None,
+                None,
+                None,
+                Vec::new(),
+                Default::default(),
+                5,
+                TcpOptions::default(),
+            ),
+            hooks: Arc::new(crate::hooks::NoHooks),
+            stats: stats::Listener::register("forward-udp-test"),
+            idle_timeout: Duration::from_secs(5),
+            max_clients: 16,
+            clients: Arc::default(),
+        };
+        tokio::spawn(Arc::new(nat).relay());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"ping", listen_addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, egress) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        // Another source cannot answer through the entry of the client
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stranger.send_to(b"spoof", egress).await.unwrap();
+        target.send_to(b"pong", egress).await.unwrap();
+        let (len, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(from, listen_addr);
+    }
+
+    #[tokio::test]
+    async fn test_forward_reset() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            // Closing with a zero linger resets the connection
+            SockRef::from(&stream)
+                .set_linger(Some(Duration::ZERO))
+                .unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+
+        let closed = Arc::new(Closed::default());
+        let connector = Connector::new(
+            None,
+            None,
+            None,
+            Vec::new(),
+            Default::default(),
+            5,
+            TcpOptions::default(),
+        );
+        let target = target_addr.to_string().parse().unwrap();
+        let result = forward(stream, peer, target, connector, false, closed.clone()).await;
+        assert!(result.is_err());
+
+        let closed = closed.0.lock().unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].peer, peer);
+        assert_eq!(closed[0].target, target_addr.to_string());
+    }
+}
@@ -0,0 +1,213 @@
+//! Background health checking of egress addresses.
+//!
+//! A sample of addresses from the CIDR is periodically bound and connected to
+//! a check target. Subnets whose probes fail several rounds in a row are
+//! marked dead and avoided by address assignment until a later probe succeeds
+//! again. The state of every
+//! probed subnet is served by the `/egress-health` and `/metrics` endpoints of
+//! the admin API.
+
+use crate::connect::Connector;
+use cidr::{IpCidr, IpInet};
+use http::Uri;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::RwLock,
+    time::Duration,
+};
+
+/// Failed probes in a row after which a subnet is marked dead by default.
+pub const DEFAULT_FAILURES: u32 = 3;
+
+/// Probed subnets and whether they are alive.
+#[derive(Debug)]
+pub struct EgressHealth {
+    state: RwLock<State>,
+    /// Failed probes in a row after which a subnet is marked dead.
+    failures: u32,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    subnets: HashMap<IpCidr, Subnet>,
+    /// Number of dead subnets by network length, so that an address is
+    /// looked up once per length rather than against every subnet.
+    dead_lengths: BTreeMap<u8, usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Subnet {
+    alive: bool,
+    /// Failed probes since the last successful one.
+    failures: u32,
+}
+
+impl Default for EgressHealth {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURES)
+    }
+}
+
+impl EgressHealth {
+    /// Creates the health of subnets marked dead after `failures` failed
+    /// probes in a row.
+    pub fn new(failures: u32) -> Self {
+        Self {
+            state: RwLock::default(),
+            failures: failures.max(1),
+        }
+    }
+
+    /// Whether `ip` belongs to a subnet marked dead.
+    pub fn is_dead(&self, ip: IpAddr) -> bool {
+        let Ok(state) = self.state.read() else {
+            return false;
+        };
+        state.dead_lengths.keys().any(|length| {
+            subnet_of(ip, *length)
+                .and_then(|subnet| state.subnets.get(&subnet))
+                .is_some_and(|subnet| !subnet.alive)
+        })
+    }
+
+    /// Records the result of a probe of `subnet`. It is marked dead once
+    /// enough probes in a row failed, and alive again by the next successful
+    /// one.
+    pub fn update(&self, subnet: IpCidr, alive: bool) {
+        let Ok(mut state) = self.state.write() else {
+            return;
+        };
+        // A subnet is assumed alive until probes fail
+        let entry = state.subnets.entry(subnet).or_insert(Subnet {
+            alive: true,
+            failures: 0,
+        });
+        entry.failures = if alive { 0 } else { entry.failures + 1 };
+        let was_alive = entry.alive;
+        entry.alive = entry.failures < self.failures;
+        if entry.alive == was_alive {
+            return;
+        }
+
+        let length = subnet.network_length();
+        if alive {
+            if let Some(count) = state.dead_lengths.get_mut(&length) {
+                *count -= 1;
+                if *count == 0 {
+                    state.dead_lengths.remove(&length);
+                }
+            }
+        } else {
+            *state.dead_lengths.entry(length).or_default() += 1;
+        }
+        tracing::info!(
+            "Egress subnet {} is {}",
+            subnet,
+            if alive { "alive again" } else { "dead" }
+        );
+        crate::notify::send(crate::notify::Event::EgressHealth { subnet, alive });
+    }
+
+    /// Number of subnets currently marked dead.
+    pub fn dead_count(&self) -> usize {
+        self.state
+            .read()
+            .map(|state| state.dead_lengths.values().sum())
+            .unwrap_or(0)
+    }
+
+    /// Returns the probed subnets and whether each is alive, sorted.
+    #[cfg_attr(not(feature = "admin"), allow(dead_code))]
+    pub fn subnets(&self) -> Vec<(IpCidr, bool)> {
+        let mut subnets = self
+            .state
+            .read()
+            .map(|state| {
+                state
+                    .subnets
+                    .iter()
+                    .map(|(subnet, state)| (*subnet, state.alive))
+                    .collect()
+            })
+            .unwrap_or_else(|_| Vec::new());
+        subnets.sort_unstable();
+        subnets
+    }
+}
+
+/// Returns the subnet of length `prefix` containing `ip`.
+pub fn subnet_of(ip: IpAddr, prefix: u8) -> Option<IpCidr> {
+    IpInet::new(ip, prefix).ok().map(|inet| inet.network())
+}
+
+/// Resolves a check URL such as `http://example.com/` or a plain
+/// `host:port` into the `host:port` probes connect to.
+pub fn check_target(url: &str) -> std::io::Result<String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let authority = uri.authority().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("health check url has no host: {url}"),
+        )
+    })?;
+
+    let port = authority.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+
+    Ok(format!("{}:{}", authority.host(), port))
+}
+
+/// Probes `samples` egress addresses against `target` every `interval`.
+pub async fn run(connector: Connector, target: String, interval: Duration, samples: usize) {
+    tracing::info!(
+        "Egress health check against {} every {:?} ({} samples)",
+        target,
+        interval,
+        samples
+    );
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        connector.check_egress_health(&target, samples).await;
+        tracing::debug!(
+            "Egress health check done, {} dead subnets",
+            connector.health().dead_count()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_consecutive_failures() {
+        let health = EgressHealth::new(2);
+        let subnet = "2001:db8:1::/64".parse().unwrap();
+        let ip = "2001:db8:1::42".parse().unwrap();
+        health.update(subnet, false);
+        health.update(subnet, true);
+        health.update(subnet, false);
+        assert!(!health.is_dead(ip));
+        health.update(subnet, false);
+        assert!(health.is_dead(ip));
+        assert!(!health.is_dead("2001:db8:2::42".parse().unwrap()));
+        assert_eq!(health.dead_count(), 1);
+
+        health.update("192.0.2.0/24".parse().unwrap(), false);
+        health.update("192.0.2.0/24".parse().unwrap(), false);
+        assert!(health.is_dead("192.0.2.7".parse().unwrap()));
+        assert_eq!(health.dead_count(), 2);
+
+        health.update(subnet, true);
+        assert!(!health.is_dead(ip));
+        assert_eq!(health.subnets().len(), 2);
+        assert_eq!(health.dead_count(), 1);
+    }
+}
@@ -0,0 +1,264 @@
+//! Connection lifecycle hooks.
+//!
+//! Embedders register a [`Hooks`] implementation with
+//! [`ProxyBuilder::hooks`](crate::ProxyBuilder::hooks) to account, alert on or
+//! filter connections without changing the handlers. Hooks are called inline
+//! on the connection's task, so they should return quickly and hand slow work
+//! off to a task of their own.
+
+use crate::extension::Extension;
+use std::{net::SocketAddr, sync::Arc};
+
+/// The protocol a client speaks to the proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// HTTP or HTTPS proxy.
+    Http,
+    /// SOCKS5 proxy.
+    Socks5,
+    /// SNI proxy.
+    Sni,
+    /// TCP port forwarding.
+    Forward,
+}
+
+/// A tunnel that has been closed.
+#[derive(Clone, Debug)]
+pub struct TunnelClose {
+    /// Protocol of the client connection.
+    pub protocol: Protocol,
+    /// Address of the client.
+    pub peer: SocketAddr,
+    /// Username the client authenticated with, including any extension.
+    pub username: Option<String>,
+    /// Target the tunnel was connected to, as requested by the client.
+    pub target: String,
+    /// Bytes sent by the client.
+    pub sent: u64,
+    /// Bytes received by the client.
+    pub received: u64,
+}
+
+/// An authentication attempt of a client.
+#[derive(Clone, Copy, Debug)]
+pub struct AuthAttempt<'a> {
+    /// Protocol of the client connection.
+    pub protocol: Protocol,
+    /// Address of the client.
+    pub peer: SocketAddr,
+    /// Username the client tried, including any extension, if it sent one.
+    pub username: Option<&'a str>,
+    /// Whether the client authenticated.
+    pub success: bool,
+    /// Whether the client presented credentials. HTTP requests without a
+    /// `Proxy-Authorization` header fail without presenting any, as they are
+    /// answered with a challenge the client is expected to retry after.
+    pub presented: bool,
+}
+
+/// A request about to be proxied.
+#[derive(Clone, Copy, Debug)]
+pub struct ProxyRequest<'a> {
+    /// Protocol of the client connection.
+    pub protocol: Protocol,
+    /// Address of the client.
+    pub peer: SocketAddr,
+    /// Username the client authenticated with, including any extension.
+    pub username: Option<&'a str>,
+    /// Target as requested by the client, as `host:port`.
+    pub target: &'a str,
+    /// Extension parsed from the username.
+    pub extension: Extension,
+}
+
+/// Outcome of [`Hooks::on_request`].
+#[derive(Clone, Copy, Debug)]
+pub enum Decision {
+    /// Proxy the request as requested.
+    Allow,
+    /// Refuse the request.
+    Deny,
+    /// Proxy the request with the egress address picked by this extension
+    /// instead of the client's.
+    Egress(Extension),
+}
+
+/// Callbacks invoked over the lifetime of a client connection.
+///
+/// All methods have empty default implementations.
+pub trait Hooks: Send + Sync {
+    /// Called when a client connects, before anything is read from it.
+    /// Returning `false` closes the connection.
+    fn on_connect(&self, protocol: Protocol, peer: SocketAddr) -> bool {
+        let _ = (protocol, peer);
+        true
+    }
+
+    /// Called with the outcome of client authentication. HTTP clients are
+    /// authenticated on every request.
+    fn on_auth(&self, protocol: Protocol, peer: SocketAddr, success: bool) {
+        let _ = (protocol, peer, success);
+    }
+
+    /// Called for every authentication attempt, along with the username the
+    /// client tried. Calls [`Hooks::on_auth`] by default.
+    fn on_auth_attempt(&self, attempt: &AuthAttempt<'_>) {
+        self.on_auth(attempt.protocol, attempt.peer, attempt.success);
+    }
+
+    /// Called for every request before it is proxied, i.e. HTTP requests and
+    /// SOCKS5 CONNECT, BIND and UDP ASSOCIATE commands. The target of a UDP
+    /// ASSOCIATE is the address the client will send datagrams from.
+    fn on_request(&self, request: &ProxyRequest<'_>) -> Decision {
+        let _ = request;
+        Decision::Allow
+    }
+
+    /// Called when a CONNECT or BIND tunnel closes.
+    fn on_tunnel_close(&self, event: &TunnelClose) {
+        let _ = event;
+    }
+}
+
+/// Hooks that do nothing, used when none are registered.
+pub(crate) struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+/// Hooks shared between the listeners and their connections.
+pub(crate) type SharedHooks = Arc<dyn Hooks>;
+
+/// Hooks called one after another. A connection is accepted only if every
+/// hook accepts it, and the first decision other than [`Decision::Allow`]
+/// applies to a request.
+pub(crate) struct Chain(pub(crate) Vec<SharedHooks>);
+
+impl Hooks for Chain {
+    fn on_connect(&self, protocol: Protocol, peer: SocketAddr) -> bool {
+        self.0.iter().all(|hooks| hooks.on_connect(protocol, peer))
+    }
+
+    fn on_auth(&self, protocol: Protocol, peer: SocketAddr, success: bool) {
+        self.0
+            .iter()
+            .for_each(|hooks| hooks.on_auth(protocol, peer, success));
+    }
+
+    fn on_auth_attempt(&self, attempt: &AuthAttempt<'_>) {
+        self.0
+            .iter()
+            .for_each(|hooks| hooks.on_auth_attempt(attempt));
+    }
+
+    fn on_request(&self, request: &ProxyRequest<'_>) -> Decision {
+        self.0
+            .iter()
+            .map(|hooks| hooks.on_request(request))
+            .find(|decision| !matches!(decision, Decision::Allow))
+            .unwrap_or(Decision::Allow)
+    }
+
+    fn on_tunnel_close(&self, event: &TunnelClose) {
+        self.0.iter().for_each(|hooks| hooks.on_tunnel_close(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Records the calls it gets, refusing connections and denying requests
+    /// if `refuse` is set.
+    #[derive(Default)]
+    struct Recorder {
+        refuse: bool,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl Hooks for Recorder {
+        fn on_connect(&self, _protocol: Protocol, _peer: SocketAddr) -> bool {
+            self.calls.lock().unwrap().push("connect".to_owned());
+            !self.refuse
+        }
+
+        fn on_auth(&self, _protocol: Protocol, _peer: SocketAddr, success: bool) {
+            self.calls.lock().unwrap().push(format!("auth {success}"));
+        }
+
+        fn on_request(&self, _request: &ProxyRequest<'_>) -> Decision {
+            self.calls.lock().unwrap().push("request".to_owned());
+            if self.refuse {
+                Decision::Deny
+            } else {
+                Decision::Allow
+            }
+        }
+
+        fn on_tunnel_close(&self, event: &TunnelClose) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("close {} {}", event.sent, event.received));
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let peer = "192.0.2.1:40000".parse().unwrap();
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder {
+            refuse: true,
+            ..Default::default()
+        });
+        let third = Arc::new(Recorder::default());
+        let chain = Chain(vec![first.clone(), second.clone(), third.clone()]);
+
+        // Every hook has to accept a connection, the first refusal ends it
+        assert!(!chain.on_connect(Protocol::Http, peer));
+        assert_eq!(third.calls(), Vec::<String>::new());
+
+        // Attempts fall back to `on_auth` and reach every hook
+        chain.on_auth_attempt(&AuthAttempt {
+            protocol: Protocol::Socks5,
+            peer,
+            username: Some("user"),
+            success: false,
+            presented: true,
+        });
+        assert_eq!(third.calls(), ["auth false"]);
+
+        // The first decision other than allow applies
+        let decision = chain.on_request(&ProxyRequest {
+            protocol: Protocol::Http,
+            peer,
+            username: None,
+            target: "example.com:443",
+            extension: Extension::default(),
+        });
+        assert!(matches!(decision, Decision::Deny));
+        assert_eq!(third.calls(), ["auth false"]);
+
+        chain.on_tunnel_close(&TunnelClose {
+            protocol: Protocol::Forward,
+            peer,
+            username: None,
+            target: "example.com:443".to_owned(),
+            sent: 1,
+            received: 2,
+        });
+        for hooks in [&first, &second, &third] {
+            assert_eq!(hooks.calls().last().unwrap(), "close 1 2");
+        }
+        assert_eq!(
+            first.calls(),
+            ["connect", "auth false", "request", "close 1 2"]
+        );
+    }
+}
@@ -0,0 +1,727 @@
+//! On-disk cache of plain HTTP responses.
+//!
+//! Only complete `200 OK` answers to GET requests are stored, and only when
+//! the response may be shared: `no-store`, `private`, `Vary` and `Set-Cookie`
+//! keep it out of the cache. A stored response is served from disk until its
+//! `s-maxage` or `max-age` has passed, and is revalidated with
+//! `If-None-Match` or `If-Modified-Since` afterwards when the origin sent an
+//! `ETag` or `Last-Modified`. Requests with credentials, cookies, ranges,
+//! conditions of their own or `no-cache` bypass the cache.
+//!
+//! Every response is kept as a `<hash>.json` file with its status and headers
+//! next to a `<hash>.body` file, so the cache survives restarts. The least
+//! recently used responses are evicted once the cache outgrows its size.
+
+use super::{deadline::DeadlineBody, error::Error};
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Headers describing the connection a response arrived on rather than the
+/// response itself.
+const HOP_BY_HOP: &[HeaderName] = &[
+    header::CONNECTION,
+    header::TRANSFER_ENCODING,
+    header::TRAILER,
+    header::UPGRADE,
+    header::PROXY_AUTHENTICATE,
+];
+
+/// Cache of plain HTTP responses in a directory.
+pub(crate) struct HttpCache {
+    dir: PathBuf,
+    max_size: u64,
+    max_object_size: u64,
+    index: Mutex<Index>,
+}
+
+/// The stored responses, by hash of their URL.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<u64, Entry>,
+    size: u64,
+}
+
+struct Entry {
+    meta: Arc<Meta>,
+    size: u64,
+    accessed: Instant,
+}
+
+/// Status and headers of a stored response.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Unix time at which the response has to be revalidated.
+    expires: u64,
+}
+
+/// What to do with a request.
+pub(crate) enum Lookup {
+    /// Answer with the stored response.
+    Hit(Response<BoxBody<Bytes, Error>>),
+    /// Forward the request and hand its response to [`HttpCache::complete`].
+    Forward(Pending),
+    /// Forward the request without caching.
+    Bypass,
+}
+
+/// A forwarded request whose response may be stored.
+pub(crate) struct Pending {
+    key: u64,
+    url: String,
+    /// The stored response being revalidated, with its body.
+    stale: Option<(Arc<Meta>, Bytes)>,
+}
+
+impl HttpCache {
+    /// Opens the cache in `dir`, creating the directory if needed and loading
+    /// the responses stored by earlier runs.
+    pub(crate) fn open(dir: &Path, max_size: u64, max_object_size: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            max_size,
+            max_object_size: max_object_size.min(max_size),
+            index: Mutex::new(Index::default()),
+        };
+
+        let mut index = Index::default();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let key = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| u64::from_str_radix(stem, 16).ok());
+            let meta = std::fs::read(&path)
+                .ok()
+                .and_then(|meta| serde_json::from_slice::<Meta>(&meta).ok());
+            let size = std::fs::metadata(path.with_extension("body")).map(|body| body.len());
+
+            match (key, meta, size) {
+                (Some(key), Some(meta), Ok(size)) if key == hash(&meta.url) => {
+                    index.size += size;
+                    index.entries.insert(
+                        key,
+                        Entry {
+                            meta: Arc::new(meta),
+                            size,
+                            accessed: Instant::now(),
+                        },
+                    );
+                }
+                _ => {
+                    let _ = std::fs::remove_file(&path);
+                    let _ = std::fs::remove_file(path.with_extension("body"));
+                }
+            }
+        }
+
+        let evicted = index.evict(cache.max_size);
+        cache.remove_files(evicted);
+        *cache.lock() = index;
+        Ok(cache)
+    }
+
+    /// Number of stored responses and their total size in bytes.
+    pub(crate) fn usage(&self) -> (usize, u64) {
+        let index = self.lock();
+        (index.entries.len(), index.size)
+    }
+
+    /// Removes the response stored for `url`, or every response without
+    /// one, and returns how many were removed.
+    pub(crate) fn purge(&self, url: Option<&str>) -> usize {
+        let keys = {
+            let mut index = self.lock();
+            let keys = match url {
+                Some(url) => {
+                    let key = hash(url);
+                    index
+                        .entries
+                        .get(&key)
+                        .filter(|entry| entry.meta.url == url)
+                        .map(|_| vec![key])
+                        .unwrap_or_default()
+                }
+                None => index.entries.keys().copied().collect(),
+            };
+            for key in &keys {
+                if let Some(entry) = index.entries.remove(key) {
+                    index.size -= entry.size;
+                }
+            }
+            keys
+        };
+
+        let purged = keys.len();
+        self.remove_files(keys);
+        purged
+    }
+
+    /// Looks up the response to `req`, adding validators to `req` when a
+    /// stale response is revalidated.
+    pub(crate) async fn lookup<B>(&self, req: &mut Request<B>) -> Lookup {
+        if !cacheable_request(req) {
+            return Lookup::Bypass;
+        }
+
+        let url = req.uri().to_string();
+        let key = hash(&url);
+        let stored = self
+            .lock()
+            .entries
+            .get_mut(&key)
+            .filter(|entry| entry.meta.url == url)
+            .map(|entry| {
+                entry.accessed = Instant::now();
+                entry.meta.clone()
+            });
+
+        let Some(meta) = stored else {
+            return Lookup::Forward(Pending {
+                key,
+                url,
+                stale: None,
+            });
+        };
+
+        // The body is read up front, as the client gets it if the origin
+        // confirms it is still valid
+        let Some(body) = self.read_body(key, &meta).await else {
+            return Lookup::Forward(Pending {
+                key,
+                url,
+                stale: None,
+            });
+        };
+        if now() < meta.expires {
+            if let Some(res) = respond(&meta, body) {
+                tracing::debug!("[cache] hit {}", url);
+                return Lookup::Hit(res);
+            }
+            return Lookup::Forward(Pending {
+                key,
+                url,
+                stale: None,
+            });
+        }
+
+        let headers = headers(&meta);
+        let mut revalidating = false;
+        if let Some(etag) = headers.get(header::ETAG) {
+            req.headers_mut()
+                .insert(header::IF_NONE_MATCH, etag.clone());
+            revalidating = true;
+        }
+        if let Some(modified) = headers.get(header::LAST_MODIFIED) {
+            req.headers_mut()
+                .insert(header::IF_MODIFIED_SINCE, modified.clone());
+            revalidating = true;
+        }
+
+        Lookup::Forward(Pending {
+            key,
+            url,
+            stale: revalidating.then_some((meta, body)),
+        })
+    }
+
+    /// Answers with the stored response if the origin confirmed it is still
+    /// valid, and stores `res` on its way to the client otherwise.
+    pub(crate) async fn complete<B>(
+        self: &Arc<Self>,
+        pending: Pending,
+        res: Response<DeadlineBody<B>>,
+    ) -> Response<BoxBody<Bytes, Error>>
+    where
+        B: Body<Data = Bytes, Error = hyper::Error> + Send + Sync + 'static,
+    {
+        if let (Some((stale, body)), StatusCode::NOT_MODIFIED) = (&pending.stale, res.status()) {
+            let meta = Arc::new(Meta {
+                url: stale.url.clone(),
+                status: stale.status,
+                headers: stale.headers.clone(),
+                expires: now() + freshness(res.headers()).unwrap_or(0),
+            });
+            // The validators are the proxy's, so the client never gets the 304
+            let cached = respond(&meta, body.clone()).unwrap_or_else(|| {
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Empty::new().map_err(|never| match never {}).boxed())
+                    .unwrap_or_default()
+            });
+            tracing::debug!("[cache] revalidated {}", pending.url);
+            self.refresh(pending.key, meta);
+            return cached;
+        }
+
+        let Some(meta) = self.storable(&pending.url, &res) else {
+            return res.map(BodyExt::boxed);
+        };
+
+        let headers = res.headers();
+        let store = Store {
+            cache: self.clone(),
+            key: pending.key,
+            meta,
+            length: headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|length| length.to_str().ok()?.parse().ok()),
+            chunked: headers
+                .get_all(header::TRANSFER_ENCODING)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|coding| coding.trim().eq_ignore_ascii_case("chunked")),
+            data: BytesMut::new(),
+        };
+        res.map(|body| {
+            body.map_inner(|body| CacheBody {
+                inner: body,
+                store: Some(store),
+            })
+            .boxed()
+        })
+    }
+
+    /// Returns the metadata to store `res` with, if it may be stored.
+    fn storable<B: Body>(&self, url: &str, res: &Response<B>) -> Option<Meta> {
+        let headers = res.headers();
+        let control = cache_control(headers);
+        if res.status() != StatusCode::OK
+            || control
+                .iter()
+                .any(|directive| matches!(directive.as_str(), "no-store" | "private"))
+            || headers.contains_key(header::VARY)
+            || headers.contains_key(header::SET_COOKIE)
+            || res
+                .body()
+                .size_hint()
+                .exact()
+                .is_some_and(|size| size > self.max_object_size)
+        {
+            return None;
+        }
+
+        let validated =
+            headers.contains_key(header::ETAG) || headers.contains_key(header::LAST_MODIFIED);
+        let freshness = match freshness(headers) {
+            Some(secs) => secs,
+            None if validated => 0,
+            None => return None,
+        };
+
+        Some(Meta {
+            url: url.to_owned(),
+            status: res.status().as_u16(),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| !HOP_BY_HOP.contains(name))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_owned()))
+                })
+                .collect(),
+            expires: now() + freshness,
+        })
+    }
+
+    /// Reads the stored body, or forgets the response if its body is gone.
+    async fn read_body(&self, key: u64, meta: &Meta) -> Option<Bytes> {
+        let path = self.path(key, "body");
+        match tokio::task::spawn_blocking(move || std::fs::read(path)).await {
+            Ok(Ok(body)) => Some(Bytes::from(body)),
+            _ => {
+                self.purge(Some(&meta.url));
+                None
+            }
+        }
+    }
+
+    /// Replaces the metadata of a revalidated response.
+    fn refresh(&self, key: u64, meta: Arc<Meta>) {
+        if let Ok(json) = serde_json::to_vec(&*meta) {
+            let path = self.path(key, "json");
+            tokio::task::spawn_blocking(move || std::fs::write(path, json));
+        }
+        if let Some(entry) = self.lock().entries.get_mut(&key) {
+            entry.meta = meta;
+        }
+    }
+
+    /// Writes a complete response and evicts older ones if the cache has
+    /// outgrown its size.
+    fn insert(&self, key: u64, meta: Meta, body: &[u8]) -> io::Result<()> {
+        std::fs::write(self.path(key, "body"), body)?;
+        std::fs::write(self.path(key, "json"), serde_json::to_vec(&meta)?)?;
+        tracing::debug!("[cache] stored {} ({} bytes)", meta.url, body.len());
+
+        let evicted = {
+            let mut index = self.lock();
+            let size = body.len() as u64;
+            let entry = Entry {
+                meta: Arc::new(meta),
+                size,
+                accessed: Instant::now(),
+            };
+            if let Some(old) = index.entries.insert(key, entry) {
+                index.size -= old.size;
+            }
+            index.size += size;
+            index.evict(self.max_size)
+        };
+        self.remove_files(evicted);
+        Ok(())
+    }
+
+    fn remove_files(&self, keys: Vec<u64>) {
+        for key in keys {
+            let _ = std::fs::remove_file(self.path(key, "json"));
+            let _ = std::fs::remove_file(self.path(key, "body"));
+        }
+    }
+
+    fn path(&self, key: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{key:016x}.{extension}"))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Index> {
+        self.index.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl Index {
+    /// Drops the least recently used responses until the index fits into
+    /// `max_size`, returning their keys.
+    fn evict(&mut self, max_size: u64) -> Vec<u64> {
+        let mut evicted = Vec::new();
+        while self.size > max_size {
+            let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.accessed)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// A response body being stored.
+struct Store {
+    cache: Arc<HttpCache>,
+    key: u64,
+    meta: Meta,
+    /// The `Content-Length` of the response.
+    length: Option<u64>,
+    /// Whether the response is chunked, so its end is marked.
+    chunked: bool,
+    data: BytesMut,
+}
+
+impl Store {
+    /// Whether the whole body has been copied once the origin ended it,
+    /// `end_stream` telling whether the body knows it is complete. A body
+    /// ending before its `Content-Length`, or delimited only by the
+    /// connection closing, may have been cut short.
+    fn complete(&self, end_stream: bool) -> bool {
+        match self.length {
+            Some(length) => self.data.len() as u64 == length,
+            None => self.chunked || end_stream,
+        }
+    }
+
+    /// Writes the complete body to the cache, off the async runtime.
+    fn finish(self) {
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = self.cache.insert(self.key, self.meta, &self.data) {
+                tracing::warn!("[cache] failed to store response: {}", err);
+            }
+        });
+    }
+}
+
+pin_project! {
+    /// A response body copied into the cache as it is relayed.
+    ///
+    /// The copy is only stored once the origin has sent the whole body, so
+    /// responses cut short by an error, the request deadline or a body
+    /// ending before its `Content-Length` are dropped.
+    struct CacheBody<B> {
+        #[pin]
+        inner: B,
+        store: Option<Store>,
+    }
+}
+
+impl<B: Body<Data = Bytes>> Body for CacheBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let frame = ready!(this.inner.as_mut().poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(store), Some(data)) = (this.store.as_mut(), frame.data_ref()) {
+                    if (store.data.len() + data.len()) as u64 > store.cache.max_object_size {
+                        *this.store = None;
+                    } else {
+                        store.data.extend_from_slice(data);
+                    }
+                }
+                // A body of known length is not polled past its last frame
+                if this.inner.is_end_stream() {
+                    if let Some(store) = this.store.take() {
+                        if store.complete(true) {
+                            store.finish();
+                        }
+                    }
+                }
+            }
+            Some(Err(_)) => *this.store = None,
+            None => {
+                if let Some(store) = this.store.take() {
+                    if store.complete(this.inner.is_end_stream()) {
+                        store.finish();
+                    } else {
+                        tracing::debug!("[cache] dropped incomplete {}", store.meta.url);
+                    }
+                }
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Whether the response to `req` may come from or go to the cache.
+fn cacheable_request<B>(req: &Request<B>) -> bool {
+    let headers = req.headers();
+    req.method() == Method::GET
+        && req.uri().host().is_some()
+        && ![
+            header::AUTHORIZATION,
+            header::COOKIE,
+            header::RANGE,
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+        ]
+        .iter()
+        .any(|name| headers.contains_key(name))
+        && !cache_control(headers)
+            .iter()
+            .any(|directive| matches!(directive.as_str(), "no-store" | "no-cache"))
+        && !headers
+            .get(header::PRAGMA)
+            .is_some_and(|pragma| pragma.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+}
+
+/// Returns the lowercase `Cache-Control` directives of `headers`.
+fn cache_control(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect()
+}
+
+/// Seconds a response with `headers` stays fresh, if the origin says.
+fn freshness(headers: &HeaderMap) -> Option<u64> {
+    let control = cache_control(headers);
+    if control.iter().any(|directive| directive == "no-cache") {
+        return Some(0);
+    }
+    let max_age = |name: &str| {
+        control.iter().find_map(|directive| {
+            directive
+                .strip_prefix(name)
+                .and_then(|value| value.strip_prefix('='))
+                .and_then(|value| value.trim_matches('"').parse().ok())
+        })
+    };
+    max_age("s-maxage").or_else(|| max_age("max-age"))
+}
+
+/// Builds the stored response described by `meta` with `body`.
+fn respond(meta: &Meta, body: Bytes) -> Option<Response<BoxBody<Bytes, Error>>> {
+    let mut res = Response::new(Full::new(body).map_err(|never| match never {}).boxed());
+    *res.status_mut() = StatusCode::from_u16(meta.status).ok()?;
+    *res.headers_mut() = headers(meta);
+    Some(res)
+}
+
+fn headers(meta: &Meta) -> HeaderMap {
+    meta.headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::from_bytes(name.as_bytes()).ok()?,
+                HeaderValue::from_str(value).ok()?,
+            ))
+        })
+        .collect()
+}
+
+fn hash(url: &str) -> u64 {
+    fxhash::hash64(url.as_bytes())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_store_and_hit() {
+        let dir = std::env::temp_dir().join(format!("vproxy-cache-{}", std::process::id()));
+        let cache = Arc::new(HttpCache::open(&dir, 1 << 20, 1 << 20).unwrap());
+        let request = || Request::get("http://example.com/app.js").body(()).unwrap();
+
+        let Lookup::Forward(pending) = cache.lookup(&mut request()).await else {
+            panic!("nothing is stored yet");
+        };
+        let res = Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .header(header::CONTENT_LENGTH, "5")
+            .body(DeadlineBody::new(
+                Full::new(Bytes::from_static(b"hello")).map_err(|never| match never {}),
+                None,
+            ))
+            .unwrap();
+        let mut body = cache.complete(pending, res).await.into_body();
+        // Like hyper, stop polling once the body has all its bytes
+        while !body.is_end_stream() {
+            body.frame().await.unwrap().unwrap();
+        }
+
+        let mut hit = None;
+        for _ in 0..100 {
+            if let Lookup::Hit(res) = cache.lookup(&mut request()).await {
+                hit = Some(res);
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let body = hit.expect("response stored").collect().await.unwrap();
+        assert_eq!(body.to_bytes(), "hello");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_incomplete_not_stored() {
+        let dir = std::env::temp_dir().join(format!("vproxy-cache-short-{}", std::process::id()));
+        let cache = Arc::new(HttpCache::open(&dir, 1 << 20, 1 << 20).unwrap());
+        let request = || Request::get("http://example.com/app.js").body(()).unwrap();
+
+        let Lookup::Forward(pending) = cache.lookup(&mut request()).await else {
+            panic!("nothing is stored yet");
+        };
+        let res = Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .header(header::CONTENT_LENGTH, "10")
+            .body(DeadlineBody::new(
+                Full::new(Bytes::from_static(b"hello")).map_err(|never| match never {}),
+                None,
+            ))
+            .unwrap();
+        let mut body = cache.complete(pending, res).await.into_body();
+        while body.frame().await.is_some() {}
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(matches!(
+            cache.lookup(&mut request()).await,
+            Lookup::Forward(_)
+        ));
+        assert_eq!(cache.usage(), (0, 0));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_freshness() {
+        let headers = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert_eq!(freshness(&headers("public, max-age=60")), Some(60));
+        assert_eq!(freshness(&headers("max-age=60, s-maxage=600")), Some(600));
+        assert_eq!(freshness(&headers("max-age=60, no-cache")), Some(0));
+        assert_eq!(freshness(&headers("public")), None);
+        assert_eq!(freshness(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_cacheable_request() {
+        let req = |method: Method, header: Option<(HeaderName, &'static str)>| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri("http://example.com/app.js");
+            if let Some((name, value)) = header {
+                req = req.header(name, value);
+            }
+            req.body(()).unwrap()
+        };
+
+        assert!(cacheable_request(&req(Method::GET, None)));
+        assert!(!cacheable_request(&req(Method::POST, None)));
+        assert!(!cacheable_request(&req(
+            Method::GET,
+            Some((header::AUTHORIZATION, "Basic dXNlcjpwYXNz"))
+        )));
+        assert!(!cacheable_request(&req(
+            Method::GET,
+            Some((header::CACHE_CONTROL, "no-cache"))
+        )));
+        assert!(cacheable_request(&req(
+            Method::GET,
+            Some((header::ACCEPT, "*/*"))
+        )));
+    }
+}
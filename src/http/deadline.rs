@@ -0,0 +1,92 @@
+use super::error::Error;
+use crate::stats::EgressSlot;
+use hyper::body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::time::{sleep_until, Instant, Sleep};
+
+pin_project! {
+    /// A response body that fails once the request deadline has passed.
+    ///
+    /// The response status has already been sent at that point, so the body
+    /// ends with an error and the connection is aborted, rather than the
+    /// client taking a truncated response for a complete one.
+    ///
+    /// The egress slot of the request, if any, is held until the body is
+    /// dropped.
+    pub struct DeadlineBody<B> {
+        #[pin]
+        inner: B,
+        #[pin]
+        sleep: Option<Sleep>,
+        slot: Option<EgressSlot>,
+    }
+}
+
+impl<B> DeadlineBody<B> {
+    /// Wraps `inner`, ending it at `deadline` if one is given.
+    pub fn new(inner: B, deadline: Option<Instant>) -> Self {
+        Self {
+            inner,
+            sleep: deadline.map(sleep_until),
+            slot: None,
+        }
+    }
+
+    /// Holds `slot` until the body is dropped.
+    pub(crate) fn with_slot(mut self, slot: Option<EgressSlot>) -> Self {
+        self.slot = slot;
+        self
+    }
+
+    /// Wraps the inner body with `f`, keeping the deadline.
+    pub fn map_inner<C>(self, f: impl FnOnce(B) -> C) -> DeadlineBody<C> {
+        DeadlineBody {
+            inner: f(self.inner),
+            sleep: self.sleep,
+            slot: self.slot,
+        }
+    }
+}
+
+impl<B> Body for DeadlineBody<B>
+where
+    B: Body,
+    B::Error: Into<Error>,
+{
+    type Data = B::Data;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        if let Some(sleep) = this.sleep.as_pin_mut() {
+            if sleep.poll(cx).is_ready() {
+                tracing::warn!("request deadline exceeded, aborting response body");
+                return Poll::Ready(Some(Err(Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "request deadline exceeded",
+                )))));
+            }
+        }
+
+        this.inner
+            .poll_frame(cx)
+            .map(|frame| frame.map(|frame| frame.map_err(Into::into)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -3,6 +3,9 @@ pub enum Error {
     #[error(transparent)]
     Http(#[from] http::Error),
 
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
     #[error(transparent)]
     Hyper(#[from] hyper::Error),
 
@@ -15,6 +18,12 @@ pub enum Error {
     #[error("Forbidden")]
     Forbidden,
 
+    #[error("Loop detected")]
+    LoopDetected,
+
+    #[error("Gateway timeout: {0}")]
+    GatewayTimeout(String),
+
     #[error(transparent)]
     Timeout(#[from] tokio::time::error::Elapsed),
 }
@@ -1,19 +1,23 @@
-use crate::BIN_NAME;
+use crate::{CertOptions, KeyAlgorithm, BIN_NAME};
 use rcgen::{
     date_time_ymd, BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
     KeyUsagePurpose, SanType,
 };
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
 /// Get self-signed certificate and key.
-pub fn get_self_signed_cert() -> crate::Result<(Vec<u8>, Vec<u8>)> {
-    let temp_dir = std::env::temp_dir().join(BIN_NAME);
-    if !temp_dir.exists() {
-        tracing::info!("Creating temp cert directory: {}", temp_dir.display());
-        std::fs::create_dir_all(&temp_dir)?;
+pub fn get_self_signed_cert(options: &CertOptions) -> crate::Result<(Vec<u8>, Vec<u8>)> {
+    let dir = cert_dir(options);
+    if !dir.exists() {
+        tracing::info!("Creating cert directory: {}", dir.display());
     }
+    create_cert_dir(&dir)?;
 
-    let cert_path = temp_dir.join("cert.pem");
-    let key_path = temp_dir.join("key.pem");
+    let (cert_path, key_path) = cert_paths(&dir);
     if cert_path.exists() && key_path.exists() {
         let cert = std::fs::read_to_string(cert_path)?;
         let key = std::fs::read(key_path)?;
@@ -22,19 +26,137 @@ pub fn get_self_signed_cert() -> crate::Result<(Vec<u8>, Vec<u8>)> {
         return Ok((cert.into_bytes(), key));
     }
 
-    let (cert, key) = generate_self_signed()?;
-    std::fs::write(cert_path, &cert)?;
-    std::fs::write(key_path, &key)?;
+    let (cert, key) = generate_self_signed(options)?;
+    store(&dir, &cert, &key)?;
     Ok((cert, key))
 }
 
+/// Returns the directory the self-signed certificate is stored in.
+pub(crate) fn cert_dir(options: &CertOptions) -> PathBuf {
+    options.cert_dir.clone().unwrap_or_else(default_cert_dir)
+}
+
+/// Returns the state directory of the user: `/var/lib/vproxy` for root,
+/// `$XDG_STATE_HOME/vproxy` or `~/.local/state/vproxy` for others. Never a
+/// shared directory such as `/tmp`, where another user could create it first.
+///
+/// The home directory is the one of the effective user rather than `$HOME`,
+/// which still names the home of root once `--user` has switched users.
+#[cfg(unix)]
+fn default_cert_dir() -> PathBuf {
+    use nix::unistd::{geteuid, User};
+
+    let system = || Path::new("/var/lib").join(BIN_NAME);
+    let uid = geteuid();
+    if uid.is_root() {
+        return system();
+    }
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| {
+            let user = User::from_uid(uid).ok().flatten()?;
+            Some(user.dir.join(".local/state"))
+        })
+        .map_or_else(system, |dir| dir.join(BIN_NAME))
+}
+
+/// Returns the local application data directory of the user.
+#[cfg(not(unix))]
+fn default_cert_dir() -> PathBuf {
+    std::env::var_os("LOCALAPPDATA")
+        .map_or_else(std::env::temp_dir, PathBuf::from)
+        .join(BIN_NAME)
+}
+
+/// Creates `dir` accessible by the owner only if it does not exist, and
+/// refuses a directory that is a symbolic link, belongs to another user or
+/// that others can write to, as they could replace the key.
+pub(crate) fn create_cert_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        let metadata = std::fs::symlink_metadata(dir)?;
+        let refuse = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("cert directory {} {}", dir.display(), reason),
+            )
+        };
+        if !metadata.is_dir() {
+            return Err(refuse("is not a directory"));
+        }
+        if metadata.uid() != nix::unistd::geteuid().as_raw() {
+            return Err(refuse("belongs to another user"));
+        }
+        if metadata.mode() & 0o022 != 0 {
+            return Err(refuse("is writable by other users"));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    std::fs::create_dir_all(dir)
+}
+
+/// Returns the paths of the certificate and key stored in `dir`.
+pub(crate) fn cert_paths(dir: &Path) -> (PathBuf, PathBuf) {
+    (dir.join("cert.pem"), dir.join("key.pem"))
+}
+
+/// Writes the certificate and key to `dir`, the key readable by the owner only.
+pub(crate) fn store(dir: &Path, cert: &[u8], key: &[u8]) -> crate::Result<()> {
+    create_cert_dir(dir)?;
+    let (cert_path, key_path) = cert_paths(dir);
+    write_new(&cert_path, cert, 0o644)?;
+    // The key is never readable by others, not even until it is written
+    write_new(&key_path, key, 0o600)?;
+    Ok(())
+}
+
+/// Replaces the file at `path` with a new one of `mode` holding `contents`.
+/// The file is created exclusively without following symbolic links, so
+/// nothing planted at `path` is written through.
+fn write_new(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode).custom_flags(nix::libc::O_NOFOLLOW);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    io::Write::write_all(&mut options.open(path)?, contents)
+}
+
 /// Generate self-signed certificate and key.
-fn generate_self_signed() -> crate::Result<(Vec<u8>, Vec<u8>)> {
+pub(crate) fn generate_self_signed(options: &CertOptions) -> crate::Result<(Vec<u8>, Vec<u8>)> {
     let mut params = CertificateParams::default();
-    params.not_before = date_time_ymd(1975, 1, 1);
-    params.not_after = date_time_ymd(4096, 1, 1);
+    match options.cert_days {
+        Some(days) => {
+            let now = time::OffsetDateTime::now_utc();
+            params.not_before = now - time::Duration::days(1);
+            params.not_after = now + time::Duration::days(days.into());
+        }
+        None => {
+            params.not_before = date_time_ymd(1975, 1, 1);
+            params.not_after = date_time_ymd(4096, 1, 1);
+        }
+    }
+    let common_name = options.cert_cn.as_deref().unwrap_or(BIN_NAME);
     let mut distinguished_name = DistinguishedName::new();
-    distinguished_name.push(DnType::CommonName, BIN_NAME);
+    distinguished_name.push(DnType::CommonName, common_name);
     distinguished_name.push(DnType::OrganizationName, BIN_NAME);
     params.distinguished_name = distinguished_name;
     params.key_usages = vec![
@@ -44,9 +166,24 @@ fn generate_self_signed() -> crate::Result<(Vec<u8>, Vec<u8>)> {
         KeyUsagePurpose::KeyEncipherment,
     ];
     params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
-    params.subject_alt_names = vec![SanType::DnsName("localhost".try_into()?)];
+    params.subject_alt_names = if options.cert_san.is_empty() {
+        vec![SanType::DnsName("localhost".try_into()?)]
+    } else {
+        options
+            .cert_san
+            .iter()
+            .map(|name| match name.parse::<IpAddr>() {
+                Ok(ip) => Ok(SanType::IpAddress(ip)),
+                Err(_) => Ok(SanType::DnsName(name.as_str().try_into()?)),
+            })
+            .collect::<crate::Result<_>>()?
+    };
 
-    let key_pair = KeyPair::generate()?;
+    let key_pair = KeyPair::generate_for(match options.cert_key_algorithm {
+        KeyAlgorithm::EcdsaP256 => &rcgen::PKCS_ECDSA_P256_SHA256,
+        KeyAlgorithm::EcdsaP384 => &rcgen::PKCS_ECDSA_P384_SHA384,
+        KeyAlgorithm::Ed25519 => &rcgen::PKCS_ED25519,
+    })?;
     let cert = params.self_signed(&key_pair)?;
 
     let cert = cert.pem();
@@ -0,0 +1,122 @@
+use super::error::Error;
+use crate::memory::{MemoryAccount, Reservation};
+use hyper::body::{Body, Buf, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Frames held by the connection after being handed to it: hyper queues up
+/// to 16 buffers for writing before it polls the body again.
+const IN_FLIGHT_FRAMES: usize = 16;
+
+pin_project! {
+    /// A body whose frames are accounted against the memory of a connection.
+    ///
+    /// The connection may still hold a frame in its write queue after polling
+    /// the next one, so the last [`IN_FLIGHT_FRAMES`] data frames stay
+    /// reserved, until the body is dropped at the latest. If a frame does not
+    /// fit under the cap, the body fails and the connection is aborted.
+    pub struct MeteredBody<B> {
+        #[pin]
+        inner: B,
+        account: MemoryAccount,
+        reservations: VecDeque<Reservation>,
+    }
+}
+
+impl<B> MeteredBody<B> {
+    /// Wraps `inner`, reserving its frames against `account`.
+    pub fn new(inner: B, account: MemoryAccount) -> Self {
+        Self {
+            inner,
+            account,
+            reservations: VecDeque::new(),
+        }
+    }
+}
+
+impl<B> Body for MeteredBody<B>
+where
+    B: Body,
+    B::Error: Into<Error>,
+{
+    type Data = B::Data;
+    type Error = Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+
+        match this.inner.poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                let len = frame.data_ref().map_or(0, Buf::remaining);
+                if len == 0 {
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+                if this.reservations.len() >= IN_FLIGHT_FRAMES {
+                    this.reservations.pop_front();
+                }
+                match this.account.reserve(len) {
+                    Ok(reservation) => {
+                        this.reservations.push_back(reservation);
+                        Poll::Ready(Some(Ok(frame)))
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            "{}, aborting response body ({} bytes in use)",
+                            err,
+                            this.account.used()
+                        );
+                        Poll::Ready(Some(Err(err.into())))
+                    }
+                }
+            }
+            poll => poll.map(|frame| frame.map(|frame| frame.map_err(Into::into))),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, StreamBody};
+
+    fn body(
+        frames: usize,
+        account: MemoryAccount,
+    ) -> impl Body<Data = Bytes, Error = Error> + Unpin {
+        let frames =
+            (0..frames).map(|_| Ok::<_, std::io::Error>(Frame::data(Bytes::from(vec![0; 10]))));
+        MeteredBody::new(StreamBody::new(tokio_stream::iter(frames)), account)
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_frames() {
+        let account = MemoryAccount::new(None);
+        let mut metered = body(IN_FLIGHT_FRAMES + 4, account.clone());
+        while metered.frame().await.is_some() {}
+        assert_eq!(account.used(), IN_FLIGHT_FRAMES * 10);
+        drop(metered);
+        assert_eq!(account.used(), 0);
+
+        let account = MemoryAccount::new(Some(25));
+        let mut metered = body(3, account.clone());
+        assert!(metered.frame().await.is_some_and(|frame| frame.is_ok()));
+        assert!(metered.frame().await.is_some_and(|frame| frame.is_ok()));
+        assert!(metered.frame().await.is_some_and(|frame| frame.is_err()));
+    }
+}
@@ -1,7 +1,27 @@
 mod accept;
+pub(crate) mod cache;
+pub mod deadline;
 pub mod error;
-mod genca;
+#[cfg(feature = "https")]
+pub(crate) mod genca;
+mod metered;
 mod server;
+#[cfg(feature = "https")]
 mod tls;
 
-pub use server::{HttpServer, HttpsServer};
+pub use server::HttpServer;
+#[cfg(feature = "https")]
+pub use server::HttpsServer;
+
+/// Loads a TLS certificate and private key the way the HTTPS server does.
+#[cfg(feature = "https")]
+pub(crate) fn check_tls(cert: &std::path::Path, key: &std::path::Path) -> std::io::Result<()> {
+    tls::RustlsConfig::from_pem_chain_file(cert, key).map(|_| ())
+}
+
+/// Returns the connections the HTTPS servers rejected for not starting with
+/// a TLS ClientHello.
+#[cfg(feature = "https")]
+pub(crate) fn tls_sniff_rejected() -> u64 {
+    tls::sniff_rejected()
+}
@@ -1,73 +1,169 @@
 use auth::Authenticator;
-use http::uri::Authority;
-use tracing::{instrument, Level};
+use http::uri::{Authority, Scheme, Uri};
+use tracing::{instrument, Instrument, Level};
 
 use super::accept::Accept;
+use super::cache::{HttpCache, Lookup};
 use super::error::Error;
-use super::genca;
-use super::tls::{RustlsAcceptor, RustlsConfig};
+use super::metered::MeteredBody;
+#[cfg(feature = "https")]
+use super::{
+    genca,
+    tls::{RustlsAcceptor, RustlsConfig},
+};
 use crate::http::accept::DefaultAcceptor;
-use crate::serve::{Context, Serve};
-use crate::{connect::Connector, extension::Extension};
+use crate::serve::{accept, Context, Serve};
+#[cfg(feature = "https")]
+use crate::CertOptions;
+use crate::{
+    connect::Connector,
+    destination::normalize_host,
+    extension::Extension,
+    hooks::{AuthAttempt, Decision, Protocol, ProxyRequest, SharedHooks, TunnelClose},
+    memory::MemoryAccount,
+    pcap::Capture,
+    relay::{self, Progress},
+    rules::EgressRules,
+    schedule::Schedule,
+    stats::{self, ActiveConnection, ConnectionId, Tunnel, TunnelInfo},
+    HttpOptions, TcpOptions, BIN_NAME,
+};
 use bytes::Bytes;
-use http::StatusCode;
+use cidr::IpCidr;
+use http::{HeaderValue, StatusCode};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::service::service_fn;
 use hyper::{body::Incoming, upgrade::Upgraded, Method, Request, Response};
 use hyper_util::{
-    rt::{TokioExecutor, TokioIo},
+    client::legacy::connect::HttpInfo,
+    rt::{TokioExecutor, TokioIo, TokioTimer},
     server::conn::auto::Builder,
 };
+use socket2::SockRef;
+#[cfg(feature = "https")]
 use std::path::PathBuf;
 use std::{
-    io::{self, ErrorKind},
-    net::SocketAddr,
-    sync::Arc,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
     time::Duration,
 };
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::Notify,
 };
 
+/// Header that asks for the routing decision of a CONNECT request instead of a tunnel.
+const DRY_RUN_HEADER: &str = "x-vproxy-dry-run";
+
+/// Header reporting the egress address of a tunnel or forwarded request.
+const EGRESS_IP_HEADER: &str = "x-vproxy-egress-ip";
+
+/// Pseudonym of this process in the `Via` header of forwarded requests, which
+/// recognizes requests that come back to it through a chain of proxies.
+static VIA_PSEUDONYM: LazyLock<String> =
+    LazyLock::new(|| format!("{}-{:08x}", BIN_NAME, rand::random::<u32>()));
+
 /// HTTP server.
 pub struct HttpServer<A = DefaultAcceptor> {
     acceptor: A,
     builder: Builder<TokioExecutor>,
     listener: TcpListener,
     http_proxy: Handler,
+    tcp: TcpOptions,
+    max_conn_memory: Option<usize>,
+    /// How long to wait for the PROXY protocol header, if one is expected.
+    proxy_protocol: Option<Duration>,
+    /// Sources allowed to send that header, any if empty.
+    proxy_protocol_from: Arc<[IpCidr]>,
+    /// How long a connection stays open without a new request.
+    keep_alive_timeout: Option<Duration>,
+    hooks: SharedHooks,
+    stats: stats::Listener,
 }
 
 impl HttpServer {
     /// Create a http server from Context.
-    pub fn new(ctx: Context) -> std::io::Result<Self> {
+    pub fn new(ctx: Context, opts: HttpOptions) -> std::io::Result<Self> {
         let socket = if ctx.bind.is_ipv4() {
             tokio::net::TcpSocket::new_v4()?
         } else {
             tokio::net::TcpSocket::new_v6()?
         };
         socket.set_reuseaddr(true)?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        socket.set_reuseport(crate::uring::enabled())?;
         socket.bind(ctx.bind)?;
 
         let listener = socket.listen(ctx.concurrent as u32)?;
         let acceptor = DefaultAcceptor::new();
         let mut builder = Builder::new(TokioExecutor::new());
-        let http_proxy = Handler::from(ctx);
+        let tcp = ctx.tcp;
+        let max_conn_memory = ctx.max_conn_memory;
+        let hooks = ctx.hooks.clone();
+        let stats = ctx.stats.clone();
+        let handshake_timeout = Duration::from_secs(ctx.handshake_timeout.max(1));
+        let proxy_protocol = ctx.proxy_protocol.then_some(handshake_timeout);
+        let proxy_protocol_from = ctx.proxy_protocol_from.clone();
+        let header_read_timeout = opts
+            .header_read_timeout
+            .map_or(handshake_timeout, |secs| Duration::from_secs(secs.max(1)));
+        let keep_alive_timeout = opts
+            .keep_alive_timeout
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
 
         builder
             .http1()
             .title_case_headers(true)
-            .preserve_header_case(true);
+            .preserve_header_case(true)
+            .timer(TokioTimer::new())
+            .header_read_timeout(header_read_timeout)
+            .keep_alive(opts.keep_alive_timeout != Some(0))
+            .max_headers(opts.max_headers);
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .max_concurrent_streams(opts.max_concurrent_streams);
+        if let Some(size) = opts.max_header_size {
+            builder
+                .http2()
+                .max_header_list_size(u32::try_from(size).unwrap_or(u32::MAX));
+        }
+
+        // Hyper's read buffer is the bulk of what an HTTP connection buffers
+        // and bounds the size of request headers, it cannot be smaller than
+        // 8 KiB.
+        if let Some(max) = [max_conn_memory, opts.max_header_size]
+            .into_iter()
+            .flatten()
+            .min()
+        {
+            builder.http1().max_buf_size(max.max(8192));
+        }
+
+        let http_proxy = Handler::new(ctx, opts);
 
         Ok(Self {
             acceptor,
             builder,
             listener,
             http_proxy,
+            tcp,
+            max_conn_memory,
+            proxy_protocol,
+            proxy_protocol_from,
+            keep_alive_timeout,
+            hooks,
+            stats,
         })
     }
 }
 
+#[cfg(feature = "https")]
 impl<A> HttpServer<A>
 where
     A: Accept<TcpStream> + Clone + Send + Sync + 'static,
@@ -81,32 +177,44 @@ where
             builder: self.builder,
             listener: self.listener,
             http_proxy: self.http_proxy,
+            tcp: self.tcp,
+            max_conn_memory: self.max_conn_memory,
+            proxy_protocol: self.proxy_protocol,
+            proxy_protocol_from: self.proxy_protocol_from,
+            keep_alive_timeout: self.keep_alive_timeout,
+            hooks: self.hooks,
+            stats: self.stats,
         }
     }
 }
 
 /// HTTPS server.
+#[cfg(feature = "https")]
 pub struct HttpsServer<A = RustlsAcceptor> {
     http: HttpServer<A>,
 }
 
+#[cfg(feature = "https")]
 impl HttpsServer {
     /// Create a https server from Context.
     pub fn new(
         ctx: Context,
+        opts: HttpOptions,
         tls_cert: Option<PathBuf>,
         tls_key: Option<PathBuf>,
+        tls_sniff_timeout: u64,
+        self_signed: CertOptions,
     ) -> std::io::Result<HttpsServer<RustlsAcceptor>> {
         let config = match (tls_cert, tls_key) {
             (Some(cert), Some(key)) => RustlsConfig::from_pem_chain_file(cert, key),
             _ => {
-                let (cert, key) = genca::get_self_signed_cert().map_err(io_other)?;
+                let (cert, key) = genca::get_self_signed_cert(&self_signed).map_err(io_other)?;
                 RustlsConfig::from_pem(cert, key)
             }
         }?;
 
-        let acceptor = RustlsAcceptor::new(config, ctx.connect_timeout);
-        HttpServer::new(ctx).map(|http| Self {
+        let acceptor = RustlsAcceptor::new(config, ctx.handshake_timeout, tls_sniff_timeout);
+        HttpServer::new(ctx, opts).map(|http| Self {
             http: http.acceptor(acceptor),
         })
     }
@@ -124,96 +232,268 @@ where
             self.listener.local_addr()?
         );
 
-        let mut incoming = self.listener;
+        let incoming = self.listener;
         let acceptor = self.acceptor;
         let builder = self.builder;
         let proxy = self.http_proxy;
+        let tcp = self.tcp;
+        let max_conn_memory = self.max_conn_memory;
+        let proxy_protocol = self.proxy_protocol;
+        let proxy_protocol_from = self.proxy_protocol_from;
+        let keep_alive_timeout = self.keep_alive_timeout;
+        let hooks = self.hooks;
+        let stats = self.stats;
 
         loop {
-            let (tcp_stream, socket_addr) = tokio::select! {
+            let (mut tcp_stream, socket_addr) = tokio::select! {
                 biased;
-                result = accept(&mut incoming) => result,
+                result = accept(&incoming) => result,
             };
 
+            // Behind a load balancer the client is only known from the header
+            if proxy_protocol.is_none() && !hooks.on_connect(Protocol::Http, socket_addr) {
+                tracing::debug!("Connection from {} rejected by hook", socket_addr);
+                continue;
+            }
+
+            if let Err(err) = tcp.apply(SockRef::from(&tcp_stream)) {
+                tracing::trace!("Failed to apply tcp options: {}", err);
+            }
+
             let proxy = proxy.clone();
             let acceptor = acceptor.clone();
             let builder = builder.clone();
+            let account = MemoryAccount::new(max_conn_memory);
+            let hooks = hooks.clone();
+            let proxy_protocol_from = proxy_protocol_from.clone();
 
-            tokio::spawn(async move {
-                if let Ok(stream) = acceptor.accept(tcp_stream).await {
-                    if let Err(err) = builder
-                        .serve_connection_with_upgrades(
-                            TokioIo::new(stream),
-                            service_fn(|req| {
-                                <Handler as Clone>::clone(&proxy).proxy(socket_addr, req)
-                            }),
-                        )
-                        .await
+            let Some(active) = ActiveConnection::admit(&stats).await else {
+                tracing::debug!(
+                    "Connection from {} rejected, too many connections",
+                    socket_addr
+                );
+                continue;
+            };
+
+            tokio::spawn(ConnectionId::new().scope(async move {
+                let _active = active;
+                let mut socket_addr = socket_addr;
+                if let Some(within) = proxy_protocol {
+                    socket_addr = match crate::proxy_protocol::accept(
+                        &mut tcp_stream,
+                        socket_addr,
+                        within,
+                        &proxy_protocol_from,
+                    )
+                    .await
                     {
+                        Ok(client) => client,
+                        Err(err) => {
+                            tracing::debug!("Connection from {} dropped: {}", socket_addr, err);
+                            return;
+                        }
+                    };
+                    if !hooks.on_connect(Protocol::Http, socket_addr) {
+                        tracing::debug!("Connection from {} rejected by hook", socket_addr);
+                        return;
+                    }
+                }
+
+                if let Ok(stream) = acceptor.accept(tcp_stream).await {
+                    let in_flight = Arc::new(InFlight::default());
+                    let conn = builder.serve_connection_with_upgrades(
+                        TokioIo::new(stream),
+                        service_fn(|req| {
+                            let busy = in_flight.start();
+                            let response = <Handler as Clone>::clone(&proxy).proxy(
+                                socket_addr,
+                                account.clone(),
+                                req,
+                            );
+                            async move {
+                                // The request is in flight until its response
+                                // body has been sent
+                                let response = response.await?;
+                                Ok::<_, Error>(response.map(|body| {
+                                    body.map_frame(move |frame| {
+                                        let _busy = &busy;
+                                        frame
+                                    })
+                                    .boxed()
+                                }))
+                            }
+                        }),
+                    );
+                    let mut conn = std::pin::pin!(conn);
+
+                    // Once the connection is idle for the keep-alive timeout,
+                    // it is closed. A new request stops the clock.
+                    let mut closing = false;
+                    let result = loop {
+                        tokio::select! {
+                            result = conn.as_mut() => break result,
+                            _ = in_flight.changed.notified() => {}
+                            _ = tokio::time::sleep(keep_alive_timeout.unwrap_or_default()),
+                                if keep_alive_timeout.is_some() && !closing && in_flight.idle() =>
+                            {
+                                conn.as_mut().graceful_shutdown();
+                                closing = true;
+                            }
+                        }
+                    };
+                    if let Err(err) = result {
                         tracing::error!("Failed to serve connection: {:?}", err);
                     }
                 }
-            });
+            }));
         }
     }
 }
 
+#[cfg(feature = "https")]
 impl Serve for HttpsServer {
     async fn serve(self) -> std::io::Result<()> {
         self.http.serve().await
     }
 }
 
-async fn accept(listener: &mut TcpListener) -> (TcpStream, SocketAddr) {
-    loop {
-        match listener.accept().await {
-            Ok(value) => return value,
-            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
-        }
-    }
-}
-
+#[cfg(feature = "https")]
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-pub(super) fn io_other<E: Into<BoxError>>(error: E) -> io::Error {
-    io::Error::new(ErrorKind::Other, error)
+#[cfg(feature = "https")]
+pub(super) fn io_other<E: Into<BoxError>>(error: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, error)
 }
 
 #[derive(Clone)]
 struct Handler {
     authenticator: Arc<Authenticator>,
+    schedule: Arc<Schedule>,
     connector: Connector,
+    rules: Option<Arc<EgressRules>>,
+    allow_dry_run: bool,
+    echo_egress_ip: bool,
+    response_header_timeout: Option<u64>,
+    request_deadline: Option<u64>,
+    connect_only: bool,
+    allow_http_port: Arc<[u16]>,
+    cache: Option<Arc<HttpCache>>,
+    hooks: SharedHooks,
 }
 
-impl From<Context> for Handler {
-    fn from(ctx: Context) -> Self {
-        let authenticator = match (ctx.auth.username, ctx.auth.password) {
-            (Some(username), Some(password)) => Authenticator::Password { username, password },
+impl Handler {
+    fn new(ctx: Context, opts: HttpOptions) -> Self {
+        let schedule = ctx.auth.schedule();
+        let authenticator = match (ctx.auth.username, ctx.auth.password, ctx.users, ctx.webhook) {
+            (_, _, _, Some(webhook)) => Authenticator::Webhook(webhook),
+            (_, _, Some(users), None) => Authenticator::Users(users),
+            (Some(username), Some(password), None, None) => {
+                Authenticator::Password { username, password }
+            }
 
             _ => Authenticator::None,
         };
 
         Handler {
             authenticator: Arc::new(authenticator),
+            schedule: Arc::new(schedule),
             connector: ctx.connector,
+            rules: ctx.rules,
+            allow_dry_run: opts.allow_dry_run,
+            echo_egress_ip: opts.echo_egress_ip,
+            response_header_timeout: opts.response_header_timeout,
+            request_deadline: opts.request_deadline,
+            connect_only: opts.connect_only,
+            allow_http_port: opts.allow_http_port.into(),
+            cache: ctx.cache,
+            hooks: ctx.hooks,
         }
     }
 }
 
 impl Handler {
-    #[instrument(skip(self), level = Level::DEBUG)]
+    #[instrument(skip(self, account), level = Level::DEBUG)]
     async fn proxy(
-        self,
+        mut self,
         socket: SocketAddr,
-        req: Request<Incoming>,
-    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+        account: MemoryAccount,
+        mut req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+        if is_looped(req.headers()) {
+            tracing::warn!("request from {} looped back to this proxy", socket);
+            return Ok(Error::LoopDetected.try_into()?);
+        }
+
         // Check if the client is authorized
-        let extension = match self.authenticator.authenticate(req.headers()).await {
-            Ok(extension) => extension,
+        let authenticated = self.authenticator.authenticate(req.headers(), socket).await;
+        self.hooks.on_auth_attempt(&AuthAttempt {
+            protocol: Protocol::Http,
+            peer: socket,
+            username: auth::username(req.headers()).as_deref(),
+            success: authenticated.is_ok(),
+            presented: req
+                .headers()
+                .contains_key(http::header::PROXY_AUTHORIZATION),
+        });
+        let (extension, login) = match authenticated {
+            Ok(authenticated) => authenticated,
             // If the client is not authorized, return an error response
             Err(e) => return Ok(e.try_into()?),
         };
 
+        if let Err(err) = normalize_target(&mut req) {
+            tracing::info!("request from {} refused: {}", socket, err);
+            let mut resp = Response::new(full("invalid destination host"));
+            *resp.status_mut() = StatusCode::BAD_REQUEST;
+            return Ok(resp);
+        }
+
+        if let Some(login) = login.as_ref().filter(|login| !login.pool.is_empty()) {
+            self.connector = self.connector.with_pool(&login.pool);
+        }
+        if let (Some(rules), Some(host)) = (&self.rules, req.uri().host()) {
+            self.connector = rules.apply(self.connector, host);
+        }
+
+        if !self.schedule.allows_now() {
+            tracing::info!("{} is outside of the access schedule", socket);
+            return Ok(Error::Forbidden.try_into()?);
+        }
+
+        let target = match req.uri().authority() {
+            Some(authority) if authority.port().is_some() => authority.to_string(),
+            _ => {
+                let port = match req.uri().scheme() {
+                    Some(scheme) if *scheme == Scheme::HTTPS => 443,
+                    _ => 80,
+                };
+                format!("{}:{}", req.uri().host().unwrap_or_default(), port)
+            }
+        };
+        if Method::CONNECT != req.method() && !self.forwards(&target) {
+            tracing::info!("plain HTTP request from {} to {} refused", socket, target);
+            return Ok(Error::Forbidden.try_into()?);
+        }
+        let username = login.as_ref().map(|login| login.username.as_str());
+        self.connector = self
+            .connector
+            .with_owner(stats::owner(username, socket.ip()));
+        let extension = self.connector.client_extension(socket.ip(), extension);
+        let extension = match self.hooks.on_request(&ProxyRequest {
+            protocol: Protocol::Http,
+            peer: socket,
+            username,
+            target: &target,
+            extension,
+        }) {
+            Decision::Allow => extension,
+            Decision::Egress(extension) => extension,
+            Decision::Deny => {
+                tracing::info!("request from {} to {} denied", socket, target);
+                return Ok(Error::Forbidden.try_into()?);
+            }
+        };
+
         if Method::CONNECT == req.method() {
             // Received an HTTP request like:
             // ```
@@ -229,18 +509,68 @@ impl Handler {
             // connection be upgraded, so we can't return a response inside
             // `on_upgrade` future.
             if let Some(authority) = req.uri().authority().cloned() {
-                tokio::task::spawn(async move {
-                    match hyper::upgrade::on(req).await {
-                        Ok(upgraded) => {
-                            if let Err(e) = self.tunnel(upgraded, authority, extension).await {
-                                tracing::warn!("server io error: {}", e);
+                if let Err(err) = self.connector.check_host(authority.host()) {
+                    tracing::info!("request from {} refused: {}", socket, err);
+                    return Ok(Error::Forbidden.try_into()?);
+                }
+                if self.is_dry_run(socket, &req) {
+                    return self.dry_run(authority, extension).await;
+                }
+
+                // The egress address is only known once connected, so the
+                // reply waits for the connection when it reports it.
+                let mut resp = Response::new(empty());
+                let server = if self.echo_egress_ip {
+                    let server = match self
+                        .connector
+                        .tcp_connector()
+                        .connect_with_authority(authority.clone(), extension)
+                        .await
+                    {
+                        Ok(server) => server,
+                        Err(err) => {
+                            tracing::info!(
+                                "CONNECT from {} to {} failed: {}",
+                                socket,
+                                authority,
+                                err
+                            );
+                            let status = match err.kind() {
+                                std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                                _ => StatusCode::BAD_GATEWAY,
                             };
+                            let mut resp = Response::new(empty());
+                            *resp.status_mut() = status;
+                            return Ok(resp);
+                        }
+                    };
+                    insert_egress_ip(&mut resp, server.local_addr().ok().map(|addr| addr.ip()));
+                    Some(server)
+                } else {
+                    None
+                };
+
+                let username = login.map(|login| login.username);
+                tokio::task::spawn(
+                    async move {
+                        match hyper::upgrade::on(req).await {
+                            Ok(upgraded) => {
+                                if let Err(e) = self
+                                    .tunnel(
+                                        upgraded, socket, username, authority, extension, server,
+                                    )
+                                    .await
+                                {
+                                    tracing::warn!("server io error: {}", e);
+                                };
+                            }
+                            Err(e) => tracing::warn!("upgrade error: {}", e),
                         }
-                        Err(e) => tracing::warn!("upgrade error: {}", e),
                     }
-                });
+                    .in_current_span(),
+                );
 
-                Ok(Response::new(empty()))
+                Ok(resp)
             } else {
                 tracing::warn!("CONNECT host is not socket addr: {:?}", req.uri());
                 let mut resp = Response::new(full("CONNECT must be to a socket address"));
@@ -249,40 +579,214 @@ impl Handler {
                 Ok(resp)
             }
         } else {
-            self.connector
+            let version = match req.version() {
+                http::Version::HTTP_10 => "1.0",
+                http::Version::HTTP_2 => "2",
+                _ => "1.1",
+            };
+            if let Ok(via) = HeaderValue::from_str(&format!("{} {}", version, *VIA_PSEUDONYM)) {
+                req.headers_mut().append(http::header::VIA, via);
+            }
+            let pending = match &self.cache {
+                Some(cache) => match cache.lookup(&mut req).await {
+                    Lookup::Hit(res) => return Ok(res),
+                    Lookup::Forward(pending) => Some(pending),
+                    Lookup::Bypass => None,
+                },
+                None => None,
+            };
+            let res = self
+                .connector
                 .http_connector()
+                .response_header_timeout(self.response_header_timeout)
+                .request_deadline(self.request_deadline)
                 .send_request(req, extension)
-                .await
-                .map(|res| res.map(|b| b.boxed()))
+                .await;
+            let res = res.map(|mut res| {
+                if self.echo_egress_ip {
+                    let egress = res
+                        .extensions()
+                        .get::<HttpInfo>()
+                        .map(|info| info.local_addr().ip());
+                    insert_egress_ip(&mut res, egress);
+                }
+                res
+            });
+            let res = match (res, &self.cache, pending) {
+                (Ok(res), Some(cache), Some(pending)) => Ok(cache.complete(pending, res).await),
+                (res, ..) => res.map(|res| res.map(BodyExt::boxed)),
+            };
+            res.map(|res| res.map(|b| MeteredBody::new(b, account).boxed()))
+                .or_else(|err| match err {
+                    // Answer with the details instead of dropping the connection
+                    Error::GatewayTimeout(_) => Ok(err.try_into()?),
+                    Error::Io(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                        tracing::info!("request from {} refused: {}", socket, err);
+                        Ok(Error::Forbidden.try_into()?)
+                    }
+                    err => Err(err),
+                })
         }
     }
 
-    // Create a TCP connection to host:port, build a tunnel between the connection
-    // and the upgraded connection
+    /// Whether plain HTTP requests may be forwarded to `target`.
+    fn forwards(&self, target: &str) -> bool {
+        if self.connect_only {
+            return false;
+        }
+        self.allow_http_port.is_empty()
+            || target
+                .rsplit_once(':')
+                .and_then(|(_, port)| port.parse().ok())
+                .is_some_and(|port| self.allow_http_port.contains(&port))
+    }
+
+    /// Whether the request asks for a dry run and the client may get one.
+    ///
+    /// Clients are trusted when they passed password authentication, or
+    /// connect from a loopback address when authentication is disabled.
+    fn is_dry_run(&self, socket: SocketAddr, req: &Request<Incoming>) -> bool {
+        if !self.allow_dry_run || !req.headers().contains_key(DRY_RUN_HEADER) {
+            return false;
+        }
+
+        match *self.authenticator {
+            Authenticator::Password { .. }
+            | Authenticator::Users(_)
+            | Authenticator::Webhook(_) => true,
+            Authenticator::None => socket.ip().is_loopback(),
+        }
+    }
+
+    /// Describes how a CONNECT to `authority` would be routed, without connecting.
+    async fn dry_run(
+        &self,
+        authority: Authority,
+        extension: Extension,
+    ) -> Result<Response<BoxBody<Bytes, Error>>, Error> {
+        let resolved = match self
+            .connector
+            .dns()
+            .lookup(authority.host(), authority.port_u16().unwrap_or(443))
+            .await
+        {
+            Ok(addrs) => addrs
+                .into_iter()
+                .map(|addr| addr.ip().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(err) => format!("error: {err}"),
+        };
+
+        let egress = self
+            .connector
+            .tcp_connector()
+            .egress_addr(extension)
+            .map(|ip| ip.map_or_else(|| "default".to_owned(), |ip| ip.to_string()))
+            .unwrap_or_else(|err| format!("error: {err}"));
+
+        let body = format!(
+            "authority: {authority}\nextension: {extension:?}\nresolved: {resolved}\negress: {egress}\n"
+        );
+
+        Response::builder()
+            .header(http::header::CONTENT_TYPE, "text/plain")
+            .body(full(body))
+            .map_err(Into::into)
+    }
+
+    // Create a TCP connection to host:port unless `server` is already connected,
+    // build a tunnel between the connection and the upgraded connection
     async fn tunnel(
         &self,
         upgraded: Upgraded,
+        socket: SocketAddr,
+        username: Option<String>,
         authority: Authority,
         extension: Extension,
+        server: Option<TcpStream>,
     ) -> std::io::Result<()> {
-        let mut server = self
-            .connector
-            .tcp_connector()
-            .connect_with_authority(authority, extension)
-            .await?;
+        let target = authority.to_string();
+        let mut server = match server {
+            Some(server) => server,
+            None => {
+                self.connector
+                    .tcp_connector()
+                    .connect_with_authority(authority, extension)
+                    .await?
+            }
+        };
 
-        match tokio::io::copy_bidirectional(&mut TokioIo::new(upgraded), &mut server).await {
-            Ok((from_client, from_server)) => {
+        let progress = Arc::new(Progress::default());
+        let _tunnel = Tunnel::register(TunnelInfo {
+            protocol: Protocol::Http,
+            client: socket,
+            username: username.clone(),
+            target: target.clone(),
+            egress: server.local_addr().ok().map(|addr| addr.ip()),
+            server: server.peer_addr().ok(),
+            progress: progress.clone(),
+            client_first: true,
+            started: std::time::Instant::now(),
+        });
+
+        // Plain HTTP connections are backed by a `TcpStream`, which lets the
+        // relay use zero-copy splicing. Anything else (e.g. TLS) is copied.
+        let result = match upgraded.downcast::<TokioIo<TcpStream>>() {
+            Ok(parts) => {
+                let mut client = parts.io.into_inner();
+                match server.write_all(&parts.read_buf).await {
+                    Ok(()) => {
+                        progress
+                            .a_to_b
+                            .fetch_add(parts.read_buf.len() as u64, Ordering::Relaxed);
+                        relay::copy_bidirectional(&mut client, &mut server, &progress)
+                            .await
+                            .map(|(from_client, from_server)| {
+                                (from_client + parts.read_buf.len() as u64, from_server)
+                            })
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Err(upgraded) => {
+                let mut client = TokioIo::new(upgraded);
+                // The capture of a TLS client shows the decrypted tunnel
+                match server
+                    .peer_addr()
+                    .ok()
+                    .and_then(|addr| Capture::open(socket, addr))
+                {
+                    Some(capture) => {
+                        relay::copy_captured(&mut client, &mut server, capture, &progress).await
+                    }
+                    None => relay::copy_buffered(&mut client, &mut server, None, &progress).await,
+                }
+            }
+        };
+
+        let (from_client, from_server) = match result {
+            Ok(copied) => {
                 tracing::info!(
                     "client wrote {} bytes and received {} bytes",
-                    from_client,
-                    from_server
+                    copied.0,
+                    copied.1
                 );
+                copied
             }
             Err(err) => {
                 tracing::trace!("tunnel error: {}", err);
+                progress.copied()
             }
-        }
+        };
+        self.hooks.on_tunnel_close(&TunnelClose {
+            protocol: Protocol::Http,
+            peer: socket,
+            username,
+            target,
+            sent: from_client,
+            received: from_server,
+        });
 
         drop(server);
 
@@ -290,29 +794,105 @@ impl Handler {
     }
 }
 
-fn empty() -> BoxBody<Bytes, hyper::Error> {
+/// Reports the egress address `egress` in a response header.
+fn insert_egress_ip<B>(resp: &mut Response<B>, egress: Option<IpAddr>) {
+    if let Some(Ok(value)) = egress.map(|ip| HeaderValue::from_str(&ip.to_canonical().to_string()))
+    {
+        resp.headers_mut().insert(EGRESS_IP_HEADER, value);
+    }
+}
+
+/// Rewrites the host of the request target into its normalized form, see
+/// [`normalize_host`].
+fn normalize_target<B>(req: &mut Request<B>) -> std::io::Result<()> {
+    let Some(authority) = req.uri().authority() else {
+        return Ok(());
+    };
+    let host = normalize_host(authority.host())?;
+    if host == authority.host() {
+        return Ok(());
+    }
+
+    let authority = match authority.port_u16() {
+        Some(port) => format!("{host}:{port}"),
+        None => host,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.authority = Some(authority.parse().map_err(std::io::Error::other)?);
+    *req.uri_mut() = Uri::from_parts(parts).map_err(std::io::Error::other)?;
+    Ok(())
+}
+
+/// Whether a `Via` header names this process, so the request went through it
+/// before.
+fn is_looped(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::VIA)
+        .iter()
+        .filter_map(|via| via.to_str().ok())
+        .flat_map(|via| via.split(','))
+        .any(|hop| hop.split_whitespace().nth(1) == Some(VIA_PSEUDONYM.as_str()))
+}
+
+/// Requests of a connection whose response has not been sent yet.
+#[derive(Default)]
+struct InFlight {
+    count: AtomicUsize,
+    /// Notified when a request starts and when the last one finishes.
+    changed: Notify,
+}
+
+/// A request counted by [`InFlight`] until it is dropped.
+struct Busy(Arc<InFlight>);
+
+impl InFlight {
+    fn start(self: &Arc<Self>) -> Busy {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.changed.notify_one();
+        Busy(self.clone())
+    }
+
+    fn idle(&self) -> bool {
+        self.count.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.changed.notify_one();
+        }
+    }
+}
+
+fn empty() -> BoxBody<Bytes, Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
         .boxed()
 }
 
-fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
+fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, Error> {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
 mod auth {
-    use super::{empty, Error};
-    use crate::extension::Extension;
+    use super::{empty, full, Error};
+    use crate::{
+        authz::AuthWebhook,
+        extension::Extension,
+        users::{Login, Pool, Users},
+    };
     use base64::Engine;
     use bytes::Bytes;
     use http::{header, HeaderMap, Response, StatusCode};
     use http_body_util::combinators::BoxBody;
+    use std::{net::SocketAddr, sync::Arc};
 
-    impl TryInto<Response<BoxBody<Bytes, hyper::Error>>> for Error {
+    impl TryInto<Response<BoxBody<Bytes, Error>>> for Error {
         type Error = http::Error;
-        fn try_into(self) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Self::Error> {
+        fn try_into(self) -> Result<Response<BoxBody<Bytes, Error>>, Self::Error> {
             match self {
                 Error::ProxyAuthenticationRequired => Response::builder()
                     .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
@@ -321,6 +901,13 @@ mod auth {
                 Error::Forbidden => Response::builder()
                     .status(StatusCode::FORBIDDEN)
                     .body(empty()),
+                Error::LoopDetected => Response::builder()
+                    .status(StatusCode::LOOP_DETECTED)
+                    .body(empty()),
+                Error::GatewayTimeout(details) => Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(full(details)),
                 _ => Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
                     .body(empty()),
@@ -335,12 +922,20 @@ mod auth {
         None,
         /// Password authentication with a username, password, and IP whitelist.
         Password { username: String, password: String },
+        /// Password authentication against the users of a credential file.
+        Users(Arc<Users>),
+        /// Password authentication by an external endpoint.
+        Webhook(Arc<AuthWebhook>),
     }
 
     impl Authenticator {
-        pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Extension, Error> {
+        pub async fn authenticate(
+            &self,
+            headers: &HeaderMap,
+            socket: SocketAddr,
+        ) -> Result<(Extension, Option<Login>), Error> {
             match self {
-                Authenticator::None => Ok(Extension::default()),
+                Authenticator::None => Ok((Extension::default(), None)),
                 Authenticator::Password {
                     username, password, ..
                 } => {
@@ -362,15 +957,58 @@ mod auth {
                         let extensions = Extension::try_from(username, auth_username)
                             .await
                             .map_err(|_| Error::Forbidden)?;
-                        Ok(extensions)
+                        let login = Login {
+                            username: auth_username.to_owned(),
+                            pool: Pool::default(),
+                        };
+                        Ok((extensions, Some(login)))
                     } else {
                         Err(Error::Forbidden)
                     }
                 }
+                Authenticator::Users(users) => {
+                    let auth_str = option_ext(headers).ok_or(Error::ProxyAuthenticationRequired)?;
+                    let (auth_username, auth_password) = auth_str
+                        .rsplit_once(':')
+                        .ok_or(Error::ProxyAuthenticationRequired)?;
+
+                    let (username, pool) = users
+                        .authenticate(auth_username, auth_password)
+                        .await
+                        .ok_or(Error::Forbidden)?;
+                    let extensions = Extension::try_from(&username, auth_username)
+                        .await
+                        .map_err(|_| Error::Forbidden)?;
+                    let login = Login {
+                        username: auth_username.to_owned(),
+                        pool,
+                    };
+                    Ok((extensions, Some(login)))
+                }
+                Authenticator::Webhook(webhook) => {
+                    let auth_str = option_ext(headers).ok_or(Error::ProxyAuthenticationRequired)?;
+                    let (auth_username, auth_password) = auth_str
+                        .rsplit_once(':')
+                        .ok_or(Error::ProxyAuthenticationRequired)?;
+
+                    let (extensions, login) = webhook
+                        .authenticate(auth_username, auth_password, socket.ip())
+                        .await
+                        .ok_or(Error::Forbidden)?;
+                    Ok((extensions, Some(login)))
+                }
             }
         }
     }
 
+    /// Returns the username of the `Proxy-Authorization` header.
+    pub fn username(headers: &HeaderMap) -> Option<String> {
+        let auth_str = option_ext(headers)?;
+        auth_str
+            .rsplit_once(':')
+            .map(|(username, _)| username.to_owned())
+    }
+
     fn option_ext(headers: &HeaderMap) -> Option<String> {
         let basic_auth = headers
             .get(header::PROXY_AUTHORIZATION)
@@ -384,3 +1022,37 @@ mod auth {
         String::from_utf8(auth_bytes).ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_looped() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!is_looped(&headers));
+
+        headers.append(http::header::VIA, HeaderValue::from_static("1.1 squid"));
+        assert!(!is_looped(&headers));
+
+        let via = format!("1.0 edge, 1.1 {} (comment)", *VIA_PSEUDONYM);
+        headers.append(http::header::VIA, HeaderValue::from_str(&via).unwrap());
+        assert!(is_looped(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight() {
+        let in_flight = Arc::new(InFlight::default());
+        assert!(in_flight.idle());
+
+        let first = in_flight.start();
+        let second = in_flight.start();
+        in_flight.changed.notified().await;
+        drop(first);
+        assert!(!in_flight.idle());
+
+        drop(second);
+        assert!(in_flight.idle());
+        in_flight.changed.notified().await;
+    }
+}
@@ -3,7 +3,8 @@
 use super::RustlsConfig;
 use pin_project_lite::pin_project;
 use std::io::{Error, ErrorKind};
-use std::time::Duration;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
 use std::{
     fmt,
     future::Future,
@@ -21,18 +22,28 @@ pin_project! {
         #[pin]
         inner: AcceptFuture<F, I>,
         config: Option<RustlsConfig>,
+        client: Option<IpAddr>,
     }
 }
 
 impl<F, I> RustlsAcceptorFuture<F, I> {
-    pub(crate) fn new(future: F, config: RustlsConfig, handshake_timeout: Duration) -> Self {
+    pub(crate) fn new(
+        future: F,
+        config: RustlsConfig,
+        handshake_timeout: Duration,
+        client: Option<IpAddr>,
+    ) -> Self {
         let inner = AcceptFuture::Inner {
             future,
             handshake_timeout,
         };
         let config = Some(config);
 
-        Self { inner, config }
+        Self {
+            inner,
+            config,
+            client,
+        }
     }
 }
 
@@ -53,6 +64,7 @@ pin_project! {
         Accept {
             #[pin]
             future: Timeout<Accept<I>>,
+            started: Instant,
         },
     }
 }
@@ -87,14 +99,21 @@ where
 
                             this.inner.set(AcceptFuture::Accept {
                                 future: timeout(handshake_timeout, future),
+                                started: Instant::now(),
                             });
                         }
                         Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                         Poll::Pending => return Poll::Pending,
                     }
                 }
-                AcceptFutureProj::Accept { future } => match future.poll(cx) {
+                AcceptFutureProj::Accept { future, started } => match future.poll(cx) {
                     Poll::Ready(Ok(Ok(stream))) => {
+                        #[cfg(feature = "metrics")]
+                        if let Some(client) = this.client {
+                            crate::metrics::observe_tls_handshake(*client, started.elapsed());
+                        }
+                        #[cfg(not(feature = "metrics"))]
+                        let _ = (this.client, started);
                         return Poll::Ready(Ok(stream));
                     }
                     Poll::Ready(Ok(Err(e))) => return Poll::Ready(Err(e)),
@@ -1,20 +1,24 @@
 pub mod future;
+mod sniff;
+
+pub(crate) use self::sniff::rejected as sniff_rejected;
+pub use self::sniff::ClientHelloAcceptor;
 
 use self::future::RustlsAcceptorFuture;
-use crate::{
-    http::accept::{Accept, DefaultAcceptor},
-    http::server::io_other,
-};
+use crate::{http::accept::Accept, http::server::io_other};
 use rustls_pemfile::Item;
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use std::time::Duration;
 use std::{fmt, io, path::Path, sync::Arc};
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
 use tokio_rustls::{rustls::ServerConfig, server::TlsStream};
 
 /// Tls acceptor using rustls.
 #[derive(Clone)]
-pub struct RustlsAcceptor<A = DefaultAcceptor> {
+pub struct RustlsAcceptor<A = ClientHelloAcceptor> {
     inner: A,
     config: RustlsConfig,
     handshake_timeout: Duration,
@@ -22,8 +26,11 @@ pub struct RustlsAcceptor<A = DefaultAcceptor> {
 
 impl RustlsAcceptor {
     /// Create a new rustls acceptor.
-    pub fn new(config: RustlsConfig, timeout: u64) -> Self {
-        let inner = DefaultAcceptor::new();
+    ///
+    /// Connections whose first bytes are not a TLS ClientHello within
+    /// `sniff_timeout` milliseconds are rejected before the handshake starts.
+    pub fn new(config: RustlsConfig, timeout: u64, sniff_timeout: u64) -> Self {
+        let inner = ClientHelloAcceptor::new(sniff_timeout);
         let handshake_timeout = Duration::from_secs(timeout);
 
         Self {
@@ -34,19 +41,20 @@ impl RustlsAcceptor {
     }
 }
 
-impl<A, I> Accept<I> for RustlsAcceptor<A>
+impl<A> Accept<TcpStream> for RustlsAcceptor<A>
 where
-    A: Accept<I>,
+    A: Accept<TcpStream>,
     A::Stream: AsyncRead + AsyncWrite + Unpin,
 {
     type Stream = TlsStream<A::Stream>;
     type Future = RustlsAcceptorFuture<A::Future, A::Stream>;
 
-    fn accept(&self, stream: I) -> Self::Future {
+    fn accept(&self, stream: TcpStream) -> Self::Future {
+        let client = stream.peer_addr().ok().map(|addr| addr.ip());
         let inner_future = self.inner.accept(stream);
         let config = self.config.clone();
 
-        RustlsAcceptorFuture::new(inner_future, config, self.handshake_timeout)
+        RustlsAcceptorFuture::new(inner_future, config, self.handshake_timeout, client)
     }
 }
 
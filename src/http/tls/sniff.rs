@@ -0,0 +1,102 @@
+//! Accept-time TLS ClientHello sniffing.
+
+use crate::http::accept::Accept;
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::net::TcpStream;
+
+/// TLS record content type of a handshake message.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// Major version byte of every SSL 3.0 / TLS record.
+const RECORD_VERSION_MAJOR: u8 = 0x03;
+
+/// Handshake message type of a ClientHello.
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+/// Connections rejected by every acceptor of the process since startup.
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the connections rejected for not starting with a ClientHello.
+pub(crate) fn rejected() -> u64 {
+    REJECTED.load(Ordering::Relaxed)
+}
+
+/// Acceptor that peeks at the first bytes of a connection and rejects it
+/// unless they look like a TLS ClientHello.
+///
+/// Plain HTTP scanners are dropped right away instead of holding a handshake
+/// slot until the rustls handshake times out. Nothing is consumed from the
+/// stream, so accepted connections are handed to rustls untouched.
+#[derive(Clone, Debug)]
+pub struct ClientHelloAcceptor {
+    timeout: Option<Duration>,
+}
+
+impl ClientHelloAcceptor {
+    /// Create a new acceptor waiting up to `timeout` milliseconds for the
+    /// first bytes. A timeout of `0` disables sniffing.
+    pub fn new(timeout: u64) -> Self {
+        Self {
+            timeout: (timeout > 0).then(|| Duration::from_millis(timeout)),
+        }
+    }
+}
+
+impl Accept<TcpStream> for ClientHelloAcceptor {
+    type Stream = TcpStream;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn accept(&self, stream: TcpStream) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let Some(timeout) = this.timeout else {
+                return Ok(stream);
+            };
+
+            let mut buf = [0u8; 6];
+            let accepted = match tokio::time::timeout(timeout, stream.peek(&mut buf)).await {
+                Ok(Ok(len)) => is_client_hello(&buf[..len]),
+                Ok(Err(err)) => return Err(err),
+                Err(_) => false,
+            };
+
+            if accepted {
+                return Ok(stream);
+            }
+
+            let rejected = REJECTED.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::debug!(
+                "Rejected non-TLS connection from {:?} ({} rejected so far)",
+                stream.peer_addr().ok(),
+                rejected
+            );
+
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "connection did not start with a TLS ClientHello",
+            ))
+        })
+    }
+}
+
+/// Checks whether `buf` can be the beginning of a TLS record carrying a
+/// ClientHello. Only the bytes available are checked.
+fn is_client_hello(buf: &[u8]) -> bool {
+    if buf.is_empty() {
+        return false;
+    }
+
+    [
+        (0, CONTENT_TYPE_HANDSHAKE),
+        (1, RECORD_VERSION_MAJOR),
+        (5, HANDSHAKE_CLIENT_HELLO),
+    ]
+    .iter()
+    .all(|&(index, expected)| buf.get(index).map_or(true, |&byte| byte == expected))
+}
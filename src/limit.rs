@@ -0,0 +1,169 @@
+//! Per-client request rate limiting.
+//!
+//! Every client IP, or /64 for IPv6, gets a token bucket refilled at the
+//! configured rate. HTTP requests, SOCKS5 commands and rejected credentials
+//! each take a token, and a request finding the bucket empty is denied. A
+//! client that runs dry can additionally be banned for a while through the
+//! login bans of [`crate::ban`], refusing its connections outright and
+//! listing it in the admin API.
+
+use crate::{
+    ban::{client_key, Bans},
+    hooks::{AuthAttempt, Decision, Hooks, ProxyRequest},
+};
+use cidr::IpCidr;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Token bucket of a client.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Rate limiter keyed by client IP, or /64 for IPv6.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    /// Bans clients exceeding the limit get, and for how long.
+    ban: Option<(Arc<Bans>, Duration)>,
+    buckets: Mutex<HashMap<IpCidr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` requests per second with bursts of
+    /// `burst`, banning clients that exceed it in `ban` for its duration.
+    pub(crate) fn new(rate: u32, burst: u32, ban: Option<(Arc<Bans>, Duration)>) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst.max(1) as f64,
+            ban,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token for `ip`, returning whether one was available.
+    fn take(&self, ip: IpAddr, now: Instant) -> bool {
+        if let Some((bans, _)) = &self.ban {
+            if bans.banned(ip, now) {
+                return false;
+            }
+        }
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return true;
+        };
+        let bucket = buckets.entry(client_key(ip)).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        if let Some((bans, ban)) = &self.ban {
+            tracing::warn!(
+                "{} exceeded the rate limit, banned for {:?}",
+                client_key(ip),
+                ban
+            );
+            bans.ban(ip, *ban, now);
+        }
+        false
+    }
+
+    /// Forgets clients whose buckets have refilled, every `interval`.
+    pub(crate) async fn prune(&self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let now = Instant::now();
+            if let Ok(mut buckets) = self.buckets.lock() {
+                buckets.retain(|_, bucket| {
+                    let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                    bucket.tokens + elapsed * self.rate < self.burst
+                });
+            }
+        }
+    }
+}
+
+impl Hooks for RateLimiter {
+    fn on_auth_attempt(&self, attempt: &AuthAttempt<'_>) {
+        // A challenged HTTP request is not a failed authentication
+        if !attempt.success && attempt.presented {
+            self.take(attempt.peer.ip(), Instant::now());
+        }
+    }
+
+    fn on_request(&self, request: &ProxyRequest<'_>) -> Decision {
+        if self.take(request.peer.ip(), Instant::now()) {
+            Decision::Allow
+        } else {
+            tracing::info!("{} exceeded the rate limit", request.peer);
+            Decision::Deny
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::Protocol;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_refill_and_ban() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+
+        let limiter = RateLimiter::new(1, 2, None);
+        assert!(limiter.take(ip, now));
+        assert!(limiter.take(ip, now));
+        assert!(!limiter.take(ip, now));
+        assert!(limiter.take(ip, now + Duration::from_secs(1)));
+
+        let bans = Arc::new(Bans::new(None, Duration::ZERO, Duration::ZERO));
+        let limiter = RateLimiter::new(1, 1, Some((bans.clone(), Duration::from_secs(60))));
+        assert!(limiter.take(ip, now));
+        assert!(!limiter.take(ip, now));
+        assert!(bans.banned(ip, now + Duration::from_secs(30)));
+        assert!(!limiter.take(ip, now + Duration::from_secs(30)));
+        assert!(!bans.banned(ip, now + Duration::from_secs(61)));
+        assert!(bans.list().starts_with("192.0.2.1 "));
+
+        // The addresses of an IPv6 /64 share their bucket
+        let limiter = RateLimiter::new(1, 1, None);
+        assert!(limiter.take("2001:db8:1::1".parse().unwrap(), now));
+        assert!(!limiter.take("2001:db8:1::2".parse().unwrap(), now));
+        assert!(limiter.take("2001:db8:2::1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn test_challenge_takes_no_token() {
+        let peer = "192.0.2.1:40000".parse::<SocketAddr>().unwrap();
+        let limiter = RateLimiter::new(1, 1, None);
+        let attempt = |presented| AuthAttempt {
+            protocol: Protocol::Http,
+            peer,
+            username: None,
+            success: false,
+            presented,
+        };
+
+        limiter.on_auth_attempt(&attempt(false));
+        assert!(limiter.take(peer.ip(), Instant::now()));
+        limiter.on_auth_attempt(&attempt(true));
+        assert!(!limiter.take(peer.ip(), Instant::now()));
+    }
+}
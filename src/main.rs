@@ -1,17 +1,10 @@
-mod connect;
+mod config;
 #[cfg(target_family = "unix")]
 mod daemon;
-mod error;
-mod extension;
-mod http;
 mod oneself;
-#[cfg(target_os = "linux")]
-mod route;
-mod serve;
-mod socks;
 
-use clap::{Args, Parser, Subcommand};
-use std::{net::SocketAddr, path::PathBuf};
+use clap::{CommandFactory, Parser, Subcommand};
+use vproxy::{BootArgs, Result, BIN_NAME};
 
 #[cfg(feature = "jemalloc")]
 #[global_allocator]
@@ -33,10 +26,6 @@ static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 #[global_allocator]
 static ALLOC: rpmalloc::RpMalloc = rpmalloc::RpMalloc;
 
-const BIN_NAME: &str = env!("CARGO_PKG_NAME");
-
-type Result<T, E = error::Error> = std::result::Result<T, E>;
-
 #[derive(Parser)]
 #[clap(author, version, about, arg_required_else_help = true)]
 #[command(args_conflicts_with_subcommands = true)]
@@ -50,25 +39,71 @@ pub enum Commands {
     /// Run server
     Run(BootArgs),
 
+    /// Validate the configuration without starting the server
+    Check(BootArgs),
+
     /// Start server daemon
     #[cfg(target_family = "unix")]
-    Start(BootArgs),
+    Start {
+        #[clap(flatten)]
+        instance: daemon::Instance,
+
+        /// Restart the server with exponential backoff when it crashes
+        #[clap(long)]
+        supervise: bool,
+
+        #[clap(flatten)]
+        args: BootArgs,
+    },
 
     /// Restart server daemon
     #[cfg(target_family = "unix")]
-    Restart(BootArgs),
+    Restart {
+        #[clap(flatten)]
+        instance: daemon::Instance,
+
+        /// Restart the server with exponential backoff when it crashes
+        #[clap(long)]
+        supervise: bool,
+
+        #[clap(flatten)]
+        args: BootArgs,
+    },
 
     /// Stop server daemon
     #[cfg(target_family = "unix")]
-    Stop,
+    Stop {
+        #[clap(flatten)]
+        instance: daemon::Instance,
+    },
 
-    /// Show server daemon process
+    /// Show server daemon process and its open connections
     #[cfg(target_family = "unix")]
-    PS,
+    PS {
+        #[clap(flatten)]
+        instance: daemon::Instance,
+
+        /// Print the process and its connections as JSON
+        #[clap(long)]
+        json: bool,
+    },
 
     /// Show server daemon log
     #[cfg(target_family = "unix")]
-    Log,
+    Log {
+        #[clap(flatten)]
+        instance: daemon::Instance,
+
+        #[clap(flatten)]
+        opts: daemon::LogArgs,
+    },
+
+    /// Manage the self-signed CA used by the https server
+    #[cfg(feature = "https")]
+    Ca {
+        #[clap(subcommand)]
+        command: vproxy::ca::CaCommand,
+    },
 
     /// Modify server installation
     #[clap(name = "self")]
@@ -76,91 +111,83 @@ pub enum Commands {
         #[clap(subcommand)]
         command: Oneself,
     },
-}
 
-/// Choose the authentication type
-#[derive(Args, Clone)]
-pub struct AuthMode {
-    /// Authentication username
-    #[clap(short, long, requires = "password")]
-    pub username: Option<String>,
+    /// Check that random addresses of a CIDR can be bound and routed
+    ProbeCidr(vproxy::probe::ProbeArgs),
 
-    /// Authentication password
-    #[clap(short, long, requires = "username")]
-    pub password: Option<String>,
-}
+    /// Drive load through a running proxy and report latency and throughput
+    Bench(vproxy::bench::BenchArgs),
 
-#[derive(Subcommand, Clone)]
-pub enum Proxy {
-    /// Http server
-    Http {
-        /// Authentication type
-        #[clap(flatten)]
-        auth: AuthMode,
+    /// Print the shell completion script
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
     },
 
-    /// Https server
-    Https {
-        /// Authentication type
-        #[clap(flatten)]
-        auth: AuthMode,
-
-        /// TLS certificate file
-        #[clap(long, requires = "tls_key")]
-        tls_cert: Option<PathBuf>,
-
-        /// TLS private key file
-        #[clap(long, requires = "tls_cert")]
-        tls_key: Option<PathBuf>,
+    /// Inspect the configuration of the server
+    Config {
+        #[clap(subcommand)]
+        command: Config,
     },
 
-    /// Socks5 server
-    Socks5 {
-        /// Authentication type
-        #[clap(flatten)]
-        auth: AuthMode,
+    /// Debugging utilities
+    Debug {
+        #[clap(subcommand)]
+        command: Debug,
     },
 }
 
-#[derive(Args, Clone)]
-pub struct BootArgs {
-    /// Log level e.g. trace, debug, info, warn, error
-    #[clap(long, env = "VPROXY_LOG", default_value = "info")]
-    log: tracing::Level,
-
-    /// Bind address
-    #[clap(short, long, default_value = "0.0.0.0:1080")]
-    bind: SocketAddr,
-
-    /// Connection timeout in seconds
-    #[clap(short = 'T', long, default_value = "10")]
-    connect_timeout: u64,
-
-    /// Concurrent connections
-    #[clap(short, long, default_value = "1024")]
-    concurrent: usize,
-
-    /// IP-CIDR, e.g. 2001:db8::/32
-    #[clap(short = 'i', long)]
-    cidr: Option<cidr::IpCidr>,
-
-    /// IP-CIDR-Range, e.g. 64
-    #[clap(short = 'r', long)]
-    cidr_range: Option<u8>,
-
-    /// Fallback address
-    #[clap(short, long)]
-    fallback: Option<std::net::IpAddr>,
-
-    #[clap(subcommand)]
-    proxy: Proxy,
+#[derive(Subcommand, Clone)]
+pub enum Config {
+    /// Print the effective configuration, from flags, environment and
+    /// defaults, as TOML
+    Dump(BootArgs),
 }
 
 #[derive(Subcommand, Clone)]
+pub enum Debug {
+    /// Run micro benchmarks of extension parsing and IP assignment
+    MicroBench {
+        /// Iterations per sample
+        #[clap(short, long, default_value = "100000")]
+        iterations: u32,
+
+        /// Number of samples per benchmark
+        #[clap(short, long, default_value = "20")]
+        samples: u32,
+    },
+}
 
+#[derive(Subcommand, Clone)]
 pub enum Oneself {
     /// Download and install updates to the proxy server
-    Update,
+    Update {
+        /// Releases to update to
+        #[clap(long, value_enum, default_value_t = oneself::Channel::Stable)]
+        channel: oneself::Channel,
+
+        /// Install this release, e.g. 2.1.0, instead of the latest one
+        #[clap(long, value_name = "X.Y.Z")]
+        version: Option<String>,
+
+        /// Install releases without verifying their signature, for builds
+        /// without an update signing key
+        #[clap(long)]
+        no_verify: bool,
+    },
+    /// Restore the version replaced by the last update
+    Rollback,
+    /// Install the proxy server as a systemd service, or a launchd job on
+    /// macOS, running `run` with the arguments after `--`
+    Install {
+        /// Directory the executable is installed to
+        #[clap(long, value_name = "DIR", default_value = "/usr/local/bin")]
+        dir: std::path::PathBuf,
+
+        /// Arguments of `vproxy run`, e.g. `-- -i 2001:db8::/48 http`
+        #[clap(last = true, value_name = "ARGS")]
+        args: Vec<String>,
+    },
     /// Uninstall proxy server
     Uninstall,
 }
@@ -168,20 +195,52 @@ pub enum Oneself {
 fn main() -> Result<()> {
     let opt = Opt::parse();
     match opt.commands {
-        Commands::Run(args) => serve::run(args),
+        Commands::Run(args) => vproxy::run(args),
+        Commands::Check(args) => vproxy::check(args),
         #[cfg(target_family = "unix")]
-        Commands::Start(args) => daemon::start(args),
+        Commands::Start {
+            instance,
+            supervise,
+            args,
+        } => daemon::start(instance, supervise, args),
         #[cfg(target_family = "unix")]
-        Commands::Restart(args) => daemon::restart(args),
+        Commands::Restart {
+            instance,
+            supervise,
+            args,
+        } => daemon::restart(instance, supervise, args),
         #[cfg(target_family = "unix")]
-        Commands::Stop => daemon::stop(),
+        Commands::Stop { instance } => daemon::stop(&instance),
         #[cfg(target_family = "unix")]
-        Commands::PS => daemon::status(),
+        Commands::PS { instance, json } => daemon::status(&instance, json),
         #[cfg(target_family = "unix")]
-        Commands::Log => daemon::log(),
+        Commands::Log { instance, opts } => daemon::log(&instance, &opts),
+        Commands::ProbeCidr(args) => vproxy::probe::probe_cidr(args),
+        Commands::Bench(args) => vproxy::bench::run(args),
+        #[cfg(feature = "https")]
+        Commands::Ca { command } => vproxy::ca::run(command),
         Commands::Oneself { command } => match command {
-            Oneself::Update => oneself::update(),
+            Oneself::Update {
+                channel,
+                version,
+                no_verify,
+            } => oneself::update(channel, version, no_verify),
+            Oneself::Rollback => oneself::rollback(),
+            Oneself::Install { dir, args } => oneself::install(&dir, args),
             Oneself::Uninstall => oneself::uninstall(),
         },
+        Commands::Completions { shell } => {
+            clap_complete::generate(shell, &mut Opt::command(), BIN_NAME, &mut std::io::stdout());
+            Ok(())
+        }
+        Commands::Config { command } => match command {
+            Config::Dump(_) => config::dump(),
+        },
+        Commands::Debug { command } => match command {
+            Debug::MicroBench {
+                iterations,
+                samples,
+            } => vproxy::debug::micro_bench(iterations, samples),
+        },
     }
 }
@@ -0,0 +1,125 @@
+//! Approximate accounting of the bytes buffered by each connection.
+//!
+//! Every connection owns a [`MemoryAccount`]. Buffers held on behalf of the
+//! connection (UDP datagrams in flight, HTTP body frames) are reserved against
+//! the account for as long as they are held, and the connection is terminated
+//! once its cap would be exceeded. The sum over all connections is kept in a
+//! process-wide counter, see [`buffered`], served with its peak and the
+//! number of exceeded caps by the statistics and the `/metrics` endpoint.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Bytes currently buffered by all connections.
+static BUFFERED: AtomicUsize = AtomicUsize::new(0);
+
+/// Highest value `BUFFERED` has reached.
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Reservations refused for exceeding the cap of their connection.
+static EXCEEDED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the usage reporter task has been started.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+/// Returns the number of bytes currently buffered by all connections.
+pub fn buffered() -> usize {
+    BUFFERED.load(Ordering::Relaxed)
+}
+
+/// Returns the highest number of bytes buffered at once.
+pub fn peak() -> usize {
+    PEAK.load(Ordering::Relaxed)
+}
+
+/// Returns the number of reservations refused for exceeding the cap of their
+/// connection, each of which usually terminated it.
+pub fn exceeded() -> u64 {
+    EXCEEDED.load(Ordering::Relaxed)
+}
+
+/// Periodically logs the aggregate memory usage. Only the first call starts
+/// a reporter, later calls return immediately.
+pub async fn report(interval: Duration) {
+    if REPORTING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        tracing::debug!(
+            "Connection buffers: {} bytes in use, {} bytes peak",
+            buffered(),
+            peak()
+        );
+    }
+}
+
+/// Bytes buffered on behalf of a single connection.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryAccount {
+    used: Arc<AtomicUsize>,
+    cap: Option<usize>,
+}
+
+impl MemoryAccount {
+    /// Create an account holding at most `cap` bytes, or unlimited if `None`.
+    pub fn new(cap: Option<usize>) -> Self {
+        Self {
+            used: Arc::new(AtomicUsize::new(0)),
+            cap,
+        }
+    }
+
+    /// Reserves `len` bytes until the returned guard is dropped.
+    ///
+    /// Fails with `OutOfMemory` if the reservation would exceed the cap, in
+    /// which case the connection should be terminated.
+    pub fn reserve(&self, len: usize) -> io::Result<Reservation> {
+        let used = self.used.fetch_add(len, Ordering::Relaxed) + len;
+        if let Some(cap) = self.cap {
+            if used > cap {
+                self.used.fetch_sub(len, Ordering::Relaxed);
+                EXCEEDED.fetch_add(1, Ordering::Relaxed);
+                return Err(io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    format!("connection memory cap of {cap} bytes exceeded"),
+                ));
+            }
+        }
+
+        let total = BUFFERED.fetch_add(len, Ordering::Relaxed) + len;
+        PEAK.fetch_max(total, Ordering::Relaxed);
+
+        Ok(Reservation {
+            used: self.used.clone(),
+            len,
+        })
+    }
+
+    /// Returns the number of bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes reserved against a [`MemoryAccount`], released on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    used: Arc<AtomicUsize>,
+    len: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.len, Ordering::Relaxed);
+        BUFFERED.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,331 @@
+//! Latency histograms, served in the Prometheus text format on the admin
+//! API's `/metrics`.
+//!
+//! DNS resolutions, outbound TCP connects and inbound TLS handshakes are
+//! timed and broken down by address family, and outbound connects also by
+//! whether the egress address came from the CIDR, the fallback address or
+//! the default route. The hits and misses of the relay buffer pool are served
+//! alongside, per buffer size, as are the counters of the statistics and the
+//! state of the subnets probed by the egress health check.
+
+use crate::{health::EgressHealth, pool::TierStats};
+use std::{
+    fmt::Write,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// A timed phase of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Resolving the destination host, cache hits excluded.
+    Dns,
+    /// Connecting to the destination.
+    Connect,
+    /// The TLS handshake of an HTTPS client.
+    TlsHandshake,
+}
+
+/// Where the egress address of an outbound connection came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Egress {
+    Cidr,
+    Fallback,
+    Default,
+}
+
+const PHASES: [(Phase, &str, &str); 3] = [
+    (
+        Phase::Dns,
+        "vproxy_dns_resolution_seconds",
+        "Time spent resolving destination hosts.",
+    ),
+    (
+        Phase::Connect,
+        "vproxy_connect_seconds",
+        "Time spent connecting to destinations.",
+    ),
+    (
+        Phase::TlsHandshake,
+        "vproxy_tls_handshake_seconds",
+        "Time spent on the TLS handshakes of clients.",
+    ),
+];
+
+const FAMILIES: [&str; 2] = ["ipv4", "ipv6"];
+
+const EGRESS: [(Egress, &str); 3] = [
+    (Egress::Cidr, "cidr"),
+    (Egress::Fallback, "fallback"),
+    (Egress::Default, "default"),
+];
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    /// Sum of the observations in microseconds.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+/// One histogram per phase, family and egress source.
+static HISTOGRAMS: [Histogram; PHASES.len() * FAMILIES.len() * EGRESS.len()] =
+    [const { Histogram::new() }; PHASES.len() * FAMILIES.len() * EGRESS.len()];
+
+fn index(phase: Phase, ipv6: bool, egress: Egress) -> usize {
+    let phase = PHASES.iter().position(|(p, ..)| *p == phase).unwrap_or(0);
+    let egress = EGRESS.iter().position(|(e, _)| *e == egress).unwrap_or(0);
+    (phase * FAMILIES.len() + ipv6 as usize) * EGRESS.len() + egress
+}
+
+/// Records that `phase` took `elapsed` for an address of the family of `ip`.
+/// Phases without an egress address are recorded as [`Egress::Default`].
+pub(crate) fn observe(phase: Phase, ip: IpAddr, egress: Egress, elapsed: Duration) {
+    let ipv6 = ip.to_canonical().is_ipv6();
+    let histogram = &HISTOGRAMS[index(phase, ipv6, egress)];
+    let secs = elapsed.as_secs_f64();
+    for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+        if secs <= *bound {
+            bucket.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    histogram.count.fetch_add(1, Ordering::Relaxed);
+    histogram
+        .sum
+        .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Records a resolution that took `elapsed`, under the family of the first
+/// address it returned.
+pub(crate) fn observe_dns(addrs: &[SocketAddr], elapsed: Duration) {
+    if let Some(addr) = addrs.first() {
+        observe(Phase::Dns, addr.ip(), Egress::Default, elapsed);
+    }
+}
+
+/// Records a connect to `target` that took `elapsed`.
+pub(crate) fn observe_connect(target: IpAddr, egress: Egress, elapsed: Duration) {
+    observe(Phase::Connect, target, egress, elapsed);
+}
+
+/// Records a TLS handshake with `client` that took `elapsed`.
+pub(crate) fn observe_tls_handshake(client: IpAddr, elapsed: Duration) {
+    observe(Phase::TlsHandshake, client, Egress::Default, elapsed);
+}
+
+/// A buffer pool series: its name, type, help and per-tier value.
+type PoolSeries = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&TierStats) -> u64,
+);
+
+/// Returns the histograms in the Prometheus text format, along with whether
+/// each subnet probed by the egress health check is alive.
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub(crate) fn render(health: &EgressHealth) -> String {
+    let mut out = String::new();
+    for (phase, name, help) in PHASES {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+
+        for (ipv6, family) in FAMILIES.iter().enumerate() {
+            for (egress, egress_name) in EGRESS {
+                if phase != Phase::Connect && egress != Egress::Default {
+                    continue;
+                }
+                let histogram = &HISTOGRAMS[index(phase, ipv6 == 1, egress)];
+                let labels = if phase == Phase::Connect {
+                    format!("family=\"{family}\",egress=\"{egress_name}\"")
+                } else {
+                    format!("family=\"{family}\"")
+                };
+
+                for (bound, bucket) in BUCKETS.iter().zip(&histogram.buckets) {
+                    let _ = writeln!(
+                        out,
+                        "{name}_bucket{{{labels},le=\"{bound}\"}} {}",
+                        bucket.load(Ordering::Relaxed)
+                    );
+                }
+                let count = histogram.count.load(Ordering::Relaxed);
+                let sum = histogram.sum.load(Ordering::Relaxed) as f64 / 1e6;
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+                let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+                let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+            }
+        }
+    }
+
+    let pool = crate::pool::stats();
+    let series: [PoolSeries; 3] = [
+        (
+            "vproxy_buffer_pool_hits_total",
+            "counter",
+            "Relay buffers served by an idle pooled buffer.",
+            |tier| tier.hits,
+        ),
+        (
+            "vproxy_buffer_pool_misses_total",
+            "counter",
+            "Relay buffers allocated because none was idle.",
+            |tier| tier.misses,
+        ),
+        (
+            "vproxy_buffer_pool_idle_buffers",
+            "gauge",
+            "Relay buffers idle in the pool.",
+            |tier| tier.idle as u64,
+        ),
+    ];
+    for (name, kind, help, value) in series {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for tier in &pool {
+            let _ = writeln!(out, "{name}{{size=\"{}\"}} {}", tier.size, value(tier));
+        }
+    }
+
+    let listeners = crate::stats::listeners();
+    write_series(
+        &mut out,
+        "vproxy_listener_active_connections",
+        "gauge",
+        "Client connections open, per listener.",
+        listeners
+            .iter()
+            .map(|(name, active, _)| (label("listener", name), *active as u64)),
+    );
+    write_series(
+        &mut out,
+        "vproxy_listener_connections_total",
+        "counter",
+        "Client connections accepted, per listener.",
+        listeners
+            .iter()
+            .map(|(name, _, total)| (label("listener", name), *total)),
+    );
+    write_series(
+        &mut out,
+        "vproxy_egress_subnet_up",
+        "gauge",
+        "Whether the last egress health probe of a subnet succeeded.",
+        health
+            .subnets()
+            .into_iter()
+            .map(|(subnet, alive)| (label("subnet", &subnet.to_string()), alive as u64)),
+    );
+    write_series(
+        &mut out,
+        "vproxy_connection_buffered_bytes",
+        "gauge",
+        "Bytes buffered on behalf of the client connections.",
+        [(String::new(), crate::memory::buffered() as u64)],
+    );
+    write_series(
+        &mut out,
+        "vproxy_connection_buffered_peak_bytes",
+        "gauge",
+        "Most bytes buffered on behalf of the client connections at once.",
+        [(String::new(), crate::memory::peak() as u64)],
+    );
+    write_series(
+        &mut out,
+        "vproxy_connection_memory_cap_exceeded_total",
+        "counter",
+        "Buffers refused for exceeding the memory cap of their connection.",
+        [(String::new(), crate::memory::exceeded())],
+    );
+
+    #[cfg(feature = "https")]
+    write_series(
+        &mut out,
+        "vproxy_tls_sniff_rejected_total",
+        "counter",
+        "Connections to the HTTPS server rejected for not starting with a TLS ClientHello.",
+        [(String::new(), crate::http::tls_sniff_rejected())],
+    );
+    out
+}
+
+/// Returns the label `key` set to `value`, escaped for the text format.
+fn label(key: &str, value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("{key}=\"{value}\"")
+}
+
+/// Writes the series `name` with a sample per set of labels, such as
+/// `listener="edge"`, or a single unlabeled sample for empty labels.
+fn write_series(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, u64)>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe() {
+        let ip = "2001:db8::1".parse().unwrap();
+        observe(
+            Phase::Connect,
+            ip,
+            Egress::Fallback,
+            Duration::from_millis(30),
+        );
+
+        let health = EgressHealth::new(1);
+        health.update("2001:db8:1::/64".parse().unwrap(), false);
+        let metrics = render(&health);
+        assert!(metrics.contains(
+            "vproxy_connect_seconds_bucket{family=\"ipv6\",egress=\"fallback\",le=\"0.05\"} 1"
+        ));
+        assert!(metrics.contains(
+            "vproxy_connect_seconds_bucket{family=\"ipv6\",egress=\"fallback\",le=\"0.025\"} 0"
+        ));
+        assert!(metrics.contains("vproxy_dns_resolution_seconds_count{family=\"ipv4\"}"));
+        assert!(metrics.contains("vproxy_buffer_pool_hits_total{size=\"16384\"}"));
+        assert!(metrics.contains("# TYPE vproxy_connection_buffered_bytes gauge"));
+        assert!(metrics.contains("vproxy_egress_subnet_up{subnet=\"2001:db8:1::/64\"} 0"));
+
+        let listener = crate::stats::Listener::register("edge \"1\"");
+        let _active = crate::stats::ActiveConnection::new(&listener);
+        assert!(render(&health)
+            .contains("vproxy_listener_active_connections{listener=\"edge \\\"1\\\"\"} 1"));
+        #[cfg(feature = "https")]
+        assert!(metrics.contains("# TYPE vproxy_tls_sniff_rejected_total counter"));
+    }
+}
@@ -0,0 +1,350 @@
+//! NDP proxy for IPv6 CIDRs routed on-link.
+//!
+//! When the upstream router considers the CIDR on-link instead of routing
+//! it to the host, it resolves every egress address with a neighbor
+//! solicitation before it delivers the replies sent to it. With
+//! `--ndp-proxy <IFACE>` the proxy listens for solicitations on that
+//! interface and answers those for addresses of its IPv6 CIDRs with the
+//! MAC address of the interface, as ndppd does.
+//!
+//! Solicitations go to the solicited-node multicast group of the address,
+//! one group per low 24 bits, too many to join for a large CIDR. The socket
+//! subscribes to all multicast traffic of the interface instead, as long as
+//! it is open. Solicitations with a wrong length or checksum are ignored.
+
+use crate::cidr_file::CidrFile;
+use cidr::IpCidr;
+use nix::{
+    libc,
+    sys::socket::{recvfrom, sendto, LinkAddr, MsgFlags},
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr},
+    os::fd::AsRawFd,
+    sync::Arc,
+};
+
+/// EtherType of IPv6.
+const ETH_P_IPV6: u16 = 0x86dd;
+
+/// IPv6 next header value of ICMPv6.
+const NEXT_HEADER_ICMPV6: u8 = 58;
+
+/// ICMPv6 type of a neighbor solicitation.
+const NEIGHBOR_SOLICITATION: u8 = 135;
+
+/// ICMPv6 type of a neighbor advertisement.
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// NDP option carrying the link-layer address of the target.
+const OPTION_TARGET_LINK_ADDRESS: u8 = 2;
+
+/// Hop limit of every NDP message, which proves it was not routed.
+const HOP_LIMIT: u8 = 255;
+
+/// Length of the IPv6 header.
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Length of a neighbor solicitation or advertisement without options.
+const NDP_MESSAGE_LEN: usize = 24;
+
+/// Solicited and override flags of an advertisement.
+const FLAGS_SOLICITED_OVERRIDE: u8 = 0x60;
+
+/// Addresses the proxy answers solicitations for.
+struct Targets {
+    cidr: Option<IpCidr>,
+    cidr_file: Option<Arc<CidrFile>>,
+}
+
+impl Targets {
+    fn contains(&self, ip: Ipv6Addr) -> bool {
+        let ip = IpAddr::V6(ip);
+        if let Some(cidr) = &self.cidr {
+            return cidr.contains(&ip);
+        }
+        self.cidr_file
+            .as_ref()
+            .is_some_and(|file| file.cidrs().iter().any(|cidr| cidr.contains(&ip)))
+    }
+}
+
+/// Starts answering neighbor solicitations received on `iface` for the
+/// addresses of `cidr`, or of the CIDRs of `cidr_file` as they change.
+pub(crate) fn spawn(
+    iface: &str,
+    cidr: Option<IpCidr>,
+    cidr_file: Option<Arc<CidrFile>>,
+) -> io::Result<()> {
+    let ifindex = nix::net::if_::if_nametoindex(iface)? as usize;
+    let mac = interface_mac(iface)?;
+
+    let socket = Socket::new(
+        Domain::PACKET,
+        Type::DGRAM,
+        Some(Protocol::from(ETH_P_IPV6.to_be() as i32)),
+    )?;
+    socket.attach_filter(&solicitation_filter())?;
+    receive_all_multicast(&socket, ifindex)?;
+
+    let targets = Targets { cidr, cidr_file };
+    tracing::info!(
+        "NDP proxy answering solicitations on {} with {}",
+        iface,
+        format_mac(&mac)
+    );
+    std::thread::Builder::new()
+        .name("ndp-proxy".to_owned())
+        .spawn(move || run(socket, ifindex, mac, targets))?;
+    Ok(())
+}
+
+/// Makes `iface` deliver every multicast frame to `socket`, solicitations
+/// for all the solicited-node groups included.
+fn receive_all_multicast(socket: &Socket, ifindex: usize) -> io::Result<()> {
+    let request = libc::packet_mreq {
+        mr_ifindex: ifindex as libc::c_int,
+        mr_type: libc::PACKET_MR_ALLMULTI as libc::c_ushort,
+        mr_alen: 0,
+        mr_address: [0; 8],
+    };
+    // SAFETY: the option value points to a `packet_mreq` of the given size
+    // that outlives the call, and the descriptor is owned by `socket`.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_PACKET,
+            libc::PACKET_ADD_MEMBERSHIP,
+            &request as *const libc::packet_mreq as *const libc::c_void,
+            std::mem::size_of::<libc::packet_mreq>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives solicitations and sends the advertisements, until the process
+/// exits.
+fn run(socket: Socket, ifindex: usize, mac: [u8; 6], targets: Targets) {
+    let fd = socket.as_raw_fd();
+    let mut buf = [0; 1500];
+    loop {
+        let (len, from) = match recvfrom::<LinkAddr>(fd, &mut buf) {
+            Ok((len, Some(from))) => (len, from),
+            Ok((_, None)) => continue,
+            Err(err) => {
+                tracing::warn!("NDP proxy failed to receive: {}", err);
+                continue;
+            }
+        };
+        // Solicitations of other interfaces and our own outgoing packets
+        if from.ifindex() != ifindex || from.pkttype() == libc::PACKET_OUTGOING {
+            continue;
+        }
+
+        let Some(advertisement) = advertisement(&buf[..len], mac, |ip| targets.contains(ip)) else {
+            continue;
+        };
+        // Sent back to the link-layer address the solicitation came from
+        if let Err(err) = sendto(fd, &advertisement, &from, MsgFlags::empty()) {
+            tracing::debug!("NDP proxy failed to answer {}: {}", from, err);
+        }
+    }
+}
+
+/// Returns the neighbor advertisement answering the IPv6 packet `packet`,
+/// if it is a valid solicitation for a proxied address.
+fn advertisement<F>(packet: &[u8], mac: [u8; 6], proxied: F) -> Option<Vec<u8>>
+where
+    F: Fn(Ipv6Addr) -> bool,
+{
+    if packet.len() < IPV6_HEADER_LEN
+        || packet[0] >> 4 != 6
+        || packet[6] != NEXT_HEADER_ICMPV6
+        || packet[7] != HOP_LIMIT
+    {
+        return None;
+    }
+    let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    let icmp = packet.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + payload_len)?;
+    if icmp.len() < NDP_MESSAGE_LEN || icmp[0] != NEIGHBOR_SOLICITATION || icmp[1] != 0 {
+        return None;
+    }
+    let source = ipv6(&packet[8..24]);
+    let destination = ipv6(&packet[24..40]);
+    if checksum(&source, &destination, icmp) != 0 || !valid_options(&icmp[NDP_MESSAGE_LEN..]) {
+        return None;
+    }
+    // Duplicate address detection, the address is not ours to defend
+    if source.is_unspecified() {
+        return None;
+    }
+    let target = ipv6(&icmp[8..24]);
+    if !proxied(target) {
+        return None;
+    }
+
+    let mut message = Vec::with_capacity(NDP_MESSAGE_LEN + 8);
+    message.extend_from_slice(&[NEIGHBOR_ADVERTISEMENT, 0, 0, 0]);
+    message.extend_from_slice(&[FLAGS_SOLICITED_OVERRIDE, 0, 0, 0]);
+    message.extend_from_slice(&target.octets());
+    message.extend_from_slice(&[OPTION_TARGET_LINK_ADDRESS, 1]);
+    message.extend_from_slice(&mac);
+    let sum = checksum(&target, &source, &message);
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let mut reply = Vec::with_capacity(IPV6_HEADER_LEN + message.len());
+    reply.extend_from_slice(&[0x60, 0, 0, 0]);
+    reply.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&[NEXT_HEADER_ICMPV6, HOP_LIMIT]);
+    reply.extend_from_slice(&target.octets());
+    reply.extend_from_slice(&source.octets());
+    reply.extend_from_slice(&message);
+    Some(reply)
+}
+
+/// Whether the NDP options `options` all have a non-zero length that fits.
+fn valid_options(mut options: &[u8]) -> bool {
+    while !options.is_empty() {
+        let len = options.get(1).map_or(0, |units| *units as usize * 8);
+        if len == 0 || len > options.len() {
+            return false;
+        }
+        options = &options[len..];
+    }
+    true
+}
+
+/// Computes the ICMPv6 checksum of `message` sent from `source` to
+/// `destination`.
+fn checksum(source: &Ipv6Addr, destination: &Ipv6Addr, message: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(40 + message.len());
+    pseudo.extend_from_slice(&source.octets());
+    pseudo.extend_from_slice(&destination.octets());
+    pseudo.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, NEXT_HEADER_ICMPV6]);
+    pseudo.extend_from_slice(message);
+
+    let mut sum = pseudo
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Classic BPF program keeping only ICMPv6 neighbor solicitations, so the
+/// rest of the IPv6 traffic of the interface is not copied to the socket.
+fn solicitation_filter() -> [libc::sock_filter; 6] {
+    const LD_B_ABS: u16 = (libc::BPF_LD | libc::BPF_B | libc::BPF_ABS) as u16;
+    const JEQ_K: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const RET_K: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    let op = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+    [
+        op(LD_B_ABS, 0, 0, 6),
+        op(JEQ_K, 0, 3, NEXT_HEADER_ICMPV6 as u32),
+        op(LD_B_ABS, 0, 0, IPV6_HEADER_LEN as u32),
+        op(JEQ_K, 0, 1, NEIGHBOR_SOLICITATION as u32),
+        op(RET_K, 0, 0, u16::MAX as u32),
+        op(RET_K, 0, 0, 0),
+    ]
+}
+
+/// Reads the MAC address of `iface`.
+fn interface_mac(iface: &str) -> io::Result<[u8; 6]> {
+    let path = format!("/sys/class/net/{iface}/address");
+    let content = std::fs::read_to_string(&path)?;
+    parse_mac(content.trim()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{path}: not an Ethernet address: {}", content.trim()),
+        )
+    })
+}
+
+fn parse_mac(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut parts = s.split(':');
+    for byte in &mut mac {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn ipv6(bytes: &[u8]) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(bytes);
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the solicitation `source` sends for `target`.
+    fn solicitation(source: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+        let destination = "ff02::1:ff00:42".parse().unwrap();
+        let mut message = vec![NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&target.octets());
+        message.extend_from_slice(&[1, 1, 2, 0, 0x5e, 0, 0, 1]);
+        let sum = checksum(&source, &destination, &message);
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+        let mut packet = vec![0x60, 0, 0, 0, 0, message.len() as u8];
+        packet.extend_from_slice(&[NEXT_HEADER_ICMPV6, HOP_LIMIT]);
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        packet.extend_from_slice(&message);
+        packet
+    }
+
+    #[test]
+    fn test_advertisement() {
+        let cidr = "2001:db8::/64".parse::<IpCidr>().unwrap();
+        let proxied = |ip: Ipv6Addr| cidr.contains(&IpAddr::V6(ip));
+        let mac = parse_mac("02:00:5e:10:00:01").unwrap();
+        let router = "fe80::1".parse().unwrap();
+        let target = "2001:db8::42".parse().unwrap();
+
+        let reply = advertisement(&solicitation(router, target), mac, proxied).unwrap();
+        assert_eq!(reply[IPV6_HEADER_LEN], NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(ipv6(&reply[8..24]), target);
+        assert_eq!(ipv6(&reply[24..40]), router);
+        assert_eq!(&reply[reply.len() - 6..], &mac);
+        // A correct checksum sums to zero with itself included
+        assert_eq!(checksum(&target, &router, &reply[IPV6_HEADER_LEN..]), 0);
+
+        let other = "2001:db8:1::42".parse().unwrap();
+        assert!(advertisement(&solicitation(router, other), mac, proxied).is_none());
+        let dad = solicitation(Ipv6Addr::UNSPECIFIED, target);
+        assert!(advertisement(&dad, mac, proxied).is_none());
+
+        let mut corrupted = solicitation(router, target);
+        corrupted[IPV6_HEADER_LEN + 2] ^= 1;
+        assert!(advertisement(&corrupted, mac, proxied).is_none());
+        let mut truncated = solicitation(router, target);
+        truncated.pop();
+        assert!(advertisement(&truncated, mac, proxied).is_none());
+        let mut zero_option = solicitation(router, target);
+        zero_option[IPV6_HEADER_LEN + NDP_MESSAGE_LEN + 1] = 0;
+        zero_option[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].fill(0);
+        let destination = ipv6(&zero_option[24..40]);
+        let sum = checksum(&router, &destination, &zero_option[IPV6_HEADER_LEN..]);
+        zero_option[IPV6_HEADER_LEN + 2..IPV6_HEADER_LEN + 4].copy_from_slice(&sum.to_be_bytes());
+        assert!(advertisement(&zero_option, mac, proxied).is_none());
+        assert!(parse_mac("02:00:5e:10:00").is_none());
+    }
+}
@@ -0,0 +1,394 @@
+//! Webhook notifications of operational events.
+//!
+//! With `--notify-url` the server POSTs to every given URL when:
+//!
+//! - it starts serving, or stops on a signal or the control socket;
+//! - its listener fails to start, e.g. because the address is in use;
+//! - failed logins reach `--notify-auth-failures` within a minute;
+//! - the egress health check marks a subnet dead, or alive again;
+//! - `--update-check` finds a new release.
+//!
+//! Notifications are JSON objects such as
+//!
+//! ```json
+//! {"event":"egress_health","message":"Egress subnet 2001:db8:1::/64 is dead","host":"proxy-1","time":"2024-01-01T12:00:00.000000Z","subnet":"2001:db8:1::/64","alive":false}
+//! ```
+//!
+//! or Slack messages with `--notify-format slack`. The same notification is
+//! sent at most once per `--notify-dedup` seconds, and at most
+//! `--notify-rate` notifications a minute, later ones being dropped.
+
+use crate::{
+    hooks::{AuthAttempt, Hooks},
+    NotifyFormat, NotifyOptions,
+};
+use cidr::IpCidr;
+use reqwest::{Client, Url};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Webhooks of the process, set once on startup.
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+/// Time allowed for an endpoint to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Window of the rate limit and of the failed login count.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// An operational event.
+pub(crate) enum Event {
+    Started { bind: SocketAddr },
+    Stopping { reason: &'static str },
+    ListenerFailed { bind: SocketAddr, error: String },
+    AuthFailures { failures: u64 },
+    EgressHealth { subnet: IpCidr, alive: bool },
+    UpdateAvailable { version: String },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Started { .. } => "started",
+            Event::Stopping { .. } => "stopping",
+            Event::ListenerFailed { .. } => "listener_failed",
+            Event::AuthFailures { .. } => "auth_failures",
+            Event::EgressHealth { .. } => "egress_health",
+            Event::UpdateAvailable { .. } => "update_available",
+        }
+    }
+
+    /// Returns what tells the event apart from others of the same name, for
+    /// deduplication.
+    fn key(&self) -> String {
+        match self {
+            Event::ListenerFailed { bind, .. } => format!("{}:{}", self.name(), bind),
+            Event::EgressHealth { subnet, alive } => {
+                format!("{}:{}:{}", self.name(), subnet, alive)
+            }
+            Event::UpdateAvailable { version } => format!("{}:{}", self.name(), version),
+            _ => self.name().to_owned(),
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Event::Started { bind } => {
+                format!("vproxy {} started on {}", env!("CARGO_PKG_VERSION"), bind)
+            }
+            Event::Stopping { reason } => format!("vproxy is stopping ({reason})"),
+            Event::ListenerFailed { bind, error } => {
+                format!("Listener on {bind} failed to start: {error}")
+            }
+            Event::AuthFailures { failures } => {
+                format!("{failures} failed logins within a minute")
+            }
+            Event::EgressHealth { subnet, alive } => format!(
+                "Egress subnet {} is {}",
+                subnet,
+                if *alive { "alive again" } else { "dead" }
+            ),
+            Event::UpdateAvailable { version } => format!(
+                "vproxy {} is available, running {}",
+                version,
+                env!("CARGO_PKG_VERSION")
+            ),
+        }
+    }
+
+    fn details(&self) -> serde_json::Value {
+        match self {
+            Event::Started { bind } => serde_json::json!({
+                "bind": bind.to_string(),
+                "version": env!("CARGO_PKG_VERSION"),
+            }),
+            Event::Stopping { reason } => serde_json::json!({ "reason": reason }),
+            Event::ListenerFailed { bind, error } => serde_json::json!({
+                "bind": bind.to_string(),
+                "error": error,
+            }),
+            Event::AuthFailures { failures } => serde_json::json!({ "failures": failures }),
+            Event::EgressHealth { subnet, alive } => serde_json::json!({
+                "subnet": subnet.to_string(),
+                "alive": alive,
+            }),
+            Event::UpdateAvailable { version } => serde_json::json!({
+                "version": version,
+                "current": env!("CARGO_PKG_VERSION"),
+            }),
+        }
+    }
+}
+
+struct Notifier {
+    urls: Vec<Url>,
+    format: NotifyFormat,
+    client: Client,
+    host: Option<String>,
+    dedup: Duration,
+    rate: u32,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// When each notification was last sent, by key.
+    sent: HashMap<String, Instant>,
+    /// Start of the rate limit window and notifications sent within it.
+    window: Option<(Instant, u32)>,
+}
+
+/// Sends notifications to the URLs of `options`, if any.
+pub(crate) fn init(options: &NotifyOptions) -> io::Result<()> {
+    if options.notify_urls.is_empty() || NOTIFIER.get().is_some() {
+        return Ok(());
+    }
+    let client = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(io::Error::other)?;
+
+    #[cfg(unix)]
+    let host = nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok());
+    #[cfg(not(unix))]
+    let host = None;
+
+    tracing::info!(
+        "Notifying {} webhooks of operational events",
+        options.notify_urls.len()
+    );
+    let _ = NOTIFIER.set(Notifier {
+        urls: options.notify_urls.clone(),
+        format: options.notify_format,
+        client,
+        host,
+        dedup: Duration::from_secs(options.notify_dedup),
+        rate: options.notify_rate,
+        state: Mutex::default(),
+    });
+    Ok(())
+}
+
+/// Whether webhooks are configured.
+pub(crate) fn enabled() -> bool {
+    NOTIFIER.get().is_some()
+}
+
+/// Sends a notification of `event` in the background.
+pub(crate) fn send(event: Event) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    if let Some(notifier) = NOTIFIER.get() {
+        if notifier.admit(&event, Instant::now()) {
+            runtime.spawn(notifier.post(event));
+        }
+    }
+}
+
+/// Sends a notification of `event`, returning once the endpoints answered,
+/// for events the process exits after.
+pub(crate) async fn deliver(event: Event) {
+    if let Some(notifier) = NOTIFIER.get() {
+        if notifier.admit(&event, Instant::now()) {
+            notifier.post(event).await;
+        }
+    }
+}
+
+impl Notifier {
+    /// Whether `event` may be sent at `now`, counting it as sent if so.
+    fn admit(&self, event: &Event, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let key = event.key();
+        if state
+            .sent
+            .get(&key)
+            .is_some_and(|sent| now.duration_since(*sent) < self.dedup)
+        {
+            return false;
+        }
+
+        let (start, count) = match state.window {
+            Some((start, count)) if now.duration_since(start) < WINDOW => (start, count),
+            _ => (now, 0),
+        };
+        if count >= self.rate {
+            tracing::debug!(
+                "Notification dropped by the rate limit: {}",
+                event.message()
+            );
+            return false;
+        }
+        state.window = Some((start, count + 1));
+
+        let dedup = self.dedup;
+        state
+            .sent
+            .retain(|_, sent| now.duration_since(*sent) < dedup);
+        state.sent.insert(key, now);
+        true
+    }
+
+    fn payload(&self, event: &Event) -> serde_json::Value {
+        let message = event.message();
+        match self.format {
+            NotifyFormat::Json => {
+                let mut payload = serde_json::json!({
+                    "event": event.name(),
+                    "message": message,
+                    "host": self.host,
+                    "time": crate::ship::rfc3339(SystemTime::now()),
+                });
+                if let (Some(payload), serde_json::Value::Object(details)) =
+                    (payload.as_object_mut(), event.details())
+                {
+                    payload.extend(details);
+                }
+                payload
+            }
+            NotifyFormat::Slack => {
+                let text = match &self.host {
+                    Some(host) => format!("[{host}] {message}"),
+                    None => message,
+                };
+                serde_json::json!({ "text": text })
+            }
+        }
+    }
+
+    async fn post(&self, event: Event) {
+        let payload = self.payload(&event);
+        for url in &self.urls {
+            let res = self
+                .client
+                .post(url.clone())
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            if let Err(err) = res {
+                tracing::warn!("Notification to {} failed: {}", url, err);
+            }
+        }
+    }
+}
+
+/// Hooks notifying when failed logins reach a threshold within a minute.
+pub(crate) struct AuthFailures {
+    threshold: u64,
+    /// Start of the current minute and failed logins within it.
+    window: Mutex<(Instant, u64)>,
+}
+
+impl AuthFailures {
+    pub(crate) fn new(threshold: u64) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+}
+
+impl Hooks for AuthFailures {
+    fn on_auth_attempt(&self, attempt: &AuthAttempt<'_>) {
+        if attempt.success || !attempt.presented {
+            return;
+        }
+        let mut window = self.window.lock().unwrap_or_else(|err| err.into_inner());
+        let now = Instant::now();
+        if now.duration_since(window.0) >= WINDOW {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        if window.1 == self.threshold {
+            send(Event::AuthFailures {
+                failures: self.threshold,
+            });
+        }
+    }
+}
+
+/// Waits for SIGINT or SIGTERM, returning its name and number.
+#[cfg(unix)]
+pub(crate) async fn shutdown_signal() -> io::Result<(&'static str, i32)> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(tokio::select! {
+        _ = interrupt.recv() => ("SIGINT", nix::libc::SIGINT),
+        _ = terminate.recv() => ("SIGTERM", nix::libc::SIGTERM),
+    })
+}
+
+/// Waits for Ctrl-C, returning its name and number.
+#[cfg(not(unix))]
+pub(crate) async fn shutdown_signal() -> io::Result<(&'static str, i32)> {
+    tokio::signal::ctrl_c().await?;
+    Ok(("Ctrl-C", 2))
+}
+
+/// Restores the default action of the signal `number` caught by
+/// [`shutdown_signal`] and raises it again, ending the process as if it had
+/// not been caught.
+#[cfg(unix)]
+pub(crate) fn reraise(number: i32) -> io::Result<()> {
+    use nix::sys::signal::{self, SigHandler, Signal};
+
+    let signal = Signal::try_from(number)?;
+    // SAFETY: restoring the default action installs no handler
+    unsafe { signal::signal(signal, SigHandler::SigDfl) }?;
+    signal::raise(signal)?;
+    Ok(())
+}
+
+/// Returns, the process ending normally after Ctrl-C.
+#[cfg(not(unix))]
+pub(crate) fn reraise(_number: i32) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admit() {
+        let notifier = Notifier {
+            urls: Vec::new(),
+            format: NotifyFormat::Json,
+            client: Client::new(),
+            host: None,
+            dedup: Duration::from_secs(300),
+            rate: 2,
+            state: Mutex::default(),
+        };
+        let now = Instant::now();
+        let dead = |subnet: &str| Event::EgressHealth {
+            subnet: subnet.parse().unwrap(),
+            alive: false,
+        };
+
+        assert!(notifier.admit(&dead("2001:db8:1::/64"), now));
+        // Deduplicated
+        assert!(!notifier.admit(&dead("2001:db8:1::/64"), now));
+        assert!(notifier.admit(&dead("2001:db8:2::/64"), now));
+        // Rate limited
+        assert!(!notifier.admit(&dead("2001:db8:3::/64"), now));
+        assert!(notifier.admit(&dead("2001:db8:3::/64"), now + WINDOW));
+        assert!(notifier.admit(&dead("2001:db8:1::/64"), now + Duration::from_secs(300)));
+
+        let payload = notifier.payload(&dead("2001:db8:1::/64"));
+        assert_eq!(payload["event"], "egress_health");
+        assert_eq!(payload["subnet"], "2001:db8:1::/64");
+        assert_eq!(payload["alive"], false);
+    }
+}
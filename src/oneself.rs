@@ -1,32 +1,102 @@
 use crate::BIN_NAME;
+use clap::ValueEnum;
 use self_update::cargo_crate_version;
 use self_update::update::UpdateStatus;
+use std::path::{Path, PathBuf};
 
-/// Updates the current executable to the latest version available.
+const REPO_OWNER: &str = "0x676e67";
+
+/// Base64 ed25519 public key release archives are signed with, set when
+/// building official releases. Updates are refused unless they carry a valid
+/// signature of it.
+const UPDATE_PUBLIC_KEY: Option<&str> = option_env!("VPROXY_UPDATE_PUBLIC_KEY");
+
+/// Releases `vproxy self update` picks from
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum Channel {
+    /// Latest stable release
+    #[default]
+    Stable,
+    /// Latest release, including pre-releases
+    Prerelease,
+}
+
+/// Updates the current executable to the latest version of `channel`, or to
+/// `version` if given.
 ///
 /// This function uses the `self_update` crate to check for updates and apply them if available.
 /// It configures the update process with various options such as repository name, binary name,
 /// target platform, and current version. If an update is found, it downloads and applies the update,
 /// and then prints the release notes or a message indicating that the update was successful.
 ///
+/// The downloaded archive must be signed with the key of
+/// `UPDATE_PUBLIC_KEY`; builds without one only update with `no_verify`.
+/// The replaced executable is kept next to the new one, for [`rollback`].
+///
 /// # Errors
 ///
 /// This function returns an error if the update process fails at any step, such as building the updater,
 /// checking for updates, or applying the update.
-pub(super) fn update() -> crate::Result<()> {
-    let status = self_update::backends::github::Update::configure()
-        .repo_owner("0x676e67")
+pub(super) fn update(
+    channel: Channel,
+    version: Option<String>,
+    no_verify: bool,
+) -> crate::Result<()> {
+    let key = verifying_key()?;
+    if key.is_none() && !no_verify {
+        return Err(std::io::Error::other(format!(
+            "this build of {BIN_NAME} has no update signing key, so downloads cannot be \
+             verified; install a release build or pass --no-verify"
+        ))
+        .into());
+    }
+
+    let tag = match (version, channel) {
+        (Some(version), _) => Some(format!("v{}", version.trim_start_matches('v'))),
+        (None, Channel::Stable) => None,
+        (None, Channel::Prerelease) => match latest_prerelease()? {
+            Some(version) => Some(format!("v{version}")),
+            None => {
+                println!("{} is up-to-date", BIN_NAME);
+                return Ok(());
+            }
+        },
+    };
+
+    let current_exe = std::env::current_exe()?;
+    let backup = backup_path(&current_exe);
+    let pending = backup.with_extension("tmp");
+    std::fs::copy(&current_exe, &pending)?;
+
+    let mut updater = self_update::backends::github::Update::configure();
+    updater
+        .repo_owner(REPO_OWNER)
         .repo_name(BIN_NAME)
         .bin_name(BIN_NAME)
         .target(self_update::get_target())
         .show_output(true)
         .show_download_progress(true)
         .no_confirm(true)
-        .current_version(cargo_crate_version!())
-        .build()?
-        .update_extended()?;
+        .current_version(cargo_crate_version!());
+    if let Some(tag) = &tag {
+        updater.target_version_tag(tag);
+    }
+    if let Some(key) = key {
+        updater.verifying_keys([key]);
+    }
+    let status = match updater
+        .build()
+        .and_then(|updater| updater.update_extended())
+    {
+        Ok(status) => status,
+        Err(err) => {
+            let _ = std::fs::remove_file(&pending);
+            return Err(err.into());
+        }
+    };
 
     if let UpdateStatus::Updated(ref release) = status {
+        std::fs::rename(&pending, &backup)?;
         if let Some(body) = &release.body {
             if !body.trim().is_empty() {
                 println!("{} upgraded to {}:\n", BIN_NAME, release.version);
@@ -35,13 +105,358 @@ pub(super) fn update() -> crate::Result<()> {
                 println!("{} upgraded to {}", BIN_NAME, release.version);
             }
         }
+        println!(
+            "The previous version is kept as {}, `{} self rollback` restores it",
+            backup.display(),
+            BIN_NAME
+        );
     } else {
+        std::fs::remove_file(&pending)?;
         println!("{} is up-to-date", BIN_NAME);
     }
 
     Ok(())
 }
 
+/// Decodes the embedded update signing key, if the build has one.
+fn verifying_key() -> crate::Result<Option<[u8; 32]>> {
+    use base64::Engine;
+
+    let Some(key) = UPDATE_PUBLIC_KEY.filter(|key| !key.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let key = base64::engine::general_purpose::STANDARD
+        .decode(key.trim())
+        .ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .ok_or_else(|| std::io::Error::other("invalid update signing key in this build"))?;
+    Ok(Some(key))
+}
+
+/// Returns the newest release, pre-releases included, if it is newer than
+/// the running version.
+fn latest_prerelease() -> crate::Result<Option<String>> {
+    let releases = self_update::backends::github::ReleaseList::configure()
+        .repo_owner(REPO_OWNER)
+        .repo_name(BIN_NAME)
+        .build()?
+        .fetch()?;
+    // Releases are listed newest first
+    Ok(releases
+        .into_iter()
+        .map(|release| release.version)
+        .find(|version| {
+            self_update::version::bump_is_greater(cargo_crate_version!(), version).unwrap_or(false)
+        }))
+}
+
+/// Restores the executable replaced by the last update.
+pub(super) fn rollback() -> crate::Result<()> {
+    let current_exe = std::env::current_exe()?;
+    let backup = backup_path(&current_exe);
+    if !backup.exists() {
+        println!("No previous version of {} to roll back to", BIN_NAME);
+        return Ok(());
+    }
+
+    self_update::self_replace::self_replace(&backup)?;
+    std::fs::remove_file(&backup)?;
+    println!("{} rolled back from {}", BIN_NAME, cargo_crate_version!());
+    Ok(())
+}
+
+/// Returns where the executable replaced by an update is kept.
+fn backup_path(exe: &Path) -> PathBuf {
+    let mut name = exe.file_name().unwrap_or_default().to_owned();
+    name.push(".old");
+    exe.with_file_name(name)
+}
+
+/// Installs the current executable to `dir`, creates the configuration
+/// directory with an environment file, and writes a systemd unit, or a
+/// launchd job on macOS, running the server with `args`.
+///
+/// Secrets among `args` are kept out of the service definition, which any
+/// user can read: they go to the environment file, or to the environment of
+/// the launchd job, both readable by root only. Existing configuration files
+/// are otherwise left untouched, the service definition is replaced.
+pub(super) fn install(dir: &Path, args: Vec<String>) -> crate::Result<()> {
+    use clap::Parser;
+
+    // Catch mistakes now rather than when the service starts
+    let command = [BIN_NAME, "run"]
+        .into_iter()
+        .map(str::to_owned)
+        .chain(args.iter().cloned());
+    if let Err(err) = crate::Opt::try_parse_from(command) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()).into());
+    }
+
+    let (config_dir, service) = match std::env::consts::OS {
+        "linux" => (
+            PathBuf::from("/etc").join(BIN_NAME),
+            PathBuf::from(format!("/etc/systemd/system/{BIN_NAME}.service")),
+        ),
+        "macos" => (
+            PathBuf::from("/usr/local/etc").join(BIN_NAME),
+            PathBuf::from(format!("/Library/LaunchDaemons/com.{BIN_NAME}.plist")),
+        ),
+        os => {
+            return Err(
+                std::io::Error::other(format!("services cannot be installed on {os}")).into(),
+            )
+        }
+    };
+
+    let exe = dir.join(BIN_NAME);
+    let current_exe = std::env::current_exe()?;
+    if current_exe.canonicalize().ok() != exe.canonicalize().ok() {
+        std::fs::create_dir_all(dir)?;
+        let tmp = exe.with_extension("tmp");
+        std::fs::copy(&current_exe, &tmp)?;
+        std::fs::rename(&tmp, &exe)?;
+        println!("Installed {}", exe.display());
+    }
+
+    let (args, secrets) = split_secrets(&args);
+    std::fs::create_dir_all(&config_dir)?;
+    let env_file = config_dir.join(format!("{BIN_NAME}.env"));
+    if std::env::consts::OS == "linux" {
+        if write_env_file(&env_file, &secrets)? {
+            println!("Created {}", env_file.display());
+        } else if !secrets.is_empty() {
+            println!("Updated {}", env_file.display());
+        }
+        write_private(&service, &systemd_unit(&exe, &env_file, &args), false)?;
+    } else {
+        if !env_file.exists() {
+            write_private(&env_file, ENV_SKELETON, true)?;
+            println!("Created {}", env_file.display());
+        }
+        let plist = launchd_plist(&exe, &args, &secrets);
+        write_private(&service, &plist, !secrets.is_empty())?;
+    }
+    println!("Wrote {}", service.display());
+
+    if std::env::consts::OS == "linux" {
+        println!("Start it with: systemctl daemon-reload && systemctl enable --now {BIN_NAME}");
+    } else {
+        println!("Start it with: launchctl load -w {}", service.display());
+    }
+    Ok(())
+}
+
+/// Environment file created by [`install`], read by the systemd unit.
+const ENV_SKELETON: &str = "\
+# Environment of the vproxy service
+# Log level: trace, debug, info, warn or error
+VPROXY_LOG=info
+# Token of the admin API, required with --admin-bind
+#VPROXY_ADMIN_TOKEN=
+";
+
+/// Flags of `vproxy run` whose value is a secret, by long and short name,
+/// with the environment variable they can be read from instead.
+const SECRET_FLAGS: [(&str, Option<char>, &str); 3] = [
+    ("password", Some('p'), "VPROXY_PASSWORD"),
+    ("admin-token", None, "VPROXY_ADMIN_TOKEN"),
+    ("log-ship-token", None, "VPROXY_LOG_SHIP_TOKEN"),
+];
+
+/// Splits the values of [`SECRET_FLAGS`] off `args`, returning the other
+/// arguments and the secrets with their environment variable.
+fn split_secrets(args: &[String]) -> (Vec<String>, Vec<(&'static str, String)>) {
+    let mut rest = Vec::new();
+    let mut secrets = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            rest.push(arg.clone());
+            rest.extend(args.by_ref().cloned());
+            break;
+        }
+        let secret = SECRET_FLAGS.iter().find_map(|(long, short, var)| {
+            // `--flag value`, `--flag=value`, `-f value`, `-fvalue` or `-f=value`
+            let inline = match arg.strip_prefix("--") {
+                Some(flag) => match flag.strip_prefix(long)? {
+                    "" => None,
+                    value => Some(value.strip_prefix('=')?),
+                },
+                None => {
+                    let value = arg.strip_prefix('-')?.strip_prefix((*short)?)?;
+                    (!value.is_empty()).then(|| value.strip_prefix('=').unwrap_or(value))
+                }
+            };
+            Some((*var, inline))
+        });
+        match secret {
+            Some((var, Some(value))) => secrets.push((var, value.to_owned())),
+            Some((var, None)) => match args.next() {
+                Some(value) => secrets.push((var, value.clone())),
+                None => rest.push(arg.clone()),
+            },
+            None => rest.push(arg.clone()),
+        }
+    }
+    (rest, secrets)
+}
+
+/// Writes `secrets` to the environment file at `path`, created from
+/// [`ENV_SKELETON`] if missing, replacing earlier values of the same
+/// variables. Returns whether the file was created.
+fn write_env_file(path: &Path, secrets: &[(&str, String)]) -> std::io::Result<bool> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(content) => Some(content),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if existing.is_some() && secrets.is_empty() {
+        return Ok(false);
+    }
+
+    let created = existing.is_none();
+    let mut content = existing
+        .as_deref()
+        .unwrap_or(ENV_SKELETON)
+        .lines()
+        .filter(|line| {
+            !secrets.iter().any(|(var, _)| {
+                line.strip_prefix(var)
+                    .is_some_and(|rest| rest.starts_with('='))
+            })
+        })
+        .map(|line| format!("{line}\n"))
+        .collect::<String>();
+    for (var, value) in secrets {
+        content.push_str(&format!("{var}={}\n", env_quote(value)));
+    }
+    write_private(path, &content, true)?;
+    Ok(created)
+}
+
+/// Writes `content` to the file at `path`, readable by root only if
+/// `private`, including when the file already exists.
+fn write_private(path: &Path, content: &str, private: bool) -> std::io::Result<()> {
+    let mode = if private { 0o600 } else { 0o644 };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    let mut file = options.open(path)?;
+    // The mode only applies to a new file
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    std::io::Write::write_all(&mut file, content.as_bytes())
+}
+
+/// Quotes `value` for a systemd environment file.
+fn env_quote(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{escaped}\"")
+}
+
+/// Returns the systemd unit running the server. Stopping on SIGINT or
+/// SIGTERM, be it by the signal or its exit status, is no failure to restart
+/// after.
+fn systemd_unit(exe: &Path, env_file: &Path, args: &[String]) -> String {
+    let command = std::iter::once(exe.display().to_string())
+        .chain(["run".to_owned()])
+        .chain(args.iter().cloned())
+        .map(|arg| systemd_quote(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        "[Unit]
+Description={BIN_NAME} proxy server
+After=network-online.target
+Wants=network-online.target
+
+[Service]
+EnvironmentFile=-{}
+ExecStart={command}
+Restart=on-failure
+RestartSec=5
+SuccessExitStatus=130 143
+LimitNOFILE=1048576
+
+[Install]
+WantedBy=multi-user.target
+",
+        env_file.display()
+    )
+}
+
+fn launchd_plist(exe: &Path, args: &[String], secrets: &[(&str, String)]) -> String {
+    let arguments = std::iter::once(exe.display().to_string())
+        .chain(["run".to_owned()])
+        .chain(args.iter().cloned())
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(&arg)))
+        .collect::<String>();
+    let environment = secrets
+        .iter()
+        .map(|(var, value)| {
+            format!(
+                "        <key>{var}</key>\n        <string>{}</string>\n",
+                xml_escape(value)
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>com.{BIN_NAME}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>EnvironmentVariables</key>
+    <dict>
+{environment}    </dict>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>/var/log/{BIN_NAME}.out</string>
+    <key>StandardErrorPath</key>
+    <string>/var/log/{BIN_NAME}.err</string>
+</dict>
+</plist>
+"#
+    )
+}
+
+/// Quotes `arg` for a systemd command line, where `%` starts a specifier
+/// and `$` a variable.
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        escaped
+    } else {
+        format!("\"{escaped}\"")
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 /// Uninstalls the current executable.
 ///
 /// This function deletes the currently running executable from the file system.
@@ -61,3 +476,65 @@ pub(super) fn uninstall() -> crate::Result<()> {
     println!("Uninstallation complete.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_systemd_quote() {
+        assert_eq!(systemd_quote("2001:db8::/48"), "2001:db8::/48");
+        assert_eq!(systemd_quote("a b"), "\"a b\"");
+        assert_eq!(systemd_quote("100%"), "100%%");
+        assert_eq!(systemd_quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(systemd_quote(""), "\"\"");
+    }
+
+    #[test]
+    fn test_split_secrets() {
+        let args = [
+            "-i",
+            "2001:db8::/48",
+            "--admin-token=t0k\"en",
+            "http",
+            "-u",
+            "alice",
+            "-p",
+            "s3cret",
+        ]
+        .map(str::to_owned);
+        let (rest, secrets) = split_secrets(&args);
+        assert_eq!(rest, ["-i", "2001:db8::/48", "http", "-u", "alice"]);
+        assert_eq!(
+            secrets,
+            [
+                ("VPROXY_ADMIN_TOKEN", "t0k\"en".to_owned()),
+                ("VPROXY_PASSWORD", "s3cret".to_owned())
+            ]
+        );
+        assert_eq!(env_quote("t0k\"en"), "\"t0k\\\"en\"");
+
+        let (_, secrets) = split_secrets(&["-ps3cret".to_owned()]);
+        assert_eq!(secrets, [("VPROXY_PASSWORD", "s3cret".to_owned())]);
+        let (rest, secrets) = split_secrets(&["--password-file".to_owned()]);
+        assert_eq!((rest.len(), secrets.len()), (1, 0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_env_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("vproxy-env-{}", std::process::id()));
+        assert!(write_env_file(&path, &[]).unwrap());
+        assert!(!write_env_file(&path, &[("VPROXY_PASSWORD", "a".to_owned())]).unwrap());
+        write_env_file(&path, &[("VPROXY_PASSWORD", "b".to_owned())]).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with(ENV_SKELETON));
+        assert!(content.ends_with("\nVPROXY_PASSWORD=\"b\"\n"));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
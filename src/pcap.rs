@@ -0,0 +1,466 @@
+//! Capture of relayed tunnels for protocol debugging.
+//!
+//! With `--pcap-dir` every tunnel is written to `<DIR>/<connection id>.pcapng`
+//! as a TCP connection between its two peers, synthesized from what the
+//! proxy relays: a handshake when the relay starts, the payload in both
+//! directions and a FIN from each side, readable with Wireshark or tcpdump. Tunnels of the HTTPS server are captured after the TLS of the
+//! client connection is terminated, while TLS spoken through a tunnel is
+//! captured as is.
+//!
+//! A capture stops at `--pcap-max-size` bytes, the tunnel going on
+//! unrecorded, and no capture is started once the directory holds
+//! `--pcap-max-files` of them. Files are only readable by the user running
+//! the server, as they hold decrypted traffic. They are written by a thread
+//! of their own; blocks that would queue up beyond [`MAX_QUEUED`] bytes are
+//! dropped rather than slowing the relay down. Captured tunnels are copied
+//! through userspace instead of being spliced, so captures are meant for
+//! debugging sessions.
+
+use crate::stats::ConnectionId;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Where captures are written, set once on startup.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Number of capture files in the directory.
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes handed to the writer thread and not written yet.
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifier of the next capture for the writer thread.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Link type of packets starting with their IP header.
+const LINKTYPE_RAW: u16 = 101;
+
+/// Largest payload of a synthesized packet.
+const MAX_SEGMENT: usize = 65_000;
+
+/// Most bytes waiting for the writer thread.
+const MAX_QUEUED: usize = 64 << 20;
+
+/// TCP flags of the synthesized packets.
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+struct Config {
+    dir: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    writer: Mutex<Sender<(u64, Op)>>,
+    /// Whether reaching `max_files` was logged.
+    full: AtomicBool,
+    /// Whether dropping blocks was logged.
+    dropped: AtomicBool,
+}
+
+/// What the writer thread does with a capture file.
+enum Op {
+    Create(PathBuf),
+    Write(Vec<u8>),
+    Flush,
+    Close,
+}
+
+/// Returns whether tunnels are captured.
+pub(crate) fn enabled() -> bool {
+    CONFIG.get().is_some()
+}
+
+/// Captures tunnels into `dir`, each up to `max_size` bytes and at most
+/// `max_files` of them.
+pub(crate) fn init(dir: PathBuf, max_size: u64, max_files: usize) -> io::Result<()> {
+    if enabled() {
+        return Ok(());
+    }
+    std::fs::create_dir_all(&dir)?;
+    let files = std::fs::read_dir(&dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "pcapng"))
+        .count();
+    FILES.store(files, Ordering::Relaxed);
+
+    let (writer, ops) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("pcap-writer".to_owned())
+        .spawn(move || write_files(ops))?;
+    tracing::warn!(
+        "Capturing tunnels to {}, up to {} bytes each and {} files",
+        dir.display(),
+        max_size,
+        max_files
+    );
+    let _ = CONFIG.set(Config {
+        dir,
+        max_size,
+        max_files,
+        writer: Mutex::new(writer),
+        full: AtomicBool::new(false),
+        dropped: AtomicBool::new(false),
+    });
+    Ok(())
+}
+
+/// Writes the blocks of every capture to its file, until the process exits.
+fn write_files(ops: Receiver<(u64, Op)>) {
+    let mut files = HashMap::<u64, (BufWriter<File>, PathBuf)>::new();
+    for (id, op) in ops {
+        match op {
+            Op::Create(path) => match create(&path) {
+                Ok(file) => {
+                    files.insert(id, (BufWriter::new(file), path));
+                }
+                Err(err) => tracing::warn!("Not capturing to {}: {}", path.display(), err),
+            },
+            Op::Write(block) => {
+                QUEUED.fetch_sub(block.len(), Ordering::Relaxed);
+                if let Some((file, path)) = files.get_mut(&id) {
+                    if let Err(err) = file.write_all(&block) {
+                        tracing::warn!("Capture {}: {}", path.display(), err);
+                        files.remove(&id);
+                    }
+                }
+            }
+            Op::Flush => {
+                if let Some((file, path)) = files.get_mut(&id) {
+                    if let Err(err) = file.flush() {
+                        tracing::debug!("Capture {}: {}", path.display(), err);
+                    }
+                }
+            }
+            Op::Close => {
+                files.remove(&id);
+            }
+        }
+    }
+}
+
+/// Creates the capture file at `path`, readable by the owner only.
+fn create(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// The capture file of a tunnel.
+pub(crate) struct Capture {
+    id: u64,
+    inner: Mutex<Writer>,
+}
+
+/// The synthesized connection of a capture, whose blocks go to the writer
+/// thread.
+struct Writer {
+    id: u64,
+    path: PathBuf,
+    /// Addresses of the peers, the first one opening the connection.
+    peers: [SocketAddr; 2],
+    /// Next sequence number of each peer.
+    seq: [u32; 2],
+    written: u64,
+    max_size: u64,
+    full: bool,
+}
+
+impl Capture {
+    /// Starts the capture of the tunnel between `a` and `b`, shown as opened
+    /// by `a`.
+    pub(crate) fn open(a: SocketAddr, b: SocketAddr) -> Option<Self> {
+        let config = CONFIG.get()?;
+        if FILES.fetch_add(1, Ordering::Relaxed) >= config.max_files {
+            FILES.fetch_sub(1, Ordering::Relaxed);
+            if !config.full.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "Capture directory {} holds {} files, not capturing more tunnels",
+                    config.dir.display(),
+                    config.max_files
+                );
+            }
+            return None;
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let connection = ConnectionId::current().unwrap_or_else(ConnectionId::new);
+        let path = config.dir.join(format!("{connection}.pcapng"));
+        send(id, Op::Create(path.clone()));
+
+        let mut writer = Writer {
+            id,
+            path,
+            peers: peers(a, b),
+            seq: [rand::random(), rand::random()],
+            written: 0,
+            max_size: config.max_size,
+            full: false,
+        };
+        writer.write(section_header());
+        writer.write(interface_description());
+        writer.packet(0, SYN, &[]);
+        writer.packet(1, SYN | ACK, &[]);
+        writer.packet(0, ACK, &[]);
+        Some(Self {
+            id,
+            inner: Mutex::new(writer),
+        })
+    }
+
+    /// Records `data` sent by `a`, or by `b` if not `from_a`.
+    pub(crate) fn data(&self, from_a: bool, data: &[u8]) {
+        let mut writer = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        for segment in data.chunks(MAX_SEGMENT) {
+            writer.packet(usize::from(!from_a), PSH | ACK, segment);
+        }
+    }
+
+    /// Records the end of the data sent by `a`, or by `b` if not `from_a`.
+    pub(crate) fn finish(&self, from_a: bool) {
+        let mut writer = self.inner.lock().unwrap_or_else(|err| err.into_inner());
+        writer.packet(usize::from(!from_a), FIN | ACK, &[]);
+        send(self.id, Op::Flush);
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        send(self.id, Op::Close);
+    }
+}
+
+impl Writer {
+    /// Writes a packet sent by the peer at index `from`.
+    fn packet(&mut self, from: usize, flags: u8, payload: &[u8]) {
+        if self.full {
+            return;
+        }
+        let to = 1 - from;
+        // SYNs and FINs take a sequence number, the first SYN acks none
+        let ack = match flags {
+            SYN => 0,
+            _ => self.seq[to],
+        };
+        let packet = ip_packet(
+            self.peers[from],
+            self.peers[to],
+            &tcp_segment(
+                self.peers[from].port(),
+                self.peers[to].port(),
+                self.seq[from],
+                ack,
+                flags,
+                payload,
+            ),
+        );
+        let consumed = payload.len() + usize::from(flags & (SYN | FIN) != 0);
+        self.seq[from] = self.seq[from].wrapping_add(consumed as u32);
+        self.write(enhanced_packet(&packet));
+    }
+
+    fn write(&mut self, block: Vec<u8>) {
+        if self.full {
+            return;
+        }
+        if self.written + block.len() as u64 > self.max_size {
+            self.full = true;
+            tracing::info!(
+                "Capture {} reached {} bytes, stopped",
+                self.path.display(),
+                self.max_size
+            );
+            send(self.id, Op::Flush);
+            return;
+        }
+        self.written += block.len() as u64;
+        send(self.id, Op::Write(block));
+    }
+}
+
+/// Hands `op` of the capture `id` to the writer thread. Blocks are dropped
+/// while the thread is behind by [`MAX_QUEUED`] bytes.
+fn send(id: u64, op: Op) {
+    let Some(config) = CONFIG.get() else {
+        return;
+    };
+    if let Op::Write(block) = &op {
+        if QUEUED.fetch_add(block.len(), Ordering::Relaxed) + block.len() > MAX_QUEUED {
+            QUEUED.fetch_sub(block.len(), Ordering::Relaxed);
+            if !config.dropped.swap(true, Ordering::Relaxed) {
+                tracing::warn!("Capture writer is behind, dropping captured packets");
+            }
+            return;
+        }
+    }
+    let writer = config.writer.lock().unwrap_or_else(|err| err.into_inner());
+    let _ = writer.send((id, op));
+}
+
+/// Returns the addresses of `a` and `b`, both IPv6 if their families
+/// differ, as a packet has a single family.
+fn peers(a: SocketAddr, b: SocketAddr) -> [SocketAddr; 2] {
+    if a.is_ipv4() == b.is_ipv4() {
+        return [a, b];
+    }
+    let v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    [v6(a), v6(b)]
+}
+
+/// Builds a TCP segment without options. The checksum is left at zero,
+/// which Wireshark does not validate by default.
+fn tcp_segment(from: u16, to: u16, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut segment = Vec::with_capacity(20 + payload.len());
+    segment.extend_from_slice(&from.to_be_bytes());
+    segment.extend_from_slice(&to.to_be_bytes());
+    segment.extend_from_slice(&seq.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[5 << 4, flags]);
+    segment.extend_from_slice(&u16::MAX.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(payload);
+    segment
+}
+
+/// Wraps `segment` into an IP packet from `from` to `to`, of the same
+/// family.
+fn ip_packet(from: SocketAddr, to: SocketAddr, segment: &[u8]) -> Vec<u8> {
+    const TCP: u8 = 6;
+    const TTL: u8 = 64;
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(from), IpAddr::V4(to)) => {
+            let mut packet = Vec::with_capacity(20 + segment.len());
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, TCP, 0, 0]);
+            packet.extend_from_slice(&from.octets());
+            packet.extend_from_slice(&to.octets());
+            let sum = checksum(&packet);
+            packet[10..12].copy_from_slice(&sum.to_be_bytes());
+            packet.extend_from_slice(segment);
+            packet
+        }
+        (from, to) => {
+            let octets = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                IpAddr::V6(ip) => ip.octets(),
+            };
+            let mut packet = Vec::with_capacity(40 + segment.len());
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+            packet.extend_from_slice(&[TCP, TTL]);
+            packet.extend_from_slice(&octets(from));
+            packet.extend_from_slice(&octets(to));
+            packet.extend_from_slice(segment);
+            packet
+        }
+    }
+}
+
+/// Computes the Internet checksum of an IPv4 header.
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Builds a pcapng block of `kind` around `body`, padded to 32 bits.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let padding = (4 - body.len() % 4) % 4;
+    let len = (12 + body.len() + padding) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.resize(block.len() + padding, 0);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&0x1a2b_3c4d_u32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Section length unknown
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(0x0a0d_0d0a, &body)
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    block(1, &body)
+}
+
+/// Builds the block of `packet`, timestamped now in microseconds.
+fn enhanced_packet(packet: &[u8]) -> Vec<u8> {
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64);
+    let mut body = Vec::with_capacity(20 + packet.len());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    block(6, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets() {
+        let client = "192.0.2.1:50000".parse().unwrap();
+        let target = "198.51.100.7:443".parse().unwrap();
+        let packet = ip_packet(client, target, &tcp_segment(50000, 443, 1, 0, SYN, &[]));
+        assert_eq!(packet.len(), 40);
+        assert_eq!(checksum(&packet[..20]), 0);
+
+        let mixed = peers(client, "[2001:db8::7]:443".parse().unwrap());
+        assert!(mixed.iter().all(SocketAddr::is_ipv6));
+        let packet = ip_packet(
+            mixed[0],
+            mixed[1],
+            &tcp_segment(50000, 443, 1, 0, SYN, b"hi"),
+        );
+        assert_eq!(packet.len(), 62);
+
+        let block = enhanced_packet(&[0; 5]);
+        assert_eq!(block.len() % 4, 0);
+        assert_eq!(&block[4..8], &(block.len() as u32).to_le_bytes());
+        assert_eq!(
+            &block[block.len() - 4..],
+            &(block.len() as u32).to_le_bytes()
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Request policy modules compiled to WebAssembly.
+//!
+//! A policy module is instantiated afresh for every request, so it cannot keep
+//! state between requests, and runs with bounded fuel and memory. It imports
+//! nothing and exports:
+//!
+//! - `memory`, its linear memory;
+//! - `alloc(len: i32) -> i32`, returning a buffer of `len` bytes;
+//! - `policy(ptr: i32, len: i32) -> i64`, deciding on the request.
+//!
+//! The request is written to a buffer from `alloc` as `key=value` lines with
+//! the keys `protocol` (`http` or `socks5`), `username` (empty without
+//! authentication), `target` (`host:port`) and `extension` (`none`, or
+//! `session`, `ttl` and `range` joined by `+`, e.g. `session+range`). `policy` returns the location of its decision as
+//! `ptr << 32 | len`. The decision is `allow`, `deny` or `egress <key>`, which
+//! pins the request to the egress address of the session `key`; an empty
+//! decision allows the request. Modules that trap, run out of fuel or answer
+//! anything else deny the request, as do decisions longer than
+//! [`MAX_DECISION`] bytes.
+//!
+//! Modules run synchronously on the thread handling the request. On the
+//! multi-threaded runtime that thread is first handed over to the blocking
+//! pool, so that other connections keep being served meanwhile.
+
+use crate::{
+    extension::Extension,
+    hooks::{Decision, Hooks, Protocol, ProxyRequest},
+};
+use std::path::Path;
+use tokio::runtime::RuntimeFlavor;
+use wasmtime::{
+    Config, Engine, InstancePre, Linker, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+/// Instructions a module may execute per request.
+const FUEL: u64 = 10_000_000;
+
+/// Linear memory a module may grow to.
+const MAX_MEMORY: usize = 16 << 20;
+
+/// Longest decision read back from a module.
+const MAX_DECISION: usize = 256;
+
+/// A loaded policy module.
+pub(crate) struct WasmPolicy {
+    pre: InstancePre<StoreLimits>,
+}
+
+impl WasmPolicy {
+    /// Compiles the module at `path`.
+    pub(crate) fn load(path: &Path) -> std::io::Result<Self> {
+        let load = || -> wasmtime::Result<Self> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::from_file(&engine, path)?;
+            let pre = Linker::new(&engine).instantiate_pre(&module)?;
+            Ok(Self { pre })
+        };
+
+        let policy = load().map_err(|err| {
+            std::io::Error::other(format!("failed to load {}: {}", path.display(), err))
+        })?;
+        tracing::info!("Loaded request policy {}", path.display());
+        Ok(policy)
+    }
+
+    /// Runs the module on `input` and returns its decision.
+    fn call(&self, input: &str) -> wasmtime::Result<String> {
+        let limits = StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build();
+        let mut store = Store::new(self.pre.module().engine(), limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL)?;
+
+        let instance = self.pre.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("module does not export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let policy = instance.get_typed_func::<(i32, i32), i64>(&mut store, "policy")?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input.as_bytes())?;
+
+        let ret = policy.call(&mut store, (ptr, len))?;
+        let (ptr, len) = ((ret >> 32) as u32 as usize, ret as u32 as usize);
+        if len > MAX_DECISION || ptr.saturating_add(len) > memory.data_size(&store) {
+            return Err(wasmtime::Error::msg(format!(
+                "decision of {len} bytes at {ptr} is out of bounds"
+            )));
+        }
+        let mut decision = vec![0; len];
+        memory.read(&store, ptr, &mut decision)?;
+        Ok(String::from_utf8(decision)?)
+    }
+
+    /// Runs the module on `input` like [`Self::call`], on the blocking pool
+    /// when the runtime has one.
+    fn decide(&self, input: &str) -> wasmtime::Result<String> {
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                tokio::task::block_in_place(|| self.call(input))
+            }
+            _ => self.call(input),
+        }
+    }
+}
+
+impl Hooks for WasmPolicy {
+    fn on_request(&self, request: &ProxyRequest<'_>) -> Decision {
+        let protocol = match request.protocol {
+            Protocol::Http => "http",
+            Protocol::Socks5 => "socks5",
+            Protocol::Sni => "sni",
+            Protocol::Forward => "forward",
+        };
+        let kinds = [
+            (request.extension.session, "session"),
+            (request.extension.ttl, "ttl"),
+            (request.extension.range, "range"),
+        ]
+        .iter()
+        .filter(|(value, _)| value.is_some())
+        .map(|(_, kind)| *kind)
+        .collect::<Vec<_>>();
+        let extension = if kinds.is_empty() {
+            "none".to_owned()
+        } else {
+            kinds.join("+")
+        };
+        let input = format!(
+            "protocol={}\nusername={}\ntarget={}\nextension={}\n",
+            protocol,
+            request.username.unwrap_or_default(),
+            request.target,
+            extension
+        );
+
+        match self.decide(&input) {
+            Ok(decision) => parse_decision(&decision).unwrap_or_else(|| {
+                tracing::warn!("Request policy returned an invalid decision: {decision:?}");
+                Decision::Deny
+            }),
+            Err(err) => {
+                tracing::warn!("Request policy failed: {}", err);
+                Decision::Deny
+            }
+        }
+    }
+}
+
+/// Parses a decision returned by a module.
+fn parse_decision(decision: &str) -> Option<Decision> {
+    match decision.trim() {
+        "" | "allow" => Some(Decision::Allow),
+        "deny" => Some(Decision::Deny),
+        decision => decision.strip_prefix("egress ").map(|key| {
+            let hash = fxhash::hash64(key.trim().as_bytes());
+            Decision::Egress(Extension::session(hash))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_decision() {
+        assert!(matches!(parse_decision(""), Some(Decision::Allow)));
+        assert!(matches!(parse_decision("deny\n"), Some(Decision::Deny)));
+        assert!(matches!(
+            parse_decision("egress eu-1"),
+            Some(Decision::Egress(Extension {
+                session: Some(_),
+                ..
+            }))
+        ));
+        assert!(parse_decision("maybe").is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//! Process-wide pool of relay buffers.
+//!
+//! Userspace tunnel relays and the UDP relays borrow their buffers from here
+//! instead of allocating them per connection or datagram. Buffers come in a
+//! few size tiers; a borrowed buffer goes back to its tier when dropped, up to
+//! [`MAX_IDLE_BYTES`] of idle buffers per tier, beyond which it is freed.
+//! Requests larger than the largest tier are allocated outside of the pool.
+//!
+//! Hits and misses of each tier are counted for the statistics and the
+//! `/metrics` endpoint of the admin API.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Buffer sizes of the tiers, smallest first.
+const TIER_SIZES: [usize; 4] = [2 * 1024, 16 * 1024, 64 * 1024, 256 * 1024];
+
+/// Bytes of idle buffers kept per tier.
+const MAX_IDLE_BYTES: usize = 16 * 1024 * 1024;
+
+struct Tier {
+    idle: Mutex<Vec<Vec<u8>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Tier {
+    const fn new() -> Self {
+        Self {
+            idle: Mutex::new(Vec::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+static TIERS: [Tier; TIER_SIZES.len()] = [const { Tier::new() }; TIER_SIZES.len()];
+
+/// Usage of a tier of the pool.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TierStats {
+    /// Size of the buffers of the tier.
+    pub size: usize,
+    /// Borrows served by an idle buffer.
+    pub hits: u64,
+    /// Borrows that allocated a buffer.
+    pub misses: u64,
+    /// Buffers waiting to be borrowed.
+    pub idle: usize,
+}
+
+/// A buffer borrowed from the pool, returned to it when dropped.
+///
+/// Its contents are left over from its previous use, only the bytes written
+/// to it are meaningful.
+#[derive(Debug)]
+pub(crate) struct Buffer {
+    buf: Vec<u8>,
+    len: usize,
+    tier: Option<usize>,
+}
+
+/// Borrows a buffer of `len` bytes from the smallest tier that fits it.
+pub(crate) fn get(len: usize) -> Buffer {
+    let Some(tier) = TIER_SIZES.iter().position(|size| *size >= len) else {
+        return Buffer {
+            buf: vec![0; len],
+            len,
+            tier: None,
+        };
+    };
+
+    let idle = TIERS[tier].idle.lock().ok().and_then(|mut idle| idle.pop());
+    let buf = match idle {
+        Some(buf) => {
+            TIERS[tier].hits.fetch_add(1, Ordering::Relaxed);
+            buf
+        }
+        None => {
+            TIERS[tier].misses.fetch_add(1, Ordering::Relaxed);
+            vec![0; TIER_SIZES[tier]]
+        }
+    };
+    Buffer {
+        buf,
+        len,
+        tier: Some(tier),
+    }
+}
+
+/// Returns the usage of each tier, smallest first.
+pub(crate) fn stats() -> Vec<TierStats> {
+    TIER_SIZES
+        .iter()
+        .zip(&TIERS)
+        .map(|(size, tier)| TierStats {
+            size: *size,
+            hits: tier.hits.load(Ordering::Relaxed),
+            misses: tier.misses.load(Ordering::Relaxed),
+            idle: tier.idle.lock().map_or(0, |idle| idle.len()),
+        })
+        .collect()
+}
+
+/// Returns the share of borrows served by an idle buffer, if any were made.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) fn hit_rate() -> Option<f64> {
+    let (hits, misses) = stats().iter().fold((0, 0), |(hits, misses), tier| {
+        (hits + tier.hits, misses + tier.misses)
+    });
+    (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64)
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl DerefMut for Buffer {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        let Some(tier) = self.tier else {
+            return;
+        };
+        if let Ok(mut idle) = TIERS[tier].idle.lock() {
+            if idle.len() < MAX_IDLE_BYTES / TIER_SIZES[tier] {
+                idle.push(std::mem::take(&mut self.buf));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let buf = get(1500);
+        assert_eq!(buf.len(), 1500);
+        assert_eq!(buf.tier, Some(0));
+        assert_eq!(buf.buf.len(), TIER_SIZES[0]);
+
+        assert_eq!(get(16 * 1024).tier, Some(1));
+        assert_eq!(get(65535).tier, Some(2));
+        assert_eq!(get(128 * 1024).tier, Some(3));
+        let large = get(1024 * 1024);
+        assert_eq!(large.tier, None);
+        assert_eq!(large.len(), 1024 * 1024);
+
+        drop(buf);
+        let hits = TIERS[0].hits.load(Ordering::Relaxed);
+        let buf = get(100);
+        assert_eq!(buf.len(), 100);
+        assert!(TIERS[0].hits.load(Ordering::Relaxed) > hits);
+    }
+}
@@ -0,0 +1,74 @@
+//! Dropping root privileges once the host is set up.
+//!
+//! With `--user` the server applies its sysctls and routes as root, then
+//! switches to the unprivileged user before it binds its listeners. On Linux
+//! it keeps only the capabilities it still needs: `CAP_NET_BIND_SERVICE` for
+//! listeners on ports below 1024, `CAP_NET_ADMIN` to route the prefixes of a
+//! CIDR file as it changes, and `CAP_NET_RAW` for `--interface` and the NDP
+//! proxy.
+
+use crate::BootArgs;
+use nix::unistd::{self, Uid, User};
+use std::io;
+
+/// Switches the process to `name`, keeping the capabilities `args` needs.
+///
+/// Must be called before the runtime threads are started, as capabilities
+/// are per thread and only inherited by threads created afterwards.
+pub(crate) fn drop_privileges(name: &str, args: &BootArgs) -> io::Result<()> {
+    let user = User::from_name(name)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no user named {name}")))?;
+    if !Uid::effective().is_root() {
+        tracing::warn!("Not running as root, ignoring --user {}", name);
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    caps::securebits::set_keepcaps(true).map_err(io::Error::other)?;
+
+    #[cfg(not(target_vendor = "apple"))]
+    unistd::setgroups(&[user.gid])?;
+    unistd::setgid(user.gid)?;
+    unistd::setuid(user.uid)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use caps::{CapSet, CapsHashSet};
+
+        let keep = capabilities(args);
+        caps::set(None, CapSet::Permitted, &keep).map_err(io::Error::other)?;
+        caps::set(None, CapSet::Effective, &keep).map_err(io::Error::other)?;
+        caps::set(None, CapSet::Inheritable, &CapsHashSet::new()).map_err(io::Error::other)?;
+        caps::securebits::set_keepcaps(false).map_err(io::Error::other)?;
+        tracing::info!("Running as {}, keeping {:?}", user.name, keep);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = args;
+        tracing::info!("Running as {}", user.name);
+    }
+
+    Ok(())
+}
+
+/// Returns the capabilities the server described by `args` needs after
+/// setup.
+#[cfg(target_os = "linux")]
+fn capabilities(args: &BootArgs) -> caps::CapsHashSet {
+    use caps::Capability;
+
+    let mut keep = caps::CapsHashSet::new();
+    keep.insert(Capability::CAP_NET_BIND_SERVICE);
+    if args.cidr_file.is_some() && !args.route.no_route {
+        keep.insert(Capability::CAP_NET_ADMIN);
+    }
+    #[cfg(feature = "ndp")]
+    let ndp = args.ndp_proxy.is_some();
+    #[cfg(not(feature = "ndp"))]
+    let ndp = false;
+    if args.interface.is_some() || ndp {
+        keep.insert(Capability::CAP_NET_RAW);
+    }
+    keep
+}
@@ -0,0 +1,113 @@
+//! CIDR routing self-test behind the `probe-cidr` subcommand.
+//!
+//! Binds random addresses of the CIDR and requests a reflector URL, which
+//! answers with the client address it sees, from each of them. A probe
+//! passes when the reflector sees the bound address, proving non-local
+//! binding and routing of the CIDR work before the proxy goes live.
+
+use cidr::IpCidr;
+use clap::Args;
+use reqwest::Url;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::TcpSocket;
+
+/// Options of the `probe-cidr` subcommand
+#[derive(Args, Clone)]
+pub struct ProbeArgs {
+    /// IP-CIDR to probe, e.g. 2001:db8::/32
+    #[clap(short = 'i', long)]
+    cidr: IpCidr,
+
+    /// URL answering with the address of the client as plain text
+    #[clap(long, default_value = "https://api.ip.sb/ip")]
+    url: Url,
+
+    /// Number of random addresses probed
+    #[clap(short = 'n', long, default_value = "5")]
+    count: usize,
+
+    /// Seconds allowed for a single probe
+    #[clap(short = 'T', long, default_value = "10")]
+    timeout: u64,
+}
+
+/// Probes random addresses of the CIDR, failing unless all of them work.
+pub fn probe_cidr(args: ProbeArgs) -> crate::Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(probe_all(args))
+}
+
+async fn probe_all(args: ProbeArgs) -> crate::Result<()> {
+    let timeout = Duration::from_secs(args.timeout.max(1));
+    let probes = (0..args.count.max(1))
+        .map(|_| {
+            let ip = crate::connect::assign_rand_ip(args.cidr);
+            (ip, tokio::spawn(probe(ip, args.url.clone(), timeout)))
+        })
+        .collect::<Vec<_>>();
+
+    let mut failures = 0;
+    for (ip, probe) in probes {
+        match probe.await? {
+            Ok(()) => println!("ok     {ip}"),
+            Err(err) => {
+                failures += 1;
+                println!("fail   {ip}: {err}");
+            }
+        }
+    }
+
+    if failures > 0 {
+        return Err(io::Error::other(format!(
+            "{failures} of {} addresses of {} do not work",
+            args.count.max(1),
+            args.cidr
+        ))
+        .into());
+    }
+    println!("all probed addresses of {} work", args.cidr);
+    Ok(())
+}
+
+/// Requests `url` from `ip`, checking the reflector sees that address.
+async fn probe(ip: IpAddr, url: Url, timeout: Duration) -> io::Result<()> {
+    // Tell binding apart from routing failures
+    let socket = match ip {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket
+        .bind(SocketAddr::new(ip, 0))
+        .map_err(|err| io::Error::new(err.kind(), format!("cannot bind: {err}")))?;
+    drop(socket);
+
+    let client = reqwest::Client::builder()
+        .local_address(ip)
+        .timeout(timeout)
+        .build()
+        .map_err(io::Error::other)?;
+    let answer = client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| io::Error::other(format!("request failed: {err}")))?
+        .text()
+        .await
+        .map_err(io::Error::other)?;
+
+    let seen = answer.trim();
+    match seen.parse::<IpAddr>() {
+        Ok(seen) if seen == ip => Ok(()),
+        Ok(seen) => Err(io::Error::other(format!("reflector saw {seen}"))),
+        Err(_) => Err(io::Error::other(format!(
+            "reflector answered {seen:?}, not an address"
+        ))),
+    }
+}
@@ -0,0 +1,261 @@
+//! HAProxy PROXY protocol.
+//!
+//! Load balancers working at the TCP level prepend a header carrying the
+//! address of the client they accepted the connection from, either as a line
+//! of text (version 1) or in binary (version 2). Listeners behind such a load
+//! balancer read it before anything else, so the client address is the one
+//! that hooks, logs and authentication see. Since whoever sends the header
+//! picks that address, `--proxy-protocol-from` limits the sources it is read
+//! from to the load balancers, and connections from anywhere else are dropped.
+//!
+//! The forward server can send a version 2 header itself, so the services it
+//! forwards to see who connected to vproxy.
+
+use cidr::IpCidr;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout,
+};
+
+/// Signature starting a version 2 header.
+const SIGNATURE_V2: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest version 1 header, line ending included.
+const MAX_V1_LEN: usize = 107;
+
+/// Reads the PROXY protocol header at the start of `stream`, returning the
+/// client address it carries, or `peer` when the header does not carry one,
+/// like the health checks of load balancers. Connections from outside
+/// `trusted` are refused before anything is read, unless it is empty.
+pub(crate) async fn accept<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    peer: SocketAddr,
+    within: Duration,
+    trusted: &[IpCidr],
+) -> io::Result<SocketAddr> {
+    let source = peer.ip().to_canonical();
+    if !trusted.is_empty() && !trusted.iter().any(|cidr| cidr.contains(&source)) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "not a trusted source of PROXY protocol headers",
+        ));
+    }
+    let header = timeout(within, read_header(stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header"))??;
+    Ok(header.unwrap_or(peer))
+}
+
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+
+    if &start == b"PROXY " {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= MAX_V1_LEN {
+                return Err(invalid("PROXY protocol header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(&line);
+    }
+
+    if start != SIGNATURE_V2[..6] {
+        return Err(invalid(
+            "connection did not start with a PROXY protocol header",
+        ));
+    }
+    let mut header = [0; 16];
+    header[..6].copy_from_slice(&start);
+    stream.read_exact(&mut header[6..]).await?;
+    if header[..12] != SIGNATURE_V2 {
+        return Err(invalid("invalid PROXY protocol signature"));
+    }
+
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+    parse_v2(header[12], header[13], &addresses)
+}
+
+/// Parses a version 1 line such as
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| invalid("PROXY protocol header is not ASCII"))?
+        .trim_end_matches("\r\n");
+    let fields = line.split(' ').collect::<Vec<_>>();
+
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("invalid PROXY protocol source address"))?;
+            let port = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol header")),
+    }
+}
+
+/// Parses the addresses of a version 2 header, given its version and command
+/// byte and its address family and protocol byte.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, sent by the load balancer itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap_or_default());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap_or_default());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_UNSPEC or AF_UNIX
+        0x0 | 0x3 => Ok(None),
+        _ => Err(invalid("truncated PROXY protocol addresses")),
+    }
+}
+
+/// Builds a version 2 header for a connection from `source` to
+/// `destination`.
+pub(crate) fn header_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE_V2.to_vec();
+    // Version 2, PROXY command
+    header.push(0x21);
+
+    match (source.ip().to_canonical(), destination.ip().to_canonical()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            // AF_INET, STREAM
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            // AF_INET6, STREAM
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_accept() {
+        let peer = "10.0.0.1:4000".parse().unwrap();
+        let within = Duration::from_secs(1);
+
+        let mut v1 = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        assert_eq!(
+            accept(&mut v1, peer, within, &[]).await.unwrap(),
+            "192.0.2.1:56324".parse().unwrap()
+        );
+        assert_eq!(v1, b"GET / HTTP/1.1\r\n");
+
+        let mut v2 = SIGNATURE_V2.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 1, 187,
+        ]);
+        v2.extend_from_slice(&[5, 1, 0]);
+        let mut stream = &v2[..];
+        assert_eq!(
+            accept(&mut stream, peer, within, &[]).await.unwrap(),
+            "192.0.2.1:56324".parse().unwrap()
+        );
+        assert_eq!(stream, [5, 1, 0]);
+
+        let mut local = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(accept(&mut local, peer, within, &[]).await.unwrap(), peer);
+
+        let mut plain = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(accept(&mut plain, peer, within, &[]).await.is_err());
+
+        for (source, destination) in [
+            ("192.0.2.1:56324", "198.51.100.1:443"),
+            ("[2001:db8::1]:56324", "198.51.100.1:443"),
+        ] {
+            let source = source.parse().unwrap();
+            let header = header_v2(source, destination.parse().unwrap());
+            let mut stream = &header[..];
+            let client = accept(&mut stream, peer, within, &[]).await.unwrap();
+            assert_eq!(client, source);
+            assert!(stream.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trusted_sources() {
+        let within = Duration::from_secs(1);
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+
+        let mut stream = &header[..];
+        let client = accept(
+            &mut stream,
+            "10.0.0.1:4000".parse().unwrap(),
+            within,
+            &trusted,
+        );
+        assert_eq!(client.await.unwrap(), "192.0.2.1:56324".parse().unwrap());
+
+        let mut stream = &header[..];
+        let client = accept(
+            &mut stream,
+            "[::ffff:10.0.0.1]:4000".parse().unwrap(),
+            within,
+            &trusted,
+        );
+        assert!(client.await.is_ok());
+
+        let mut stream = &header[..];
+        let client = accept(
+            &mut stream,
+            "192.0.2.9:4000".parse().unwrap(),
+            within,
+            &trusted,
+        );
+        let err = client.await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(stream.len(), header.len());
+    }
+}
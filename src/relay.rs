@@ -0,0 +1,264 @@
+//! Bidirectional relaying between two TCP streams.
+//!
+//! On Linux the data is moved with `splice(2)` through an intermediate pipe, so
+//! payload bytes never have to be copied into userspace. Other platforms copy
+//! through buffers borrowed from [`crate::pool`]. On io_uring workers the copy
+//! is driven by io_uring instead. Tunnels captured with `--pcap-dir` are copied
+//! through userspace, see [`crate::pcap`].
+
+use crate::pcap::Capture;
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Size of the buffer of each direction of a userspace relay, unless
+/// `--relay-buffer-size` says otherwise.
+const BUF_SIZE: usize = 16 * 1024;
+
+/// Smallest buffer size accepted for `--relay-buffer-size`.
+const MIN_BUF_SIZE: usize = 4 * 1024;
+
+/// Buffer size set with `--relay-buffer-size`, 0 when unset.
+static CONFIGURED_BUF_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the size of the buffer of each direction of every relay.
+pub(crate) fn set_buffer_size(size: usize) {
+    let size = size.max(MIN_BUF_SIZE);
+    tracing::info!("Relaying tunnels through {} byte buffers", size);
+    CONFIGURED_BUF_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Returns the configured relay buffer size, or `default`.
+pub(crate) fn buffer_size(default: usize) -> usize {
+    match CONFIGURED_BUF_SIZE.load(Ordering::Relaxed) {
+        0 => default,
+        size => size,
+    }
+}
+
+/// Bytes copied so far in each direction of a relay.
+///
+/// Counted as the data moves on Linux, and once the relay is done elsewhere.
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Bytes copied from `a` to `b`.
+    pub a_to_b: AtomicU64,
+    /// Bytes copied from `b` to `a`.
+    pub b_to_a: AtomicU64,
+}
+
+impl Progress {
+    /// Returns the bytes copied from `a` to `b` and from `b` to `a` so far,
+    /// what a relay that failed got through.
+    pub fn copied(&self) -> (u64, u64) {
+        (
+            self.a_to_b.load(Ordering::Relaxed),
+            self.b_to_a.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Copies data in both directions between `a` and `b` until both sides reach
+/// EOF, shutting down the write half of each stream once its peer is done.
+///
+/// Returns the number of bytes copied from `a` to `b` and from `b` to `a`,
+/// which are also counted in `progress`.
+#[inline]
+pub async fn copy_bidirectional(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    progress: &Arc<Progress>,
+) -> io::Result<(u64, u64)> {
+    if crate::pcap::enabled() {
+        // A peer that already went away is relayed uncaptured
+        if let (Ok(a_addr), Ok(b_addr)) = (a.peer_addr(), b.peer_addr()) {
+            if let Some(capture) = Capture::open(a_addr, b_addr) {
+                return copy_captured(a, b, capture, progress).await;
+            }
+        }
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if crate::uring::enabled() {
+        return crate::uring::copy_bidirectional(a, b, progress.clone()).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        splice::copy_bidirectional(a, b, progress).await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        copy_buffered(a, b, None, progress).await
+    }
+}
+
+/// Copies data in both directions between `a` and `b` like
+/// [`copy_bidirectional`], recording it into `capture`.
+pub(crate) async fn copy_captured<A, B>(
+    a: &mut A,
+    b: &mut B,
+    capture: Capture,
+    progress: &Progress,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    copy_buffered(a, b, Some(&capture), progress).await
+}
+
+/// Copies data in both directions between `a` and `b` through pooled
+/// buffers, recording it into `capture` if any.
+pub(crate) async fn copy_buffered<A, B>(
+    a: &mut A,
+    b: &mut B,
+    capture: Option<&Capture>,
+    progress: &Progress,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    tokio::try_join!(
+        copy_one_way(&mut a_read, &mut b_write, capture, true, &progress.a_to_b),
+        copy_one_way(&mut b_read, &mut a_write, capture, false, &progress.b_to_a)
+    )
+}
+
+/// Copies data from `from` to `to` until `from` reaches EOF, recording it as
+/// sent by `a` if `from_a`.
+async fn copy_one_way<R, W>(
+    from: &mut R,
+    to: &mut W,
+    capture: Option<&Capture>,
+    from_a: bool,
+    counter: &AtomicU64,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = crate::pool::get(buffer_size(BUF_SIZE));
+    let mut total = 0u64;
+    loop {
+        let len = from.read(&mut buf).await?;
+        if len == 0 {
+            if let Some(capture) = capture {
+                capture.finish(from_a);
+            }
+            if let Err(err) = to.shutdown().await {
+                tracing::trace!("relay shutdown error: {}", err);
+            }
+            return Ok(total);
+        }
+        if let Some(capture) = capture {
+            capture.data(from_a, &buf[..len]);
+        }
+        to.write_all(&buf[..len]).await?;
+        total += len as u64;
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod splice {
+    use super::{AtomicU64, Ordering, Progress};
+    use nix::{
+        fcntl::{fcntl, splice, FcntlArg, OFlag, SpliceFFlags},
+        sys::socket::{shutdown, Shutdown},
+        unistd::pipe2,
+    };
+    use std::{
+        io::{self, ErrorKind},
+        os::fd::{AsFd, AsRawFd},
+    };
+    use tokio::{io::Interest, net::TcpStream};
+
+    /// Maximum number of bytes moved per `splice(2)` call, the default pipe
+    /// capacity, unless `--relay-buffer-size` resizes the pipe.
+    const PIPE_SIZE: usize = 1 << 16;
+
+    pub(super) async fn copy_bidirectional(
+        a: &mut TcpStream,
+        b: &mut TcpStream,
+        progress: &Progress,
+    ) -> io::Result<(u64, u64)> {
+        let (a, b) = (&*a, &*b);
+        tokio::try_join!(
+            copy_one_way(a, b, &progress.a_to_b),
+            copy_one_way(b, a, &progress.b_to_a)
+        )
+    }
+
+    /// Moves data from `from` to `to` through a pipe until `from` reaches EOF,
+    /// adding the bytes moved to `counter`.
+    async fn copy_one_way(
+        from: &TcpStream,
+        to: &TcpStream,
+        counter: &AtomicU64,
+    ) -> io::Result<u64> {
+        let (pipe_rd, pipe_wr) = pipe2(OFlag::O_NONBLOCK | OFlag::O_CLOEXEC)?;
+        let pipe_size = match super::buffer_size(0) {
+            0 => PIPE_SIZE,
+            // The kernel rounds the size up, or refuses it beyond
+            // /proc/sys/fs/pipe-max-size for unprivileged processes
+            size => match fcntl(pipe_wr.as_raw_fd(), FcntlArg::F_SETPIPE_SZ(size as _)) {
+                Ok(size) => size as usize,
+                Err(err) => {
+                    tracing::trace!("pipe resize error: {}", err);
+                    PIPE_SIZE
+                }
+            },
+        };
+        let flags = SpliceFFlags::SPLICE_F_MOVE | SpliceFFlags::SPLICE_F_NONBLOCK;
+        let mut total = 0u64;
+
+        loop {
+            let len = loop {
+                from.readable().await?;
+                match from.try_io(Interest::READABLE, || {
+                    splice(from, None, &pipe_wr, None, pipe_size, flags).map_err(io::Error::from)
+                }) {
+                    Ok(len) => break len,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err),
+                }
+            };
+
+            if len == 0 {
+                // The peer will not send anything else, propagate the EOF.
+                if let Err(err) = shutdown(to.as_fd().as_raw_fd(), Shutdown::Write) {
+                    tracing::trace!("splice shutdown error: {}", err);
+                }
+                return Ok(total);
+            }
+
+            let mut remaining = len;
+            while remaining > 0 {
+                to.writable().await?;
+                match to.try_io(Interest::WRITABLE, || {
+                    splice(&pipe_rd, None, to, None, remaining, flags).map_err(io::Error::from)
+                }) {
+                    Ok(written) => remaining -= written,
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            total += len as u64;
+            counter.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+}
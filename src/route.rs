@@ -1,146 +1,261 @@
+use crate::RouteOptions;
 use cidr::IpCidr;
 use futures::TryStreamExt;
 use netlink_packet_route::{
-    route::{RouteAddress, RouteAttribute, RouteProtocol, RouteScope, RouteType},
+    route::{RouteAddress, RouteAttribute, RouteMessage, RouteProtocol, RouteScope, RouteType},
     AddressFamily,
 };
 use rtnetlink::{new_connection, Error, Handle, IpVersion};
+use std::net::{IpAddr, SocketAddr};
 use sysctl::{Sysctl, SysctlError};
+use tokio::net::TcpSocket;
 
-/// Attempts to add a route to the given subnet on the loopback interface.
+/// Applies the sysctls and adds the local route `subnet` needs to be bound,
+/// unless `--no-sysctl` or `--no-route` leave them to the operator.
+pub async fn prepare_cidr(subnet: &IpCidr, opts: &RouteOptions) {
+    if !opts.no_sysctl {
+        sysctl_ipv6_no_local_bind(subnet);
+        sysctl_ipv6_all_enable_ipv6(subnet);
+    }
+    if !opts.no_route {
+        sysctl_route_add_cidr(subnet, opts).await;
+    }
+}
+
+/// Attempts to add a local route for the given subnet on the configured
+/// interface, `lo` unless `--route-interface` says otherwise.
 ///
-/// This function uses the `ip` command to add a route to the loopback
-/// interface. It checks if the current user has root privileges before
-/// attempting to add the route. If the user does not have root privileges, the
-/// function returns immediately. If the `ip` command fails, it prints an error
-/// message to the console.
+/// This function uses netlink to add the route to the configured table with
+/// the configured priority. If the route cannot be added, e.g. for lack of
+/// privileges, it logs a warning with the command to add it by hand.
 ///
 /// # Arguments
 ///
 /// * `subnet` - The subnet for which to add a route.
+/// * `opts` - The interface, table and priority of the route.
 ///
 /// # Example
 ///
 /// ```
 /// let subnet = cidr::IpCidr::from_str("192.168.1.0/24").unwrap();
-/// sysctl_route_add_cidr(&subnet);
+/// sysctl_route_add_cidr(&subnet, &opts);
 /// ```
-pub async fn sysctl_route_add_cidr(subnet: &IpCidr) {
-    let (connection, handle, _) = new_connection().unwrap();
+pub async fn sysctl_route_add_cidr(subnet: &IpCidr, opts: &RouteOptions) {
+    let (connection, handle, _) = match new_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::warn!("Failed to open a netlink connection: {}", err);
+            return;
+        }
+    };
 
     tokio::spawn(connection);
 
-    if let Err(e) = add_route(handle.clone(), subnet).await {
-        tracing::trace!("Failed to apply route: {}", e);
+    if let Err(e) = add_route(handle.clone(), subnet, opts).await {
+        tracing::warn!(
+            "Failed to add local route for {}: {}; run `{}` as root",
+            subnet,
+            e,
+            route_command("add", subnet, opts)
+        );
     }
 }
 
-async fn add_route(handle: Handle, cidr: &IpCidr) -> Result<(), Error> {
-    const LOCAL_TABLE_ID: u8 = 255;
+/// Attempts to remove the local route of the given subnet from the configured
+/// interface, e.g. once its prefix is removed from the CIDR file.
+///
+/// Failures are logged with the command to remove the route by hand.
+pub async fn sysctl_route_del_cidr(subnet: &IpCidr, opts: &RouteOptions) {
+    let (connection, handle, _) = match new_connection() {
+        Ok(conn) => conn,
+        Err(err) => {
+            tracing::warn!("Failed to open a netlink connection: {}", err);
+            return;
+        }
+    };
+
+    tokio::spawn(connection);
 
-    let route = handle.route();
-    let iface_idx = handle
+    if let Err(e) = del_route(handle, subnet, opts).await {
+        tracing::warn!(
+            "Failed to remove local route for {}: {}; run `{}` as root",
+            subnet,
+            e,
+            route_command("del", subnet, opts)
+        );
+    }
+}
+
+/// Checks that addresses of `subnet` can be bound, which requires the local
+/// route and, for IPv6, non-local binding to be set up.
+///
+/// The returned error explains how to set them up by hand.
+pub fn check_cidr_bind(subnet: &IpCidr, opts: &RouteOptions) -> std::io::Result<()> {
+    let ip = subnet.first_address();
+    let socket = match ip {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    socket.bind(SocketAddr::new(ip, 0)).map_err(|err| {
+        let mut remediation = route_command("add", subnet, opts);
+        if subnet.is_ipv6() {
+            remediation.push_str(" && sysctl -w net.ipv6.ip_nonlocal_bind=1");
+        }
+        std::io::Error::new(
+            err.kind(),
+            format!(
+                "cannot bind to addresses of {subnet} ({err}); run vproxy as root (or with \
+                     CAP_NET_ADMIN) or set up the route by hand: `{remediation}`"
+            ),
+        )
+    })
+}
+
+/// Returns the `ip route` command adding or deleting the local route of
+/// `subnet` by hand.
+fn route_command(action: &str, subnet: &IpCidr, opts: &RouteOptions) -> String {
+    let mut command = format!(
+        "ip route {action} local {subnet} dev {}",
+        opts.route_interface
+    );
+    if opts.route_table != LOCAL_TABLE_ID {
+        command.push_str(&format!(" table {}", opts.route_table));
+    }
+    command
+}
+
+/// ID of the `local` routing table.
+const LOCAL_TABLE_ID: u32 = 255;
+
+/// Returns the routing table of `route`. IDs above 255 do not fit the header
+/// and are only carried by an attribute.
+fn route_table(route: &RouteMessage) -> u32 {
+    route
+        .attributes
+        .iter()
+        .find_map(|attr| match attr {
+            RouteAttribute::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(route.header.table.into())
+}
+
+/// Returns the family, version and destination of the route of `cidr`.
+fn route_destination(cidr: &IpCidr) -> (IpVersion, AddressFamily, RouteAddress) {
+    match cidr {
+        IpCidr::V4(v4) => (
+            IpVersion::V4,
+            AddressFamily::Inet,
+            RouteAddress::Inet(v4.first_address()),
+        ),
+        IpCidr::V6(v6) => (
+            IpVersion::V6,
+            AddressFamily::Inet6,
+            RouteAddress::Inet6(v6.first_address()),
+        ),
+    }
+}
+
+/// Returns the local route of `cidr` in the configured table, if there is one.
+async fn find_route(
+    handle: &Handle,
+    cidr: &IpCidr,
+    opts: &RouteOptions,
+) -> Result<Option<RouteMessage>, Error> {
+    let (ip_version, address_family, route_address) = route_destination(cidr);
+    let mut routes = handle.route().get(ip_version).execute();
+    while let Some(route) = routes.try_next().await? {
+        tracing::trace!(
+            "route attributes: {:?}\nroute header: {:?}",
+            route.attributes,
+            route.header
+        );
+        if route.header.address_family == address_family
+            && route.header.destination_prefix_length == cidr.network_length()
+            && route_table(&route) == opts.route_table
+            && route.attributes.iter().any(
+                |attr| matches!(attr, RouteAttribute::Destination(dest) if dest == &route_address),
+            )
+        {
+            return Ok(Some(route));
+        }
+    }
+    Ok(None)
+}
+
+async fn add_route(handle: Handle, cidr: &IpCidr, opts: &RouteOptions) -> Result<(), Error> {
+    let Some(link) = handle
         .link()
         .get()
-        .match_name("lo".to_owned())
+        .match_name(opts.route_interface.clone())
         .execute()
         .try_next()
         .await?
-        .unwrap()
-        .header
-        .index;
+    else {
+        tracing::warn!("No interface named {}", opts.route_interface);
+        return Ok(());
+    };
+    let iface_idx = link.header.index;
 
     // Check if the route already exists
-    let route_check = |ip_version: IpVersion,
-                       address_family: AddressFamily,
-                       destination_prefix_length: u8,
-                       route_address: RouteAddress| async move {
-        let mut routes = handle.route().get(ip_version).execute();
-        while let Some(route) = routes.try_next().await? {
-            let header = route.header;
-            tracing::trace!(
-                "route attributes: {:?}\nroute header: {:?}",
-                route.attributes,
-                header
-            );
-            if header.address_family == address_family
-                && header.destination_prefix_length == destination_prefix_length
-                && header.table == LOCAL_TABLE_ID
-            {
-                for attr in route.attributes.iter() {
-                    if let RouteAttribute::Destination(dest) = attr {
-                        if dest == &route_address {
-                            tracing::info!("IP route {} already exists", cidr);
-                            return Ok(true);
-                        }
-                    }
-                }
-            }
-        }
-        Ok(false)
-    };
+    if find_route(&handle, cidr, opts).await?.is_some() {
+        tracing::info!("IP route {} already exists", cidr);
+        return Ok(());
+    }
 
-    // Add a route to the loopback interface.
+    // Add a local route to the configured interface.
+    let route = handle.route();
     match cidr {
         IpCidr::V4(v4) => {
-            if !route_check(
-                IpVersion::V4,
-                AddressFamily::Inet,
-                v4.network_length(),
-                RouteAddress::Inet(v4.first_address()),
-            )
-            .await?
-            {
-                route
-                    .add()
-                    .v4()
-                    .destination_prefix(v4.first_address(), v4.network_length())
-                    .kind(RouteType::Local)
-                    .protocol(RouteProtocol::Boot)
-                    .scope(RouteScope::Universe)
-                    .output_interface(iface_idx)
-                    .priority(1024)
-                    .table_id(LOCAL_TABLE_ID.into())
-                    .execute()
-                    .await?;
-                tracing::info!("Added IPv4 route {}", cidr);
-            }
+            route
+                .add()
+                .v4()
+                .destination_prefix(v4.first_address(), v4.network_length())
+                .kind(RouteType::Local)
+                .protocol(RouteProtocol::Boot)
+                .scope(RouteScope::Universe)
+                .output_interface(iface_idx)
+                .priority(opts.route_priority)
+                .table_id(opts.route_table)
+                .execute()
+                .await?;
+            tracing::info!("Added IPv4 route {} on {}", cidr, opts.route_interface);
         }
         IpCidr::V6(v6) => {
-            if !route_check(
-                IpVersion::V6,
-                AddressFamily::Inet6,
-                v6.network_length(),
-                RouteAddress::Inet6(v6.first_address()),
-            )
-            .await?
-            {
-                route
-                    .add()
-                    .v6()
-                    .destination_prefix(v6.first_address(), v6.network_length())
-                    .kind(RouteType::Local)
-                    .protocol(RouteProtocol::Boot)
-                    .scope(RouteScope::Universe)
-                    .output_interface(iface_idx)
-                    .priority(1024)
-                    .table_id(LOCAL_TABLE_ID.into())
-                    .execute()
-                    .await?;
-                tracing::info!("Added IPv6 route {}", cidr);
-            }
+            route
+                .add()
+                .v6()
+                .destination_prefix(v6.first_address(), v6.network_length())
+                .kind(RouteType::Local)
+                .protocol(RouteProtocol::Boot)
+                .scope(RouteScope::Universe)
+                .output_interface(iface_idx)
+                .priority(opts.route_priority)
+                .table_id(opts.route_table)
+                .execute()
+                .await?;
+            tracing::info!("Added IPv6 route {} on {}", cidr, opts.route_interface);
         }
     }
 
     Ok(())
 }
 
+async fn del_route(handle: Handle, cidr: &IpCidr, opts: &RouteOptions) -> Result<(), Error> {
+    if let Some(route) = find_route(&handle, cidr, opts).await? {
+        handle.route().del(route).execute().await?;
+        tracing::info!("Removed IP route {}", cidr);
+    }
+    Ok(())
+}
+
 /// Tries to disable local binding for IPv6.
 ///
 /// This function uses the `sysctl` command to disable local binding for IPv6.
 /// It attempts to change the setting by calling the `execute_sysctl` function
-/// with the appropriate parameters. If the `sysctl` command fails, it logs an
-/// error message.
+/// with the appropriate parameters. If the `sysctl` command fails, it logs a
+/// warning with the command to run by hand.
 ///
 /// # Example
 ///
@@ -149,16 +264,14 @@ async fn add_route(handle: Handle, cidr: &IpCidr) -> Result<(), Error> {
 /// ```
 pub fn sysctl_ipv6_no_local_bind(subnet: &IpCidr) {
     if subnet.is_ipv6() {
-        if let Err(err) = execute_sysctl("net.ipv6.ip_nonlocal_bind", "1") {
-            tracing::trace!("Failed to execute sysctl: {}", err)
-        }
+        set_sysctl("net.ipv6.ip_nonlocal_bind", "1");
     }
 }
 
 /// This function uses the `sysctl` command to enable IPv6 on all interfaces.
 /// It attempts to change the setting by calling the `execute_sysctl` function
-/// with the appropriate parameters. If the `sysctl` command fails, it logs an
-/// error message.
+/// with the appropriate parameters. If the `sysctl` command fails, it logs a
+/// warning with the command to run by hand.
 ///
 /// # Example
 ///
@@ -182,9 +295,21 @@ pub fn sysctl_ipv6_no_local_bind(subnet: &IpCidr) {
 ///
 pub fn sysctl_ipv6_all_enable_ipv6(subnet: &IpCidr) {
     if subnet.is_ipv6() {
-        if let Err(err) = execute_sysctl("net.ipv6.conf.all.disable_ipv6", "0") {
-            tracing::trace!("Failed to execute sysctl: {}", err)
-        }
+        set_sysctl("net.ipv6.conf.all.disable_ipv6", "0");
+    }
+}
+
+/// Sets a kernel parameter, logging the command to set it by hand if that
+/// fails, e.g. for lack of privileges.
+fn set_sysctl(command: &str, value: &str) {
+    if let Err(err) = execute_sysctl(command, value) {
+        tracing::warn!(
+            "Failed to set {}: {}; run `sysctl -w {}={}` as root",
+            command,
+            err,
+            command,
+            value
+        )
     }
 }
 
@@ -0,0 +1,179 @@
+//! Destination based egress rules.
+//!
+//! Each non-empty line that is not a `#` comment maps a destination pattern
+//! to the egress used for it:
+//!
+//! ```text
+//! pattern [direct | cidr=IP-CIDR [cidr-range=N] [fallback=IP]]
+//! *.internal.corp direct
+//! api.example.com fallback=192.0.2.10
+//! *.example.com   cidr=2001:db8:2::/48 cidr-range=64
+//! *               cidr=2001:db8:1::/48
+//! ```
+//!
+//! A pattern is a host name or address, `*.domain` matching the subdomains of
+//! `domain`, or `*` matching every destination. The first matching rule
+//! wins. Its settings replace the egress of the command line and the user
+//! entirely, so `direct` connects from the default address of the host.
+//! Destinations no rule matches keep their egress.
+
+use crate::{connect::Connector, users::Pool};
+use std::{io, path::Path};
+
+/// Egress rules loaded from a rules file.
+#[derive(Debug)]
+pub(crate) struct EgressRules {
+    rules: Vec<(String, Pool)>,
+}
+
+impl EgressRules {
+    /// Loads the rules file at `path`.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        parse(&content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })
+    }
+
+    /// Number of rules.
+    pub(crate) fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns the patterns and egress pools, in file order.
+    pub(crate) fn rules(&self) -> &[(String, Pool)] {
+        &self.rules
+    }
+
+    /// Returns the egress pool of the first rule matching `host`.
+    pub(crate) fn lookup(&self, host: &str) -> Option<&Pool> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        self.rules
+            .iter()
+            .find(|(pattern, _)| matches(pattern, host))
+            .map(|(_, pool)| pool)
+    }
+
+    /// Returns `connector` with the egress of the rule matching `host`, if
+    /// any.
+    pub(crate) fn apply(&self, connector: Connector, host: &str) -> Connector {
+        match self.lookup(host) {
+            Some(pool) => {
+                tracing::debug!("egress rule for {}: {:?}", host, pool);
+                connector.with_egress(pool)
+            }
+            None => connector,
+        }
+    }
+}
+
+/// Whether `host` matches `pattern`: a domain, a `*.` wildcard matching its
+/// subdomains, or `*` matching everything.
+pub(crate) fn matches(pattern: &str, host: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .len()
+            .checked_sub(domain.len())
+            .filter(|&at| host.is_char_boundary(at))
+            .is_some_and(|at| host[..at].ends_with('.') && host[at..].eq_ignore_ascii_case(domain)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Parses the content of a rules file.
+fn parse(content: &str) -> Result<EgressRules, String> {
+    let mut rules = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let rule = parse_line(line).map_err(|err| format!("line {}: {}", number + 1, err))?;
+        rules.push(rule);
+    }
+
+    Ok(EgressRules { rules })
+}
+
+/// Parses a `pattern strategy` line.
+fn parse_line(line: &str) -> Result<(String, Pool), String> {
+    let mut fields = line.split_whitespace();
+    let pattern = fields.next().unwrap_or_default().to_ascii_lowercase();
+    let settings = fields.collect::<Vec<_>>();
+
+    let pool = match settings.as_slice() {
+        [] => return Err(format!("missing egress for {pattern}")),
+        ["direct"] => Pool::default(),
+        settings => {
+            let mut pool = Pool::default();
+            for field in settings {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected key=value or direct: {field}"))?;
+                pool.set(key, value)?;
+            }
+            if pool.cidr.is_none() && pool.fallback.is_none() {
+                return Err(format!("{pattern} needs a cidr or fallback"));
+            }
+            pool
+        }
+    };
+
+    Ok((pattern, pool))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_lookup() {
+        let rules = parse(
+            "# egress rules\n\
+             *.internal.corp direct\n\
+             api.example.com fallback=192.0.2.10\n\
+             *.example.com cidr=2001:db8:2::/48 cidr-range=64\n",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 3);
+
+        assert_eq!(rules.lookup("git.INTERNAL.corp"), Some(&Pool::default()));
+        assert_eq!(
+            rules
+                .lookup("api.example.com")
+                .and_then(|pool| pool.fallback),
+            Some("192.0.2.10".parse().unwrap())
+        );
+        assert_eq!(
+            rules
+                .lookup("www.example.com")
+                .and_then(|pool| pool.cidr_range),
+            Some(64)
+        );
+        assert_eq!(rules.lookup("example.com"), None);
+        assert_eq!(rules.lookup("internal.corp"), None);
+
+        assert!(parse("*.example.com").is_err());
+        assert!(parse("*.example.com cidr-range=64").is_err());
+        assert!(parse("*.example.com direct cidr=10.0.0.0/8").is_err());
+        assert!(parse("* color=red").is_err());
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("example.com", "example.com"));
+        assert!(!matches("example.com", "www.example.com"));
+        assert!(matches("*.example.com", "www.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "badexample.com"));
+        assert!(matches("*", "example.com"));
+    }
+}
@@ -0,0 +1,178 @@
+//! Opt-in sampling of anonymized tunnel statistics.
+//!
+//! A configurable fraction of closed tunnels is recorded into aggregates that
+//! help plan egress pools: the distribution of target top-level domains and a
+//! histogram of transferred bytes. Nothing identifying is kept, only the last
+//! label of a target host name is recorded, IP address targets are counted as
+//! `ip`, and clients are never recorded. The aggregates are logged
+//! periodically.
+
+use crate::hooks::{Hooks, TunnelClose};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Maximum number of distinct TLDs tracked, later ones are counted as `other`.
+const MAX_TLDS: usize = 256;
+
+/// Number of power-of-two size buckets, the last one is open ended.
+const SIZE_BUCKETS: usize = 32;
+
+/// Aggregates of sampled tunnels.
+pub(crate) struct Sampler {
+    rate: f64,
+    sampled: AtomicU64,
+    tlds: Mutex<HashMap<String, u64>>,
+    sizes: [AtomicU64; SIZE_BUCKETS],
+}
+
+impl Sampler {
+    /// Create a sampler recording a `rate` fraction of tunnels.
+    pub(crate) fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            sampled: AtomicU64::new(0),
+            tlds: Mutex::new(HashMap::new()),
+            sizes: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+
+    /// Records `target` and the bytes transferred, if the tunnel is sampled.
+    fn record(&self, target: &str, bytes: u64) {
+        if rand::random::<f64>() >= self.rate {
+            return;
+        }
+
+        self.sampled.fetch_add(1, Ordering::Relaxed);
+        self.sizes[size_bucket(bytes)].fetch_add(1, Ordering::Relaxed);
+
+        if let Ok(mut tlds) = self.tlds.lock() {
+            let tld = redact(target);
+            if tlds.len() < MAX_TLDS || tlds.contains_key(&tld) {
+                *tlds.entry(tld).or_insert(0) += 1;
+            } else {
+                *tlds.entry("other".to_owned()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    /// Logs the aggregates every `interval`.
+    pub(crate) async fn report(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+
+            let mut tlds = self
+                .tlds
+                .lock()
+                .map(|tlds| {
+                    tlds.iter()
+                        .map(|(k, v)| (k.clone(), *v))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            tlds.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+            tlds.truncate(10);
+
+            let sizes = self
+                .sizes
+                .iter()
+                .enumerate()
+                .filter_map(|(bucket, count)| {
+                    let count = count.load(Ordering::Relaxed);
+                    (count > 0).then(|| {
+                        // The last bucket holds everything from its lower bound
+                        if bucket == SIZE_BUCKETS - 1 {
+                            format!(">={}B:{}", 1u64 << (bucket - 1), count)
+                        } else {
+                            format!("<{}B:{}", 1u64 << bucket, count)
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            tracing::info!(
+                "Sampled {} tunnels, top TLDs: {:?}, sizes: [{}]",
+                self.sampled.load(Ordering::Relaxed),
+                tlds,
+                sizes.join(" ")
+            );
+        }
+    }
+}
+
+impl Hooks for Sampler {
+    fn on_tunnel_close(&self, event: &TunnelClose) {
+        self.record(&event.target, event.sent + event.received);
+    }
+}
+
+/// Reduces a `host:port` target to its top-level domain.
+fn redact(target: &str) -> String {
+    // A bare IPv6 address would otherwise lose its last group as a port
+    if target.parse::<std::net::IpAddr>().is_ok()
+        || target.parse::<std::net::SocketAddr>().is_ok()
+        || target
+            .strip_prefix('[')
+            .and_then(|target| target.strip_suffix(']'))
+            .is_some_and(|ip| ip.parse::<std::net::Ipv6Addr>().is_ok())
+    {
+        return "ip".to_owned();
+    }
+
+    let host = match target.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => target,
+    };
+
+    host.trim_end_matches('.')
+        .rsplit('.')
+        .next()
+        .filter(|tld| {
+            !tld.is_empty() && tld.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+        .map_or_else(|| "invalid".to_owned(), str::to_ascii_lowercase)
+}
+
+/// Parses a sampling rate, which must lie between 0 and 1.
+pub(crate) fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("sample rate must be between 0 and 1: {s}")),
+    }
+}
+
+/// Returns the power-of-two bucket of `bytes`.
+fn size_bucket(bytes: u64) -> usize {
+    ((u64::BITS - bytes.leading_zeros()) as usize).min(SIZE_BUCKETS - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("www.Example.COM:443"), "com");
+        assert_eq!(redact("example.org."), "org");
+        assert_eq!(redact("192.0.2.1:80"), "ip");
+        assert_eq!(redact("[2001:db8::1]:443"), "ip");
+        assert_eq!(redact("2001:db8::1"), "ip");
+        assert_eq!(redact("[2001:db8::1]"), "ip");
+        assert_eq!(redact("localhost:8080"), "localhost");
+    }
+
+    #[test]
+    fn test_size_bucket() {
+        assert_eq!(size_bucket(0), 0);
+        assert_eq!(size_bucket(1), 1);
+        assert_eq!(size_bucket(1500), 11);
+        assert_eq!(size_bucket(u64::MAX), SIZE_BUCKETS - 1);
+    }
+}
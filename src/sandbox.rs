@@ -0,0 +1,378 @@
+//! Sandboxing of the serving process on Linux.
+//!
+//! With `--sandbox` the server confines itself once the host is set up,
+//! before it serves any connection:
+//!
+//! - Landlock rules limit the filesystem to reading the system and its
+//!   configuration files, and to writing the directories of its state and
+//!   log files;
+//! - a seccomp filter lets through the syscalls of networking, file access,
+//!   memory, threads and timers, and makes every other one, such as
+//!   `execve`, `ptrace` or `mount`, fail with `EPERM`. Threads are created
+//!   without new namespaces, `ioctl` is limited to the requests of socket
+//!   and terminal state, and io_uring, whose submissions bypass seccomp, is
+//!   only allowed with `--io-uring`.
+//!
+//! A parser bug exploited by a client can then neither run programs nor
+//! touch the rest of the host. Kernels without Landlock run the server with
+//! the seccomp filter alone.
+
+use crate::BootArgs;
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use nix::libc;
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule, TargetArch,
+};
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Syscalls the server makes once it is set up: networking, files, memory,
+/// threads and timers. Every other syscall fails with `EPERM` in the
+/// sandbox, except for those with filtered arguments below.
+const ALLOWED_SYSCALLS: &[i64] = &[
+    // Sockets
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_connect,
+    libc::SYS_shutdown,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    // Reading and writing descriptors
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_preadv,
+    libc::SYS_pwritev,
+    libc::SYS_sendfile,
+    libc::SYS_splice,
+    libc::SYS_copy_file_range,
+    libc::SYS_close,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_fcntl,
+    libc::SYS_lseek,
+    // Files, confined by Landlock
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_readlinkat,
+    libc::SYS_getdents64,
+    libc::SYS_getcwd,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    libc::SYS_ftruncate,
+    libc::SYS_fallocate,
+    libc::SYS_fadvise64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
+    // Memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    libc::SYS_memfd_create,
+    // Threads, signals and process information
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getcpu,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_getrusage,
+    libc::SYS_sysinfo,
+    libc::SYS_uname,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_getppid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getresuid,
+    libc::SYS_getresgid,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_restart_syscall,
+    // Events and time
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_timerfd_gettime,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+];
+
+/// Flags of `clone` creating namespaces, which threads never need.
+const CLONE_NAMESPACES: u64 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWCGROUP) as u64;
+
+/// `ioctl` requests for socket and terminal state, made by the standard
+/// library and the logger. Others, such as `TIOCSTI` injecting input into
+/// the terminal, are denied.
+// The request type is `c_ulong` with glibc and `c_int` with musl
+#[allow(clippy::unnecessary_cast)]
+const ALLOWED_IOCTLS: [u64; 6] = [
+    libc::FIONBIO as u64,
+    libc::FIONREAD as u64,
+    libc::FIOCLEX as u64,
+    libc::FIONCLEX as u64,
+    libc::TCGETS as u64,
+    libc::TIOCGWINSZ as u64,
+];
+
+/// Syscalls of io_uring, allowed only with `--io-uring`.
+const IO_URING_SYSCALLS: [i64; 3] = [
+    libc::SYS_io_uring_setup,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+];
+
+/// Older syscalls of x86_64 that the C library may still use, which newer
+/// architectures only have in their `*at` or `p*` form.
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_LEGACY_SYSCALLS: &[i64] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_mkdir,
+    libc::SYS_chmod,
+    libc::SYS_unlink,
+    libc::SYS_rename,
+    libc::SYS_pipe,
+    libc::SYS_dup2,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_create,
+    libc::SYS_epoll_wait,
+    libc::SYS_getrlimit,
+    libc::SYS_arch_prctl,
+];
+
+#[cfg(not(target_arch = "x86_64"))]
+const ALLOWED_LEGACY_SYSCALLS: &[i64] = &[];
+
+/// Directories every server reads from: name resolution, certificates,
+/// process statistics and random numbers.
+const SYSTEM_PATHS: [&str; 4] = ["/etc", "/proc", "/sys", "/dev"];
+
+/// Confines the process as described in the module documentation.
+///
+/// Must be called before the runtime threads are started, as Landlock only
+/// confines the calling thread and the threads it creates afterwards. The
+/// seccomp filter is synchronized to every thread of the process.
+pub(crate) fn apply(args: &BootArgs) -> io::Result<()> {
+    #[cfg(feature = "io-uring")]
+    let io_uring = args.io_uring;
+    #[cfg(not(feature = "io-uring"))]
+    let io_uring = false;
+
+    restrict_filesystem(args)?;
+    restrict_syscalls(io_uring)?;
+    tracing::info!("Sandbox: Landlock and seccomp applied");
+    Ok(())
+}
+
+fn restrict_filesystem(args: &BootArgs) -> io::Result<()> {
+    let (read, write) = paths(args);
+    let abi = ABI::V3;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .and_then(|ruleset| ruleset.create())
+        .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(read, AccessFs::from_read(abi))))
+        .and_then(|ruleset| ruleset.add_rules(path_beneath_rules(write, AccessFs::from_all(abi))))
+        .and_then(|ruleset| ruleset.restrict_self())
+        .map_err(io::Error::other)?;
+
+    if status.ruleset == RulesetStatus::NotEnforced {
+        tracing::warn!("Sandbox: Landlock is not supported by the kernel");
+    }
+    Ok(())
+}
+
+fn restrict_syscalls(io_uring: bool) -> io::Result<()> {
+    let rule = |arg, len, op, value| {
+        SeccompCondition::new(arg, len, op, value)
+            .and_then(|condition| SeccompRule::new(vec![condition]))
+            .map_err(io::Error::other)
+    };
+
+    let mut rules = ALLOWED_SYSCALLS
+        .iter()
+        .chain(ALLOWED_LEGACY_SYSCALLS)
+        .chain(IO_URING_SYSCALLS.iter().filter(|_| io_uring))
+        // An empty rule list matches every call of the syscall
+        .map(|syscall| (*syscall, Vec::<SeccompRule>::new()))
+        .collect::<BTreeMap<_, _>>();
+    rules.insert(
+        libc::SYS_clone,
+        vec![rule(
+            0,
+            SeccompCmpArgLen::Qword,
+            SeccompCmpOp::MaskedEq(CLONE_NAMESPACES),
+            0,
+        )?],
+    );
+    rules.insert(
+        libc::SYS_ioctl,
+        ALLOWED_IOCTLS
+            .iter()
+            // The kernel only reads the lower half of the request
+            .map(|request| rule(1, SeccompCmpArgLen::Dword, SeccompCmpOp::Eq, *request))
+            .collect::<io::Result<_>>()?,
+    );
+
+    // The flags of `clone3` are behind a pointer seccomp cannot follow, so it
+    // fails with `ENOSYS` from a filter of its own and the C library falls
+    // back to `clone`. The most recent filter decides between two errors, so
+    // the filter of the other syscalls lets it through. That one is
+    // installed last, as it denies installing filters.
+    rules.insert(libc::SYS_clone3, Vec::new());
+    let clone3 = BTreeMap::from([(libc::SYS_clone3, Vec::new())]);
+    apply_filter(
+        clone3,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+    )?;
+    apply_filter(
+        rules,
+        SeccompAction::Errno(libc::EPERM as u32),
+        SeccompAction::Allow,
+    )
+}
+
+/// Installs a filter taking `matched` for the calls matching `rules` and
+/// `mismatched` for the others, on every thread of the process.
+fn apply_filter(
+    rules: BTreeMap<i64, Vec<SeccompRule>>,
+    mismatched: SeccompAction,
+    matched: SeccompAction,
+) -> io::Result<()> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(io::Error::other)?;
+    let filter = SeccompFilter::new(rules, mismatched, matched, arch).map_err(io::Error::other)?;
+    let program = BpfProgram::try_from(filter).map_err(io::Error::other)?;
+    seccompiler::apply_filter_all_threads(&program).map_err(io::Error::other)
+}
+
+/// Returns the existing directories the server reads from and writes to.
+///
+/// Files are covered through their directory, so a configuration file
+/// replaced by renaming a new one over it can still be reloaded.
+fn paths(args: &BootArgs) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let parent = |path: &PathBuf| {
+        path.parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map_or_else(|| PathBuf::from("."), Path::to_path_buf)
+    };
+
+    let mut read = SYSTEM_PATHS.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let auth_file = args.proxy.auth().and_then(|auth| auth.auth_file.as_ref());
+    read.extend(
+        [&args.cidr_file, &args.hosts, &args.egress_rules]
+            .into_iter()
+            .flatten()
+            .chain(auth_file)
+            .map(parent),
+    );
+    #[cfg(feature = "wasm")]
+    read.extend(args.policy_wasm.iter().map(parent));
+
+    let mut write = [
+        &args.stats_file,
+        &args.audit_log,
+        &args.egress_ban_file,
+        &args.control_socket,
+    ]
+    .into_iter()
+    .flatten()
+    .map(parent)
+    .collect::<Vec<_>>();
+    write.extend(args.proxy.http().and_then(|http| http.cache_dir.clone()));
+    write.extend(args.pcap_dir.clone());
+    write.extend(args.log_ship.log_ship_buffer.clone());
+    #[cfg(feature = "https")]
+    if let crate::Proxy::Https {
+        tls_cert,
+        tls_key,
+        cert,
+        ..
+    } = &args.proxy
+    {
+        read.extend([tls_cert, tls_key].into_iter().flatten().map(parent));
+        let dir = crate::http::genca::cert_dir(cert);
+        // Created private here, before the loop below would
+        let _ = crate::http::genca::create_cert_dir(&dir);
+        write.push(dir);
+    }
+    // Routing of the prefixes added to a CIDR file
+    if args.cidr_file.is_some() {
+        write.push(PathBuf::from("/proc/sys/net"));
+    }
+
+    // Directories created after the sandbox is applied could not be used
+    for dir in &write {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    read.retain(|path| path.exists());
+    write.retain(|path| path.exists());
+    (read, write)
+}
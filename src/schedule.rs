@@ -0,0 +1,215 @@
+//! Time-of-day access schedules.
+//!
+//! A schedule is a list of windows such as `mon-fri 08:00-20:00`. Clients are
+//! only served while the current time, shifted by the configured UTC offset,
+//! falls into one of the windows. An empty schedule always allows access.
+//!
+//! The offset is fixed, daylight saving time is not followed, and the access
+//! schedule applies to every client alike, there are no per-user schedules.
+
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A set of weekdays and a time range during which access is allowed.
+///
+/// Written as `DAYS`, `HH:MM-HH:MM` or `DAYS HH:MM-HH:MM`, where `DAYS` is a
+/// comma separated list of days or day ranges, e.g. `mon-fri` or `sat,sun`. A
+/// time range whose end is before its start spans midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Window {
+    /// Bit `n` is set if the window applies on day `n`, Monday being 0.
+    days: u8,
+    /// Start minute of the day, inclusive.
+    start: u32,
+    /// End minute of the day, exclusive.
+    end: u32,
+}
+
+impl Window {
+    /// Whether `minute` of weekday `day` falls into the window.
+    fn contains(&self, day: u32, minute: u32) -> bool {
+        let on = |day: u32| self.days & (1 << (day % 7)) != 0;
+        if self.start <= self.end {
+            on(day) && (self.start..self.end).contains(&minute)
+        } else {
+            // Spans midnight, the early part belongs to the previous day's window
+            (on(day) && minute >= self.start) || (on(day + 6) && minute < self.end)
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut window = Window {
+            days: 0x7f,
+            start: 0,
+            end: MINUTES_PER_DAY,
+        };
+
+        let mut parts = s.split_whitespace();
+        let (first, second) = (parts.next(), parts.next());
+        if parts.next().is_some() {
+            return Err(format!("invalid schedule window: {s}"));
+        }
+
+        let (days, times) = match (first, second) {
+            (Some(days), Some(times)) => (Some(days), Some(times)),
+            (Some(part), None) if part.contains(':') => (None, Some(part)),
+            (Some(part), None) => (Some(part), None),
+            _ => return Err("empty schedule window".to_owned()),
+        };
+
+        if let Some(days) = days {
+            window.days = parse_days(days)?;
+        }
+        if let Some(times) = times {
+            let (start, end) = times
+                .split_once('-')
+                .ok_or_else(|| format!("invalid time range: {times}"))?;
+            window.start = parse_time(start)?;
+            window.end = parse_time(end)?;
+        }
+
+        Ok(window)
+    }
+}
+
+/// Parses `mon-fri,sun` into a weekday bitmask.
+fn parse_days(s: &str) -> Result<u8, String> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|day| name.eq_ignore_ascii_case(day))
+            .ok_or_else(|| format!("invalid day: {name}"))
+    };
+
+    let mut days = 0u8;
+    for part in s.split(',') {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(part)?, day(part)?),
+        };
+
+        let mut n = first;
+        loop {
+            days |= 1 << n;
+            if n == last {
+                break;
+            }
+            n = (n + 1) % 7;
+        }
+    }
+
+    Ok(days)
+}
+
+/// Parses `HH:MM` into minutes since midnight, accepting `24:00`.
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid time: {s}");
+    let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+    let hours = hours.parse::<u32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<u32>().map_err(|_| invalid())?;
+    if minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// A fixed offset from UTC, written as `+HH:MM` or `-HH:MM`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UtcOffset(i32);
+
+impl FromStr for UtcOffset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sign, rest) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(format!("invalid UTC offset: {s}")),
+        };
+        let minutes = parse_time(rest).map_err(|_| format!("invalid UTC offset: {s}"))?;
+        Ok(UtcOffset(sign * minutes as i32 * 60))
+    }
+}
+
+/// Access windows at a fixed UTC offset.
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    offset: UtcOffset,
+}
+
+impl Schedule {
+    /// Create a schedule from `windows` written at the UTC offset `offset`.
+    pub fn new(windows: Vec<Window>, offset: UtcOffset) -> Self {
+        Self { windows, offset }
+    }
+
+    /// Whether access is allowed right now.
+    pub fn allows_now(&self) -> bool {
+        self.windows.is_empty() || self.allows(SystemTime::now())
+    }
+
+    /// Whether access is allowed at `time`.
+    fn allows(&self, time: SystemTime) -> bool {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64)
+            + self.offset.0 as i64;
+        let days = secs.div_euclid(86400);
+        // The epoch was a Thursday
+        let day = (days + 3).rem_euclid(7) as u32;
+        let minute = (secs.rem_euclid(86400) / 60) as u32;
+
+        self.windows
+            .iter()
+            .any(|window| window.contains(day, minute))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 2024-01-01 was a Monday.
+    fn monday_utc(hours: u64, minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_704_067_200 + hours * 3600 + minutes * 60)
+    }
+
+    #[test]
+    fn test_weekday_window() {
+        let schedule = Schedule::new(vec!["mon-fri 08:00-20:00".parse().unwrap()], UtcOffset(0));
+        assert!(schedule.allows(monday_utc(8, 0)));
+        assert!(!schedule.allows(monday_utc(20, 0)));
+        // Saturday noon
+        assert!(!schedule.allows(monday_utc(5 * 24 + 12, 0)));
+    }
+
+    #[test]
+    fn test_overnight_window_with_offset() {
+        let schedule = Schedule::new(
+            vec!["fri 22:00-02:00".parse().unwrap()],
+            "+02:00".parse().unwrap(),
+        );
+        // Saturday 01:00 local is Friday 23:00 UTC
+        assert!(schedule.allows(monday_utc(4 * 24 + 23, 0)));
+        // Friday 01:00 local belongs to Thursday night
+        assert!(!schedule.allows(monday_utc(3 * 24 + 23, 0)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!("mon-fri 08:00".parse::<Window>().is_err());
+        assert!("someday".parse::<Window>().is_err());
+        assert!("25:00-26:00".parse::<Window>().is_err());
+        assert_eq!("sat,sun".parse::<Window>().unwrap().days, 0b110_0000);
+    }
+}
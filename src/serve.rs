@@ -1,10 +1,30 @@
+#[cfg(feature = "https")]
+use crate::http::HttpsServer;
+#[cfg(feature = "socks")]
+use crate::socks::Socks5Server;
 use crate::{
+    audit::AuditLog,
+    authz::AuthWebhook,
+    ban::Bans,
     connect::Connector,
-    http::{HttpServer, HttpsServer},
-    socks::Socks5Server,
-    AuthMode, BootArgs, Proxy, Result,
+    destination::DestinationGuard,
+    forward::ForwardServer,
+    hooks::{Chain, NoHooks, SharedHooks},
+    http::{cache::HttpCache, HttpServer},
+    limit::RateLimiter,
+    notify::{AuthFailures, Event},
+    rules::EgressRules,
+    sampling::Sampler,
+    ship::LogShipper,
+    sni::SniServer,
+    users::Users,
+    AuthMode, BootArgs, Proxy, Result, TcpOptions,
 };
-use std::net::SocketAddr;
+use cidr::IpCidr;
+use std::sync::Arc;
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::Instrument;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 /// The `Serve` trait defines a common interface for starting HTTP and SOCKS5 servers.
@@ -59,6 +79,25 @@ pub trait Serve {
     async fn serve(self) -> std::io::Result<()>;
 }
 
+/// Accepts the next connection of `listener`.
+///
+/// Failures, such as running out of file descriptors, are logged and the
+/// accept is retried after a delay growing up to a second, rather than
+/// ending the listener.
+pub(crate) async fn accept(listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut delay = Duration::from_millis(50);
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(err) => {
+                tracing::warn!("Failed to accept a connection: {}", err);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
 /// Run the server with the provided boot arguments.
 pub fn run(args: BootArgs) -> Result<()> {
     // Initialize the logger with a filter that ignores WARN level logs for netlink_proto
@@ -66,38 +105,217 @@ pub fn run(args: BootArgs) -> Result<()> {
         .add_directive(args.log.into())
         .add_directive("netlink_proto=error".parse()?);
 
-    tracing::subscriber::set_global_default(
-        FmtSubscriber::builder()
-            .with_max_level(args.log)
-            .with_env_filter(filter)
-            .finish(),
-    )?;
+    let builder = FmtSubscriber::builder()
+        .with_max_level(args.log)
+        .with_env_filter(filter);
+    #[cfg(unix)]
+    if args.log_syslog {
+        let syslog = crate::syslog::Syslog::connect()?;
+        tracing::subscriber::set_global_default(
+            builder
+                .with_writer(syslog)
+                .with_ansi(false)
+                .without_time()
+                .finish(),
+        )?;
+    } else {
+        tracing::subscriber::set_global_default(builder.finish())?;
+    }
+    #[cfg(not(unix))]
+    tracing::subscriber::set_global_default(builder.finish())?;
 
     tracing::info!("OS: {}", std::env::consts::OS);
     tracing::info!("Arch: {}", std::env::consts::ARCH);
     tracing::info!("Version: {}", env!("CARGO_PKG_VERSION"));
+    if let Some(cidr) = &args.cidr {
+        tracing::info!("CIDR: {}", cidr);
+    }
     tracing::info!("Concurrent: {}", args.concurrent);
     tracing::info!("Connect timeout: {:?}s", args.connect_timeout);
 
+    // Installing a release overwrites the executable and runs it, which the
+    // sandbox and an unprivileged user cannot do
+    #[cfg(unix)]
+    if !args.update.update_windows.is_empty() {
+        let confined = args.user.is_some();
+        #[cfg(all(target_os = "linux", feature = "sandbox"))]
+        let confined = confined || args.sandbox;
+        if confined {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--update-window cannot be used with --sandbox or --user",
+            )
+            .into());
+        }
+    }
+
     let cpu_cores = num_cpus::get();
     let blocking_threads = (cpu_cores as f64 * 1.5).round() as usize;
 
+    // Set up on a runtime of its own, so that privileges are dropped before
+    // the threads serving connections exist
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(setup(&args))?;
+
+    #[cfg(unix)]
+    if let Some(user) = &args.user {
+        crate::privilege::drop_privileges(user, &args)?;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    if args.sandbox {
+        crate::sandbox::apply(&args)?;
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if args.io_uring {
+        tracing::info!("Runtime: io_uring ({} workers)", cpu_cores);
+
+        return crate::uring::run(cpu_cores, move || listen(args.clone()));
+    }
+
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .worker_threads(cpu_cores)
         .max_blocking_threads(blocking_threads)
         .build()?
-        .block_on(async {
-            #[cfg(target_os = "linux")]
-            if let Some(cidr) = &args.cidr {
-                crate::route::sysctl_ipv6_no_local_bind(cidr);
-                crate::route::sysctl_ipv6_all_enable_ipv6(cidr);
-                crate::route::sysctl_route_add_cidr(cidr).await;
+        .block_on(listen(args))
+}
+
+/// Prepares the host and runs the server described by `args` on the current
+/// runtime.
+pub(crate) async fn start(args: BootArgs) -> Result<()> {
+    setup(&args).await?;
+    listen(args).await
+}
+
+/// Runs the server described by `args` on the current runtime, once the host
+/// is prepared.
+async fn listen(args: BootArgs) -> Result<()> {
+    let span = listener_span(&args);
+    let bind = args.bind;
+    crate::notify::init(&args.notify)?;
+    let server = match Server::new(args) {
+        Ok(server) => server,
+        Err(err) => {
+            let error = err.to_string();
+            crate::notify::deliver(Event::ListenerFailed { bind, error }).await;
+            return Err(err.into());
+        }
+    };
+    if !crate::notify::enabled() {
+        return server.serve().instrument(span).await.map_err(Into::into);
+    }
+
+    crate::notify::send(Event::Started { bind });
+    tokio::select! {
+        res = server.serve().instrument(span) => res.map_err(Into::into),
+        signal = crate::notify::shutdown_signal() => {
+            let (reason, number) = signal?;
+            tracing::info!("Stopping on {}", reason);
+            crate::notify::deliver(Event::Stopping { reason }).await;
+            // End as without the handler, so a supervisor sees a clean stop
+            crate::notify::reraise(number)?;
+            Ok(())
+        }
+    }
+}
+
+/// Returns the name of the listener, or its proxy type if it has none.
+fn listener_name(args: &BootArgs) -> &str {
+    args.name.as_deref().unwrap_or(match args.proxy {
+        Proxy::Http { .. } => "http",
+        #[cfg(feature = "https")]
+        Proxy::Https { .. } => "https",
+        #[cfg(feature = "socks")]
+        Proxy::Socks5 { .. } => "socks5",
+        Proxy::Sni { .. } => "sni",
+        Proxy::Forward { .. } => "forward",
+    })
+}
+
+/// Creates the span labelling everything logged by the listener with its name.
+fn listener_span(args: &BootArgs) -> tracing::Span {
+    tracing::info_span!("listener", name = listener_name(args))
+}
+
+/// Prepares the host before the server starts accepting connections.
+///
+/// Fails if addresses of the CIDR cannot be bound and no fallback address is
+/// configured, as every connection would fail otherwise.
+async fn setup(args: &BootArgs) -> Result<()> {
+    #[cfg(all(target_os = "linux", feature = "route"))]
+    if let Some(cidr) = &args.cidr {
+        crate::route::prepare_cidr(cidr, &args.route).await;
+
+        if let Err(err) = crate::route::check_cidr_bind(cidr, &args.route) {
+            if args.fallback.is_none() {
+                return Err(err.into());
+            }
+            tracing::warn!("{}, using the fallback address instead", err);
+            crate::status::add_route_failure();
+        }
+    }
+
+    if let Some(path) = &args.cidr_file {
+        let cidr_file = crate::cidr_file::CidrFile::load(path)?;
+        for cidr in cidr_file.cidrs().iter() {
+            crate::cidr_file::setup(cidr, &args.route).await;
+        }
+    }
+
+    // Users of the credential file may have CIDRs of their own
+    #[cfg(all(target_os = "linux", feature = "route"))]
+    if let Some(path) = args.proxy.auth().and_then(|auth| auth.auth_file.as_ref()) {
+        for cidr in Users::load(path)?.cidrs() {
+            crate::route::prepare_cidr(&cidr, &args.route).await;
+
+            if let Err(err) = crate::route::check_cidr_bind(&cidr, &args.route) {
+                tracing::warn!("{}", err);
+                crate::status::add_route_failure();
             }
+        }
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "route")))]
+    let _ = args;
+
+    Ok(())
+}
+
+/// Builds the egress connector described by `args`.
+pub(crate) fn connector(args: &BootArgs) -> Connector {
+    let exclude = args
+        .exclude_ip
+        .iter()
+        .map(|ip| IpCidr::new_host(*ip))
+        .chain(args.exclude_cidr.iter().copied())
+        .collect::<Vec<_>>();
 
-            let server = Server::new(args)?;
-            server.serve().await.map_err(Into::into)
-        })
+    Connector::new(
+        args.cidr,
+        args.cidr_range,
+        args.fallback,
+        exclude,
+        args.assign_mode,
+        args.connect_timeout,
+        args.tcp,
+    )
+    .with_interface(args.interface.clone())
+    .with_resolve(args.resolve)
+    .with_avoid_collisions(args.avoid_collisions)
+    .with_max_conns_per_ip(args.max_conns_per_ip)
+    .with_health_check_failures(args.health.health_check_failures)
+    .with_listeners(match &args.proxy {
+        Proxy::Forward { forward } => forward.tcp.iter().map(|rule| rule.listen).collect(),
+        _ => vec![args.bind],
+    })
+    .with_guard(
+        (!args.destination.allow_private_destinations)
+            .then(|| DestinationGuard::new(args.destination.allow_destination.clone())),
+    )
 }
 
 /// Run the server with the provided boot arguments.
@@ -108,12 +326,42 @@ pub struct Context {
     /// Number of concurrent connections
     pub concurrent: usize,
 
-    /// Connect timeout
-    pub connect_timeout: u64,
+    /// Seconds a client has to finish its handshake
+    pub handshake_timeout: u64,
+
+    /// Whether connections start with a PROXY protocol header
+    pub proxy_protocol: bool,
+
+    /// Sources allowed to send the PROXY protocol header, any if empty
+    pub proxy_protocol_from: Arc<[IpCidr]>,
 
     /// Authentication type
     pub auth: AuthMode,
 
+    /// Users of the credential file, if one is configured
+    pub users: Option<Arc<Users>>,
+
+    /// Authentication webhook, if one is configured
+    pub webhook: Option<Arc<AuthWebhook>>,
+
+    /// Destination based egress rules, if a rules file is configured
+    pub rules: Option<Arc<EgressRules>>,
+
+    /// TCP socket options for accepted connections
+    pub tcp: TcpOptions,
+
+    /// Maximum bytes buffered per connection
+    pub max_conn_memory: Option<usize>,
+
+    /// Cache of plain HTTP responses, if a cache directory is configured
+    pub cache: Option<Arc<HttpCache>>,
+
+    /// Lifecycle hooks
+    pub hooks: SharedHooks,
+
+    /// Connection counters of the listener
+    pub stats: crate::stats::Listener,
+
     /// Connector
     pub connector: Connector,
 }
@@ -127,10 +375,18 @@ enum Server {
     Http(HttpServer),
 
     /// Represents an HTTPS server.
+    #[cfg(feature = "https")]
     Https(HttpsServer),
 
     /// Represents a SOCKS5 server.
+    #[cfg(feature = "socks")]
     Socks5(Socks5Server),
+
+    /// Represents an SNI proxy.
+    Sni(SniServer),
+
+    /// Represents a TCP forwarding server.
+    Forward(ForwardServer),
 }
 
 impl Server {
@@ -166,37 +422,337 @@ impl Server {
     /// let server = Server::new(args)?;
     /// ```
     fn new(args: BootArgs) -> std::io::Result<Server> {
-        let ctx = move |auth: AuthMode| Context {
+        crate::stats::limit_connections(args.concurrent, args.concurrent_overflow);
+        let stats = crate::stats::Listener::register(listener_name(&args));
+        crate::dns::init(&args.resolver, args.resolve)?;
+        if let Some(size) = args.relay_buffer_size {
+            crate::relay::set_buffer_size(size);
+        }
+        if let Some(dir) = &args.pcap_dir {
+            crate::pcap::init(dir.clone(), args.pcap_max_size, args.pcap_max_files)?;
+        }
+        let cidr_file = match &args.cidr_file {
+            Some(path) => {
+                let cidr_file = Arc::new(crate::cidr_file::CidrFile::load(path)?);
+                tracing::info!(
+                    "Loaded {} CIDRs from {}",
+                    cidr_file.cidrs().len(),
+                    path.display()
+                );
+                tokio::spawn(cidr_file.clone().watch(args.route.clone()));
+                Some(cidr_file)
+            }
+            None => None,
+        };
+        #[cfg(all(target_os = "linux", feature = "ndp"))]
+        if let Some(iface) = &args.ndp_proxy {
+            crate::ndp::spawn(iface, args.cidr, cidr_file.clone())?;
+        }
+        let egress_bans = match &args.egress_ban_file {
+            Some(path) => {
+                let egress_bans = crate::egress_ban::EgressBans::load(path)?;
+                tracing::info!(
+                    "Loaded {} egress bans from {}",
+                    egress_bans.len(),
+                    path.display()
+                );
+                Arc::new(egress_bans)
+            }
+            None => Arc::default(),
+        };
+        let upstreams = crate::upstream::UpstreamPool::load(&args.upstream)?.map(Arc::new);
+        if let Some(upstreams) = &upstreams {
+            tracing::info!("Connecting through {} upstream proxies", upstreams.len());
+        }
+        let connector = connector(&args)
+            .with_cidr_file(cidr_file)
+            .with_egress_bans(egress_bans)
+            .with_upstreams(upstreams.clone());
+        if let Some(upstreams) = upstreams {
+            tokio::spawn(upstreams.check(
+                connector.clone(),
+                args.upstream.upstream_check_target.clone(),
+                Duration::from_secs(args.upstream.upstream_check_interval.max(1)),
+            ));
+        }
+
+        if let Some(path) = &args.hosts {
+            let hosts = crate::dns::load_hosts(path)?;
+            tracing::info!("Loaded {} hosts from {}", hosts.len(), path.display());
+            connector.dns().set_hosts(hosts);
+        }
+
+        if !args.dns.dns_prefetch.is_empty() {
+            tokio::spawn(crate::dns::prefetch(
+                connector.dns().clone(),
+                args.dns.dns_prefetch.clone(),
+                Duration::from_secs(args.dns.dns_prefetch_ttl.max(1)),
+            ));
+        }
+
+        if let (Some(url), Some(_)) = (&args.health.health_check, args.cidr) {
+            let target = crate::health::check_target(url)?;
+            tokio::spawn(crate::health::run(
+                connector.clone(),
+                target,
+                Duration::from_secs(args.health.health_check_interval.max(1)),
+                args.health.health_check_samples,
+            ));
+        }
+
+        let mut hooks = args.hooks.into_iter().collect::<Vec<SharedHooks>>();
+        if let Some(path) = &args.audit_log {
+            tracing::info!("Audit log: {}", path.display());
+            hooks.push(Arc::new(AuditLog::open(path)?));
+        }
+        if crate::notify::enabled() {
+            hooks.push(Arc::new(AuthFailures::new(
+                args.notify.notify_auth_failures,
+            )));
+        }
+        if let Some(url) = &args.log_ship.log_ship_url {
+            tracing::info!("Shipping access logs to {}", url);
+            hooks.push(Arc::new(LogShipper::start(url.clone(), &args.log_ship)?));
+        }
+        let rate_limit_ban = args
+            .rate_limit
+            .rate_limit
+            .and(args.rate_limit.rate_limit_ban)
+            .map(Duration::from_secs);
+        let bans = (args.ban.ban_after.is_some() || rate_limit_ban.is_some()).then(|| {
+            let window = Duration::from_secs(args.ban.ban_window);
+            let time = Duration::from_secs(args.ban.ban_time);
+            if let Some(failures) = args.ban.ban_after {
+                tracing::info!(
+                    "Banning clients for {:?} after {} failed logins within {:?}",
+                    time,
+                    failures,
+                    window
+                );
+            }
+            let bans = Arc::new(Bans::new(args.ban.ban_after, window, time));
+            tokio::spawn({
+                let bans = bans.clone();
+                async move { bans.prune(Duration::from_secs(60)).await }
+            });
+            bans
+        });
+        if let Some(bans) = &bans {
+            hooks.push(bans.clone());
+        }
+        if let Some(rate) = args.rate_limit.rate_limit {
+            let burst = args.rate_limit.rate_limit_burst.unwrap_or(rate);
+            let ban = bans.clone().zip(rate_limit_ban);
+            tracing::info!(
+                "Rate limit: {} requests/s per client, burst {}",
+                rate,
+                burst
+            );
+            let limiter = Arc::new(RateLimiter::new(rate, burst, ban));
+            tokio::spawn({
+                let limiter = limiter.clone();
+                async move { limiter.prune(Duration::from_secs(60)).await }
+            });
+            hooks.push(limiter);
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(path) = &args.policy_wasm {
+            hooks.push(Arc::new(crate::policy::WasmPolicy::load(path)?));
+        }
+        if let Some(rate) = args.sampling.sample_rate {
+            tracing::info!("Sampling {}% of tunnels", rate * 100.0);
+            let sampler = Arc::new(Sampler::new(rate));
+            let interval = Duration::from_secs(args.sampling.sample_report_interval.max(1));
+            tokio::spawn({
+                let sampler = sampler.clone();
+                async move { sampler.report(interval).await }
+            });
+            hooks.push(sampler);
+        }
+        #[cfg(unix)]
+        let control_socket = args.control_socket.as_ref();
+        #[cfg(not(unix))]
+        let control_socket = None::<&std::path::PathBuf>;
+        #[cfg(feature = "admin")]
+        let admin_bind = args.admin.admin_bind;
+        #[cfg(not(feature = "admin"))]
+        let admin_bind = None::<SocketAddr>;
+        if control_socket.is_some() || args.stats_file.is_some() || admin_bind.is_some() {
+            hooks.push(Arc::new(crate::stats::Usage));
+        }
+        #[cfg(unix)]
+        if let Some(path) = control_socket {
+            tokio::spawn(crate::control::serve(path.clone()));
+        }
+        if let Some(path) = &args.stats_file {
+            tracing::info!("Writing statistics to {}", path.display());
+            tokio::spawn(crate::stats::export(
+                path.clone(),
+                Duration::from_secs(args.stats_interval.max(1)),
+            ));
+        }
+        if args.update.update_check.is_some() {
+            tokio::spawn(crate::update::run(
+                args.update.clone(),
+                control_socket.cloned(),
+            ));
+        }
+        let hooks: SharedHooks = match hooks.len() {
+            0 => Arc::new(NoHooks),
+            1 => hooks.remove(0),
+            _ => Arc::new(Chain(hooks)),
+        };
+
+        let auth = args.proxy.auth();
+        let users = match auth.and_then(|auth| auth.auth_file.as_ref()) {
+            Some(path) => {
+                let users = Users::load(path)?;
+                tracing::info!("Loaded {} users from {}", users.len(), path.display());
+                Some(Arc::new(users))
+            }
+            None => None,
+        };
+
+        let webhook = match auth {
+            Some(AuthMode {
+                auth_url: Some(url),
+                auth_url_cache_ttl,
+                ..
+            }) => {
+                tracing::info!("Authenticating logins with {}", url);
+                let ttl = Duration::from_secs(*auth_url_cache_ttl);
+                Some(Arc::new(AuthWebhook::new(url.clone(), ttl)?))
+            }
+            _ => None,
+        };
+
+        let rules = match &args.egress_rules {
+            Some(path) => {
+                let rules = EgressRules::load(path)?;
+                tracing::info!(
+                    "Loaded {} egress rules from {}",
+                    rules.len(),
+                    path.display()
+                );
+                Some(Arc::new(rules))
+            }
+            None => None,
+        };
+
+        let cache = match args
+            .proxy
+            .http()
+            .and_then(|http| Some((http, http.cache_dir.as_ref()?)))
+        {
+            Some((http, dir)) => {
+                const MIB: u64 = 1024 * 1024;
+                let cache = HttpCache::open(
+                    dir,
+                    http.cache_size.saturating_mul(MIB),
+                    http.cache_max_object_size.saturating_mul(MIB),
+                )?;
+                let (responses, size) = cache.usage();
+                tracing::info!(
+                    "Caching responses in {} ({} stored, {} bytes)",
+                    dir.display(),
+                    responses,
+                    size
+                );
+                Some(Arc::new(cache))
+            }
+            None => None,
+        };
+
+        let proxy_protocol_from: Arc<[IpCidr]> = args.proxy_protocol_from.into();
+        if args.proxy_protocol && proxy_protocol_from.is_empty() {
+            tracing::warn!(
+                "PROXY protocol headers are accepted from any source, restrict them with --proxy-protocol-from"
+            );
+        }
+
+        #[cfg(feature = "admin")]
+        if let Some(bind) = args.admin.admin_bind {
+            let admin = crate::admin::Admin::new(
+                args.admin.admin_token.clone(),
+                users.clone(),
+                bans.clone(),
+                cache.clone(),
+                connector.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(err) = admin.serve(bind).await {
+                    tracing::error!("Admin API error: {}", err);
+                }
+            });
+        }
+
+        let ctx = |auth: AuthMode| Context {
             auth,
+            users: users.clone(),
+            webhook: webhook.clone(),
+            rules: rules.clone(),
+            cache: cache.clone(),
             bind: args.bind,
             concurrent: args.concurrent,
-            connect_timeout: args.connect_timeout,
-            connector: Connector::new(
-                args.cidr,
-                args.cidr_range,
-                args.fallback,
-                args.connect_timeout,
-            ),
+            handshake_timeout: args.handshake_timeout,
+            proxy_protocol: args.proxy_protocol,
+            proxy_protocol_from: proxy_protocol_from.clone(),
+            tcp: args.tcp,
+            max_conn_memory: args.max_conn_memory,
+            hooks: hooks.clone(),
+            stats: stats.clone(),
+            connector: connector.clone(),
         };
 
         match args.proxy {
-            Proxy::Http { auth } => HttpServer::new(ctx(auth)).map(Server::Http),
+            Proxy::Http { auth, http } => HttpServer::new(ctx(auth), http).map(Server::Http),
+            #[cfg(feature = "https")]
             Proxy::Https {
                 auth,
+                http,
                 tls_cert,
                 tls_key,
-            } => HttpsServer::new(ctx(auth), tls_cert, tls_key).map(Server::Https),
-            Proxy::Socks5 { auth } => Socks5Server::new(ctx(auth)).map(Server::Socks5),
+                tls_sniff_timeout,
+                cert,
+            } => HttpsServer::new(ctx(auth), http, tls_cert, tls_key, tls_sniff_timeout, cert)
+                .map(Server::Https),
+            #[cfg(feature = "socks")]
+            Proxy::Socks5 { auth, socks5 } => {
+                Socks5Server::new(ctx(auth), socks5).map(Server::Socks5)
+            }
+            Proxy::Sni { sni } => SniServer::new(
+                sni,
+                args.bind,
+                args.concurrent,
+                connector,
+                args.tcp,
+                hooks,
+                stats,
+            )
+            .map(Server::Sni),
+            Proxy::Forward { forward } => {
+                // Forward targets are set by the operator, not by clients
+                let connector = connector.unguarded();
+                ForwardServer::new(forward, args.concurrent, connector, args.tcp, hooks, stats)
+                    .map(Server::Forward)
+            }
         }
     }
 }
 
 impl Serve for Server {
     async fn serve(self) -> std::io::Result<()> {
+        tokio::spawn(crate::memory::report(Duration::from_secs(60)));
+        crate::status::set_listening();
+
         match self {
             Server::Http(server) => server.serve().await,
+            #[cfg(feature = "https")]
             Server::Https(server) => server.serve().await,
+            #[cfg(feature = "socks")]
             Server::Socks5(server) => server.serve().await,
+            Server::Sni(server) => server.serve().await,
+            Server::Forward(server) => server.serve().await,
         }
     }
 }
@@ -0,0 +1,483 @@
+//! Shipping of access-log records to Loki or Elasticsearch.
+//!
+//! With `--log-ship-url` a record is made of every request and of every
+//! closed tunnel, e.g.
+//!
+//! ```json
+//! {"event":"tunnel","protocol":"http","client_ip":"192.0.2.1","username":"alice","target":"example.com:443","sent":518,"received":4096,"connection":"3f2a9c01"}
+//! ```
+//!
+//! and the records are pushed in batches to a Loki push API, as the lines
+//! of a stream labelled with `job`, or to an Elasticsearch bulk API, as
+//! documents with an `@timestamp`. A batch is pushed once full or every
+//! `--log-ship-interval` seconds.
+//!
+//! Batches the endpoint could not take are kept in `--log-ship-buffer`, if
+//! given, and pushed again, oldest first, once it answers; those it refuses
+//! as invalid are dropped. The documents of a batch Elasticsearch rejects
+//! for the time being, e.g. with 429, are kept the same way. Records are
+//! dropped rather than slowing connections down when the endpoint falls too
+//! far behind.
+
+use crate::{
+    hooks::{Decision, Hooks, ProxyRequest, TunnelClose},
+    stats::{protocol_name, ConnectionId},
+    LogShipKind, LogShipOptions,
+};
+use reqwest::{header, Client, StatusCode, Url};
+use std::{
+    io,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+/// Records waiting to be pushed, beyond which new ones are dropped.
+const QUEUE_SIZE: usize = 65536;
+
+/// Time allowed for the endpoint to take a batch.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pushes access-log records to a log store.
+pub(crate) struct LogShipper {
+    records: mpsc::Sender<Record>,
+    dropped: AtomicU64,
+}
+
+struct Record {
+    time: SystemTime,
+    fields: serde_json::Value,
+}
+
+impl LogShipper {
+    /// Starts pushing records to `url` as `options` say, from a task of the
+    /// current runtime.
+    pub(crate) fn start(url: Url, options: &LogShipOptions) -> io::Result<Self> {
+        let client = Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+        let buffer = match &options.log_ship_buffer {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                Some(Buffer {
+                    dir: dir.clone(),
+                    max_size: options.log_ship_buffer_size,
+                    extension: match options.log_ship_kind {
+                        LogShipKind::Loki => "loki",
+                        LogShipKind::Elasticsearch => "ndjson",
+                    },
+                })
+            }
+            None => None,
+        };
+
+        let sink = Sink {
+            url,
+            kind: options.log_ship_kind,
+            index: options.log_ship_index.clone(),
+            token: options.log_ship_token.clone(),
+            client,
+            buffer,
+        };
+        let (records, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(sink.run(
+            rx,
+            options.log_ship_batch.max(1),
+            Duration::from_secs(options.log_ship_interval.max(1)),
+        ));
+
+        Ok(Self {
+            records,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    fn push(&self, fields: serde_json::Value) {
+        let record = Record {
+            time: SystemTime::now(),
+            fields,
+        };
+        if self.records.try_send(record).is_err() {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped % 10_000 == 1 {
+                tracing::warn!("Log shipping is behind, {} records dropped", dropped);
+            }
+        }
+    }
+}
+
+impl Hooks for LogShipper {
+    fn on_request(&self, request: &ProxyRequest<'_>) -> Decision {
+        self.push(serde_json::json!({
+            "event": "request",
+            "protocol": protocol_name(request.protocol),
+            "client_ip": request.peer.ip().to_string(),
+            "username": request.username,
+            "target": request.target,
+            "connection": ConnectionId::current().map(|id| id.to_string()),
+        }));
+        Decision::Allow
+    }
+
+    fn on_tunnel_close(&self, event: &TunnelClose) {
+        self.push(serde_json::json!({
+            "event": "tunnel",
+            "protocol": protocol_name(event.protocol),
+            "client_ip": event.peer.ip().to_string(),
+            "username": event.username,
+            "target": event.target,
+            "sent": event.sent,
+            "received": event.received,
+            "connection": ConnectionId::current().map(|id| id.to_string()),
+        }));
+    }
+}
+
+/// The endpoint records are pushed to.
+struct Sink {
+    url: Url,
+    kind: LogShipKind,
+    index: String,
+    token: Option<String>,
+    client: Client,
+    buffer: Option<Buffer>,
+}
+
+impl Sink {
+    async fn run(self, mut rx: mpsc::Receiver<Record>, batch: usize, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut records = Vec::with_capacity(batch);
+        loop {
+            let limit = batch - records.len();
+            tokio::select! {
+                received = rx.recv_many(&mut records, limit) => {
+                    if received == 0 {
+                        // Every sender is gone
+                        self.flush(&mut records).await;
+                        return;
+                    }
+                    if records.len() < batch {
+                        continue;
+                    }
+                }
+                _ = ticker.tick() => {}
+            }
+            self.flush(&mut records).await;
+        }
+    }
+
+    /// Pushes `records`, then the buffered batches if it went through.
+    async fn flush(&self, records: &mut Vec<Record>) {
+        if !records.is_empty() {
+            let body = encode(self.kind, &self.index, records);
+            records.clear();
+            let rest = match self.post(&body).await {
+                Ok(None) => None,
+                Ok(Some(rest)) => Some(rest),
+                Err(err) if retryable(&err) => {
+                    tracing::warn!("Log shipping to {} failed: {}", self.url, err);
+                    Some(body)
+                }
+                Err(err) => {
+                    tracing::warn!("Log shipping to {} refused a batch: {}", self.url, err);
+                    None
+                }
+            };
+            if let Some(rest) = rest {
+                if let Some(buffer) = self.buffer.clone() {
+                    let res = tokio::task::spawn_blocking(move || buffer.store(&rest)).await;
+                    if let Ok(Err(err)) = res {
+                        tracing::warn!("Failed to buffer a batch of records: {}", err);
+                    }
+                }
+                return;
+            }
+        }
+
+        let Some(buffer) = &self.buffer else {
+            return;
+        };
+        loop {
+            let oldest = buffer.clone();
+            let Ok(Ok(Some((path, body)))) =
+                tokio::task::spawn_blocking(move || oldest.oldest()).await
+            else {
+                return;
+            };
+            match self.post(&body).await {
+                Ok(None) => {}
+                Ok(Some(rest)) => {
+                    // Keep the rejected documents in place of the batch
+                    let _ = tokio::task::spawn_blocking(move || crate::file::replace(&path, &rest))
+                        .await;
+                    return;
+                }
+                Err(err) if retryable(&err) => return,
+                Err(err) => {
+                    tracing::warn!("Dropping buffered batch {}: {}", path.display(), err);
+                }
+            }
+            let removed = tokio::task::spawn_blocking(move || std::fs::remove_file(path)).await;
+            if !matches!(removed, Ok(Ok(()))) {
+                return;
+            }
+        }
+    }
+
+    /// Pushes `body`, returning the part of it to push again later, if the
+    /// endpoint rejected some of its records for the time being.
+    async fn post(&self, body: &[u8]) -> reqwest::Result<Option<Vec<u8>>> {
+        let content_type = match self.kind {
+            LogShipKind::Loki => "application/json",
+            LogShipKind::Elasticsearch => "application/x-ndjson",
+        };
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.to_vec());
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req.send().await?.error_for_status()?;
+
+        // Documents Elasticsearch rejects are reported in a successful answer
+        if self.kind == LogShipKind::Elasticsearch {
+            let answer = resp.json::<serde_json::Value>().await?;
+            if answer["errors"] == true {
+                let (rest, retried, dropped) = rejected(body, &answer);
+                tracing::warn!(
+                    "Elasticsearch rejected records of a batch, {} to retry, {} dropped",
+                    retried,
+                    dropped
+                );
+                return Ok(rest);
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Whether a push failing with `err` may succeed later.
+fn retryable(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => true,
+    }
+}
+
+/// Returns the documents of the bulk request `body` that Elasticsearch
+/// rejected in its `answer` but may take later, as a bulk request of their
+/// own, along with the number of them and of those rejected for good.
+fn rejected(body: &[u8], answer: &serde_json::Value) -> (Option<Vec<u8>>, usize, usize) {
+    let mut rest = Vec::new();
+    let (mut retried, mut dropped) = (0, 0);
+    let mut lines = body.split_inclusive(|&byte| byte == b'\n');
+    for item in answer["items"].as_array().into_iter().flatten() {
+        let (Some(action), Some(document)) = (lines.next(), lines.next()) else {
+            break;
+        };
+        let status = item
+            .as_object()
+            .and_then(|item| item.values().next())
+            .and_then(|result| result["status"].as_u64())
+            .and_then(|status| u16::try_from(status).ok())
+            .and_then(|status| StatusCode::from_u16(status).ok());
+        match status {
+            Some(status) if status.is_success() => {}
+            Some(status) if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => {
+                rest.extend_from_slice(action);
+                rest.extend_from_slice(document);
+                retried += 1;
+            }
+            _ => dropped += 1,
+        }
+    }
+    ((!rest.is_empty()).then_some(rest), retried, dropped)
+}
+
+/// Returns the request body pushing `records` to an endpoint of `kind`.
+fn encode(kind: LogShipKind, index: &str, records: &[Record]) -> Vec<u8> {
+    match kind {
+        LogShipKind::Loki => {
+            let values = records
+                .iter()
+                .map(|record| {
+                    let nanos = record
+                        .time
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_nanos();
+                    serde_json::json!([nanos.to_string(), record.fields.to_string()])
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "streams": [{ "stream": { "job": index }, "values": values }],
+            })
+            .to_string()
+            .into_bytes()
+        }
+        LogShipKind::Elasticsearch => {
+            let action = serde_json::json!({ "index": { "_index": index } }).to_string();
+            let mut body = String::new();
+            for record in records {
+                let mut document = record.fields.clone();
+                document["@timestamp"] = rfc3339(record.time).into();
+                body.push_str(&action);
+                body.push('\n');
+                body.push_str(&document.to_string());
+                body.push('\n');
+            }
+            body.into_bytes()
+        }
+    }
+}
+
+/// Directory of the batches waiting to be pushed again, one file each,
+/// named after the time they were made so that names sort by age.
+#[derive(Clone)]
+struct Buffer {
+    dir: PathBuf,
+    max_size: u64,
+    extension: &'static str,
+}
+
+impl Buffer {
+    fn store(&self, body: &[u8]) -> io::Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let path = self.dir.join(format!("{nanos:020}.{}", self.extension));
+        crate::file::replace(&path, body)?;
+
+        // Drop the oldest batches beyond the size limit
+        let batches = self.batches()?;
+        let mut size = batches.iter().map(|(_, len)| len).sum::<u64>();
+        for (path, len) in batches {
+            if size <= self.max_size {
+                break;
+            }
+            tracing::warn!("Log shipping buffer full, dropping {}", path.display());
+            std::fs::remove_file(&path)?;
+            size -= len;
+        }
+        Ok(())
+    }
+
+    /// Returns the oldest batch and its path.
+    fn oldest(&self) -> io::Result<Option<(PathBuf, Vec<u8>)>> {
+        let Some((path, _)) = self.batches()?.into_iter().next() else {
+            return Ok(None);
+        };
+        let body = std::fs::read(&path)?;
+        Ok(Some((path, body)))
+    }
+
+    /// Returns the batches, oldest first, with their size.
+    fn batches(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+        let mut batches = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(self.extension) {
+                continue;
+            }
+            batches.push((path, entry.metadata()?.len()));
+        }
+        batches.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(batches)
+    }
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with microseconds.
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs() as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Civil date of the days since the epoch, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        elapsed.subsec_micros()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc3339() {
+        let time = UNIX_EPOCH + Duration::from_micros(1_709_210_096_000_042);
+        assert_eq!(rfc3339(time), "2024-02-29T12:34:56.000042Z");
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000000Z");
+    }
+
+    #[test]
+    fn test_encode() {
+        let records = [Record {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            fields: serde_json::json!({ "event": "request" }),
+        }];
+
+        let loki = encode(LogShipKind::Loki, "vproxy", &records);
+        let loki = serde_json::from_slice::<serde_json::Value>(&loki).unwrap();
+        assert_eq!(loki["streams"][0]["stream"]["job"], "vproxy");
+        assert_eq!(loki["streams"][0]["values"][0][0], "1700000000000000000");
+        assert_eq!(
+            loki["streams"][0]["values"][0][1],
+            "{\"event\":\"request\"}"
+        );
+
+        let bulk = encode(LogShipKind::Elasticsearch, "vproxy", &records);
+        let bulk = String::from_utf8(bulk).unwrap();
+        let lines = bulk.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "{\"index\":{\"_index\":\"vproxy\"}}");
+        assert!(lines[1].contains("\"@timestamp\":\"2023-11-14T22:13:20.000000Z\""));
+        assert!(bulk.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let records = ["a", "b", "c"].map(|event| Record {
+            time: UNIX_EPOCH,
+            fields: serde_json::json!({ "event": event }),
+        });
+        let bulk = encode(LogShipKind::Elasticsearch, "vproxy", &records);
+        let answer = serde_json::json!({
+            "errors": true,
+            "items": [
+                { "index": { "status": 201 } },
+                { "index": { "status": 429 } },
+                { "index": { "status": 400 } },
+            ],
+        });
+
+        let (rest, retried, dropped) = rejected(&bulk, &answer);
+        let rest = String::from_utf8(rest.unwrap()).unwrap();
+        assert_eq!((retried, dropped), (1, 1));
+        assert_eq!(rest.lines().count(), 2);
+        assert!(rest.lines().nth(1).unwrap().contains("\"event\":\"b\""));
+    }
+}
@@ -0,0 +1,329 @@
+//! SNI proxy.
+//!
+//! Reads the TLS ClientHello of every connection, takes the server name
+//! from its SNI extension and relays the stream, ClientHello included, to
+//! that host through the [`Connector`]. TLS is never terminated, so plain
+//! HTTPS clients use the egress rotation by pointing the DNS of the target
+//! hosts at the proxy.
+
+use crate::{
+    connect::Connector,
+    extension::Extension,
+    hooks::{Decision, Protocol, ProxyRequest, SharedHooks, TunnelClose},
+    relay::{self, Progress},
+    rules::matches,
+    serve::{accept, Serve},
+    stats::{self, ActiveConnection, ConnectionId, Tunnel, TunnelInfo},
+    SniOptions, TcpOptions,
+};
+use socket2::SockRef;
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// TLS record content type of a handshake message.
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+
+/// Handshake message type of a ClientHello.
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+/// Extension type of the server name indication.
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+
+/// Largest TLS record payload.
+const MAX_RECORD_SIZE: usize = 16384;
+
+/// SNI proxy server.
+pub struct SniServer {
+    listener: TcpListener,
+    opts: Arc<SniOptions>,
+    connector: Connector,
+    tcp: TcpOptions,
+    hooks: SharedHooks,
+    stats: stats::Listener,
+}
+
+impl SniServer {
+    /// Create an SNI proxy listening on `bind`, with a backlog of
+    /// `concurrent` connections.
+    pub fn new(
+        opts: SniOptions,
+        bind: SocketAddr,
+        concurrent: usize,
+        connector: Connector,
+        tcp: TcpOptions,
+        hooks: SharedHooks,
+        stats: stats::Listener,
+    ) -> io::Result<Self> {
+        let socket = if bind.is_ipv4() {
+            tokio::net::TcpSocket::new_v4()?
+        } else {
+            tokio::net::TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        socket.set_reuseport(crate::uring::enabled())?;
+        socket.bind(bind)?;
+
+        Ok(Self {
+            listener: socket.listen(concurrent as u32)?,
+            opts: Arc::new(opts),
+            connector,
+            tcp,
+            hooks,
+            stats,
+        })
+    }
+}
+
+impl Serve for SniServer {
+    async fn serve(self) -> io::Result<()> {
+        tracing::info!("SNI proxy listening on {}", self.listener.local_addr()?);
+
+        loop {
+            let (stream, peer) = accept(&self.listener).await;
+            if !self.hooks.on_connect(Protocol::Sni, peer) {
+                tracing::debug!("[SNI] connection from {} rejected by hook", peer);
+                continue;
+            }
+
+            if let Err(err) = self.tcp.apply(SockRef::from(&stream)) {
+                tracing::trace!("[SNI] failed to apply tcp options: {}", err);
+            }
+
+            let opts = self.opts.clone();
+            let connector = self.connector.clone();
+            let hooks = self.hooks.clone();
+            let Some(active) = ActiveConnection::admit(&self.stats).await else {
+                tracing::debug!(
+                    "[SNI] connection from {} rejected, too many connections",
+                    peer
+                );
+                continue;
+            };
+            tokio::spawn(ConnectionId::new().scope(async move {
+                let _active = active;
+                if let Err(err) = handle(stream, peer, &opts, connector, hooks).await {
+                    tracing::debug!("[SNI] {} error: {}", peer, err);
+                }
+            }));
+        }
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    opts: &SniOptions,
+    connector: Connector,
+    hooks: SharedHooks,
+) -> io::Result<()> {
+    let timeout = Duration::from_millis(opts.sni_timeout.max(1));
+    let hello = tokio::time::timeout(timeout, read_client_hello(&mut stream))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no ClientHello received"))??;
+    let host = server_name(&hello[5..])
+        .ok_or_else(|| invalid("ClientHello without server name"))?
+        .to_ascii_lowercase();
+
+    if !opts.sni_allow.is_empty() && !opts.sni_allow.iter().any(|allowed| matches(allowed, &host)) {
+        tracing::info!("[SNI] {} requested {}, which is not allowed", peer, host);
+        return Ok(());
+    }
+
+    let target = format!("{}:{}", host, opts.sni_port);
+    let connector = connector.with_owner(stats::owner(None, peer.ip()));
+    let extension = connector.client_extension(peer.ip(), Extension::default());
+    let extension = match hooks.on_request(&ProxyRequest {
+        protocol: Protocol::Sni,
+        peer,
+        username: None,
+        target: &target,
+        extension,
+    }) {
+        Decision::Allow => extension,
+        Decision::Egress(extension) => extension,
+        Decision::Deny => {
+            tracing::info!("[SNI] request from {} to {} denied", peer, target);
+            return Ok(());
+        }
+    };
+
+    let mut target_stream = connector
+        .tcp_connector()
+        .connect_with_domain((host, opts.sni_port), extension)
+        .await?;
+    target_stream.write_all(&hello).await?;
+
+    let progress = Arc::new(Progress::default());
+    progress
+        .a_to_b
+        .fetch_add(hello.len() as u64, std::sync::atomic::Ordering::Relaxed);
+    let _tunnel = Tunnel::register(TunnelInfo {
+        protocol: Protocol::Sni,
+        client: peer,
+        username: None,
+        target: target.clone(),
+        egress: target_stream.local_addr().ok().map(|addr| addr.ip()),
+        server: target_stream.peer_addr().ok(),
+        progress: progress.clone(),
+        client_first: true,
+        started: std::time::Instant::now(),
+    });
+
+    // The ClientHello is already counted in the progress of a failed relay
+    let result = relay::copy_bidirectional(&mut stream, &mut target_stream, &progress).await;
+    let (sent, received) = match &result {
+        Ok((sent, received)) => (sent + hello.len() as u64, *received),
+        Err(_) => progress.copied(),
+    };
+    tracing::info!(
+        "[SNI] {} wrote {} bytes and received {} bytes",
+        peer,
+        sent,
+        received
+    );
+    hooks.on_tunnel_close(&TunnelClose {
+        protocol: Protocol::Sni,
+        peer,
+        username: None,
+        target,
+        sent,
+        received,
+    });
+    result.map(drop)
+}
+
+/// Reads the TLS record carrying the ClientHello, header included.
+async fn read_client_hello(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut record = vec![0; 5];
+    stream.read_exact(&mut record).await?;
+    if record[0] != CONTENT_TYPE_HANDSHAKE {
+        return Err(invalid("connection did not start with a TLS handshake"));
+    }
+
+    let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+    if len > MAX_RECORD_SIZE {
+        return Err(invalid("TLS record too large"));
+    }
+    record.resize(5 + len, 0);
+    stream.read_exact(&mut record[5..]).await?;
+    Ok(record)
+}
+
+/// Returns the server name of the ClientHello in the handshake message
+/// `msg`, if it has one.
+fn server_name(msg: &[u8]) -> Option<&str> {
+    let mut reader = Reader(msg);
+    if reader.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let len = reader.u24()?;
+    let mut hello = Reader(reader.bytes(len)?);
+
+    // Version and random
+    hello.bytes(2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.bytes(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.bytes(cipher_suites)?;
+    let compression_methods = hello.u8()? as usize;
+    hello.bytes(compression_methods)?;
+
+    let len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.bytes(len)?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.bytes(len)?);
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let len = data.u16()? as usize;
+        let mut names = Reader(data.bytes(len)?);
+        while !names.0.is_empty() {
+            let kind = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.bytes(len)?;
+            // Host name
+            if kind == 0 {
+                return std::str::from_utf8(name).ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// Reads big-endian fields of a handshake message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3)
+            .map(|bytes| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a minimal ClientHello handshake message naming `host`.
+    fn client_hello(host: &str) -> Vec<u8> {
+        let mut sni = vec![0x00];
+        sni.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        sni.extend_from_slice(host.as_bytes());
+        let mut ext = Vec::new();
+        ext.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+        ext.extend_from_slice(&(sni.len() as u16 + 2).to_be_bytes());
+        ext.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&sni);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ext);
+
+        let mut msg = vec![HANDSHAKE_CLIENT_HELLO];
+        msg.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        msg.extend_from_slice(&body);
+        msg
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(
+            server_name(&client_hello("example.com")),
+            Some("example.com")
+        );
+        assert_eq!(server_name(&client_hello("example.com")[..40]), None);
+        assert_eq!(server_name(&[0x02, 0, 0, 0]), None);
+    }
+}
@@ -1,5 +1,6 @@
 mod error;
 mod proto;
 mod server;
+mod stun;
 
 pub use server::Socks5Server;
@@ -38,6 +38,12 @@ impl From<AddressType> for u8 {
     }
 }
 
+/// Maximum length of a domain name, bounded by the single length octet.
+pub const MAX_DOMAIN_LEN: usize = u8::MAX as usize;
+
+/// Maximum length of a single DNS label.
+pub const MAX_LABEL_LEN: usize = 63;
+
 /// SOCKS5 Adderss Format
 ///
 /// ```plain
@@ -81,7 +87,52 @@ impl Address {
     }
 
     pub const fn max_serialized_len() -> usize {
-        1 + 1 + u8::MAX as usize + 2
+        1 + 1 + MAX_DOMAIN_LEN + 2
+    }
+
+    /// Validates a domain name carried in a SOCKS5 address.
+    ///
+    /// Empty names, names longer than [`MAX_DOMAIN_LEN`] bytes and names with
+    /// embedded NUL bytes are always rejected. When `strict` is set, every
+    /// dot-separated label must additionally be non-empty and at most
+    /// [`MAX_LABEL_LEN`] bytes long, as required by RFC 1035.
+    pub fn validate_domain(domain: &str, strict: bool) -> std::io::Result<()> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+
+        if domain.is_empty() {
+            return Err(invalid("Empty domain address".to_owned()));
+        }
+
+        if domain.len() > MAX_DOMAIN_LEN {
+            return Err(invalid(format!(
+                "Domain address is {} bytes long, the maximum is {MAX_DOMAIN_LEN}",
+                domain.len()
+            )));
+        }
+
+        if domain.contains('\0') {
+            return Err(invalid("Domain address contains a NUL byte".to_owned()));
+        }
+
+        if strict {
+            // A single trailing dot denotes the root label and is allowed.
+            let name = domain.strip_suffix('.').unwrap_or(domain);
+            for label in name.split('.') {
+                if label.is_empty() {
+                    return Err(invalid(format!(
+                        "Domain address {domain} has an empty label"
+                    )));
+                }
+                if label.len() > MAX_LABEL_LEN {
+                    return Err(invalid(format!(
+                        "Domain address {domain} has a {} byte label, the maximum is {MAX_LABEL_LEN}",
+                        label.len()
+                    )));
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -114,6 +165,7 @@ impl StreamOperation for Address {
                         return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
                     }
                 };
+                Self::validate_domain(&addr, false)?;
                 Ok(Self::DomainAddress(addr, port))
             }
             AddressType::IPv6 => {
@@ -192,6 +244,7 @@ impl AsyncStreamOperation for Address {
                         return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
                     }
                 };
+                Self::validate_domain(&addr, false)?;
                 Ok(Self::DomainAddress(addr, port))
             }
             AddressType::IPv6 => {
@@ -333,7 +386,63 @@ impl TryFrom<&str> for Address {
                 (addr, "0")
             };
             let port = port.parse::<u16>()?;
+            Address::validate_domain(addr, false)?;
             Ok(Address::DomainAddress(addr.to_owned(), port))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_domain(domain: &[u8], port: u16) -> Vec<u8> {
+        let mut buf = vec![u8::from(AddressType::Domain), domain.len() as u8];
+        buf.extend_from_slice(domain);
+        buf.extend_from_slice(&port.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_validate_domain() {
+        assert!(Address::validate_domain("example.com", true).is_ok());
+        assert!(Address::validate_domain("example.com.", true).is_ok());
+        assert!(Address::validate_domain("", false).is_err());
+        assert!(Address::validate_domain("exa\0mple.com", false).is_err());
+        assert!(Address::validate_domain(&"a".repeat(MAX_DOMAIN_LEN + 1), false).is_err());
+
+        let long_label = format!("{}.com", "a".repeat(MAX_LABEL_LEN + 1));
+        assert!(Address::validate_domain(&long_label, false).is_ok());
+        assert!(Address::validate_domain(&long_label, true).is_err());
+        assert!(Address::validate_domain("example..com", true).is_err());
+    }
+
+    #[test]
+    fn test_parse_domain_with_nul_is_rejected() {
+        let buf = encode_domain(b"exa\0mple.com", 443);
+        assert!(Address::try_from(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_parse_domain_roundtrip() {
+        let buf = encode_domain(b"example.com", 443);
+        let addr = Address::try_from(buf.as_slice()).unwrap();
+        assert_eq!(addr, Address::DomainAddress("example.com".to_owned(), 443));
+        assert_eq!(Vec::<u8>::from(addr), buf);
+    }
+
+    #[test]
+    fn test_parse_random_input_never_panics() {
+        for _ in 0..10_000 {
+            let len = rand::random::<u8>() as usize;
+            let mut buf = (0..len).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+            if let Some(atyp) = buf.first_mut() {
+                *atyp = [0x01, 0x03, 0x04][*atyp as usize % 3];
+            }
+
+            if let Ok(Address::DomainAddress(domain, _)) = Address::try_from(buf.as_slice()) {
+                assert!(Address::validate_domain(&domain, false).is_ok());
+            }
+        }
+    }
+}
@@ -48,6 +48,28 @@ impl From<Reply> for u8 {
     }
 }
 
+impl From<&std::io::Error> for Reply {
+    /// Picks the reply describing why connecting to the destination failed.
+    fn from(err: &std::io::Error) -> Self {
+        #[cfg(unix)]
+        {
+            use nix::errno::Errno;
+            match err.raw_os_error().map(Errno::from_raw) {
+                Some(Errno::ENETUNREACH | Errno::ENETDOWN) => return Reply::NetworkUnreachable,
+                Some(Errno::EHOSTUNREACH | Errno::EHOSTDOWN) => return Reply::HostUnreachable,
+                _ => {}
+            }
+        }
+
+        match err.kind() {
+            std::io::ErrorKind::PermissionDenied => Reply::ConnectionNotAllowed,
+            std::io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            std::io::ErrorKind::TimedOut => Reply::TtlExpired,
+            _ => Reply::HostUnreachable,
+        }
+    }
+}
+
 impl std::fmt::Display for Reply {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
@@ -64,3 +86,29 @@ impl std::fmt::Display for Reply {
         write!(f, "{}", s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn test_reply_from_error() {
+        let reply = |kind| Reply::from(&Error::from(kind));
+        assert_eq!(
+            reply(ErrorKind::ConnectionRefused),
+            Reply::ConnectionRefused
+        );
+        assert_eq!(reply(ErrorKind::TimedOut), Reply::TtlExpired);
+        assert_eq!(
+            reply(ErrorKind::PermissionDenied),
+            Reply::ConnectionNotAllowed
+        );
+        assert_eq!(reply(ErrorKind::Other), Reply::HostUnreachable);
+        #[cfg(unix)]
+        assert_eq!(
+            Reply::from(&Error::from_raw_os_error(nix::libc::ENETUNREACH)),
+            Reply::NetworkUnreachable
+        );
+    }
+}
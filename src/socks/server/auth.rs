@@ -1,11 +1,14 @@
 use crate::{
+    authz::AuthWebhook,
     extension::Extension,
     socks::proto::{handshake::password, AsyncStreamOperation, Method, UsernamePassword},
+    users::{Login, Pool, Users},
 };
 use password::{Request, Response, Status::*};
 use std::{
     future::Future,
     io::{Error, ErrorKind},
+    sync::Arc,
 };
 use tokio::net::TcpStream;
 
@@ -18,6 +21,8 @@ pub trait Auth: Send {
 pub enum AuthAdaptor {
     NoAuth(NoAuth),
     Password(PasswordAuth),
+    Users(UsersAuth),
+    Webhook(WebhookAuth),
 }
 
 impl AuthAdaptor {
@@ -31,15 +36,25 @@ impl AuthAdaptor {
     {
         Self::Password(PasswordAuth::new(username, password))
     }
+
+    pub(crate) fn new_users(users: Arc<Users>) -> Self {
+        Self::Users(UsersAuth { users })
+    }
+
+    pub(crate) fn new_webhook(webhook: Arc<AuthWebhook>) -> Self {
+        Self::Webhook(WebhookAuth { webhook })
+    }
 }
 
 impl Auth for AuthAdaptor {
-    type Output = std::io::Result<(bool, Extension)>;
+    type Output = std::io::Result<(bool, Extension, Option<Login>)>;
 
     fn method(&self) -> Method {
         match self {
             Self::NoAuth(auth) => auth.method(),
             Self::Password(auth) => auth.method(),
+            Self::Users(auth) => auth.method(),
+            Self::Webhook(auth) => auth.method(),
         }
     }
 
@@ -47,6 +62,8 @@ impl Auth for AuthAdaptor {
         match self {
             Self::NoAuth(auth) => auth.execute(stream).await,
             Self::Password(auth) => auth.execute(stream).await,
+            Self::Users(auth) => auth.execute(stream).await,
+            Self::Webhook(auth) => auth.execute(stream).await,
         }
     }
 }
@@ -55,14 +72,14 @@ impl Auth for AuthAdaptor {
 pub struct NoAuth;
 
 impl Auth for NoAuth {
-    type Output = std::io::Result<(bool, Extension)>;
+    type Output = std::io::Result<(bool, Extension, Option<Login>)>;
 
     fn method(&self) -> Method {
         Method::NoAuth
     }
 
     async fn execute(&self, _stream: &mut TcpStream) -> Self::Output {
-        Ok((true, Extension::None))
+        Ok((true, Extension::default(), None))
     }
 }
 
@@ -85,7 +102,7 @@ impl PasswordAuth {
 }
 
 impl Auth for PasswordAuth {
-    type Output = std::io::Result<(bool, Extension)>;
+    type Output = std::io::Result<(bool, Extension, Option<Login>)>;
 
     fn method(&self) -> Method {
         Method::Password
@@ -100,17 +117,109 @@ impl Auth for PasswordAuth {
 
         let resp = Response::new(if is_equal { Succeeded } else { Failed });
         resp.write_to_async_stream(stream).await?;
+        let username = req.user_pass.username;
         if is_equal {
-            let extension = Extension::try_from(&self.inner.username, req.user_pass.username)
+            let extension = Extension::try_from(&self.inner.username, username.as_str())
                 .await
                 .map_err(|_| Error::new(ErrorKind::Other, "failed to parse extension"))?;
 
-            Ok((true, extension))
+            let login = Login {
+                username,
+                pool: Pool::default(),
+            };
+            Ok((true, extension, Some(login)))
         } else {
-            Err(Error::new(
-                ErrorKind::Other,
-                "username or password is incorrect",
+            Ok((
+                false,
+                Extension::default(),
+                Some(Login {
+                    username,
+                    pool: Pool::default(),
+                }),
             ))
         }
     }
 }
+
+/// Username and password checked against the users of a credential file.
+pub struct UsersAuth {
+    users: Arc<Users>,
+}
+
+impl Auth for UsersAuth {
+    type Output = std::io::Result<(bool, Extension, Option<Login>)>;
+
+    fn method(&self) -> Method {
+        Method::Password
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+        let req = Request::retrieve_from_async_stream(stream).await?;
+
+        let user = self
+            .users
+            .authenticate(&req.user_pass.username, &req.user_pass.password)
+            .await;
+
+        let username = req.user_pass.username;
+        let resp = Response::new(if user.is_some() { Succeeded } else { Failed });
+        resp.write_to_async_stream(stream).await?;
+        match user {
+            Some((name, pool)) => {
+                let extension = Extension::try_from(&name, username.as_str())
+                    .await
+                    .map_err(|_| Error::other("failed to parse extension"))?;
+
+                Ok((true, extension, Some(Login { username, pool })))
+            }
+            None => Ok((
+                false,
+                Extension::default(),
+                Some(Login {
+                    username,
+                    pool: Pool::default(),
+                }),
+            )),
+        }
+    }
+}
+
+/// Username and password checked by an external endpoint.
+pub struct WebhookAuth {
+    webhook: Arc<AuthWebhook>,
+}
+
+impl Auth for WebhookAuth {
+    type Output = std::io::Result<(bool, Extension, Option<Login>)>;
+
+    fn method(&self) -> Method {
+        Method::Password
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+        let req = Request::retrieve_from_async_stream(stream).await?;
+        let peer = stream.peer_addr()?;
+
+        let granted = self
+            .webhook
+            .authenticate(&req.user_pass.username, &req.user_pass.password, peer.ip())
+            .await;
+
+        let resp = Response::new(if granted.is_some() { Succeeded } else { Failed });
+        resp.write_to_async_stream(stream).await?;
+        match granted {
+            Some((extension, login)) => Ok((true, extension, Some(login))),
+            None => {
+                let username = req.user_pass.username;
+                Ok((
+                    false,
+                    Extension::default(),
+                    Some(Login {
+                        username,
+                        pool: Pool::default(),
+                    }),
+                ))
+            }
+        }
+    }
+}
@@ -1,17 +1,24 @@
 use crate::socks::proto::{
     Address, AsyncStreamOperation, Reply, Response, StreamOperation, UdpHeader,
 };
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
+use socket2::{SockAddr, SockRef};
 use std::{
+    collections::VecDeque,
+    io::IoSlice,
     net::SocketAddr,
     pin::Pin,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
     task::{Context, Poll},
     time::Duration,
 };
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest, ReadBuf},
     net::{TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Notify,
 };
 
 /// Socks5 connection type `UdpAssociate`
@@ -195,6 +202,10 @@ impl<S> From<UdpAssociate<S>> for TcpStream {
     }
 }
 
+/// Smallest receive buffer allocated at once, so that several packets share
+/// an allocation.
+const MIN_RECV_BUF_SIZE: usize = 64 * 1024;
+
 /// This is a helper for managing the associated UDP socket.
 ///
 /// It will add the socks5 UDP header to every UDP packet it sends, also try to
@@ -204,6 +215,11 @@ impl<S> From<UdpAssociate<S>> for TcpStream {
 /// [`set_recv_buffer_size()`](#method.set_recv_buffer_size), and be read with
 /// [`get_max_packet_size()`](#method.get_recv_buffer_size).
 ///
+/// Received packets are carved out of a shared receive buffer, whose memory
+/// is reclaimed once they are dropped, and sent packets are written with the
+/// header and payload as separate iovecs, so relaying does not allocate or
+/// copy per packet.
+///
 /// You can create this struct by using
 /// [`AssociatedUdpSocket::from::<(UdpSocket,
 /// usize)>()`](#impl-From<UdpSocket>), the first element of the tuple is the
@@ -218,6 +234,7 @@ impl<S> From<UdpAssociate<S>> for TcpStream {
 pub struct AssociatedUdpSocket {
     socket: UdpSocket,
     buf_size: AtomicUsize,
+    recv_buf: Mutex<BytesMut>,
 }
 
 impl AssociatedUdpSocket {
@@ -249,11 +266,11 @@ impl AssociatedUdpSocket {
     /// connected.
     pub async fn recv(&self) -> std::io::Result<(Bytes, u8, Address)> {
         loop {
-            let max_packet_size = self.buf_size.load(Ordering::Acquire);
-            let mut buf = vec![0; max_packet_size];
-            let len = self.socket.recv(&mut buf).await?;
-            buf.truncate(len);
-            let pkt = Bytes::from(buf);
+            let mut buf = self.take_recv_buf();
+            self.socket
+                .recv_buf(&mut (&mut buf).limit(self.buf_size.load(Ordering::Acquire)))
+                .await?;
+            let pkt = self.split_recv_buf(buf);
 
             if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
                 let pkt = pkt.slice(header.len()..);
@@ -267,11 +284,12 @@ impl AssociatedUdpSocket {
     /// the remote target address and the source address.
     pub async fn recv_from(&self) -> std::io::Result<(Bytes, u8, Address, SocketAddr)> {
         loop {
-            let max_packet_size = self.buf_size.load(Ordering::Acquire);
-            let mut buf = vec![0; max_packet_size];
-            let (len, src_addr) = self.socket.recv_from(&mut buf).await?;
-            buf.truncate(len);
-            let pkt = Bytes::from(buf);
+            let mut buf = self.take_recv_buf();
+            let (_, src_addr) = self
+                .socket
+                .recv_buf_from(&mut (&mut buf).limit(self.buf_size.load(Ordering::Acquire)))
+                .await?;
+            let pkt = self.split_recv_buf(buf);
 
             if let Ok(header) = UdpHeader::retrieve_from_async_stream(&mut pkt.as_ref()).await {
                 let pkt = pkt.slice(header.len()..);
@@ -280,6 +298,32 @@ impl AssociatedUdpSocket {
         }
     }
 
+    /// Takes the receive buffer, emptied, with room for a packet of the
+    /// maximum size. Its memory is reused once the packets previously received
+    /// into it are dropped.
+    fn take_recv_buf(&self) -> BytesMut {
+        let mut buf = self
+            .recv_buf
+            .lock()
+            .map(|mut buf| std::mem::take(&mut *buf))
+            .unwrap_or_default();
+        let max_packet_size = self.buf_size.load(Ordering::Acquire);
+        if buf.capacity() < max_packet_size {
+            buf.reserve(max_packet_size.max(MIN_RECV_BUF_SIZE));
+        }
+        buf
+    }
+
+    /// Splits the packet received into `buf` off it, putting the rest of the
+    /// buffer back for the next packet.
+    fn split_recv_buf(&self, mut buf: BytesMut) -> Bytes {
+        let pkt = buf.split().freeze();
+        if let Ok(mut recv_buf) = self.recv_buf.lock() {
+            *recv_buf = buf;
+        }
+        pkt
+    }
+
     /// Sends a UDP relay packet to the remote address to which it is connected.
     /// The socks5 UDP header will be added to the packet.
     pub async fn send<P: AsRef<[u8]>>(
@@ -288,12 +332,18 @@ impl AssociatedUdpSocket {
         frag: u8,
         from_addr: Address,
     ) -> std::io::Result<usize> {
-        let header = UdpHeader::new(frag, from_addr);
-        let mut buf = BytesMut::with_capacity(header.len() + pkt.as_ref().len());
-        header.write_to_buf(&mut buf);
-        buf.extend_from_slice(pkt.as_ref());
+        let (header, header_len) = encode_header(frag, from_addr)?;
+        let bufs = [
+            IoSlice::new(&header[..header_len]),
+            IoSlice::new(pkt.as_ref()),
+        ];
 
-        self.socket.send(&buf).await.map(|len| len - header.len())
+        self.socket
+            .async_io(Interest::WRITABLE, || {
+                SockRef::from(&self.socket).send_vectored(&bufs)
+            })
+            .await
+            .map(|len| len.saturating_sub(header_len))
     }
 
     /// Sends a UDP relay packet to a specified remote address to which it is
@@ -305,16 +355,38 @@ impl AssociatedUdpSocket {
         from_addr: Address,
         to_addr: SocketAddr,
     ) -> std::io::Result<usize> {
-        let header = UdpHeader::new(frag, from_addr);
-        let mut buf = BytesMut::with_capacity(header.len() + pkt.as_ref().len());
-        header.write_to_buf(&mut buf);
-        buf.extend_from_slice(pkt.as_ref());
+        let (header, header_len) = encode_header(frag, from_addr)?;
+        let bufs = [
+            IoSlice::new(&header[..header_len]),
+            IoSlice::new(pkt.as_ref()),
+        ];
+        let to_addr = SockAddr::from(to_addr);
 
         self.socket
-            .send_to(&buf, to_addr)
+            .async_io(Interest::WRITABLE, || {
+                SockRef::from(&self.socket).send_to_vectored(&bufs, &to_addr)
+            })
             .await
-            .map(|len| len - header.len())
+            .map(|len| len.saturating_sub(header_len))
+    }
+}
+
+/// Serializes the socks5 UDP header of a packet from `from_addr` on the stack.
+fn encode_header(
+    frag: u8,
+    from_addr: Address,
+) -> std::io::Result<([u8; UdpHeader::max_serialized_len()], usize)> {
+    let header = UdpHeader::new(frag, from_addr);
+    let len = header.len();
+    if len > UdpHeader::max_serialized_len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "address too long for a socks5 UDP header",
+        ));
     }
+    let mut buf = [0; UdpHeader::max_serialized_len()];
+    header.write_to_buf(&mut &mut buf[..]);
+    Ok((buf, len))
 }
 
 impl From<(UdpSocket, usize)> for AssociatedUdpSocket {
@@ -323,6 +395,7 @@ impl From<(UdpSocket, usize)> for AssociatedUdpSocket {
         AssociatedUdpSocket {
             socket: from.0,
             buf_size: AtomicUsize::new(from.1),
+            recv_buf: Mutex::default(),
         }
     }
 }
@@ -347,3 +420,60 @@ impl AsMut<UdpSocket> for AssociatedUdpSocket {
         &mut self.socket
     }
 }
+
+/// A bounded queue of datagrams waiting to be sent by a UDP association.
+///
+/// When the queue is full the oldest datagram is dropped, so a slow
+/// destination delays its own traffic instead of stalling the receive path
+/// of the whole association.
+#[derive(Debug)]
+pub struct SendQueue<T> {
+    items: Mutex<VecDeque<T>>,
+    notify: Notify,
+    capacity: usize,
+    dropped: AtomicU64,
+}
+
+impl<T> SendQueue<T> {
+    /// Create a queue holding at most `capacity` datagrams.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            notify: Notify::new(),
+            capacity: capacity.max(1),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Queues `item`, dropping the oldest queued datagram if the queue is full.
+    pub fn push(&self, item: T) {
+        if let Ok(mut items) = self.items.lock() {
+            if items.len() >= self.capacity {
+                items.pop_front();
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            items.push_back(item);
+        }
+        self.notify.notify_one();
+    }
+
+    /// Waits for the oldest queued datagram.
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self
+                .items
+                .lock()
+                .ok()
+                .and_then(|mut items| items.pop_front())
+            {
+                return item;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Number of datagrams dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -1,8 +1,11 @@
 use self::{associate::UdpAssociate, bind::Bind, connect::Connect};
 use super::{super::error::Error, auth::Auth};
-use crate::socks::{
-    proto::{self, handshake, Address, AsyncStreamOperation, Command, Method},
-    server::AuthAdaptor,
+use crate::{
+    destination::normalize_host,
+    socks::{
+        proto::{self, handshake, Address, AsyncStreamOperation, Command, Method, Reply, Response},
+        server::AuthAdaptor,
+    },
 };
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{io::AsyncWriteExt, net::TcpStream};
@@ -158,8 +161,36 @@ impl AuthenticatedStream {
     ///
     /// Note that this method will not implicitly close the connection even if
     /// the client sends an invalid request.
-    pub async fn wait_request(mut self) -> Result<ClientConnection, Error> {
-        let req = proto::Request::retrieve_from_async_stream(&mut self.0).await?;
+    ///
+    /// Domain addresses are normalized, internationalized names included, and
+    /// when `strict_domain` is set they are additionally checked label by
+    /// label. The client is answered with `AddressTypeNotSupported` if any of
+    /// the checks fails, strict or not.
+    pub async fn wait_request(mut self, strict_domain: bool) -> Result<ClientConnection, Error> {
+        let mut req = match proto::Request::retrieve_from_async_stream(&mut self.0).await {
+            Ok(req) => req,
+            // Mostly a domain that is not UTF-8 or fails the basic checks
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                let resp = Response::new(Reply::AddressTypeNotSupported, Address::unspecified());
+                resp.write_to_async_stream(&mut self.0).await?;
+                return Err(err.into());
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Address::DomainAddress(ref mut domain, _) = req.address {
+            let normalized = Address::validate_domain(domain, strict_domain)
+                .and_then(|()| normalize_host(domain));
+            match normalized {
+                Ok(normalized) => *domain = normalized,
+                Err(err) => {
+                    let resp =
+                        Response::new(Reply::AddressTypeNotSupported, Address::unspecified());
+                    resp.write_to_async_stream(&mut self.0).await?;
+                    return Err(err.into());
+                }
+            }
+        }
 
         match req.command {
             Command::UdpAssociate => Ok(ClientConnection::UdpAssociate(
@@ -268,3 +299,21 @@ pub enum ClientConnection {
     Bind(Bind<bind::NeedFirstReply>, Address),
     Connect(Connect<connect::NeedReply>, Address),
 }
+
+impl ClientConnection {
+    /// Answers the request with the failure `reply` and closes the connection.
+    pub async fn refuse(self, reply: Reply) -> std::io::Result<()> {
+        match self {
+            ClientConnection::UdpAssociate(associate, _) => {
+                associate.reply(reply, Address::unspecified()).await?;
+            }
+            ClientConnection::Bind(bind, _) => {
+                bind.reply(reply, Address::unspecified()).await?;
+            }
+            ClientConnection::Connect(connect, _) => {
+                connect.reply(reply, Address::unspecified()).await?;
+            }
+        }
+        Ok(())
+    }
+}
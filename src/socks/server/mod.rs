@@ -1,8 +1,15 @@
+use bytes::Bytes;
+use cidr::IpCidr;
 use connection::{
     bind::{self, Bind},
     connect::{self, Connect},
 };
-use std::{net::SocketAddr, sync::Arc};
+use socket2::SockRef;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 use tokio::net::TcpListener;
 
 pub mod auth;
@@ -10,7 +17,8 @@ pub mod connection;
 
 use super::{
     proto::{Address, Reply, UdpHeader},
-    server::connection::associate::{self, AssociatedUdpSocket},
+    server::connection::associate::{self, AssociatedUdpSocket, SendQueue},
+    stun,
 };
 pub use crate::socks::server::{
     auth::AuthAdaptor,
@@ -23,39 +31,88 @@ use crate::{
 };
 use crate::{
     connect::{TcpConnector, UdpConnector},
+    destination::normalize_host,
     extension::Extension,
+    hooks::{AuthAttempt, Decision, Protocol, ProxyRequest, SharedHooks, TunnelClose},
+    memory::{MemoryAccount, Reservation},
+    relay::{self, Progress},
+    rules::EgressRules,
+    schedule::Schedule,
+    stats::{self, ActiveConnection, ConnectionId, Tunnel, TunnelInfo},
+    Socks5Options, TcpOptions,
 };
 
-use tokio::{io::AsyncWriteExt, net::UdpSocket, sync::RwLock};
+use tokio::{
+    io::AsyncWriteExt,
+    net::UdpSocket,
+    sync::RwLock,
+    time::{timeout_at, Instant},
+};
 use tracing::{instrument, Level};
 
 pub struct Socks5Server {
     listener: TcpListener,
     auth: Arc<AuthAdaptor>,
     connector: Connector,
+    settings: Arc<Settings>,
+    tcp: TcpOptions,
+    max_conn_memory: Option<usize>,
+    hooks: SharedHooks,
+    stats: stats::Listener,
 }
 
 impl Socks5Server {
     /// Create a new socks5 server
-    pub fn new(ctx: Context) -> std::io::Result<Self> {
-        let auth = match (ctx.auth.username, ctx.auth.password) {
-            (Some(username), Some(password)) => AuthAdaptor::new_password(username, password),
+    pub fn new(ctx: Context, opts: Socks5Options) -> std::io::Result<Self> {
+        let schedule = ctx.auth.schedule();
+        let auth = match (ctx.auth.username, ctx.auth.password, ctx.users, ctx.webhook) {
+            (_, _, _, Some(webhook)) => AuthAdaptor::new_webhook(webhook),
+            (_, _, Some(users), None) => AuthAdaptor::new_users(users),
+            (Some(username), Some(password), None, None) => {
+                AuthAdaptor::new_password(username, password)
+            }
 
             _ => AuthAdaptor::new_no_auth(),
         };
 
+        // A UDP association holds a datagram of each direction at least
+        let needed = 2 * max_packet_size(&opts);
+        if let Some(cap) = ctx.max_conn_memory.filter(|cap| *cap < needed) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "--max-conn-memory of {cap} bytes is below the {needed} bytes a SOCKS5 UDP \
+                     association needs, raise it or lower --udp-max-packet-size"
+                ),
+            ));
+        }
+
         let socket = if ctx.bind.is_ipv4() {
             tokio::net::TcpSocket::new_v4()?
         } else {
             tokio::net::TcpSocket::new_v6()?
         };
         socket.set_reuseaddr(true)?;
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        socket.set_reuseport(crate::uring::enabled())?;
         socket.bind(ctx.bind)?;
 
         Ok(Self {
             listener: socket.listen(ctx.concurrent as _)?,
             auth: Arc::new(auth),
             connector: ctx.connector,
+            settings: Arc::new(Settings {
+                schedule,
+                rules: ctx.rules,
+                opts,
+                handshake_timeout: Duration::from_secs(ctx.handshake_timeout.max(1)),
+                proxy_protocol: ctx.proxy_protocol,
+                proxy_protocol_from: ctx.proxy_protocol_from,
+            }),
+            tcp: ctx.tcp,
+            max_conn_memory: ctx.max_conn_memory,
+            hooks: ctx.hooks,
+            stats: ctx.stats,
         })
     }
 }
@@ -64,60 +121,240 @@ impl Serve for Socks5Server {
     async fn serve(self) -> std::io::Result<()> {
         tracing::info!("Socks5 server listening on {}", self.listener.local_addr()?);
 
-        while let Ok((stream, socket_addr)) = self.listener.accept().await {
+        if let Some(bind) = self.settings.opts.stun_bind {
+            tokio::spawn(async move {
+                if let Err(err) = stun::serve(bind).await {
+                    tracing::error!("[STUN] endpoint error: {}", err);
+                }
+            });
+        }
+
+        while let Ok((mut stream, socket_addr)) = self.listener.accept().await {
+            // Behind a load balancer the client is only known from the header
+            if !self.settings.proxy_protocol
+                && !self.hooks.on_connect(Protocol::Socks5, socket_addr)
+            {
+                tracing::debug!("[SOCKS5] connection from {} rejected by hook", socket_addr);
+                continue;
+            }
+
+            if let Err(err) = self.tcp.apply(SockRef::from(&stream)) {
+                tracing::trace!("[SOCKS5] failed to apply tcp options: {}", err);
+            }
+
             let connector = self.connector.clone();
             let auth = self.auth.clone();
-            tokio::spawn(async move {
+            let settings = self.settings.clone();
+            let account = MemoryAccount::new(self.max_conn_memory);
+            let hooks = self.hooks.clone();
+            let Some(active) = ActiveConnection::admit(&self.stats).await else {
+                tracing::debug!(
+                    "[SOCKS5] connection from {} rejected, too many connections",
+                    socket_addr
+                );
+                continue;
+            };
+            tokio::spawn(ConnectionId::new().scope(async move {
+                let _active = active;
+                let mut socket_addr = socket_addr;
+                if settings.proxy_protocol {
+                    socket_addr = match crate::proxy_protocol::accept(
+                        &mut stream,
+                        socket_addr,
+                        settings.handshake_timeout,
+                        &settings.proxy_protocol_from,
+                    )
+                    .await
+                    {
+                        Ok(client) => client,
+                        Err(err) => {
+                            tracing::debug!(
+                                "[SOCKS5] connection from {} dropped: {}",
+                                socket_addr,
+                                err
+                            );
+                            return;
+                        }
+                    };
+                    if !hooks.on_connect(Protocol::Socks5, socket_addr) {
+                        tracing::debug!(
+                            "[SOCKS5] connection from {} rejected by hook",
+                            socket_addr
+                        );
+                        return;
+                    }
+                }
+
                 if let Err(err) = handle(
                     IncomingConnection::new(stream, auth),
                     socket_addr,
                     connector,
+                    settings,
+                    account,
+                    hooks,
                 )
                 .await
                 {
                     tracing::trace!("[SOCKS5] error: {}", err);
                 }
-            });
+            }));
         }
 
         Ok(())
     }
 }
 
+/// Settings shared by the connections of a server.
+struct Settings {
+    schedule: Schedule,
+    rules: Option<Arc<EgressRules>>,
+    opts: Socks5Options,
+    /// Time a client has to authenticate and send its request.
+    handshake_timeout: Duration,
+    /// Whether connections start with a PROXY protocol header.
+    proxy_protocol: bool,
+    /// Sources allowed to send that header, any if empty.
+    proxy_protocol_from: Arc<[IpCidr]>,
+}
+
 async fn handle(
     conn: IncomingConnection,
     socket_addr: SocketAddr,
     connector: Connector,
+    settings: Arc<Settings>,
+    account: MemoryAccount,
+    hooks: SharedHooks,
 ) -> std::io::Result<()> {
-    let (conn, res) = conn.authenticate().await?;
-    let (res, extension) = res?;
+    let Settings {
+        schedule,
+        rules,
+        opts,
+        handshake_timeout,
+        proxy_protocol: _,
+        proxy_protocol_from: _,
+    } = &*settings;
+    let opts = *opts;
+    let handshake_deadline = Instant::now() + *handshake_timeout;
+
+    let (conn, res) = timeout_at(handshake_deadline, conn.authenticate())
+        .await
+        .map_err(|_| handshake_timed_out(socket_addr))??;
+    hooks.on_auth_attempt(&AuthAttempt {
+        protocol: Protocol::Socks5,
+        peer: socket_addr,
+        username: match &res {
+            Ok((_, _, Some(login))) => Some(login.username.as_str()),
+            _ => None,
+        },
+        success: matches!(res, Ok((true, ..))),
+        presented: res.is_ok(),
+    });
+    let (res, extension, login) = res?;
 
     if !res {
         tracing::info!("[SOCKS5] authentication failed: {}", socket_addr);
         return Ok(());
     }
 
-    match conn.wait_request().await? {
+    let connector = match login.as_ref().filter(|login| !login.pool.is_empty()) {
+        Some(login) => connector.with_pool(&login.pool),
+        None => connector,
+    };
+
+    let request = timeout_at(handshake_deadline, conn.wait_request(opts.strict_domain))
+        .await
+        .map_err(|_| handshake_timed_out(socket_addr))??;
+    if !schedule.allows_now() {
+        tracing::info!("[SOCKS5] {} is outside of the access schedule", socket_addr);
+        return request.refuse(Reply::ConnectionNotAllowed).await;
+    }
+
+    let target = match &request {
+        ClientConnection::Connect(_, addr)
+        | ClientConnection::UdpAssociate(_, addr)
+        | ClientConnection::Bind(_, addr) => addr.to_string(),
+    };
+    let username = login.as_ref().map(|login| login.username.as_str());
+    let connector = connector.with_owner(stats::owner(username, socket_addr.ip()));
+    let extension = connector.client_extension(socket_addr.ip(), extension);
+    let extension = match hooks.on_request(&ProxyRequest {
+        protocol: Protocol::Socks5,
+        peer: socket_addr,
+        username,
+        target: &target,
+        extension,
+    }) {
+        Decision::Allow => extension,
+        Decision::Egress(extension) => extension,
+        Decision::Deny => {
+            tracing::info!("[SOCKS5] request from {} to {} denied", socket_addr, target);
+            return request.refuse(Reply::ConnectionNotAllowed).await;
+        }
+    };
+
+    // UDP associations carry no single destination to match
+    let connector = match (rules, &request) {
+        (Some(rules), ClientConnection::Connect(_, addr) | ClientConnection::Bind(_, addr)) => {
+            match addr {
+                Address::DomainAddress(domain, _) => rules.apply(connector, domain),
+                Address::SocketAddress(addr) => rules.apply(connector, &addr.ip().to_string()),
+            }
+        }
+        _ => connector,
+    };
+
+    match request {
         ClientConnection::Connect(connect, addr) => {
-            hanlde_connect_proxy(connector.tcp_connector(), connect, addr, extension).await
+            hanlde_connect_proxy(
+                connector.tcp_connector(),
+                connect,
+                addr,
+                extension,
+                socket_addr,
+                login.map(|login| login.username),
+                &hooks,
+            )
+            .await
         }
         ClientConnection::UdpAssociate(associate, addr) => {
-            handle_udp_proxy(connector.udp_connector(), associate, addr, extension).await
+            handle_udp_proxy(
+                connector.udp_connector(),
+                associate,
+                addr,
+                extension,
+                opts,
+                account,
+            )
+            .await
         }
         ClientConnection::Bind(bind, addr) => {
-            hanlde_bind_proxy(connector.tcp_connector(), bind, addr, extension).await
+            hanlde_bind_proxy(
+                connector.tcp_connector(),
+                bind,
+                addr,
+                extension,
+                Duration::from_secs(opts.bind_accept_timeout),
+                socket_addr,
+                login.map(|login| login.username),
+                &hooks,
+            )
+            .await
         }
     }
 }
 
-#[instrument(skip(connector, connect), level = Level::DEBUG)]
+#[instrument(skip(connector, connect, hooks), level = Level::DEBUG)]
 #[inline]
 async fn hanlde_connect_proxy(
     connector: TcpConnector<'_>,
     connect: Connect<connect::NeedReply>,
     addr: Address,
     extension: Extension,
+    peer: SocketAddr,
+    username: Option<String>,
+    hooks: &SharedHooks,
 ) -> std::io::Result<()> {
+    let target = addr.to_string();
     let target_stream = match addr {
         Address::DomainAddress(domain, port) => {
             connector
@@ -129,22 +366,48 @@ async fn hanlde_connect_proxy(
 
     match target_stream {
         Ok(mut target_stream) => {
-            let mut conn = connect
-                .reply(Reply::Succeeded, Address::unspecified())
-                .await?;
+            let bound = target_stream
+                .local_addr()
+                .map_or_else(|_| Address::unspecified(), Address::from);
+            let mut conn = connect.reply(Reply::Succeeded, bound).await?;
+
+            let progress = Arc::new(Progress::default());
+            let _tunnel = Tunnel::register(TunnelInfo {
+                protocol: Protocol::Socks5,
+                client: peer,
+                username: username.clone(),
+                target: target.clone(),
+                egress: target_stream.local_addr().ok().map(|addr| addr.ip()),
+                server: target_stream.peer_addr().ok(),
+                progress: progress.clone(),
+                client_first: false,
+                started: std::time::Instant::now(),
+            });
 
-            match tokio::io::copy_bidirectional(&mut target_stream, &mut conn).await {
-                Ok((from_client, from_server)) => {
-                    tracing::info!(
-                        "[TCP] client wrote {} bytes and received {} bytes",
-                        from_client,
-                        from_server
-                    );
-                }
-                Err(err) => {
-                    tracing::trace!("[TCP] tunnel error: {}", err);
-                }
-            };
+            // The target is `a` of the relay, the client `b`
+            let (from_client, from_server) =
+                match relay::copy_bidirectional(&mut target_stream, &mut conn, &progress).await {
+                    Ok(copied) => {
+                        tracing::info!(
+                            "[TCP] client wrote {} bytes and received {} bytes",
+                            copied.0,
+                            copied.1
+                        );
+                        copied
+                    }
+                    Err(err) => {
+                        tracing::trace!("[TCP] tunnel error: {}", err);
+                        progress.copied()
+                    }
+                };
+            hooks.on_tunnel_close(&TunnelClose {
+                protocol: Protocol::Socks5,
+                peer,
+                username,
+                target,
+                sent: from_server,
+                received: from_client,
+            });
 
             drop(target_stream);
 
@@ -152,7 +415,7 @@ async fn hanlde_connect_proxy(
         }
         Err(err) => {
             let mut conn = connect
-                .reply(Reply::HostUnreachable, Address::unspecified())
+                .reply(Reply::from(&err), Address::unspecified())
                 .await?;
             conn.shutdown().await?;
             Err(err)
@@ -160,15 +423,50 @@ async fn hanlde_connect_proxy(
     }
 }
 
-#[instrument(skip(connector, associate), level = Level::DEBUG)]
+/// Largest MTU of the network interfaces other than loopback, the default
+/// size of relayed UDP datagrams, or 1500 where it cannot be read.
+static INTERFACE_MTU: LazyLock<usize> = LazyLock::new(|| {
+    #[cfg(target_os = "linux")]
+    {
+        let mtu = std::fs::read_dir("/sys/class/net")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_name() != "lo")
+            .filter_map(|entry| std::fs::read_to_string(entry.path().join("mtu")).ok())
+            .filter_map(|mtu| mtu.trim().parse::<usize>().ok())
+            .max();
+        if let Some(mtu) = mtu {
+            tracing::debug!("[UDP] relaying datagrams of up to {} bytes", mtu);
+            return mtu.clamp(576, u16::MAX as usize);
+        }
+    }
+    1500
+});
+
+/// Returns the size of the largest UDP datagram relayed.
+fn max_packet_size(opts: &Socks5Options) -> usize {
+    opts.udp_max_packet_size.map_or(*INTERFACE_MTU, usize::from)
+}
+
+fn handshake_timed_out(peer: SocketAddr) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!("{peer} did not complete the handshake in time"),
+    )
+}
+
+#[instrument(skip(connector, associate, opts, account), level = Level::DEBUG)]
 #[inline]
 async fn handle_udp_proxy(
     connector: UdpConnector<'_>,
     associate: UdpAssociate<associate::NeedReply>,
     _: Address,
     extension: Extension,
+    opts: Socks5Options,
+    account: MemoryAccount,
 ) -> std::io::Result<()> {
-    const MAX_UDP_RELAY_PACKET_SIZE: usize = 1500;
+    let max_packet_size = max_packet_size(&opts);
 
     let listen_ip = associate.local_addr()?.ip();
     let udp_socket = UdpSocket::bind(SocketAddr::from((listen_ip, 0))).await;
@@ -181,42 +479,73 @@ async fn handle_udp_proxy(
                 .reply(Reply::Succeeded, Address::from(listen_addr))
                 .await?;
 
-            let buf_size = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
+            let buf_size = max_packet_size - UdpHeader::max_serialized_len();
             let listen_udp = AssociatedUdpSocket::from((udp_socket, buf_size));
 
             let incoming_addr = Arc::new(RwLock::new(SocketAddr::from(([0, 0, 0, 0], 0))));
             let dispatch_socket = connector.bind_socket(extension).await?;
 
+            // Only client datagrams keep the association alive by default, so
+            // unsolicited traffic from the internet cannot hold it open.
+            let idle_timeout = opts.udp_idle_timeout.map(Duration::from_secs);
+            let mut last_activity = Instant::now();
+
+            // Datagrams from the client are sent by a separate future, so a
+            // slow destination cannot stall receiving from the client.
+            let send_queue = SendQueue::<(Bytes, Address, Reservation)>::new(opts.udp_send_queue);
+            let sender = async {
+                loop {
+                    let (pkt, dst_addr, _reservation) = send_queue.pop().await;
+                    let res = match dst_addr {
+                        Address::SocketAddress(dst_addr) => {
+                            connector
+                                .send_packet_with_addr(&dispatch_socket, &pkt, dst_addr)
+                                .await
+                        }
+                        Address::DomainAddress(domain, port) => match normalize_host(&domain) {
+                            Ok(domain) => {
+                                connector
+                                    .send_packet_with_domain(&dispatch_socket, &pkt, (domain, port))
+                                    .await
+                            }
+                            Err(err) => Err(err),
+                        },
+                    };
+
+                    if let Err(err) = res {
+                        tracing::debug!("[UDP] send error: {}", err);
+                    }
+                }
+            };
+            tokio::pin!(sender);
+
             let res = loop {
+                let idle_deadline = idle_timeout.map(|timeout| last_activity + timeout);
+
                 tokio::select! {
                     res = async {
-                        let buf_size = MAX_UDP_RELAY_PACKET_SIZE - UdpHeader::max_serialized_len();
+                        let buf_size = max_packet_size - UdpHeader::max_serialized_len();
                         listen_udp.set_max_packet_size(buf_size);
 
                         let (pkt, frag, dst_addr, src_addr) = listen_udp.recv_from().await?;
+                        let reservation = account.reserve(pkt.len())?;
                         if frag != 0 {
                             return Err("[UDP] packet fragment is not supported".into());
                         }
                         *incoming_addr.write().await = src_addr;
                         tracing::info!("[UDP] {src_addr} -> {dst_addr} incoming packet size {}", pkt.len());
 
-                        match dst_addr {
-                            Address::SocketAddress(dst_addr) => {
-                                connector.send_packet_with_addr(&dispatch_socket, &pkt, dst_addr).await?;
-                            }
-                            Address::DomainAddress(domain, port) => {
-                                connector.send_packet_with_domain(&dispatch_socket, &pkt, (domain, port)).await?;
-                            }
-                        };
-
+                        send_queue.push((pkt, dst_addr, reservation));
                         Ok::<_, Error>(())
                     } => {
                         if res.is_err() {
                             break res;
                         }
+                        last_activity = Instant::now();
                     },
                     res = async {
-                        let mut buf = vec![0u8; MAX_UDP_RELAY_PACKET_SIZE];
+                        let _reservation = account.reserve(max_packet_size)?;
+                        let mut buf = crate::pool::get(max_packet_size);
                         let (len, remote_addr) = dispatch_socket.recv_from(&mut buf).await?;
                         let incoming_addr = *incoming_addr.read().await;
                         tracing::info!("[UDP] {incoming_addr} <- {remote_addr} feedback to incoming");
@@ -227,7 +556,20 @@ async fn handle_udp_proxy(
                         if res.is_err() {
                             break res;
                         }
+                        if opts.udp_idle_remote_activity {
+                            last_activity = Instant::now();
+                        }
                     },
+                    _ = async {
+                        match idle_deadline {
+                            Some(deadline) => tokio::time::sleep_until(deadline).await,
+                            None => std::future::pending().await,
+                        }
+                    } => {
+                        tracing::info!("[UDP] {} association idle, closing", listen_addr);
+                        break Ok::<_, Error>(());
+                    },
+                    _ = &mut sender => {},
                     _ = reply_listener.wait_until_closed() => {
                         tracing::info!("[UDP] {} listener closed", listen_addr);
                         break Ok::<_, Error>(());
@@ -235,6 +577,14 @@ async fn handle_udp_proxy(
                 };
             };
 
+            if send_queue.dropped() > 0 {
+                tracing::info!(
+                    "[UDP] {} dropped {} datagrams on a full send queue",
+                    listen_addr,
+                    send_queue.dropped()
+                );
+            }
+
             reply_listener.shutdown().await?;
 
             res.map_err(Into::into)
@@ -304,19 +654,25 @@ async fn handle_udp_proxy(
 ///
 /// * `connector` - The connector instance.
 /// * `bind` - The BIND request details.
-/// * `addr` - The address to bind to.
+/// * `addr` - The address the client expects the inbound connection from.
 /// * `extension` - Additional extensions.
+/// * `accept_timeout` - How long to wait for the inbound connection.
 ///
 /// # Returns
 ///
 /// A `Result` indicating success or failure.
-#[instrument(skip(connector, bind, _addr), level = Level::DEBUG)]
+#[instrument(skip(connector, bind, addr, hooks), level = Level::DEBUG)]
 #[inline]
+#[allow(clippy::too_many_arguments)]
 async fn hanlde_bind_proxy(
     connector: TcpConnector<'_>,
     bind: Bind<bind::NeedFirstReply>,
-    _addr: Address,
+    addr: Address,
     extension: Extension,
+    accept_timeout: Duration,
+    peer: SocketAddr,
+    username: Option<String>,
+    hooks: &SharedHooks,
 ) -> std::io::Result<()> {
     let listen_ip =
         connector.bind_socket_addr(|| bind.local_addr().map(|socket| socket.ip()), extension)?;
@@ -326,7 +682,22 @@ async fn hanlde_bind_proxy(
         .reply(Reply::Succeeded, Address::from(listener.local_addr()?))
         .await?;
 
-    let (mut inbound, inbound_addr) = listener.accept().await?;
+    let (mut inbound, inbound_addr) =
+        match tokio::time::timeout(accept_timeout, listener.accept()).await {
+            Ok(accepted) => accepted?,
+            Err(_) => {
+                tracing::info!("[BIND] no connection to {} in time", listen_ip);
+                return refuse_bind(conn, Reply::GeneralFailure).await;
+            }
+        };
+    if !expected_peer(&connector, &addr, inbound_addr).await {
+        tracing::info!(
+            "[BIND] refused connection from {}, expected {}",
+            inbound_addr,
+            addr
+        );
+        return refuse_bind(conn, Reply::ConnectionNotAllowed).await;
+    }
     tracing::info!("[BIND] accepted connection from {}", inbound_addr);
 
     match conn
@@ -334,14 +705,37 @@ async fn hanlde_bind_proxy(
         .await
     {
         Ok(mut conn) => {
-            match tokio::io::copy_bidirectional(&mut inbound, &mut conn).await {
+            let progress = Arc::new(Progress::default());
+            let _tunnel = Tunnel::register(TunnelInfo {
+                protocol: Protocol::Socks5,
+                client: peer,
+                username: username.clone(),
+                target: inbound_addr.to_string(),
+                egress: listener.local_addr().ok().map(|addr| addr.ip()),
+                server: None,
+                progress: progress.clone(),
+                client_first: false,
+                started: std::time::Instant::now(),
+            });
+
+            let (a, b) = match relay::copy_bidirectional(&mut inbound, &mut conn, &progress).await {
                 Ok((a, b)) => {
                     tracing::trace!("[BIND] client wrote {} bytes and received {} bytes", a, b);
+                    (a, b)
                 }
                 Err(err) => {
                     tracing::trace!("[BIND] tunnel error: {}", err);
+                    progress.copied()
                 }
-            }
+            };
+            hooks.on_tunnel_close(&TunnelClose {
+                protocol: Protocol::Socks5,
+                peer,
+                username,
+                target: inbound_addr.to_string(),
+                sent: b,
+                received: a,
+            });
 
             drop(inbound);
 
@@ -353,3 +747,34 @@ async fn hanlde_bind_proxy(
         }
     }
 }
+
+/// Whether `inbound` comes from the host the client named in its BIND
+/// request. An unspecified address matches any. Only the address is
+/// compared, as the port of the request is not the one the peer connects
+/// from in general.
+async fn expected_peer(
+    connector: &TcpConnector<'_>,
+    expected: &Address,
+    inbound: SocketAddr,
+) -> bool {
+    let inbound = inbound.ip().to_canonical();
+    let matches = |addr: SocketAddr| {
+        let addr = addr.ip().to_canonical();
+        addr.is_unspecified() || addr == inbound
+    };
+    match expected {
+        Address::SocketAddress(addr) => matches(*addr),
+        Address::DomainAddress(domain, port) => connector
+            .lookup(domain, *port)
+            .await
+            .is_ok_and(|addrs| addrs.into_iter().any(matches)),
+    }
+}
+
+/// Answers a BIND request with a failed second reply and closes it.
+async fn refuse_bind(conn: Bind<bind::NeedSecondReply>, reply: Reply) -> std::io::Result<()> {
+    match conn.reply(reply, Address::unspecified()).await {
+        Ok(mut conn) => conn.shutdown().await,
+        Err((err, _)) => Err(err),
+    }
+}
@@ -0,0 +1,145 @@
+//! Tiny address discovery endpoint for UDP clients.
+//!
+//! SOCKS5 UDP clients send a datagram to this endpoint through the relay and
+//! learn the address and port their traffic leaves from. STUN binding requests
+//! (RFC 5389) are answered with a binding success response carrying an
+//! `XOR-MAPPED-ADDRESS` attribute. Any other datagram is dropped, so that
+//! the endpoint cannot be used to reflect traffic at spoofed sources.
+
+use bytes::{BufMut, BytesMut};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_SUCCESS: u16 = 0x0101;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Answers every binding request received on `bind` with its observed source
+/// address.
+pub async fn serve(bind: SocketAddr) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(bind).await?;
+    tracing::info!(
+        "Address discovery endpoint listening on {}",
+        socket.local_addr()?
+    );
+
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(value) => value,
+            Err(err) => {
+                tracing::trace!("[STUN] receive error: {}", err);
+                continue;
+            }
+        };
+
+        let Some(reply) = response(&buf[..len], peer) else {
+            tracing::trace!("[STUN] dropping {} bytes from {}", len, peer);
+            continue;
+        };
+        if let Err(err) = socket.send_to(&reply, peer).await {
+            tracing::trace!("[STUN] send to {} error: {}", peer, err);
+        }
+    }
+}
+
+/// Builds the reply to `request` received from `peer`, if it is a binding
+/// request.
+fn response(request: &[u8], peer: SocketAddr) -> Option<BytesMut> {
+    binding_transaction_id(request).map(|transaction_id| binding_success(transaction_id, peer))
+}
+
+/// Returns the transaction ID of a well-formed STUN binding request.
+fn binding_transaction_id(request: &[u8]) -> Option<[u8; 12]> {
+    if request.len() < HEADER_LEN {
+        return None;
+    }
+
+    let message_type = u16::from_be_bytes([request[0], request[1]]);
+    let length = usize::from(u16::from_be_bytes([request[2], request[3]]));
+    let cookie = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+    if message_type != BINDING_REQUEST
+        || cookie != MAGIC_COOKIE
+        || length % 4 != 0
+        || length != request.len() - HEADER_LEN
+    {
+        return None;
+    }
+
+    request[8..HEADER_LEN].try_into().ok()
+}
+
+/// Encodes a binding success response carrying `peer` as `XOR-MAPPED-ADDRESS`.
+fn binding_success(transaction_id: [u8; 12], peer: SocketAddr) -> BytesMut {
+    let mut attr = BytesMut::with_capacity(20);
+    attr.put_u8(0);
+    let port = peer.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    match peer.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            attr.put_u8(0x01);
+            attr.put_u16(port);
+            attr.put_u32(u32::from(ip) ^ MAGIC_COOKIE);
+        }
+        IpAddr::V6(ip) => {
+            attr.put_u8(0x02);
+            attr.put_u16(port);
+            let mut key = [0u8; 16];
+            key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+            key[4..].copy_from_slice(&transaction_id);
+            attr.put_u128(u128::from(ip) ^ u128::from_be_bytes(key));
+        }
+    }
+
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + 4 + attr.len());
+    buf.put_u16(BINDING_SUCCESS);
+    buf.put_u16(4 + attr.len() as u16);
+    buf.put_u32(MAGIC_COOKIE);
+    buf.put_slice(&transaction_id);
+    buf.put_u16(XOR_MAPPED_ADDRESS);
+    buf.put_u16(attr.len() as u16);
+    buf.put_slice(&attr);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binding_success_ipv4() {
+        let transaction_id = [7u8; 12];
+        let mut request = vec![0x00, 0x01, 0x00, 0x00];
+        request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        request.extend_from_slice(&transaction_id);
+
+        let peer: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let reply = response(&request, peer).unwrap();
+
+        assert_eq!(reply.len(), 32);
+        assert_eq!(&reply[..2], &BINDING_SUCCESS.to_be_bytes());
+        assert_eq!(&reply[8..20], &transaction_id);
+        assert_eq!(&reply[20..22], &XOR_MAPPED_ADDRESS.to_be_bytes());
+
+        let port = u16::from_be_bytes([reply[26], reply[27]]) ^ (MAGIC_COOKIE >> 16) as u16;
+        let ip = u32::from_be_bytes([reply[28], reply[29], reply[30], reply[31]]) ^ MAGIC_COOKIE;
+        assert_eq!(SocketAddr::from((ip.to_be_bytes(), port)), peer);
+    }
+
+    #[test]
+    fn test_other_datagrams_dropped() {
+        let peer: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        assert!(response(b"ping", peer).is_none());
+
+        let mut request = vec![0x00, 0x01, 0x00, 0x04];
+        request.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        request.extend_from_slice(&[7u8; 12]);
+        assert!(response(&request, peer).is_none());
+        request.extend_from_slice(&[0u8; 4]);
+        assert!(response(&request, peer).is_some());
+
+        request[0] = 0x01;
+        assert!(response(&request, peer).is_none());
+    }
+}
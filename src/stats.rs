@@ -0,0 +1,911 @@
+//! Live statistics of the running process, as served by the control socket
+//! and written to the statistics file.
+//!
+//! Client connections are counted while they are open, in total and per
+//! listener, see [`ActiveConnection`], and open tunnels are listed, see
+//! [`Tunnel`].
+//! Requests and tunneled bytes are summed per user by the [`Usage`] hooks,
+//! and per protocol and egress address when a tunnel closes.
+//!
+//! The egress addresses of open tunnels are indexed by their owner, the user
+//! or else the client address, see [`egress_taken`].
+//!
+//! Requests and tunneled bytes are also counted per destination host, with
+//! counters halving every hour so that the busiest hosts reflect recent
+//! traffic, see [`top_destinations`].
+
+use crate::{
+    hooks::{Decision, Hooks, Protocol, ProxyRequest, TunnelClose},
+    relay::Progress,
+    Overflow,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::Instrument;
+
+/// Client connections currently open.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// Client connections accepted since startup.
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Connection counters of the listeners of the process.
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// When the process started serving.
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Usage of every user that made a request.
+static USERS: LazyLock<Mutex<HashMap<String, UserUsage>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Tunnels currently open, by id.
+static TUNNELS: LazyLock<Mutex<HashMap<u64, TunnelInfo>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Owners of the open tunnels, by egress address, once per tunnel.
+static EGRESS_OWNERS: LazyLock<Mutex<HashMap<IpAddr, Vec<u64>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Slots reserved by connections being dialed and plain HTTP requests in
+/// flight, by egress address.
+static EGRESS_SLOTS: LazyLock<Mutex<HashMap<IpAddr, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Number of open tunnels, by server address.
+static SERVER_TUNNELS: LazyLock<Mutex<HashMap<SocketAddr, usize>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Id of the next tunnel.
+static NEXT_TUNNEL: AtomicU64 = AtomicU64::new(0);
+
+/// Traffic of the closed tunnels, by protocol and by egress address.
+static CLOSED: LazyLock<Mutex<ClosedTunnels>> = LazyLock::new(Mutex::default);
+
+/// Recent traffic, by destination host.
+static DESTINATIONS: LazyLock<Mutex<Destinations>> = LazyLock::new(Mutex::default);
+
+/// Most users tracked, later ones are summed up as `other`.
+const MAX_USERS: usize = 4096;
+
+/// Most egress addresses tracked, later ones are summed up as `other`.
+const MAX_EGRESS: usize = 4096;
+
+/// Most destination hosts tracked, the least requested ones making room for
+/// new ones.
+const MAX_DESTINATIONS: usize = 4096;
+
+/// Destination hosts evicted at once when a new one needs room, so the scan
+/// for the least requested ones is paid once per that many new hosts.
+const DESTINATION_EVICTION: usize = MAX_DESTINATIONS / 8;
+
+/// Time in which the counters of a destination host halve.
+const DESTINATION_HALF_LIFE: Duration = Duration::from_secs(3600);
+
+/// Usage of a single user.
+#[derive(Clone, Copy, Default)]
+struct UserUsage {
+    requests: u64,
+    sent: u64,
+    received: u64,
+}
+
+/// Traffic of a group of closed tunnels.
+#[derive(Clone, Copy, Default)]
+struct Traffic {
+    tunnels: u64,
+    sent: u64,
+    received: u64,
+}
+
+#[derive(Default)]
+struct ClosedTunnels {
+    protocols: HashMap<&'static str, Traffic>,
+    egress: HashMap<String, Traffic>,
+}
+
+/// Decaying counters of a destination host.
+#[derive(Clone, Copy)]
+struct DestinationUsage {
+    requests: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl DestinationUsage {
+    /// Returns the counters decayed to `now`.
+    fn at(self, now: Instant) -> Self {
+        let halvings = now.saturating_duration_since(self.updated).as_secs_f64()
+            / DESTINATION_HALF_LIFE.as_secs_f64();
+        let factor = 0.5f64.powf(halvings);
+        Self {
+            requests: self.requests * factor,
+            bytes: self.bytes * factor,
+            updated: now,
+        }
+    }
+}
+
+/// Destination hosts, bounded by [`MAX_DESTINATIONS`].
+#[derive(Default)]
+struct Destinations(HashMap<String, DestinationUsage>);
+
+impl Destinations {
+    fn record(&mut self, host: &str, requests: f64, bytes: f64, now: Instant) {
+        let host = host.to_ascii_lowercase();
+        if !self.0.contains_key(&host) && self.0.len() >= MAX_DESTINATIONS {
+            self.evict(now);
+        }
+
+        let usage = self.0.entry(host).or_insert(DestinationUsage {
+            requests: 0.0,
+            bytes: 0.0,
+            updated: now,
+        });
+        *usage = usage.at(now);
+        usage.requests += requests;
+        usage.bytes += bytes;
+    }
+
+    /// Removes the [`DESTINATION_EVICTION`] hosts with the fewest requests at
+    /// `now`.
+    fn evict(&mut self, now: Instant) {
+        let mut hosts = self
+            .0
+            .iter()
+            .map(|(host, usage)| (usage.at(now).requests, host))
+            .collect::<Vec<_>>();
+        let count = DESTINATION_EVICTION.min(hosts.len());
+        if count == 0 {
+            return;
+        }
+        hosts.select_nth_unstable_by(count - 1, |(a, _), (b, _)| a.total_cmp(b));
+        let least = hosts[..count]
+            .iter()
+            .map(|(_, host)| (*host).clone())
+            .collect::<Vec<_>>();
+        for host in least {
+            self.0.remove(&host);
+        }
+    }
+
+    /// Returns the `n` hosts with the most bytes, or requests if not
+    /// `by_bytes`, and their counters at `now`.
+    fn top(&self, n: usize, by_bytes: bool, now: Instant) -> Vec<(String, DestinationUsage)> {
+        let mut top = self
+            .0
+            .iter()
+            .map(|(host, usage)| (host.clone(), usage.at(now)))
+            .collect::<Vec<_>>();
+        let key = |usage: &DestinationUsage| {
+            if by_bytes {
+                usage.bytes
+            } else {
+                usage.requests
+            }
+        };
+        top.sort_by(|(_, a), (_, b)| key(b).total_cmp(&key(a)));
+        top.truncate(n);
+        top
+    }
+}
+
+/// Returns the host of a `host:port` target.
+fn target_host(target: &str) -> &str {
+    if let Some(rest) = target.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') && port.parse::<u16>().is_ok() => host,
+        _ => target,
+    }
+}
+
+/// Limit on the client connections open at once, shared by all listeners of
+/// the process.
+static LIMIT: OnceLock<ConnectionLimit> = OnceLock::new();
+
+/// Client connections waiting for a slot of the connection limit.
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Client connections rejected by the connection limit since startup.
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+struct ConnectionLimit {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    overflow: Overflow,
+}
+
+/// Limits the client connections open at once to `max`, handling the ones
+/// beyond it as `overflow` says. Only the first call has an effect.
+pub(crate) fn limit_connections(max: usize, overflow: Overflow) {
+    let _ = LIMIT.set(ConnectionLimit {
+        semaphore: Arc::new(Semaphore::new(max.clamp(1, Semaphore::MAX_PERMITS))),
+        max,
+        overflow,
+    });
+}
+
+/// Connection counters of a listener, labelled with its name.
+#[derive(Clone)]
+pub(crate) struct Listener(Arc<ListenerCounters>);
+
+struct ListenerCounters {
+    name: String,
+    active: AtomicUsize,
+    total: AtomicU64,
+}
+
+impl Listener {
+    /// Returns the counters of the listener named `name`, shared with the
+    /// listeners of the process registered under the same name.
+    pub(crate) fn register(name: &str) -> Self {
+        let mut listeners = LISTENERS.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(listener) = listeners.iter().find(|listener| listener.0.name == name) {
+            return listener.clone();
+        }
+        let listener = Self(Arc::new(ListenerCounters {
+            name: name.to_owned(),
+            active: AtomicUsize::new(0),
+            total: AtomicU64::new(0),
+        }));
+        listeners.push(listener.clone());
+        listener
+    }
+}
+
+/// Returns the name, open and accepted connections of every listener.
+pub(crate) fn listeners() -> Vec<(String, usize, u64)> {
+    LISTENERS
+        .lock()
+        .map(|listeners| {
+            listeners
+                .iter()
+                .map(|listener| {
+                    (
+                        listener.0.name.clone(),
+                        listener.0.active.load(Ordering::Relaxed),
+                        listener.0.total.load(Ordering::Relaxed),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// A client connection of a listener, counted as active until dropped.
+pub(crate) struct ActiveConnection {
+    listener: Listener,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ActiveConnection {
+    /// Counts a connection that is not subject to the connection limit.
+    pub(crate) fn new(listener: &Listener) -> Self {
+        Self::count(listener, None)
+    }
+
+    /// Admits a client connection of `listener` under the connection limit,
+    /// waiting for a slot or returning `None` when the limit is reached,
+    /// depending on the overflow mode.
+    pub(crate) async fn admit(listener: &Listener) -> Option<Self> {
+        let Some(limit) = LIMIT.get() else {
+            return Some(Self::new(listener));
+        };
+
+        let permit = match limit.overflow {
+            Overflow::Reject => limit.semaphore.clone().try_acquire_owned().ok(),
+            Overflow::Queue => match limit.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    WAITING.fetch_add(1, Ordering::Relaxed);
+                    let permit = limit.semaphore.clone().acquire_owned().await.ok();
+                    WAITING.fetch_sub(1, Ordering::Relaxed);
+                    permit
+                }
+            },
+        };
+        match permit {
+            Some(permit) => Some(Self::count(listener, Some(permit))),
+            None => {
+                REJECTED.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn count(listener: &Listener, permit: Option<OwnedSemaphorePermit>) -> Self {
+        LazyLock::force(&STARTED);
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        TOTAL.fetch_add(1, Ordering::Relaxed);
+        listener.0.active.fetch_add(1, Ordering::Relaxed);
+        listener.0.total.fetch_add(1, Ordering::Relaxed);
+        Self {
+            listener: listener.clone(),
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        self.listener.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+tokio::task_local! {
+    /// Id of the client connection served by the current task.
+    static CONNECTION_ID: ConnectionId;
+}
+
+/// Short random id of a client connection, attached to its log lines and
+/// audit records so that they can be correlated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ConnectionId(u32);
+
+impl ConnectionId {
+    pub(crate) fn new() -> Self {
+        Self(rand::random())
+    }
+
+    /// Returns the id of the connection served by the current task.
+    pub(crate) fn current() -> Option<Self> {
+        CONNECTION_ID.try_with(|id| *id).ok()
+    }
+
+    /// Runs `task` as the task serving this connection, inside a `conn` span
+    /// carrying the id.
+    pub(crate) fn scope<F: Future>(self, task: F) -> impl Future<Output = F::Output> {
+        let span = tracing::info_span!("conn", id = %self);
+        CONNECTION_ID.scope(self, task.instrument(span))
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
+
+/// Description of an open tunnel.
+pub(crate) struct TunnelInfo {
+    pub(crate) protocol: Protocol,
+    pub(crate) client: SocketAddr,
+    pub(crate) username: Option<String>,
+    pub(crate) target: String,
+    pub(crate) egress: Option<IpAddr>,
+    /// Address of the server end, the upstream proxy if there is one.
+    pub(crate) server: Option<SocketAddr>,
+    /// Bytes relayed so far.
+    pub(crate) progress: Arc<Progress>,
+    /// Whether the client is the `a` side of the relay.
+    pub(crate) client_first: bool,
+    pub(crate) started: Instant,
+}
+
+impl TunnelInfo {
+    /// Bytes sent and received by the client so far.
+    fn traffic(&self) -> (u64, u64) {
+        let a_to_b = self.progress.a_to_b.load(Ordering::Relaxed);
+        let b_to_a = self.progress.b_to_a.load(Ordering::Relaxed);
+        if self.client_first {
+            (a_to_b, b_to_a)
+        } else {
+            (b_to_a, a_to_b)
+        }
+    }
+}
+
+/// An open tunnel, listed until dropped.
+pub(crate) struct Tunnel(u64);
+
+impl Tunnel {
+    pub(crate) fn register(info: TunnelInfo) -> Self {
+        let id = NEXT_TUNNEL.fetch_add(1, Ordering::Relaxed);
+        if let (Some(egress), Ok(mut owners)) = (info.egress, EGRESS_OWNERS.lock()) {
+            let owner = owner(info.username.as_deref(), info.client.ip());
+            owners.entry(egress.to_canonical()).or_default().push(owner);
+        }
+        if let (Some(server), Ok(mut servers)) = (info.server, SERVER_TUNNELS.lock()) {
+            *servers.entry(server).or_default() += 1;
+        }
+        if let Ok(mut tunnels) = TUNNELS.lock() {
+            tunnels.insert(id, info);
+        }
+        Self(id)
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        let Some(info) = TUNNELS
+            .lock()
+            .ok()
+            .and_then(|mut tunnels| tunnels.remove(&self.0))
+        else {
+            return;
+        };
+
+        if let (Some(egress), Ok(mut owners)) = (info.egress, EGRESS_OWNERS.lock()) {
+            let owner = owner(info.username.as_deref(), info.client.ip());
+            let egress = egress.to_canonical();
+            if let Some(tunnels) = owners.get_mut(&egress) {
+                if let Some(index) = tunnels.iter().position(|tunnel| *tunnel == owner) {
+                    tunnels.swap_remove(index);
+                }
+                if tunnels.is_empty() {
+                    owners.remove(&egress);
+                }
+            }
+        }
+        if let (Some(server), Ok(mut servers)) = (info.server, SERVER_TUNNELS.lock()) {
+            if let Some(count) = servers.get_mut(&server) {
+                *count -= 1;
+                if *count == 0 {
+                    servers.remove(&server);
+                }
+            }
+        }
+
+        let (sent, received) = info.traffic();
+        let Ok(mut closed) = CLOSED.lock() else {
+            return;
+        };
+        let add = |traffic: &mut Traffic| {
+            traffic.tunnels += 1;
+            traffic.sent += sent;
+            traffic.received += received;
+        };
+        add(closed
+            .protocols
+            .entry(protocol_name(info.protocol))
+            .or_default());
+        let egress = info
+            .egress
+            .map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+        let key = if closed.egress.len() < MAX_EGRESS || closed.egress.contains_key(&egress) {
+            egress
+        } else {
+            "other".to_owned()
+        };
+        add(closed.egress.entry(key).or_default());
+    }
+}
+
+/// Returns the owner of the connections of `username` from `client`: the
+/// user, whatever extension the login has, or the client without a login.
+pub(crate) fn owner(username: Option<&str>, client: IpAddr) -> u64 {
+    match username {
+        Some(username) => fxhash::hash64(crate::extension::base_username(username)),
+        None => fxhash::hash64(&client.to_canonical()),
+    }
+}
+
+/// Whether an open tunnel of an owner other than `owner` egresses from `ip`.
+pub(crate) fn egress_taken(ip: IpAddr, owner: u64) -> bool {
+    EGRESS_OWNERS.lock().is_ok_and(|owners| {
+        owners
+            .get(&ip.to_canonical())
+            .is_some_and(|tunnels| tunnels.iter().any(|tunnel| *tunnel != owner))
+    })
+}
+
+/// A connection slot of an egress address, released when dropped.
+///
+/// Slots are held while a connection is dialed and while a plain HTTP
+/// request is in flight, so these count towards `--max-conns-per-ip` along
+/// with the open tunnels.
+#[derive(Debug)]
+pub(crate) struct EgressSlot(IpAddr);
+
+/// Reserves a slot of `ip`, unless its open tunnels and reserved slots
+/// already reach `max`.
+pub(crate) fn reserve_egress(ip: IpAddr, max: usize) -> Option<EgressSlot> {
+    let ip = ip.to_canonical();
+    let mut slots = EGRESS_SLOTS.lock().unwrap_or_else(|err| err.into_inner());
+    let tunnels = EGRESS_OWNERS
+        .lock()
+        .map_or(0, |owners| owners.get(&ip).map_or(0, Vec::len));
+    let reserved = slots.entry(ip).or_default();
+    if tunnels + *reserved >= max {
+        if *reserved == 0 {
+            slots.remove(&ip);
+        }
+        return None;
+    }
+    *reserved += 1;
+    Some(EgressSlot(ip))
+}
+
+impl Drop for EgressSlot {
+    fn drop(&mut self) {
+        let mut slots = EGRESS_SLOTS.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(reserved) = slots.get_mut(&self.0) {
+            *reserved -= 1;
+            if *reserved == 0 {
+                slots.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Returns the number of open tunnels whose server end is `addr`.
+pub(crate) fn server_tunnels(addr: SocketAddr) -> usize {
+    SERVER_TUNNELS
+        .lock()
+        .map_or(0, |servers| servers.get(&addr).copied().unwrap_or(0))
+}
+
+/// Hooks summing up requests and tunneled bytes per user.
+pub(crate) struct Usage;
+
+impl Usage {
+    fn record(username: &str, change: impl FnOnce(&mut UserUsage)) {
+        let user = crate::extension::base_username(username);
+        let Ok(mut users) = USERS.lock() else {
+            return;
+        };
+
+        let key = if users.len() < MAX_USERS || users.contains_key(user) {
+            user
+        } else {
+            "other"
+        };
+        change(users.entry(key.to_owned()).or_default());
+    }
+}
+
+impl Hooks for Usage {
+    fn on_request(&self, request: &ProxyRequest<'_>) -> Decision {
+        if let Some(username) = request.username {
+            Self::record(username, |usage| usage.requests += 1);
+        }
+        if let Ok(mut destinations) = DESTINATIONS.lock() {
+            destinations.record(target_host(request.target), 1.0, 0.0, Instant::now());
+        }
+        Decision::Allow
+    }
+
+    fn on_tunnel_close(&self, event: &TunnelClose) {
+        if let Some(username) = &event.username {
+            Self::record(username, |usage| {
+                usage.sent += event.sent;
+                usage.received += event.received;
+            });
+        }
+        if let Ok(mut destinations) = DESTINATIONS.lock() {
+            let bytes = (event.sent + event.received) as f64;
+            destinations.record(target_host(&event.target), 0.0, bytes, Instant::now());
+        }
+    }
+}
+
+/// Returns the `n` destination hosts with the most recent requests, or bytes
+/// if `by_bytes`, as a table for the admin API.
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub(crate) fn top_destinations(n: usize, by_bytes: bool) -> String {
+    let top = DESTINATIONS
+        .lock()
+        .map(|destinations| destinations.top(n, by_bytes, Instant::now()))
+        .unwrap_or_default();
+
+    let mut report = format!("{:<48} {:>12} {:>16}\n", "HOST", "REQUESTS", "BYTES");
+    for (host, usage) in top {
+        let _ = writeln!(
+            report,
+            "{:<48} {:>12.0} {:>16.0}",
+            host, usage.requests, usage.bytes
+        );
+    }
+    report
+}
+
+/// Returns the number of open client connections.
+pub(crate) fn active_connections() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Returns the statistics as text, for the `stats` command of the control
+/// socket.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) fn report() -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "uptime: {}s", STARTED.elapsed().as_secs());
+    let _ = writeln!(
+        report,
+        "active connections: {}",
+        ACTIVE.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        report,
+        "total connections: {}",
+        TOTAL.load(Ordering::Relaxed)
+    );
+    for (name, active, total) in listeners() {
+        let _ = writeln!(
+            report,
+            "listener {}: {} active, {} total connections",
+            name, active, total
+        );
+    }
+    if let Some(limit) = LIMIT.get() {
+        let _ = writeln!(
+            report,
+            "connection limit: {} ({} in use, {} waiting, {} rejected)",
+            limit.max,
+            limit.max - limit.semaphore.available_permits().min(limit.max),
+            WAITING.load(Ordering::Relaxed),
+            REJECTED.load(Ordering::Relaxed)
+        );
+    }
+    let _ = writeln!(
+        report,
+        "connection buffers: {} bytes ({} peak, cap exceeded {} times)",
+        crate::memory::buffered(),
+        crate::memory::peak(),
+        crate::memory::exceeded()
+    );
+    if let Some(hit_rate) = crate::pool::hit_rate() {
+        let _ = writeln!(report, "buffer pool hit rate: {:.1}%", hit_rate * 100.0);
+    }
+    #[cfg(feature = "https")]
+    let _ = writeln!(
+        report,
+        "non-TLS connections rejected: {}",
+        crate::http::tls_sniff_rejected()
+    );
+    let _ = writeln!(
+        report,
+        "egress addresses in use: {}",
+        EGRESS_OWNERS.lock().map_or(0, |owners| owners.len())
+    );
+
+    let mut users = USERS
+        .lock()
+        .map(|users| {
+            users
+                .iter()
+                .map(|(user, usage)| (user.clone(), *usage))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if !users.is_empty() {
+        users.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let _ = writeln!(
+            report,
+            "{:<24} {:>10} {:>16} {:>16}",
+            "USER", "REQUESTS", "SENT", "RECEIVED"
+        );
+        for (user, usage) in users {
+            let _ = writeln!(
+                report,
+                "{:<24} {:>10} {:>16} {:>16}",
+                user, usage.requests, usage.sent, usage.received
+            );
+        }
+    }
+
+    report
+}
+
+/// Returns the open tunnels, as a table or a JSON array, for the
+/// `connections` command of the control socket.
+#[cfg_attr(not(unix), allow(dead_code))]
+pub(crate) fn connections(json: bool) -> String {
+    let Ok(tunnels) = TUNNELS.lock() else {
+        return String::new();
+    };
+    let mut tunnels = tunnels.values().collect::<Vec<_>>();
+    tunnels.sort_by_key(|tunnel| std::cmp::Reverse(tunnel.started));
+
+    if json {
+        let tunnels = tunnels
+            .into_iter()
+            .map(|tunnel| {
+                let (sent, received) = tunnel.traffic();
+                serde_json::json!({
+                    "protocol": protocol_name(tunnel.protocol),
+                    "client": tunnel.client.to_string(),
+                    "username": tunnel.username,
+                    "target": tunnel.target,
+                    "egress": tunnel.egress.map(|ip| ip.to_string()),
+                    "duration": tunnel.started.elapsed().as_secs(),
+                    "sent": sent,
+                    "received": received,
+                })
+            })
+            .collect::<Vec<_>>();
+        return format!("{}\n", serde_json::Value::Array(tunnels));
+    }
+
+    let mut report = format!(
+        "{:<7} {:<40} {:<16} {:<40} {:<40} {:>9} {:>12} {:>12}\n",
+        "PROTO", "CLIENT", "USER", "TARGET", "EGRESS", "DURATION", "SENT", "RECEIVED"
+    );
+    for tunnel in tunnels {
+        let (sent, received) = tunnel.traffic();
+        let _ = writeln!(
+            report,
+            "{:<7} {:<40} {:<16} {:<40} {:<40} {:>8}s {:>12} {:>12}",
+            protocol_name(tunnel.protocol),
+            tunnel.client,
+            tunnel.username.as_deref().unwrap_or("-"),
+            tunnel.target,
+            tunnel
+                .egress
+                .map_or_else(|| "-".to_owned(), |ip| ip.to_string()),
+            tunnel.started.elapsed().as_secs(),
+            sent,
+            received
+        );
+    }
+    report
+}
+
+/// Returns the aggregate statistics as JSON, for the statistics file.
+fn snapshot() -> serde_json::Value {
+    let users = USERS
+        .lock()
+        .map(|users| {
+            users
+                .iter()
+                .map(|(user, usage)| {
+                    let usage = serde_json::json!({
+                        "requests": usage.requests,
+                        "sent": usage.sent,
+                        "received": usage.received,
+                    });
+                    (user.clone(), usage)
+                })
+                .collect::<serde_json::Map<_, _>>()
+        })
+        .unwrap_or_default();
+
+    let traffic = |traffic: &Traffic| {
+        serde_json::json!({
+            "tunnels": traffic.tunnels,
+            "sent": traffic.sent,
+            "received": traffic.received,
+        })
+    };
+    let (protocols, egress) = CLOSED
+        .lock()
+        .map(|closed| {
+            let protocols = closed
+                .protocols
+                .iter()
+                .map(|(protocol, usage)| (protocol.to_string(), traffic(usage)))
+                .collect::<serde_json::Map<_, _>>();
+            let egress = closed
+                .egress
+                .iter()
+                .map(|(ip, usage)| (ip.clone(), traffic(usage)))
+                .collect::<serde_json::Map<_, _>>();
+            (protocols, egress)
+        })
+        .unwrap_or_default();
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+    let listeners = listeners()
+        .into_iter()
+        .map(|(name, active, total)| {
+            let counters = serde_json::json!({
+                "active_connections": active,
+                "total_connections": total,
+            });
+            (name, counters)
+        })
+        .collect::<serde_json::Map<_, _>>();
+    let snapshot = serde_json::json!({
+        "timestamp": timestamp,
+        "uptime": STARTED.elapsed().as_secs(),
+        "active_connections": ACTIVE.load(Ordering::Relaxed),
+        "total_connections": TOTAL.load(Ordering::Relaxed),
+        "rejected_connections": REJECTED.load(Ordering::Relaxed),
+        "open_tunnels": TUNNELS.lock().map_or(0, |tunnels| tunnels.len()),
+        "listeners": listeners,
+        "buffered_bytes": crate::memory::buffered(),
+        "buffered_peak_bytes": crate::memory::peak(),
+        "memory_cap_exceeded": crate::memory::exceeded(),
+        "users": users,
+        "protocols": protocols,
+        "egress": egress,
+    });
+    #[cfg(feature = "https")]
+    let snapshot = {
+        let mut snapshot = snapshot;
+        snapshot["non_tls_rejected"] = crate::http::tls_sniff_rejected().into();
+        snapshot
+    };
+    snapshot
+}
+
+/// Writes the aggregate statistics to `path` every `interval`, through a
+/// temporary file renamed over it so readers never see a partial file.
+pub(crate) async fn export(path: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let path = path.clone();
+        let snapshot = snapshot();
+        match tokio::task::spawn_blocking(move || write_atomically(&path, &snapshot)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::warn!("failed to write the statistics file: {}", err),
+            Err(err) => tracing::warn!("failed to write the statistics file: {}", err),
+        }
+    }
+}
+
+fn write_atomically(path: &Path, snapshot: &serde_json::Value) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    std::fs::write(&tmp, format!("{snapshot:#}\n"))?;
+    std::fs::rename(&tmp, path)
+}
+
+pub(crate) fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Http => "http",
+        Protocol::Socks5 => "socks5",
+        Protocol::Sni => "sni",
+        Protocol::Forward => "forward",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_host() {
+        assert_eq!(target_host("example.com:443"), "example.com");
+        assert_eq!(target_host("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(target_host("2001:db8::1"), "2001:db8::1");
+        assert_eq!(target_host("example.com"), "example.com");
+    }
+
+    #[test]
+    fn test_destinations() {
+        let now = Instant::now();
+        let mut destinations = Destinations::default();
+        destinations.record("old.example", 8.0, 100.0, now);
+        destinations.record("New.example", 1.0, 0.0, now);
+        destinations.record("new.example", 0.0, 1000.0, now);
+
+        let later = now + DESTINATION_HALF_LIFE * 2;
+        let top = destinations.top(1, false, later);
+        assert_eq!(top[0].0, "old.example");
+        assert_eq!(top[0].1.requests, 2.0);
+        let top = destinations.top(10, true, later);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, "new.example");
+        assert_eq!(top[0].1.bytes, 250.0);
+
+        for i in 1..MAX_DESTINATIONS {
+            destinations.record(&format!("{i}.example"), 1.0, 0.0, later);
+        }
+        assert_eq!(
+            destinations.0.len(),
+            MAX_DESTINATIONS - DESTINATION_EVICTION + 1
+        );
+        assert!(destinations.0.contains_key("old.example"));
+        assert!(!destinations.0.contains_key("new.example"));
+    }
+}
@@ -0,0 +1,134 @@
+//! Process readiness, as served by the `/readyz` endpoint of the admin API.
+//!
+//! An instance is ready once its listener accepts connections, every CIDR it
+//! assigns egress addresses from can be bound, it is not running out of file
+//! descriptors and it is not draining its connections to restart or stop.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Whether the listener accepts connections.
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Number of CIDRs whose addresses cannot be bound.
+static ROUTE_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// What open connections are being drained before, if they are.
+static DRAINING: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Time between two looks at the connections left to drain.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of the open file limit above which the process is under pressure.
+const MAX_OPEN_FILES_RATIO: f64 = 0.9;
+
+/// Records that the listener accepts connections.
+pub(crate) fn set_listening() {
+    LISTENING.store(true, Ordering::Relaxed);
+}
+
+/// Records what open connections are being drained before, e.g.
+/// `restarting`, or `None` once they no longer are, for load balancers to
+/// send new clients elsewhere.
+pub(crate) fn set_draining(action: Option<&'static str>) {
+    *DRAINING.lock().unwrap_or_else(|err| err.into_inner()) = action;
+}
+
+/// Reports the process not ready and waits for its client connections to
+/// close before `action`, at most `timeout`.
+pub(crate) async fn drain(timeout: Duration, action: &'static str) {
+    set_draining(Some(action));
+    let started = Instant::now();
+    let mut logged = 0;
+    loop {
+        let active = crate::stats::active_connections();
+        if active == 0 {
+            return;
+        }
+        if started.elapsed() >= timeout {
+            tracing::warn!("{} connections still open, {} anyway", active, action);
+            return;
+        }
+        if active != logged {
+            tracing::info!("Draining {} connections before {}", active, action);
+            logged = active;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Records a CIDR whose addresses cannot be bound.
+#[cfg_attr(not(all(target_os = "linux", feature = "route")), allow(dead_code))]
+pub(crate) fn add_route_failure() {
+    ROUTE_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns whether the process is ready to serve, along with a report of
+/// each check, one per line.
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub(crate) fn readiness() -> (bool, String) {
+    let mut ready = true;
+    let mut report = String::new();
+
+    let listening = LISTENING.load(Ordering::Relaxed);
+    ready &= listening;
+    report.push_str(if listening {
+        "listener: ok\n"
+    } else {
+        "listener: not listening\n"
+    });
+
+    if let Some(action) = *DRAINING.lock().unwrap_or_else(|err| err.into_inner()) {
+        ready = false;
+        report.push_str(&format!("draining: {action}\n"));
+    }
+
+    let route_failures = ROUTE_FAILURES.load(Ordering::Relaxed);
+    ready &= route_failures == 0;
+    if route_failures == 0 {
+        report.push_str("routes: ok\n");
+    } else {
+        report.push_str(&format!("routes: {route_failures} CIDRs cannot be bound\n"));
+    }
+
+    if let Some((open, limit)) = open_files() {
+        let pressure = open as f64 > limit as f64 * MAX_OPEN_FILES_RATIO;
+        ready &= !pressure;
+        report.push_str(&format!(
+            "open files: {open}/{limit}{}\n",
+            if pressure { " (under pressure)" } else { "" }
+        ));
+    }
+
+    report.push_str(&format!(
+        "connection buffers: {} bytes\n",
+        crate::memory::buffered()
+    ));
+
+    (ready, report)
+}
+
+/// Returns the number of open file descriptors and their soft limit.
+#[cfg(target_os = "linux")]
+fn open_files() -> Option<(usize, usize)> {
+    let open = std::fs::read_dir("/proc/self/fd").ok()?.count();
+    let limits = std::fs::read_to_string("/proc/self/limits").ok()?;
+    let limit = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some((open, limit))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_files() -> Option<(usize, usize)> {
+    None
+}
@@ -0,0 +1,196 @@
+//! Logging to the local syslog daemon.
+//!
+//! With `--log-syslog` every log line, including the tunnel and request
+//! lines, is sent as an RFC 5424 message to the syslog socket of the host
+//! instead of standard output, e.g.
+//!
+//! ```text
+//! <30>1 2024-01-01T12:00:00.000000Z host vproxy 4242 - - conn{id=3f2a9c01}: vproxy::http::server: client wrote 518 bytes and received 4096 bytes
+//! ```
+//!
+//! with the `daemon` facility and the severity of the log level. A syslog
+//! daemon that restarts is reconnected to on the next message.
+//!
+//! Logging never waits for the daemon: messages it has no room for are
+//! dropped, and counted in a warning sent once it catches up. Messages
+//! longer than the socket takes are truncated.
+
+use std::{
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Sockets of the syslog daemon, on Linux, macOS and the BSDs.
+const SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+/// Facility of the messages, `daemon`.
+const FACILITY: u8 = 3;
+
+/// Length every syslog receiver takes, which too long messages are not
+/// truncated below.
+const MIN_MESSAGE_LEN: usize = 480;
+
+/// Sends log lines to the syslog daemon.
+#[derive(Clone)]
+pub(crate) struct Syslog(Arc<Inner>);
+
+struct Inner {
+    socket: Mutex<UnixDatagram>,
+    /// `HOSTNAME APP-NAME PROCID` of the messages.
+    origin: String,
+    /// Messages dropped since the last one sent.
+    dropped: AtomicU64,
+}
+
+impl Syslog {
+    /// Connects to the syslog socket of the host.
+    pub(crate) fn connect() -> io::Result<Self> {
+        let socket = connect()?;
+        let hostname = nix::unistd::gethostname()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".to_owned());
+        Ok(Self(Arc::new(Inner {
+            socket: Mutex::new(socket),
+            origin: format!("{} {} {}", hostname, crate::BIN_NAME, std::process::id()),
+            dropped: AtomicU64::new(0),
+        })))
+    }
+
+    fn line(&self, level: Level) -> Line {
+        Line {
+            syslog: self.clone(),
+            level,
+            buf: Vec::new(),
+        }
+    }
+
+    fn message(&self, level: Level, msg: &str) -> String {
+        format!(
+            "<{}>1 {} {} - - {}",
+            FACILITY * 8 + severity(level),
+            crate::ship::rfc3339(SystemTime::now()),
+            self.0.origin,
+            msg.trim_end()
+        )
+    }
+
+    fn send(&self, level: Level, msg: &[u8]) {
+        let message = self.message(level, &String::from_utf8_lossy(msg));
+
+        let mut socket = self.0.socket.lock().unwrap_or_else(|err| err.into_inner());
+        let sent = match send(&socket, &message) {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => {
+                // The daemon may have restarted, leaving the socket disconnected
+                connect().and_then(|new| {
+                    *socket = new;
+                    send(&socket, &message)
+                })
+            }
+            sent => sent,
+        };
+        if sent.is_err() {
+            self.0.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let dropped = self.0.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            let warning = format!("{dropped} log messages dropped, the syslog daemon was behind");
+            if send(&socket, &self.message(Level::WARN, &warning)).is_err() {
+                self.0.dropped.fetch_add(dropped, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Sends `message` on `socket` without waiting, truncating it as long as it
+/// is too long for the socket.
+fn send(socket: &UnixDatagram, mut message: &str) -> io::Result<()> {
+    loop {
+        match socket.send(message.as_bytes()) {
+            Ok(_) => return Ok(()),
+            Err(err)
+                if err.raw_os_error() == Some(nix::libc::EMSGSIZE)
+                    && message.len() > MIN_MESSAGE_LEN =>
+            {
+                let mut len = (message.len() / 2).max(MIN_MESSAGE_LEN);
+                while !message.is_char_boundary(len) {
+                    len -= 1;
+                }
+                message = &message[..len];
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn connect() -> io::Result<UnixDatagram> {
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no syslog socket found");
+    for path in SOCKETS.iter().map(Path::new).filter(|path| path.exists()) {
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(path) {
+            Ok(()) => return Ok(socket),
+            Err(err) => last = err,
+        }
+    }
+    Err(last)
+}
+
+/// Returns the syslog severity of `level`.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// A log line, sent once fully written.
+pub(crate) struct Line {
+    syslog: Syslog,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            self.syslog.send(self.level, &self.buf);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.line(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.line(*meta.level())
+    }
+}
@@ -0,0 +1,240 @@
+//! Scheduled checks for new releases.
+//!
+//! With `--update-check` the server looks for a release newer than itself
+//! every given number of hours. A new release is logged, reported by the
+//! `/update` endpoint of the admin API and notified to the webhooks.
+//!
+//! With `--update-window` it is also installed during the next maintenance
+//! window: `self update` downloads and verifies it like from the command
+//! line, the server reports itself not ready and gives open connections
+//! `--update-drain-timeout` seconds to finish, then executes the new release
+//! in place of itself, keeping its process id and arguments.
+
+use crate::{
+    notify::{self, Event},
+    schedule::Schedule,
+    UpdateCheckOptions, BIN_NAME,
+};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Releases of the project, newest first.
+const RELEASES_URL: &str = "https://api.github.com/repos/0x676e67/vproxy/releases?per_page=20";
+
+/// Time allowed for the release list to be fetched.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time between two looks at the maintenance windows.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether the checks run, as every worker of the process starts them.
+static CHECKING: AtomicBool = AtomicBool::new(false);
+
+/// Newest release found, if newer than the running one.
+static LATEST: Mutex<Option<String>> = Mutex::new(None);
+
+/// Returns the running and the newest known version, for the admin API.
+#[cfg_attr(not(feature = "admin"), allow(dead_code))]
+pub(crate) fn report() -> String {
+    let latest = LATEST
+        .lock()
+        .ok()
+        .and_then(|latest| latest.clone())
+        .unwrap_or_else(|| "none newer".to_owned());
+    format!(
+        "current: {}\nlatest: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        latest
+    )
+}
+
+/// Checks for new releases as `options` say, installing them within its
+/// maintenance windows. Only the first call checks, later calls return
+/// immediately.
+pub(crate) async fn run(options: UpdateCheckOptions, control_socket: Option<PathBuf>) {
+    let Some(hours) = options.update_check else {
+        return;
+    };
+    if CHECKING.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let windows = Schedule::new(options.update_windows.clone(), options.update_utc_offset);
+    let install = cfg!(unix) && !options.update_windows.is_empty();
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(hours.max(1) * 3600));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let version = match latest(options.update_prerelease).await {
+            Ok(Some(version)) => version,
+            Ok(None) => continue,
+            Err(err) => {
+                tracing::warn!("Checking for a new release failed: {}", err);
+                continue;
+            }
+        };
+
+        let known = LATEST
+            .lock()
+            .map(|mut latest| latest.replace(version.clone()))
+            .ok()
+            .flatten();
+        if known.as_deref() != Some(version.as_str()) {
+            tracing::warn!(
+                "{} {} is available, running {}",
+                BIN_NAME,
+                version,
+                env!("CARGO_PKG_VERSION")
+            );
+            notify::send(Event::UpdateAvailable {
+                version: version.clone(),
+            });
+        }
+        if !install {
+            continue;
+        }
+
+        while !windows.allows_now() {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        if let Err(err) = install_release(&version).await {
+            tracing::error!("Installing {} {} failed: {}", BIN_NAME, version, err);
+            continue;
+        }
+        crate::status::drain(
+            Duration::from_secs(options.update_drain_timeout),
+            "restarting",
+        )
+        .await;
+        notify::deliver(Event::Stopping { reason: "update" }).await;
+        let err = reexec(control_socket.as_ref());
+        tracing::error!("Restarting into {} {} failed: {}", BIN_NAME, version, err);
+        crate::status::set_draining(None);
+    }
+}
+
+/// Returns the newest release, pre-releases included if `prerelease`, if it
+/// is newer than the running version.
+async fn latest(prerelease: bool) -> io::Result<Option<String>> {
+    let releases = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .map_err(io::Error::other)?
+        .get(RELEASES_URL)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(io::Error::other)?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(io::Error::other)?;
+
+    let newest = releases
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|release| release["draft"] != true)
+        .filter(|release| prerelease || release["prerelease"] != true)
+        .filter_map(|release| release["tag_name"].as_str())
+        .map(|tag| tag.trim_start_matches('v'))
+        .filter(|version| newer(version, env!("CARGO_PKG_VERSION")))
+        // Releases are listed by date, a patch of an older line may come first
+        .max_by_key(|version| version_key(version));
+    Ok(newest.map(ToOwned::to_owned))
+}
+
+/// Whether `version` is newer than `current`, a pre-release such as
+/// `2.1.0-rc.1` being older than the release of the same number.
+fn newer(version: &str, current: &str) -> bool {
+    version_key(version) > version_key(current)
+}
+
+/// Sort key of `version`: its numbers, then whether it is a release, then
+/// its pre-release part.
+fn version_key(version: &str) -> (Vec<u64>, bool, Option<&str>) {
+    let (number, pre) = match version.split_once('-') {
+        Some((number, pre)) => (number, Some(pre)),
+        None => (version, None),
+    };
+    let number = number
+        .split('.')
+        .map(|part| part.parse::<u64>().unwrap_or(0))
+        .collect();
+    // No pre-release sorts after any pre-release
+    (number, pre.is_none(), pre)
+}
+
+/// Installs `version` over the running executable with `self update`.
+async fn install_release(version: &str) -> io::Result<()> {
+    tracing::warn!("Installing {} {}", BIN_NAME, version);
+    let status = tokio::process::Command::new(std::env::current_exe()?)
+        .args(["self", "update", "--version", version])
+        .stdin(std::process::Stdio::null())
+        .status()
+        .await?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "self update exited with {status}"
+        )))
+    }
+}
+
+/// Executes the installed release with the arguments of the process,
+/// returning only if that fails.
+#[cfg(unix)]
+fn reexec(control_socket: Option<&PathBuf>) -> io::Error {
+    use std::os::unix::process::CommandExt;
+
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => return err,
+    };
+    let args = crate::control::server_args(
+        std::env::args_os().skip(1),
+        control_socket.map(PathBuf::as_path),
+    );
+    std::process::Command::new(exe).args(args).exec()
+}
+
+#[cfg(not(unix))]
+fn reexec(_control_socket: Option<&PathBuf>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "restarting in place is only supported on unix",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newer() {
+        assert!(newer("2.0.56", "2.0.55"));
+        assert!(newer("2.1.0", "2.0.55"));
+        assert!(!newer("2.0.55", "2.0.55"));
+        assert!(!newer("2.0.9", "2.0.55"));
+        assert!(newer("2.1.0-rc.1", "2.0.55"));
+        assert!(!newer("2.1.0-rc.1", "2.1.0"));
+        assert!(newer("2.1.0", "2.1.0-rc.1"));
+
+        let newest = ["2.0.57", "2.2.0-rc.1", "2.1.3", "2.2.0"]
+            .into_iter()
+            .max_by_key(|version| version_key(version));
+        assert_eq!(newest, Some("2.2.0"));
+    }
+}
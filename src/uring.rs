@@ -0,0 +1,160 @@
+//! Optional io_uring runtime, enabled with the `io-uring` cargo feature and the
+//! `--io-uring` flag.
+//!
+//! Every worker thread runs its own tokio-uring runtime and binds its own
+//! `SO_REUSEPORT` listener, so accepted connections are spread across cores by
+//! the kernel. Tunnel payloads are then copied with io_uring read/write
+//! operations instead of epoll readiness notifications plus one syscall per
+//! read and write.
+
+use crate::relay::Progress;
+use std::{
+    cell::OnceCell,
+    future::Future,
+    io,
+    os::fd::AsFd,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Size of the buffer used by each direction of a tunnel, unless
+/// `--relay-buffer-size` says otherwise.
+const BUF_SIZE: usize = 1 << 16;
+
+/// A tunnel handed over to the local dispatcher of the current worker.
+struct Job {
+    a: std::net::TcpStream,
+    b: std::net::TcpStream,
+    progress: Arc<Progress>,
+    reply: oneshot::Sender<io::Result<(u64, u64)>>,
+}
+
+thread_local! {
+    static DISPATCHER: OnceCell<mpsc::UnboundedSender<Job>> = const { OnceCell::new() };
+}
+
+/// Returns `true` if the current thread is an io_uring worker.
+pub fn enabled() -> bool {
+    DISPATCHER.with(|dispatcher| dispatcher.get().is_some())
+}
+
+/// Runs `serve` on `workers` threads, each driving its own io_uring runtime.
+///
+/// Returns once every worker has stopped, with the first error encountered.
+pub fn run<F, Fut>(workers: usize, serve: F) -> crate::Result<()>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = crate::Result<()>>,
+{
+    let serve = Arc::new(serve);
+    let handles = (0..workers)
+        .map(|id| {
+            let serve = serve.clone();
+            std::thread::Builder::new()
+                .name(format!("vproxy-uring-{id}"))
+                .spawn(move || tokio_uring::start(worker(serve())))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| io::Error::other("io_uring worker panicked"))??;
+    }
+
+    Ok(())
+}
+
+/// Serves connections on the current worker while dispatching tunnels to
+/// io_uring tasks.
+async fn worker<Fut>(serve: Fut) -> crate::Result<()>
+where
+    Fut: Future<Output = crate::Result<()>>,
+{
+    let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+    DISPATCHER.with(|dispatcher| dispatcher.set(tx)).ok();
+
+    // io_uring operations are `!Send` and must be spawned from the local set
+    // of the worker, which tasks started with `tokio::spawn` are not part of.
+    tokio_uring::spawn(async move {
+        while let Some(job) = rx.recv().await {
+            tokio_uring::spawn(async move {
+                let a = Rc::new(tokio_uring::net::TcpStream::from_std(job.a));
+                let b = Rc::new(tokio_uring::net::TcpStream::from_std(job.b));
+                let progress = job.progress;
+                let result = tokio::try_join!(
+                    copy_one_way(a.clone(), b.clone(), &progress.a_to_b),
+                    copy_one_way(b, a, &progress.b_to_a)
+                );
+                let _ = job.reply.send(result);
+            });
+        }
+    });
+
+    serve.await
+}
+
+/// Copies data in both directions between `a` and `b` with io_uring until
+/// both sides reach EOF.
+///
+/// The streams are duplicated rather than moved, so the caller keeps
+/// ownership and closes them once the copy is done.
+pub async fn copy_bidirectional(
+    a: &tokio::net::TcpStream,
+    b: &tokio::net::TcpStream,
+    progress: Arc<Progress>,
+) -> io::Result<(u64, u64)> {
+    let dispatcher = DISPATCHER
+        .with(|dispatcher| dispatcher.get().cloned())
+        .ok_or_else(|| io::Error::other("io_uring runtime is not running on this thread"))?;
+
+    let (reply, result) = oneshot::channel();
+    let job = Job {
+        a: std::net::TcpStream::from(a.as_fd().try_clone_to_owned()?),
+        b: std::net::TcpStream::from(b.as_fd().try_clone_to_owned()?),
+        progress,
+        reply,
+    };
+
+    dispatcher
+        .send(job)
+        .map_err(|_| io::Error::other("io_uring dispatcher stopped"))?;
+    result
+        .await
+        .map_err(|_| io::Error::other("io_uring tunnel task dropped"))?
+}
+
+/// Moves data from `from` to `to` until `from` reaches EOF, adding the bytes
+/// moved to `counter`.
+async fn copy_one_way(
+    from: Rc<tokio_uring::net::TcpStream>,
+    to: Rc<tokio_uring::net::TcpStream>,
+    counter: &AtomicU64,
+) -> io::Result<u64> {
+    let mut buf = Vec::with_capacity(crate::relay::buffer_size(BUF_SIZE));
+    let mut total = 0u64;
+
+    loop {
+        let (res, read_buf) = from.read(buf).await;
+        let len = res?;
+        if len == 0 {
+            // The peer will not send anything else, propagate the EOF.
+            if let Err(err) = to.shutdown(std::net::Shutdown::Write) {
+                tracing::trace!("io_uring shutdown error: {}", err);
+            }
+            return Ok(total);
+        }
+
+        let (res, mut write_buf) = to.write_all(read_buf).await;
+        res?;
+        total += len as u64;
+        counter.fetch_add(len as u64, Ordering::Relaxed);
+
+        write_buf.clear();
+        buf = write_buf;
+    }
+}
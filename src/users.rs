@@ -0,0 +1,490 @@
+//! Multi-user credential file.
+//!
+//! Each non-empty line that is not a `#` comment holds one user:
+//!
+//! ```text
+//! username:password [cidr=IP-CIDR] [cidr-range=N] [fallback=IP] [disabled]
+//! alice:secret cidr=2001:db8:1::/48 cidr-range=64
+//! bob:hunter2 fallback=192.0.2.10
+//! ```
+//!
+//! The optional settings override the egress pool of the command line for
+//! the user's requests, so one instance can serve distinct pools to different
+//! customers. Disabled users cannot log in. Clients log in with the username,
+//! optionally followed by an extension such as `alice-session-123`.
+//!
+//! Passwords may be stored as bcrypt (`$2b$...`, e.g. from `htpasswd -nbB`)
+//! or Argon2 (`$argon2id$...`) hashes instead of plaintext. Verifying a hash
+//! is slow by design, so the last password that matched is remembered in
+//! memory.
+//!
+//! Users changed at runtime are written back to the file, which drops its
+//! comments.
+
+use cidr::IpCidr;
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, RwLock},
+};
+
+/// Key of the password digests kept in memory, drawn for each process.
+static DIGEST_KEY: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+/// Returns the HMAC-SHA256 of `password` under a key of the process, kept in
+/// memory in place of the password.
+pub(crate) fn digest(password: &str) -> [u8; 32] {
+    use hmac::{Hmac, Mac};
+
+    let mut mac =
+        Hmac::<sha2::Sha256>::new_from_slice(&*DIGEST_KEY).expect("HMAC takes keys of any length");
+    mac.update(password.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+/// Egress settings overriding the command line ones for a user.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Pool {
+    /// CIDR egress addresses are assigned from.
+    pub(crate) cidr: Option<IpCidr>,
+    /// Prefix length of the range extension.
+    pub(crate) cidr_range: Option<u8>,
+    /// Egress address used when the CIDR cannot be used.
+    pub(crate) fallback: Option<IpAddr>,
+}
+
+impl Pool {
+    /// Whether the pool overrides nothing.
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Pool::default()
+    }
+
+    /// Sets the `cidr`, `cidr-range` or `fallback` setting `key` to `value`.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "cidr" => self.cidr = Some(value.parse().map_err(|e| format!("{e}"))?),
+            "cidr-range" => self.cidr_range = Some(value.parse().map_err(|e| format!("{e}"))?),
+            "fallback" => self.fallback = Some(value.parse().map_err(|e| format!("{e}"))?),
+            key => return Err(format!("unknown setting: {key}")),
+        }
+        Ok(())
+    }
+}
+
+/// A stored password.
+#[derive(Clone, Debug)]
+enum Secret {
+    Plain(String),
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Secret {
+    fn new(stored: &str) -> Secret {
+        if stored.starts_with("$2a$") || stored.starts_with("$2b$") || stored.starts_with("$2y$") {
+            Secret::Bcrypt(stored.to_owned())
+        } else if stored.starts_with("$argon2") {
+            Secret::Argon2(stored.to_owned())
+        } else {
+            Secret::Plain(stored.to_owned())
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Secret::Plain(s) | Secret::Bcrypt(s) | Secret::Argon2(s) => s,
+        }
+    }
+
+    /// Whether `password` matches, which is slow for hashes.
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Secret::Plain(stored) => stored == password,
+            Secret::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Secret::Argon2(hash) => argon2::PasswordHash::new(hash).is_ok_and(|hash| {
+                use argon2::PasswordVerifier;
+                argon2::Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+/// A user of the credential file.
+#[derive(Debug)]
+pub(crate) struct User {
+    password: Secret,
+    pool: Pool,
+    disabled: bool,
+    /// Digest of the last password that matched a hashed secret.
+    verified: Mutex<Option<[u8; 32]>>,
+}
+
+impl User {
+    fn new(password: Secret, pool: Pool, disabled: bool) -> Self {
+        Self {
+            password,
+            pool,
+            disabled,
+            verified: Mutex::new(None),
+        }
+    }
+
+    /// Whether `password` matches, off the async runtime for hashes.
+    async fn verify(self: Arc<Self>, password: &str) -> bool {
+        if let Secret::Plain(stored) = &self.password {
+            return stored == password;
+        }
+        let digest = digest(password);
+        if let Ok(verified) = self.verified.lock() {
+            if *verified == Some(digest) {
+                return true;
+            }
+        }
+
+        let password = password.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let ok = self.password.verify(&password);
+            if ok {
+                if let Ok(mut verified) = self.verified.lock() {
+                    *verified = Some(digest);
+                }
+            }
+            ok
+        })
+        .await
+        .unwrap_or(false)
+    }
+}
+
+impl std::fmt::Display for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.password.as_str())?;
+        if let Some(cidr) = self.pool.cidr {
+            write!(f, " cidr={cidr}")?;
+        }
+        if let Some(range) = self.pool.cidr_range {
+            write!(f, " cidr-range={range}")?;
+        }
+        if let Some(fallback) = self.pool.fallback {
+            write!(f, " fallback={fallback}")?;
+        }
+        if self.disabled {
+            f.write_str(" disabled")?;
+        }
+        Ok(())
+    }
+}
+
+/// A client login, also reported for failed attempts.
+#[derive(Clone, Debug)]
+pub struct Login {
+    /// Username the client logged in with, including any extension.
+    pub(crate) username: String,
+    /// Egress pool of the user.
+    pub(crate) pool: Pool,
+}
+
+/// Users loaded from a credential file.
+#[derive(Debug)]
+pub(crate) struct Users {
+    path: PathBuf,
+    users: RwLock<HashMap<String, Arc<User>>>,
+    /// Held while a change is written, so that changes apply one at a time.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Users {
+    /// Loads the credential file at `path`.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let users = parse(&content).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), err),
+            )
+        })?;
+        Ok(Self {
+            path: path.to_owned(),
+            users: RwLock::new(users),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Returns the users, one `username [disabled]` per line.
+    pub(crate) fn list(&self) -> String {
+        let users = self.users.read().map(|users| {
+            let mut names = users
+                .iter()
+                .map(|(name, user)| {
+                    if user.disabled {
+                        format!("{name} disabled\n")
+                    } else {
+                        format!("{name}\n")
+                    }
+                })
+                .collect::<Vec<_>>();
+            names.sort_unstable();
+            names.concat()
+        });
+        users.unwrap_or_default()
+    }
+
+    /// Adds the user `name`, or replaces its password and settings, from
+    /// `password [settings]`, then saves the file.
+    pub(crate) async fn put(&self, name: &str, settings: &str) -> Result<(), String> {
+        if name.is_empty() || name.contains([':', ' ']) {
+            return Err(format!("invalid username: {name}"));
+        }
+        let (name, user) = parse_line(&format!("{name}:{}", settings.trim()))?;
+        self.update(|users| {
+            users.insert(name, Arc::new(user));
+        })
+        .await
+        .map_err(|err| err.to_string())
+    }
+
+    /// Disables or enables the user `name`, then saves the file. Returns
+    /// whether the user exists.
+    pub(crate) async fn set_disabled(&self, name: &str, disabled: bool) -> io::Result<bool> {
+        let mut found = false;
+        self.update(|users| {
+            if let Some(user) = users.get_mut(name) {
+                found = true;
+                *user = Arc::new(User::new(user.password.clone(), user.pool, disabled));
+            }
+        })
+        .await?;
+        Ok(found)
+    }
+
+    /// Applies `change` to a copy of the users and writes it to the file,
+    /// then makes it current. Logins keep being served by the current users
+    /// meanwhile, and a failed write leaves them unchanged.
+    async fn update<F>(&self, change: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut HashMap<String, Arc<User>>),
+    {
+        let _saving = self.saving.lock().await;
+        let mut users = self
+            .users
+            .read()
+            .map_err(|_| io::Error::other("users lock poisoned"))?
+            .clone();
+        change(&mut users);
+
+        let mut lines = users
+            .iter()
+            .map(|(name, user)| format!("{name}:{user}\n"))
+            .collect::<Vec<_>>();
+        lines.sort_unstable();
+
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || crate::file::replace(&path, lines.concat().as_bytes()))
+            .await
+            .map_err(io::Error::other)??;
+
+        *self
+            .users
+            .write()
+            .map_err(|_| io::Error::other("users lock poisoned"))? = users;
+        Ok(())
+    }
+
+    /// Returns the number of users.
+    pub(crate) fn len(&self) -> usize {
+        self.users.read().map_or(0, |users| users.len())
+    }
+
+    /// Returns the distinct CIDRs of the users' pools.
+    pub(crate) fn cidrs(&self) -> Vec<IpCidr> {
+        let mut cidrs = self.users.read().map_or_else(
+            |_| Vec::new(),
+            |users| users.values().filter_map(|user| user.pool.cidr).collect(),
+        );
+        cidrs.sort_unstable_by_key(|cidr| cidr.to_string());
+        cidrs.dedup();
+        cidrs
+    }
+
+    /// Returns the pool of the user `name`, if there is such a user.
+    pub(crate) fn pool(&self, name: &str) -> Option<Pool> {
+        let users = self.users.read().ok()?;
+        users.get(name).map(|user| user.pool)
+    }
+
+    /// Returns the users with their pools, sorted by name.
+    pub(crate) fn pools(&self) -> Vec<(String, Pool)> {
+        let mut pools = self.users.read().map_or_else(
+            |_| Vec::new(),
+            |users| {
+                users
+                    .iter()
+                    .map(|(name, user)| (name.clone(), user.pool))
+                    .collect()
+            },
+        );
+        pools.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        pools
+    }
+
+    /// Checks the credentials of a client, returning the name of the matched
+    /// user, which `username` may extend, and the user's pool.
+    pub(crate) async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Option<(String, Pool)> {
+        let (name, user) = self.find(username)?;
+        if user.disabled {
+            return None;
+        }
+
+        let pool = user.pool;
+        user.verify(password).await.then_some((name, pool))
+    }
+
+    /// Finds the user `username` logs in as: the longest user name it starts
+    /// with, followed by an extension.
+    fn find(&self, username: &str) -> Option<(String, Arc<User>)> {
+        let users = self.users.read().ok()?;
+
+        let candidates = std::iter::once(username).chain(
+            username
+                .rmatch_indices('-')
+                .map(|(index, _)| &username[..index]),
+        );
+        for name in candidates {
+            if let Some(user) = users.get(name) {
+                return Some((name.to_owned(), user.clone()));
+            }
+        }
+
+        None
+    }
+}
+
+/// Parses the content of a credential file.
+fn parse(content: &str) -> Result<HashMap<String, Arc<User>>, String> {
+    let mut users = HashMap::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, user) =
+            parse_line(line).map_err(|err| format!("line {}: {}", number + 1, err))?;
+        if users.insert(name.clone(), Arc::new(user)).is_some() {
+            return Err(format!("line {}: duplicate user: {}", number + 1, name));
+        }
+    }
+
+    Ok(users)
+}
+
+/// Parses a `username:password [settings]` line.
+fn parse_line(line: &str) -> Result<(String, User), String> {
+    let mut fields = line.split_whitespace();
+    let (name, password) = fields
+        .next()
+        .and_then(|field| field.split_once(':'))
+        .filter(|(name, password)| !name.is_empty() && !password.is_empty())
+        .ok_or_else(|| "expected username:password".to_owned())?;
+
+    let mut user = User::new(Secret::new(password), Pool::default(), false);
+    for field in fields {
+        if field == "disabled" {
+            user.disabled = true;
+            continue;
+        }
+
+        let (key, value) = field
+            .split_once('=')
+            .ok_or_else(|| format!("expected key=value: {field}"))?;
+        user.pool.set(key, value)?;
+    }
+
+    Ok((name.to_owned(), user))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_and_authenticate() {
+        let users = Users {
+            path: PathBuf::new(),
+            users: RwLock::new(
+                parse(
+                    "# customers\n\
+                     alice:secret cidr=2001:db8:1::/48 cidr-range=64\n\
+                     alice-eu:other fallback=192.0.2.10\n",
+                )
+                .unwrap(),
+            ),
+            saving: tokio::sync::Mutex::new(()),
+        };
+
+        let (name, pool) = users
+            .authenticate("alice-session-1", "secret")
+            .await
+            .unwrap();
+        assert_eq!(name, "alice");
+        assert_eq!(pool.cidr, Some("2001:db8:1::/48".parse().unwrap()));
+        assert_eq!(pool.cidr_range, Some(64));
+
+        let (name, pool) = users.authenticate("alice-eu-ttl-5", "other").await.unwrap();
+        assert_eq!(name, "alice-eu");
+        assert_eq!(pool.fallback, Some("192.0.2.10".parse().unwrap()));
+
+        assert!(users.authenticate("alice", "other").await.is_none());
+        assert!(users.authenticate("bob", "secret").await.is_none());
+        assert!(parse("carol:pw color=red").is_err());
+
+        let (name, user) = parse_line("dave:pw cidr-range=64 disabled").unwrap();
+        assert_eq!(format!("{name}:{user}"), "dave:pw cidr-range=64 disabled");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_update() {
+        let path = std::env::temp_dir().join(format!("vproxy-users-{}", std::process::id()));
+        std::fs::write(&path, "alice:secret\n").unwrap();
+        let users = Users::load(&path).unwrap();
+
+        users.put("bob", "pw cidr-range=64").await.unwrap();
+        assert!(users.set_disabled("alice", true).await.unwrap());
+        assert_eq!(users.list(), "alice disabled\nbob\n");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "alice:secret disabled\nbob:pw cidr-range=64\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // A change that cannot be saved is not applied
+        let users = Users {
+            path: path.join("users"),
+            ..users
+        };
+        assert!(users.put("carol", "pw").await.is_err());
+        assert_eq!(users.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_hashed_password() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let (_, user) = parse_line(&format!("erin:{hash}")).unwrap();
+        assert!(matches!(user.password, Secret::Bcrypt(_)));
+
+        let user = Arc::new(user);
+        assert!(!user.clone().verify("wrong").await);
+        assert!(user.clone().verify("secret").await);
+        // Answered from the cache
+        assert!(user.verify("secret").await);
+    }
+}